@@ -0,0 +1,432 @@
+//! Compression of rotated-out files.
+use std::fs;
+use std::io::{Cursor, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+
+/// Level of gzip unless another one is given.
+pub const DEFAULT_GZIP_LEVEL: i32 = 9;
+
+/// Level of zip unless another one is given.
+pub const DEFAULT_ZIP_LEVEL: i32 = 6;
+
+/// Level of zstd unless another one is given.
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Preset of xz unless another one is given.
+pub const DEFAULT_XZ_LEVEL: i32 = 6;
+
+/// Level of bzip2 unless another one is given.
+pub const DEFAULT_BZIP2_LEVEL: i32 = 9;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    GZip,
+    Zip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    /// LZ4 frame format, much faster than the others at a lower ratio
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// XZ, for archives kept for years where ratio matters more than speed
+    #[cfg(feature = "xz")]
+    Xz,
+    #[cfg(feature = "bzip2")]
+    Bzip2,
+}
+
+impl Compression {
+    /// Every compression enabled in this build.
+    pub(crate) const ALL: &'static [Compression] = &[
+        Compression::GZip,
+        Compression::Zip,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4,
+        #[cfg(feature = "xz")]
+        Compression::Xz,
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2,
+    ];
+
+    /// Returns the file extension of the compressed output, e.g. `.gz`.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::GZip => ".gz",
+            Compression::Zip => ".zip",
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => ".zst",
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => ".lz4",
+            #[cfg(feature = "xz")]
+            Compression::Xz => ".xz",
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => ".bz2",
+        }
+    }
+
+    /// Returns the level used unless another one is given, 9 for gzip, 6
+    /// for zip, 3 for zstd, the preset 6 for xz and 9 for bzip2, see the
+    /// `DEFAULT_*_LEVEL` constants. LZ4 has no levels and ignores it.
+    pub fn default_level(&self) -> i32 {
+        match self {
+            Compression::GZip => DEFAULT_GZIP_LEVEL,
+            Compression::Zip => DEFAULT_ZIP_LEVEL,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => DEFAULT_ZSTD_LEVEL,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => 0,
+            #[cfg(feature = "xz")]
+            Compression::Xz => DEFAULT_XZ_LEVEL,
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => DEFAULT_BZIP2_LEVEL,
+        }
+    }
+
+    /// Returns this compression at `level`, e.g. gzip at 1 for speed or zstd
+    /// at 19 for ratio.
+    pub fn with_level(self, level: i32) -> CompressionLevel {
+        CompressionLevel {
+            compression: self,
+            level,
+        }
+    }
+}
+
+/// A compression method at a given level.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CompressionLevel {
+    pub compression: Compression,
+    pub level: i32,
+}
+
+/// What to do when the output path of compression already exists, e.g. a
+/// stale archive left behind by an earlier run.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Overwrite the existing archive.
+    Truncate,
+    /// Fail the compression and keep the uncompressed file.
+    Error,
+    /// Write to `{file}.1.gz`, `{file}.2.gz`, ... instead.
+    UniqueSuffix,
+}
+
+/// Compresses a rotated-out file.
+pub trait Compressor: Send + Sync {
+    /// Returns the file extension of the compressed output, e.g. `.gz`.
+    fn extension(&self) -> &str;
+
+    /// Compresses the content of the file named `file_name`, last modified
+    /// at `modified`, from `reader` to `writer`.
+    ///
+    /// Formats that record it should store `modified` rather than the
+    /// current time.
+    fn compress(
+        &self,
+        file_name: &str,
+        modified: SystemTime,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+    ) -> Result<(), Error>;
+}
+
+impl Compressor for Compression {
+    fn extension(&self) -> &str {
+        Compression::extension(self)
+    }
+
+    fn compress(
+        &self,
+        file_name: &str,
+        modified: SystemTime,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+    ) -> Result<(), Error> {
+        self.with_level(self.default_level())
+            .compress(file_name, modified, reader, writer)
+    }
+}
+
+impl Compressor for CompressionLevel {
+    fn extension(&self) -> &str {
+        self.compression.extension()
+    }
+
+    fn compress(
+        &self,
+        file_name: &str,
+        modified: SystemTime,
+        reader: &mut dyn Read,
+        writer: &mut dyn Write,
+    ) -> Result<(), Error> {
+        match self.compression {
+            Compression::GZip => {
+                let mtime = modified
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                let mut encoder = flate2::GzBuilder::new().mtime(mtime as u32).write(
+                    writer,
+                    flate2::Compression::new(self.level.clamp(0, 9) as u32),
+                );
+                std::io::copy(reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+            Compression::Zip => {
+                let mut options =
+                    zip::write::FileOptions::default().compression_level(Some(self.level));
+                // zip stores local time, and only years from 1980 to 2107
+                let dt: DateTime<Local> = modified.into();
+                if let Ok(dt) = zip::DateTime::from_date_and_time(
+                    dt.year() as u16,
+                    dt.month() as u8,
+                    dt.day() as u8,
+                    dt.hour() as u8,
+                    dt.minute() as u8,
+                    dt.second() as u8,
+                ) {
+                    options = options.last_modified_time(dt);
+                }
+                // zip needs to seek, so build the archive in memory
+                let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+                zip.start_file(file_name, options)?;
+                std::io::copy(reader, &mut zip)?;
+                writer.write_all(&zip.finish()?.into_inner())?;
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                zstd::stream::copy_encode(reader, writer, self.level)?;
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(writer);
+                std::io::copy(reader, &mut encoder)?;
+                encoder.finish().map_err(Error::other)?;
+            }
+            #[cfg(feature = "xz")]
+            Compression::Xz => {
+                let mut encoder = xz2::write::XzEncoder::new(writer, self.level.clamp(0, 9) as u32);
+                std::io::copy(reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+            #[cfg(feature = "bzip2")]
+            Compression::Bzip2 => {
+                let level = bzip2::Compression::new(self.level.clamp(1, 9) as u32);
+                let mut encoder = bzip2::write::BzEncoder::new(writer, level);
+                std::io::copy(reader, &mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Deletes an archive left incomplete by a failing or panicking compressor
+/// when dropped, unless disarmed.
+struct Partial<'a>(Option<&'a Path>);
+
+impl Drop for Partial<'_> {
+    fn drop(&mut self) {
+        if let Some(path) = self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Compresses `file` next to it and deletes it, returns the path of the
+/// output and the applied policy if the output path already existed.
+pub(crate) fn compress_file(
+    file: &Path,
+    compressor: &dyn Compressor,
+    conflict: ConflictPolicy,
+) -> Result<(PathBuf, Option<ConflictPolicy>), Error> {
+    let extension = compressor.extension();
+    let mut out_file_path = file.as_os_str().to_owned();
+    out_file_path.push(extension);
+
+    let mut conflicted = None;
+    let mut index = 0;
+    let mut out_file = loop {
+        let result = if conflict == ConflictPolicy::Truncate {
+            if Path::new(out_file_path.as_os_str()).exists() {
+                conflicted = Some(conflict);
+            }
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(out_file_path.as_os_str())
+        } else {
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(out_file_path.as_os_str())
+        };
+        match result {
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                conflicted = Some(conflict);
+                if conflict == ConflictPolicy::Error {
+                    return Err(e);
+                }
+                index += 1;
+                out_file_path = file.as_os_str().to_owned();
+                out_file_path.push(format!(".{}{}", index, extension));
+            }
+            result => break result?,
+        }
+    };
+
+    // the raw file is the only copy until the archive is complete
+    let mut partial = Partial(Some(Path::new(out_file_path.as_os_str())));
+    let file_name = file.file_name().unwrap().to_str().unwrap();
+    let mut input = fs::File::open(file)?;
+    let metadata = input.metadata()?;
+    let modified = metadata.modified()?;
+    compressor.compress(file_name, modified, &mut input, &mut out_file)?;
+    out_file.flush()?;
+
+    // keep sorting and retention by age working after compression
+    out_file.set_modified(modified)?;
+    out_file.set_permissions(metadata.permissions())?;
+    partial.0 = None;
+    drop(partial);
+
+    fs::remove_file(file)?;
+    Ok((PathBuf::from(out_file_path), conflicted))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{Compression, ConflictPolicy};
+
+    #[test]
+    fn preserve_modified_time() {
+        let root_dir = "./target/tmp25";
+        let _ = std::fs::remove_dir_all(root_dir);
+        std::fs::create_dir_all(root_dir).unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(1_714_521_600);
+
+        for compression in [Compression::GZip, Compression::Zip] {
+            let file = Path::new(root_dir).join("2024-05-01-00-00-00.log");
+            std::fs::write(&file, "The quick brown fox jumps over the lazy dog\n").unwrap();
+            let f = std::fs::File::options().write(true).open(&file).unwrap();
+            f.set_modified(modified).unwrap();
+            drop(f);
+
+            let (out, _) =
+                super::compress_file(&file, &compression, ConflictPolicy::Truncate).unwrap();
+            let metadata = std::fs::metadata(&out).unwrap();
+            assert_eq!(modified, metadata.modified().unwrap());
+
+            match compression {
+                Compression::GZip => {
+                    let file = std::fs::File::open(&out).unwrap();
+                    let mut decoder = flate2::read::GzDecoder::new(file);
+                    decoder.read_to_end(&mut Vec::new()).unwrap();
+                    assert_eq!(1_714_521_600, decoder.header().unwrap().mtime());
+                }
+                Compression::Zip => {
+                    let file = std::fs::File::open(&out).unwrap();
+                    let mut zip = zip::ZipArchive::new(file).unwrap();
+                    // stored in local time
+                    let dt = zip.by_index(0).unwrap().last_modified();
+                    assert_eq!(2024, dt.year());
+                    assert!((4..=5).contains(&dt.month()));
+                }
+                #[cfg(feature = "zstd")]
+                Compression::Zstd => unreachable!(),
+                #[cfg(feature = "lz4")]
+                Compression::Lz4 => unreachable!(),
+                #[cfg(feature = "xz")]
+                Compression::Xz => unreachable!(),
+                #[cfg(feature = "bzip2")]
+                Compression::Bzip2 => unreachable!(),
+            }
+        }
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4() {
+        let root_dir = "./target/tmp100";
+        let _ = std::fs::remove_dir_all(root_dir);
+        std::fs::create_dir_all(root_dir).unwrap();
+        let file = Path::new(root_dir).join("2024-05-01-00-00-00.log");
+        let text = "The quick brown fox jumps over the lazy dog\n".repeat(100);
+        std::fs::write(&file, &text).unwrap();
+
+        let (out, _) =
+            super::compress_file(&file, &Compression::Lz4, ConflictPolicy::Truncate).unwrap();
+        assert!(out.to_str().unwrap().ends_with(".log.lz4"));
+        assert!(!file.exists());
+        let bytes = std::fs::read(&out).unwrap();
+        // the magic number of the frame format
+        assert_eq!([0x04, 0x22, 0x4d, 0x18], bytes[..4]);
+        assert!(bytes.len() < text.len());
+        let mut content = String::new();
+        lz4_flex::frame::FrameDecoder::new(bytes.as_slice())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(text, content);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[cfg(all(feature = "xz", feature = "bzip2"))]
+    #[test]
+    fn archival() {
+        let root_dir = "./target/tmp101";
+        let _ = std::fs::remove_dir_all(root_dir);
+        std::fs::create_dir_all(root_dir).unwrap();
+        let file = Path::new(root_dir).join("2024-05-01-00-00-00.log");
+        let text = "The quick brown fox jumps over the lazy dog\n".repeat(100);
+        let compress = |compressor: &dyn super::Compressor| {
+            std::fs::write(&file, &text).unwrap();
+            let (out, _) =
+                super::compress_file(&file, compressor, ConflictPolicy::Truncate).unwrap();
+            let bytes = std::fs::read(&out).unwrap();
+            std::fs::remove_file(out).unwrap();
+            bytes
+        };
+
+        for preset in [0, 9] {
+            let bytes = compress(&Compression::Xz.with_level(preset));
+            assert_eq!(b"\xfd7zXZ\0", &bytes[..6]);
+            let mut content = String::new();
+            xz2::read::XzDecoder::new(bytes.as_slice())
+                .read_to_string(&mut content)
+                .unwrap();
+            assert_eq!(text, content);
+        }
+
+        // the block size is the level, stored in the header
+        for (compressor, header) in [
+            (Compression::Bzip2.with_level(1), b"BZh1"),
+            (
+                Compression::Bzip2.with_level(Compression::Bzip2.default_level()),
+                b"BZh9",
+            ),
+        ] {
+            let bytes = compress(&compressor);
+            assert_eq!(header, &bytes[..4]);
+            let mut content = String::new();
+            bzip2::read::BzDecoder::new(bytes.as_slice())
+                .read_to_string(&mut content)
+                .unwrap();
+            assert_eq!(text, content);
+        }
+        assert_eq!(".xz", Compression::Xz.extension());
+        assert_eq!(".bz2", Compression::Bzip2.extension());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+}
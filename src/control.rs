@@ -0,0 +1,103 @@
+//! A control socket to rotate, flush or inspect a running instance from
+//! another process, see `RotatingFile::serve_control`.
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::*;
+
+use crate::{Inner, RotationTrigger};
+
+// how often to check for new connections and whether to stop
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Binds the socket at `path` and serves it in a background thread until
+/// the instance is closed or dropped.
+pub(crate) fn spawn(
+    inner: Weak<Inner>,
+    path: &Path,
+    thread_name: String,
+) -> Result<JoinHandle<()>, Error> {
+    // a socket left behind by an earlier run
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    listener.set_nonblocking(true)?;
+    let path = path.to_path_buf();
+
+    std::thread::Builder::new()
+        .name(thread_name)
+        .spawn(move || {
+            loop {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let Some(inner) = inner.upgrade() else {
+                            break;
+                        };
+                        if let Err(e) = serve(&inner, stream) {
+                            error!("{}", e);
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                        match inner.upgrade() {
+                            Some(inner) if !inner.threads.stopped.load(Ordering::Acquire) => {}
+                            _ => break,
+                        }
+                        std::thread::sleep(POLL_INTERVAL);
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        std::thread::sleep(POLL_INTERVAL);
+                    }
+                }
+            }
+            let _ = fs::remove_file(&path);
+        })
+}
+
+fn serve(inner: &Arc<Inner>, stream: UnixStream) -> Result<(), Error> {
+    stream.set_nonblocking(false)?;
+    // wake up now and then to stop with the instance
+    stream.set_read_timeout(Some(POLL_INTERVAL))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    let mut line = String::new();
+    loop {
+        match reader.read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if inner.threads.stopped.load(Ordering::Acquire) {
+                    return Ok(());
+                }
+                // a partial line is kept in `line`
+                continue;
+            }
+            Err(e) => return Err(e),
+        }
+        let response = match line.trim() {
+            "rotate" => inner
+                .rotate(RotationTrigger::External)
+                .map(|_| "ok".to_string()),
+            "flush" => inner.flush().map(|_| "ok".to_string()),
+            "status" => Ok(inner.status()),
+            command => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown command {}", command),
+            )),
+        };
+        match response {
+            Ok(response) => writeln!(writer, "{}", response)?,
+            Err(e) => writeln!(writer, "error: {}", e)?,
+        }
+        line.clear();
+    }
+}
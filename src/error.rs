@@ -0,0 +1,74 @@
+//! The error type of `RotatingFile`.
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/// Why an operation of a `RotatingFile` failed.
+///
+/// Converts to and from `io::Error`, so `?` works in functions returning
+/// either.
+#[derive(Debug)]
+pub enum RotatingFileError {
+    /// Writing, flushing or creating a file failed, e.g. the disk is full
+    Io(Error),
+    /// Compressing, or running the pipeline on, a rotated-out file failed
+    Compression(Error),
+    /// A thread panicked, while writing or in the background
+    Poisoned,
+    /// The configuration can't work, e.g. an invalid date format
+    InvalidConfig(String),
+}
+
+impl RotatingFileError {
+    /// Returns the kind of the underlying `io::Error`, if any, e.g. to
+    /// check for `ErrorKind::StorageFull`.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RotatingFileError::Io(e) | RotatingFileError::Compression(e) => e.kind(),
+            RotatingFileError::Poisoned => ErrorKind::Other,
+            RotatingFileError::InvalidConfig(_) => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+impl fmt::Display for RotatingFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RotatingFileError::Io(e) => write!(f, "{}", e),
+            RotatingFileError::Compression(e) => write!(f, "compression failed: {}", e),
+            RotatingFileError::Poisoned => write!(f, "a thread panicked"),
+            RotatingFileError::InvalidConfig(reason) => {
+                write!(f, "invalid configuration: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RotatingFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RotatingFileError::Io(e) | RotatingFileError::Compression(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for RotatingFileError {
+    /// Unwraps a `RotatingFileError::Poisoned` carried in `e`, see the
+    /// conversion the other way.
+    fn from(e: Error) -> Self {
+        let inner = e.get_ref().and_then(|inner| inner.downcast_ref());
+        if let Some(RotatingFileError::Poisoned) = inner {
+            return RotatingFileError::Poisoned;
+        }
+        RotatingFileError::Io(e)
+    }
+}
+
+impl From<RotatingFileError> for Error {
+    fn from(e: RotatingFileError) -> Self {
+        match e {
+            RotatingFileError::Io(e) => e,
+            e => Error::new(e.kind(), e),
+        }
+    }
+}
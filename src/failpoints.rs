@@ -0,0 +1,136 @@
+//! Failure injection, so that applications can test how they recover from
+//! errors of a `RotatingFile` deterministically, e.g. in CI. Behind the
+//! `failpoints` feature.
+//!
+//! ## Example
+//!
+//! ```
+//! use rotating_file::failpoints::FailPoint;
+//! use rotating_file::RotatingFile;
+//!
+//! let root_dir = "./target/tmp-failpoints";
+//! let rotating_file = RotatingFile::new(root_dir, None, None, None, None, None, None);
+//! rotating_file.failpoints().fail(FailPoint::Open, 1);
+//! assert!(rotating_file.writeln("first").is_err());
+//! assert!(rotating_file.writeln("written").is_ok());
+//! rotating_file.close();
+//! # std::fs::remove_dir_all(root_dir).unwrap();
+//! ```
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::Mutex;
+
+/// Where a failure can be injected.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FailPoint {
+    /// Creating the next file
+    Open,
+    /// Writing a line or buffer to the active file
+    Write,
+    /// Compressing a rotated-out file
+    Compress,
+    /// Renaming a rotated-out file, with range or reason naming
+    Rename,
+}
+
+/// The failures armed for an instance, see `RotatingFile::failpoints`.
+#[derive(Debug, Default)]
+pub struct FailPoints {
+    // how many more times each point fails, and with what
+    armed: Mutex<HashMap<FailPoint, (usize, ErrorKind)>>,
+}
+
+impl FailPoints {
+    /// Makes the next `times` operations at `point` fail with
+    /// `ErrorKind::Other`.
+    pub fn fail(&self, point: FailPoint, times: usize) {
+        self.fail_with(point, times, ErrorKind::Other);
+    }
+
+    /// Makes the next `times` operations at `point` fail with `kind`, e.g.
+    /// `ErrorKind::StorageFull` or `ErrorKind::ReadOnlyFilesystem`.
+    pub fn fail_with(&self, point: FailPoint, times: usize, kind: ErrorKind) {
+        let mut armed = self.armed.lock().unwrap();
+        if times == 0 {
+            armed.remove(&point);
+        } else {
+            armed.insert(point, (times, kind));
+        }
+    }
+
+    /// Disarms every point.
+    pub fn clear(&self) {
+        self.armed.lock().unwrap().clear();
+    }
+
+    /// Fails if `point` is armed, counting the failure.
+    pub(crate) fn check(&self, point: FailPoint) -> Result<(), Error> {
+        let mut armed = self.armed.lock().unwrap();
+        let Some((times, kind)) = armed.get_mut(&point) else {
+            return Ok(());
+        };
+        let kind = *kind;
+        *times -= 1;
+        if *times == 0 {
+            armed.remove(&point);
+        }
+        Err(Error::new(kind, format!("injected failure at {:?}", point)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::FailPoint;
+    use crate::{Compression, RotatingFile};
+
+    #[test]
+    fn failpoints() {
+        let root_dir = "./target/tmp71";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = RotatingFile::new(
+            root_dir,
+            None,
+            None,
+            Some(Compression::GZip),
+            None,
+            None,
+            None,
+        );
+        let failpoints = rotating_file.failpoints();
+
+        failpoints.fail_with(FailPoint::Write, 2, ErrorKind::StorageFull);
+        rotating_file.writeln("first").unwrap();
+        for _ in 0..2 {
+            let e = rotating_file.writeln("lost").unwrap_err();
+            assert_eq!(ErrorKind::StorageFull, e.kind());
+        }
+        rotating_file.writeln("second").unwrap();
+
+        // the file is left uncompressed
+        failpoints.fail(FailPoint::Compress, 1);
+        let rotated = rotating_file.rotate().unwrap();
+        assert!(rotating_file.try_close().is_err());
+        assert_eq!("first\nsecond\n", std::fs::read_to_string(rotated).unwrap());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn failed_open() {
+        let root_dir = "./target/tmp107";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = RotatingFile::new(root_dir, None, None, None, None, None, None);
+        rotating_file.failpoints().fail(FailPoint::Open, 1);
+
+        // reported as lost, so never written by the next successful write
+        assert!(rotating_file.writeln("lost").is_err());
+        rotating_file.writeln("first").unwrap();
+        let rotated = rotating_file.rotate().unwrap();
+        assert_eq!("first\n", std::fs::read_to_string(rotated).unwrap());
+        rotating_file.close();
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+}
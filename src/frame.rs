@@ -0,0 +1,202 @@
+//! Length-prefixed binary frames, e.g. bincode-encoded payloads, written to
+//! a rotating file and read back.
+//!
+//! Each frame is a little-endian `u32` length, a flags byte and the payload.
+//! Files only rotate between frames, so each file can be read on its own.
+//!
+//! ## Example
+//!
+//! ```
+//! use rotating_file::{frame::{FrameReader, FrameWriter}, reader, RotatingFile};
+//!
+//! let root_dir = "./target/tmp-frame";
+//! let writer = FrameWriter::new(RotatingFile::new(root_dir, None, None, None, None, None, None));
+//! writer.write(b"\x01\x02\x03").unwrap();
+//! writer.close();
+//!
+//! let files = reader::files(root_dir, writer.get_ref().naming()).unwrap();
+//! let frames = FrameReader::new(files).collect::<Result<Vec<_>, _>>().unwrap();
+//! assert_eq!(vec![vec![1, 2, 3]], frames);
+//! # std::fs::remove_dir_all(root_dir).unwrap();
+//! ```
+use std::io::{BufRead, Error, ErrorKind, Read, Write};
+use std::path::PathBuf;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use crate::reader;
+use crate::{RotatingFile, RotatingFileError};
+
+// the payload is deflate-compressed
+const COMPRESSED: u8 = 1;
+
+/// Writes frames to a rotating file.
+///
+/// The line options of the file, e.g. sequence numbers or checksums, don't
+/// apply to frames, and frames shouldn't be mixed with lines.
+pub struct FrameWriter {
+    file: RotatingFile,
+    compression: Option<flate2::Compression>,
+}
+
+impl FrameWriter {
+    /// Writes frames to `file`, uncompressed.
+    pub fn new(file: RotatingFile) -> Self {
+        FrameWriter {
+            file,
+            compression: None,
+        }
+    }
+
+    /// Compresses each frame with deflate at `level`, 0-9.
+    pub fn with_compression(mut self, level: u32) -> Self {
+        self.compression = Some(flate2::Compression::new(level));
+        self
+    }
+
+    /// Returns the rotating file written to, e.g. for its naming.
+    pub fn get_ref(&self) -> &RotatingFile {
+        &self.file
+    }
+
+    /// Writes `payload` as a single frame.
+    pub fn write(&self, payload: &[u8]) -> Result<(), RotatingFileError> {
+        let (flags, payload) = match self.compression {
+            Some(level) => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), level);
+                encoder.write_all(payload)?;
+                (COMPRESSED, encoder.finish()?)
+            }
+            None => (0, payload.to_vec()),
+        };
+        let len = u32::try_from(payload.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "frame too large"))?;
+
+        let mut frame = Vec::with_capacity(payload.len() + 5);
+        frame.extend_from_slice(&len.to_le_bytes());
+        frame.push(flags);
+        frame.extend_from_slice(&payload);
+        self.file.write_all(&frame)
+    }
+
+    /// Closes the rotating file, see `RotatingFile::close`.
+    pub fn close(&self) {
+        self.file.close();
+    }
+}
+
+/// Reads the frames of files written by a [`FrameWriter`], in order.
+///
+/// A frame cut short at the end of a file, e.g. by a crash, yields an error
+/// of kind `UnexpectedEof`.
+pub struct FrameReader {
+    files: std::vec::IntoIter<PathBuf>,
+    reader: Option<Box<dyn BufRead>>,
+}
+
+impl FrameReader {
+    /// Creates a reader of `files`, e.g. as listed by [`reader::files`].
+    pub fn new(files: Vec<PathBuf>) -> Self {
+        FrameReader {
+            files: files.into_iter(),
+            reader: None,
+        }
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        loop {
+            if let Some(reader) = self.reader.as_mut() {
+                if !reader.fill_buf()?.is_empty() {
+                    let mut header = [0u8; 5];
+                    reader.read_exact(&mut header)?;
+                    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+                    // grown as read, a corrupt length mustn't allocate up front
+                    let mut payload = Vec::new();
+                    reader.take(len).read_to_end(&mut payload)?;
+                    if (payload.len() as u64) < len {
+                        return Err(ErrorKind::UnexpectedEof.into());
+                    }
+                    if header[4] & COMPRESSED != 0 {
+                        let mut decompressed = Vec::new();
+                        DeflateDecoder::new(payload.as_slice()).read_to_end(&mut decompressed)?;
+                        payload = decompressed;
+                    }
+                    return Ok(Some(payload));
+                }
+            }
+            match self.files.next() {
+                Some(path) => self.reader = Some(reader::open(path)?),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Iterator for FrameReader {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameReader, FrameWriter};
+    use crate::{reader, Compression, RotatingFile};
+
+    #[test]
+    fn frames_across_rotations() {
+        let root_dir = "./target/tmp35";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let writer = FrameWriter::new(RotatingFile::new(
+            root_dir,
+            Some(1),
+            None,
+            Some(Compression::GZip),
+            None,
+            None,
+            None,
+        ))
+        .with_compression(6);
+
+        // 300 bytes of noise each, 3 fit in a kilobyte even uncompressed
+        let payloads = (0..10u32)
+            .map(|i| {
+                (0..300u32)
+                    .map(|j| (i.wrapping_mul(7919) ^ j.wrapping_mul(104729)) as u8)
+                    .collect::<Vec<u8>>()
+            })
+            .collect::<Vec<_>>();
+        for payload in payloads.iter() {
+            writer.write(payload).unwrap();
+        }
+        writer.close();
+
+        let files = reader::files(root_dir, writer.get_ref().naming()).unwrap();
+        assert!(files.len() > 1);
+        let frames = FrameReader::new(files)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(payloads, frames);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_length() {
+        let root_dir = "./target/tmp111";
+        let _ = std::fs::remove_dir_all(root_dir);
+        std::fs::create_dir_all(root_dir).unwrap();
+        // claims 4 GiB, holds 3 bytes
+        let path = std::path::Path::new(root_dir).join("2024-05-01-00-00-00.log");
+        std::fs::write(&path, b"\xff\xff\xff\xff\x00abc").unwrap();
+
+        let mut frames = FrameReader::new(vec![path]);
+        let e = frames.next().unwrap().unwrap_err();
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, e.kind());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+}
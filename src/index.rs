@@ -0,0 +1,303 @@
+//! Per-file token bloom filters, so that searches skip files that can't
+//! contain a token without decompressing them, and sparse line indexes, so
+//! that readers seek within large plaintext files without scanning them.
+//!
+//! A filter is saved next to each rotated-out file as `{file}.bloom` once it
+//! is finalized, see `RotatingFile::with_bloom_index`. Tokens are the runs of
+//! ASCII letters, digits and `_` in the written bytes, case-sensitive.
+//!
+//! A line index is saved as `{file}.lines` next to each rotated-out file
+//! left uncompressed, see `RotatingFile::with_line_index` and
+//! [`reader::open_at_line`](crate::reader::open_at_line).
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::naming::NamingConfig;
+
+const MAGIC: &[u8; 4] = b"RFBF";
+
+const LINES_MAGIC: &[u8; 4] = b"RFLI";
+
+// bits set per token
+const HASHES: u32 = 5;
+
+/// A bloom filter over the tokens of a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    words: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter of `bits` bits, rounded up to a multiple of
+    /// 64. More bits mean fewer false positives for files with many
+    /// distinct tokens.
+    pub fn new(bits: usize) -> Self {
+        BloomFilter {
+            words: vec![0; bits.div_ceil(64).max(1)],
+            hashes: HASHES,
+        }
+    }
+
+    /// Adds `token`.
+    pub fn insert(&mut self, token: &[u8]) {
+        for bit in self.bits(token) {
+            self.words[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Adds every token of `buf`.
+    pub fn insert_tokens(&mut self, buf: &[u8]) {
+        for token in tokens(buf) {
+            self.insert(token);
+        }
+    }
+
+    /// Returns false if `token` was never added, true if it may have been.
+    pub fn may_contain(&self, token: &[u8]) -> bool {
+        self.bits(token)
+            .all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn bits(&self, token: &[u8]) -> impl Iterator<Item = usize> {
+        // double hashing, stable across builds since filters are persisted
+        let hash = |seed: u32| {
+            let mut hasher = crc32fast::Hasher::new_with_initial(seed);
+            hasher.update(token);
+            hasher.finalize() as u64
+        };
+        let (h1, h2) = (hash(0), hash(0x9e37_79b9) | 1);
+        let len = self.words.len() as u64 * 64;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+
+    /// Serializes the filter, the inverse of `from_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.words.len() * 8);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&self.hashes.to_le_bytes());
+        for word in self.words.iter() {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Parses a filter serialized with `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid bloom filter");
+        let rest = bytes.strip_prefix(MAGIC).ok_or_else(invalid)?;
+        if rest.len() < 12 || (rest.len() - 4) % 8 != 0 {
+            return Err(invalid());
+        }
+        let (hashes, words) = rest.split_at(4);
+        Ok(BloomFilter {
+            words: words
+                .chunks_exact(8)
+                .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+                .collect(),
+            hashes: u32::from_le_bytes(hashes.try_into().unwrap()),
+        })
+    }
+}
+
+/// Returns the tokens of `buf`, i.e. its runs of ASCII letters, digits and
+/// `_`.
+pub fn tokens(buf: &[u8]) -> impl Iterator<Item = &[u8]> {
+    buf.split(|b| !(b.is_ascii_alphanumeric() || *b == b'_'))
+        .filter(|token| !token.is_empty())
+}
+
+/// Returns where the filter of the file at `path` is saved.
+pub fn sidecar_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut sidecar = path.as_ref().as_os_str().to_owned();
+    sidecar.push(".bloom");
+    PathBuf::from(sidecar)
+}
+
+/// The byte offset and write time of every `every`th line of a file, counting
+/// from 0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LineIndex {
+    every: u64,
+    // line, byte offset and seconds since epoch it was written at
+    entries: Vec<(u64, u64, u64)>,
+    // bytes and lines inserted so far
+    offset: u64,
+    lines: u64,
+    // whether the next byte starts a line
+    at_line_start: bool,
+}
+
+impl LineIndex {
+    /// Creates an empty index of every `every`th line, at least 1. Smaller
+    /// intervals mean shorter scans after seeking at the cost of a bigger
+    /// sidecar, 24 bytes per entry.
+    pub fn new(every: u64) -> Self {
+        LineIndex {
+            every: every.max(1),
+            entries: Vec::new(),
+            offset: 0,
+            lines: 0,
+            at_line_start: true,
+        }
+    }
+
+    /// Indexes the lines starting in `buf`, written at `timestamp` seconds
+    /// since epoch, after everything inserted so far.
+    pub fn insert(&mut self, buf: &[u8], timestamp: u64) {
+        for (i, b) in buf.iter().enumerate() {
+            if self.at_line_start && self.lines.is_multiple_of(self.every) {
+                self.entries
+                    .push((self.lines, self.offset + i as u64, timestamp));
+            }
+            self.at_line_start = *b == b'\n';
+            if self.at_line_start {
+                self.lines += 1;
+            }
+        }
+        self.offset += buf.len() as u64;
+    }
+
+    /// Returns the line and byte offset of the closest indexed line at or
+    /// before `line`, `(0, 0)` if there is none.
+    pub fn seek_line(&self, line: u64) -> (u64, u64) {
+        let i = self.entries.partition_point(|(l, _, _)| *l <= line);
+        self.entry(i)
+    }
+
+    /// Returns the line and byte offset of the last indexed line written
+    /// before `timestamp` seconds since epoch, so that no line written at
+    /// `timestamp` or later comes before it, `(0, 0)` if there is none.
+    pub fn seek_time(&self, timestamp: u64) -> (u64, u64) {
+        let i = self.entries.partition_point(|(_, _, t)| *t < timestamp);
+        self.entry(i)
+    }
+
+    // the entry before the `i`th
+    fn entry(&self, i: usize) -> (u64, u64) {
+        i.checked_sub(1)
+            .map_or((0, 0), |i| (self.entries[i].0, self.entries[i].1))
+    }
+
+    /// Serializes the index, the inverse of `from_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(12 + self.entries.len() * 24);
+        bytes.extend_from_slice(LINES_MAGIC);
+        bytes.extend_from_slice(&self.every.to_le_bytes());
+        for (line, offset, timestamp) in self.entries.iter() {
+            bytes.extend_from_slice(&line.to_le_bytes());
+            bytes.extend_from_slice(&offset.to_le_bytes());
+            bytes.extend_from_slice(&timestamp.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Parses an index serialized with `to_bytes`, it can't be inserted
+    /// into since where the last line ends is unknown.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let invalid = || Error::new(ErrorKind::InvalidData, "invalid line index");
+        let rest = bytes.strip_prefix(LINES_MAGIC).ok_or_else(invalid)?;
+        if rest.len() < 8 || (rest.len() - 8) % 24 != 0 {
+            return Err(invalid());
+        }
+        let (every, entries) = rest.split_at(8);
+        let word = |chunk: &[u8]| u64::from_le_bytes(chunk.try_into().unwrap());
+        let entries = entries
+            .chunks_exact(24)
+            .map(|entry| (word(&entry[..8]), word(&entry[8..16]), word(&entry[16..])))
+            .collect::<Vec<_>>();
+        Ok(LineIndex {
+            every: word(every).max(1),
+            offset: entries.last().map_or(0, |entry| entry.1),
+            lines: entries.last().map_or(0, |entry| entry.0),
+            at_line_start: false,
+            entries,
+        })
+    }
+}
+
+/// Returns where the line index of the file at `path` is saved.
+pub fn line_index_path<P: AsRef<Path>>(path: P) -> PathBuf {
+    let mut sidecar = path.as_ref().as_os_str().to_owned();
+    sidecar.push(".lines");
+    PathBuf::from(sidecar)
+}
+
+/// Returns the line index of the file at `path`, None if it has none, e.g.
+/// because it was compressed.
+pub fn line_index<P: AsRef<Path>>(path: P) -> Result<Option<LineIndex>, Error> {
+    match fs::read(line_index_path(path)) {
+        Ok(bytes) => LineIndex::from_bytes(&bytes).map(Some),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns false if the file at `path` can't contain `token` according to
+/// its filter, true if it may or has no filter, e.g. the active file.
+pub fn may_contain<P: AsRef<Path>>(path: P, token: &str) -> Result<bool, Error> {
+    match fs::read(sidecar_path(path)) {
+        Ok(bytes) => Ok(BloomFilter::from_bytes(&bytes)?.may_contain(token.as_bytes())),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the files in `dir` named with `naming` that may contain `token`,
+/// oldest first, e.g. to grep only those.
+///
+/// Filter by `FileInfo::parse(..).timestamp` on top to search a time range.
+pub fn files_containing<P: AsRef<Path>>(
+    dir: P,
+    naming: &NamingConfig,
+    token: &str,
+) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    for file in crate::reader::files(dir, naming)? {
+        if may_contain(&file, token)? {
+            files.push(file);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BloomFilter, LineIndex};
+
+    #[test]
+    fn bloom_filter() {
+        let mut bloom = BloomFilter::new(1000);
+        bloom.insert_tokens(br#"{"s":"BTCUSDT","p":"64000.5"}"#);
+        assert!(bloom.may_contain(b"BTCUSDT"));
+        assert!(bloom.may_contain(b"64000"));
+        assert!(!bloom.may_contain(b"ETHUSDT"));
+        assert!(!bloom.may_contain(b"btcusdt"));
+
+        let parsed = BloomFilter::from_bytes(&bloom.to_bytes()).unwrap();
+        assert_eq!(bloom, parsed);
+        assert!(BloomFilter::from_bytes(b"RFBF").is_err());
+    }
+
+    #[test]
+    fn line_index() {
+        let mut index = LineIndex::new(2);
+        index.insert(b"a\nbb\n", 100);
+        // a line split across writes starts at the first
+        index.insert(b"cc", 101);
+        index.insert(b"c\nd\ne\n", 102);
+        assert_eq!(vec![(0, 0, 100), (2, 5, 101), (4, 11, 102)], index.entries);
+
+        assert_eq!((0, 0), index.seek_line(1));
+        assert_eq!((2, 5), index.seek_line(3));
+        assert_eq!((4, 11), index.seek_line(100));
+        assert_eq!((0, 0), index.seek_time(100));
+        assert_eq!((2, 5), index.seek_time(102));
+        assert_eq!((4, 11), index.seek_time(200));
+
+        let parsed = LineIndex::from_bytes(&index.to_bytes()).unwrap();
+        assert_eq!(index.entries, parsed.entries);
+        assert!(LineIndex::from_bytes(b"RFLI").is_err());
+    }
+}
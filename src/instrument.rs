@@ -0,0 +1,238 @@
+//! Spans for the crate's own operations, behind the `instrument` feature,
+//! so that they show up in traces rather than only in log lines.
+//!
+//! Spans are emitted with `tracing-core` at target `rotating_file` and level
+//! `INFO`: `rotate`, `compress`, `delete` and `upload`. Each carries the
+//! `path` and size in `bytes` of the file it works on, and records
+//! `elapsed_us` when it ends, `compressed_bytes` once compressed and `error`
+//! if it failed. Without the feature, spans are no-ops.
+use std::io::Error;
+use std::path::Path;
+
+#[cfg(feature = "instrument")]
+use std::time::Instant;
+#[cfg(feature = "instrument")]
+use tracing_core::callsite::{Callsite, DefaultCallsite, Identifier};
+#[cfg(feature = "instrument")]
+use tracing_core::field::{display, FieldSet, Value};
+#[cfg(feature = "instrument")]
+use tracing_core::span::{Attributes, Id, Record};
+#[cfg(feature = "instrument")]
+use tracing_core::{dispatcher, Dispatch, Kind, Level, Metadata};
+
+/// An operation traced with a span.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Op {
+    Rotate,
+    Compress,
+    Delete,
+    Upload,
+}
+
+// fields shared by every span, in the order of the values passed
+#[cfg(feature = "instrument")]
+const FIELDS: &[&str] = &["path", "bytes", "compressed_bytes", "elapsed_us", "error"];
+
+#[cfg(feature = "instrument")]
+macro_rules! callsite {
+    ($callsite:ident, $metadata:ident, $name:literal) => {
+        static $callsite: DefaultCallsite = DefaultCallsite::new(&$metadata);
+        static $metadata: Metadata<'static> = Metadata::new(
+            $name,
+            "rotating_file",
+            Level::INFO,
+            Some(file!()),
+            Some(line!()),
+            Some(module_path!()),
+            FieldSet::new(FIELDS, Identifier(&$callsite)),
+            Kind::SPAN,
+        );
+    };
+}
+
+#[cfg(feature = "instrument")]
+callsite!(ROTATE, ROTATE_METADATA, "rotate");
+#[cfg(feature = "instrument")]
+callsite!(COMPRESS, COMPRESS_METADATA, "compress");
+#[cfg(feature = "instrument")]
+callsite!(DELETE, DELETE_METADATA, "delete");
+#[cfg(feature = "instrument")]
+callsite!(UPLOAD, UPLOAD_METADATA, "upload");
+
+#[cfg(feature = "instrument")]
+impl Op {
+    fn callsite(self) -> &'static DefaultCallsite {
+        match self {
+            Op::Rotate => &ROTATE,
+            Op::Compress => &COMPRESS,
+            Op::Delete => &DELETE,
+            Op::Upload => &UPLOAD,
+        }
+    }
+}
+
+/// The span of an operation, entered on the current thread until dropped.
+pub(crate) struct Span {
+    #[cfg(feature = "instrument")]
+    entered: Option<Entered>,
+}
+
+#[cfg(feature = "instrument")]
+struct Entered {
+    dispatch: Dispatch,
+    id: Id,
+    metadata: &'static Metadata<'static>,
+    start: Instant,
+}
+
+impl Span {
+    /// Enters the span of `op` on the file at `path` of `bytes` bytes, if the
+    /// current subscriber is interested.
+    #[cfg(feature = "instrument")]
+    pub(crate) fn enter(op: Op, path: &Path, bytes: u64) -> Span {
+        let callsite = op.callsite();
+        if callsite.interest().is_never() {
+            return Span { entered: None };
+        }
+        let metadata = callsite.metadata();
+        let entered = dispatcher::get_default(|dispatch| {
+            if !dispatch.enabled(metadata) {
+                return None;
+            }
+            let path = display(path.display());
+            let values: [Option<&dyn Value>; 5] = [Some(&path), Some(&bytes), None, None, None];
+            let values = metadata.fields().value_set_all(&values);
+            let id = dispatch.new_span(&Attributes::new(metadata, &values));
+            dispatch.enter(&id);
+            Some(Entered {
+                dispatch: dispatch.clone(),
+                id,
+                metadata,
+                start: Instant::now(),
+            })
+        });
+        Span { entered }
+    }
+
+    #[cfg(not(feature = "instrument"))]
+    pub(crate) fn enter(_: Op, _: &Path, _: u64) -> Span {
+        Span {}
+    }
+
+    /// Records the size of the archive.
+    pub(crate) fn record_compressed(&self, bytes: u64) {
+        #[cfg(feature = "instrument")]
+        self.record([None, None, Some(&bytes), None, None]);
+        #[cfg(not(feature = "instrument"))]
+        let _ = bytes;
+    }
+
+    /// Records the error of `result`, if any, and returns it.
+    pub(crate) fn result<T>(&self, result: Result<T, Error>) -> Result<T, Error> {
+        #[cfg(feature = "instrument")]
+        if let Err(e) = result.as_ref() {
+            self.record([None, None, None, None, Some(&display(e))]);
+        }
+        result
+    }
+
+    #[cfg(feature = "instrument")]
+    fn record(&self, values: [Option<&dyn Value>; 5]) {
+        if let Some(entered) = self.entered.as_ref() {
+            let values = entered.metadata.fields().value_set_all(&values);
+            entered.dispatch.record(&entered.id, &Record::new(&values));
+        }
+    }
+}
+
+// unconditional, so that dropping a span early reads the same either way
+impl Drop for Span {
+    fn drop(&mut self) {
+        #[cfg(feature = "instrument")]
+        if let Some(entered) = self.entered.take() {
+            let elapsed_us = entered.start.elapsed().as_micros() as u64;
+            let values: [Option<&dyn Value>; 5] = [None, None, None, Some(&elapsed_us), None];
+            let values = entered.metadata.fields().value_set_all(&values);
+            entered.dispatch.record(&entered.id, &Record::new(&values));
+            entered.dispatch.exit(&entered.id);
+            entered.dispatch.try_close(entered.id);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "instrument"))]
+mod tests {
+    use std::fmt::Debug;
+    use std::sync::{Arc, Mutex};
+
+    use tracing_core::field::{Field, Visit};
+    use tracing_core::span::{Attributes, Id, Record};
+    use tracing_core::{dispatcher, Dispatch, Event, Metadata, Subscriber};
+
+    // the name of each span seen, with its fields as `name=value`
+    type Spans = Vec<(&'static str, Vec<String>)>;
+
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Spans>>);
+
+    struct Fields<'a>(&'a mut Vec<String>);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = Vec::new();
+            span.record(&mut Fields(&mut fields));
+            let mut spans = self.0.lock().unwrap();
+            spans.push((span.metadata().name(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.0.lock().unwrap();
+            let (_, fields) = &mut spans[span.into_u64() as usize - 1];
+            values.record(&mut Fields(fields));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, _: &Event<'_>) {}
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn spans() {
+        let root_dir = "./target/tmp87";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let collector = Collector::default();
+        let dispatch = Dispatch::new(collector.clone());
+        let rotating_file = crate::RotatingFile::new(root_dir, None, None, None, None, None, None);
+        dispatcher::with_default(&dispatch, || {
+            rotating_file.writeln("traced").unwrap();
+            let rotated = rotating_file.rotate().unwrap();
+            assert!(rotating_file.delete_rotated(&rotated).unwrap());
+        });
+        rotating_file.close();
+
+        let spans = collector.0.lock().unwrap();
+        let names = spans.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+        assert_eq!(vec!["rotate", "delete"], names);
+        for (_, fields) in spans.iter() {
+            assert!(fields.contains(&"bytes=7".to_string()));
+            assert!(fields.iter().any(|field| field.starts_with("elapsed_us=")));
+        }
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+}
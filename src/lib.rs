@@ -3,14 +3,14 @@
 //! ## Example
 //!
 //! ```
-//! use rotating_file::RotatingFile;
+//! use rotating_file::{ByteSize, RotatingFile};
 //!
 //! let root_dir = "./target/tmp";
 //! let s = "The quick brown fox jumps over the lazy dog";
 //! let _ = std::fs::remove_dir_all(root_dir);
 //!
-//! // rotated by 1 kilobyte, compressed with gzip
-//! let rotating_file = RotatingFile::new(root_dir, Some(1), None, None, None, None, None);
+//! // rotated by 1 kilobyte
+//! let rotating_file = RotatingFile::builder(root_dir).max_size(ByteSize::kb(1)).build();
 //! for _ in 0..24 {
 //!     rotating_file.writeln(s).unwrap();
 //! }
@@ -19,69 +19,589 @@
 //! assert_eq!(2, std::fs::read_dir(root_dir).unwrap().count());
 //! std::fs::remove_dir_all(root_dir).unwrap();
 //! ```
+//!
+//! ## Modules
+//!
+//! The pieces of a `RotatingFile` are split along their seams, each with a
+//! public trait so that they can be swapped out:
+//!
+//! - [`naming`] How files are named and parsed back, see [`NamingConfig`].
+//! - [`policy`] When the active file is rotated out, see [`RotationPolicy`].
+//! - [`compress`] How rotated-out files are compressed, see [`Compressor`].
+//! - [`writer`] Where the bytes of the active file go, see [`Backend`].
+//! - [`streams`] Several streams or instances that rotate together, see
+//!   [`streams::StreamGroup`] and [`streams::RotationGroup`].
+//! - [`frame`] Length-prefixed binary frames, see [`frame::FrameWriter`].
+//! - [`reader`] Reading files back, merged across shards, see [`reader::MergeReader`].
+//! - [`index`] Bloom filters to skip files when searching, see [`index::BloomFilter`].
+//! - [`stats`] Write latency statistics, see [`Histogram`].
+//! - [`lifecycle`] The states a file goes through, see [`FileLifecycle`].
+//! - [`pipeline`] What happens to rotated-out files after compression, see [`Pipeline`].
+//! - [`error`] Why an operation failed, see [`RotatingFileError`].
+//!
+//! ## Features
+//!
+//! - `bzip2` Bzip2 compression, see `Compression::Bzip2`.
+//! - `chrono-tz` Time zones of the IANA database for names and boundaries,
+//!   see `Timezone::Tz`.
+//! - `failpoints` Failure injection for testing recovery, see [`failpoints`].
+//! - `instrument` `tracing` spans for rotation, compression, deletion and
+//!   upload, with durations and byte counts.
+//! - `lz4` LZ4 compression, see `Compression::Lz4`.
+//! - `sighup` Reopening on SIGHUP for external `logrotate` setups, see
+//!   `RotatingFile::reopen_on_sighup`.
+//! - `systemd` Watchdog pings reflecting the health of the writer, see
+//!   `RotatingFile::start_watchdog`.
+//! - `tracing` A non-blocking writer for `tracing-subscriber`, see [`tracing::non_blocking`].
+//! - `xz` XZ compression with a configurable preset, see `Compression::Xz`.
+//! - `zstd` Zstandard compression, see `Compression::Zstd`.
+
+pub mod compress;
+#[cfg(unix)]
+mod control;
+pub mod error;
+#[cfg(feature = "failpoints")]
+pub mod failpoints;
+pub mod frame;
+pub mod index;
+mod instrument;
+pub mod lifecycle;
+pub mod naming;
+pub mod pipeline;
+pub mod policy;
+pub mod reader;
+#[cfg(all(unix, feature = "sighup"))]
+mod sighup;
+pub mod stats;
+pub mod streams;
+#[cfg(all(unix, feature = "systemd"))]
+mod systemd;
+mod threads;
+#[cfg(feature = "tracing")]
+pub mod tracing;
+pub mod writer;
+
+pub use compress::{Compression, CompressionLevel, Compressor, ConflictPolicy};
+pub use error::RotatingFileError;
+pub use lifecycle::FileLifecycle;
+pub use naming::{CollisionPolicy, FileInfo, IndexPosition, NamingConfig, Timezone};
+pub use pipeline::{Pipeline, Stage, Uploader};
+pub use policy::{
+    ByteSize, CalendarPeriod, CalendarPolicy, FileStats, IntervalPolicy, LinePolicy, MaxAgePolicy,
+    RotationPolicy, RotationTrigger, SizePolicy,
+};
+pub use stats::Histogram;
+pub use uuid::Uuid;
+pub use writer::{
+    strip_ansi, verify_checksum, AbortHandle, Backend, FileBackend, NewlinePolicy, NullBackend,
+    OverflowPolicy, Sanitizer, Sink, Utf8Policy,
+};
+
+use std::collections::HashMap;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError, RwLock, TryLockError};
 use std::thread::JoinHandle;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::{ffi::OsString, fs, io::Error, sync::Mutex};
-use std::{io::BufWriter, sync::Arc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fs, io::Error, io::ErrorKind};
 
-use chrono::{DateTime, NaiveDateTime, Utc};
-use flate2::write::GzEncoder;
+use chrono::{DateTime, Utc};
 use log::*;
 
-#[derive(Copy, Clone)]
-pub enum Compression {
-    GZip,
-    Zip,
+use instrument::{Op, Span};
+use lifecycle::{Journal, Links};
+use naming::{Buckets, IndexCache};
+use pipeline::Manifest;
+use policy::Retention;
+use stats::LatencyRecorder;
+use streams::GroupShared;
+use threads::Threads;
+use writer::{AbortState, Backfill, ContextState, CurrentContext, LineFormat};
+
+/// How rotated-out files are measured when accounting for disk usage.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SizeMeasure {
+    /// Size of the file as written, before compression.
+    Raw,
+    /// Size on disk, i.e. the compressed size once compression finishes.
+    Compressed,
+}
+
+/// Whether files are written where they should be.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Health {
+    /// Writing to `root_dir`.
+    Healthy,
+    /// `root_dir` is read-only and there is no fallback directory, lines are
+    /// dropped and `writeln` fails with `ErrorKind::ReadOnlyFilesystem`.
+    ReadOnly,
+    /// `root_dir` is read-only, writing to the fallback directory.
+    Fallback,
+}
+
+// how often(in seconds) to check whether `root_dir` is writable again
+const PROBE_INTERVAL: u64 = 1;
+
+/// Buffer size of the active file without coalescing, as `BufWriter::new`,
+/// see `RotatingFile::with_buffer_size`.
+pub const DEFAULT_BUFFER_SIZE: usize = 8 * 1024;
+
+type PostCreateHook = Box<dyn Fn(&Path) -> Result<(), Error> + Send + Sync>;
+type LifecycleHook = Box<dyn Fn(&Path, FileLifecycle) + Send + Sync>;
+type CollisionHook = Box<dyn Fn(&Path) + Send + Sync>;
+type ErrorHook = Box<dyn Fn(&RotatingFileError) + Send + Sync>;
+type Clock = Box<dyn Fn() -> SystemTime + Send + Sync>;
+
+/// Emitted once a rotated-out file has been finalized, i.e. renamed and
+/// compressed as configured.
+#[derive(Clone, Debug)]
+pub struct RotationEvent {
+    /// Final path of the file, the archive if compressed
+    pub path: PathBuf,
+    /// Size in bytes of the file before compression
+    pub raw_size: u64,
+    /// The policy applied if the archive path already existed
+    pub conflict: Option<ConflictPolicy>,
+    /// Identifies the rotation of this file, also in its manifest entry and
+    /// passed to `Uploader::upload_with_id`
+    pub id: Uuid,
+    /// Why the file was rotated out
+    pub trigger: RotationTrigger,
+}
+
+/// Emitted when interval buckets are skipped without a file, e.g. because the
+/// process was down or the clock jumped forward.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Gap {
+    /// Start of the first bucket without a file
+    pub start: DateTime<Utc>,
+    /// Start of the bucket of the file created after the gap
+    pub end: DateTime<Utc>,
+}
+
+/// Emitted once no more files will be written for an interval bucket, i.e.
+/// the active file is in a later bucket, and all files of the bucket have
+/// been finalized. Only applies to interval rotation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BucketClosed {
+    /// Start of the bucket
+    pub start: DateTime<Utc>,
+    /// Final paths of the files of the bucket, in the order they were finalized
+    pub files: Vec<PathBuf>,
+    /// Size in bytes of the files before compression
+    pub raw_size: u64,
+}
+
+/// Emitted once the command set with `with_post_process_command` has run on
+/// a rotated-out file, whether it succeeded or not.
+#[derive(Clone, Debug)]
+pub struct PostProcessed {
+    /// The file passed to the command, the archive if compressed
+    pub path: PathBuf,
+    /// Exit status and captured stdout and stderr of the command
+    pub output: std::process::Output,
+}
+
+/// Rotated-out files waiting for compression, queued or in progress.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompressionBacklog {
+    pub files: usize,
+    /// Size in bytes of the files before compression
+    pub bytes: u64,
+}
+
+/// What to do with a rotated-out file when the compression backlog would
+/// exceed `max_backlog_bytes`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BacklogPolicy {
+    /// Compress it in the writing thread, slowing writes down to the pace of
+    /// compression.
+    Synchronous,
+    /// Leave it uncompressed.
+    Skip,
+}
+
+/// Emitted when a rotated-out file is not compressed in the background
+/// because the compression backlog is over its limit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BacklogExceeded {
+    /// The rotated-out file
+    pub path: PathBuf,
+    /// The backlog at rotation, without the file
+    pub backlog: CompressionBacklog,
+    /// What was done with the file instead
+    pub policy: BacklogPolicy,
+}
+
+/// What `RotatingFileBuilder` is configured with, `Config::default()` being
+/// what an unconfigured `RotatingFile` uses, e.g. for config validators to
+/// compare against instead of hard-coding the defaults.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Max size of the file after which it will rotate, default to 0, i.e.
+    /// unlimited
+    pub max_size: ByteSize,
+    /// How often to rotate, default to zero, i.e. unlimited
+    pub interval: Duration,
+    /// Max number of lines of the file, default to 0, i.e. unlimited
+    pub max_lines: u64,
+    /// Default to None, i.e. no compression
+    pub compression: Option<Compression>,
+    /// Default to `naming::DEFAULT_DATE_FORMAT`
+    pub date_format: String,
+    /// Default to `naming::DEFAULT_PREFIX`
+    pub prefix: String,
+    /// Default to `naming::DEFAULT_SUFFIX`
+    pub suffix: String,
+    /// Age after which rotated-out files are deleted, default to None,
+    /// i.e. kept forever
+    pub retention_age: Option<Duration>,
+    /// Buffer size of the active file, default to `DEFAULT_BUFFER_SIZE`
+    pub buffer_size: usize,
+    /// Level to compress at, default to None, i.e.
+    /// `Compression::default_level`
+    pub compression_level: Option<i32>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_size: ByteSize::default(),
+            interval: Duration::ZERO,
+            max_lines: 0,
+            compression: None,
+            date_format: naming::DEFAULT_DATE_FORMAT.to_string(),
+            prefix: naming::DEFAULT_PREFIX.to_string(),
+            suffix: naming::DEFAULT_SUFFIX.to_string(),
+            retention_age: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            compression_level: None,
+        }
+    }
+}
+
+/// Builds a `RotatingFile` from the arguments of `RotatingFile::new`, see
+/// `RotatingFile::builder`.
+#[derive(Clone, Debug)]
+pub struct RotatingFileBuilder {
+    root_dir: String,
+    config: Config,
 }
 
-struct CurrentContext {
-    file: BufWriter<fs::File>,
-    file_path: OsString,
-    timestamp: u64,
-    total_written: usize,
+impl RotatingFileBuilder {
+    /// Max size of the file after which it will rotate, 0 means unlimited.
+    pub fn max_size(mut self, max_size: ByteSize) -> Self {
+        self.config.max_size = max_size;
+        self
+    }
+
+    /// How often to rotate, down to the millisecond, zero means unlimited,
+    /// e.g. `Duration::from_millis(500)` for benchmarks. Files of buckets
+    /// starting within the same second are told apart by their index.
+    pub fn rotate_every(mut self, interval: Duration) -> Self {
+        self.config.interval = interval;
+        self
+    }
+
+    /// Max number of lines of the file after which it will rotate, 0 means
+    /// unlimited, see `RotatingFile::with_max_lines`.
+    pub fn max_lines(mut self, max_lines: u64) -> Self {
+        self.config.max_lines = max_lines;
+        self
+    }
+
+    /// Same as `max_size(ByteSize::kb(size))`, as `size` in `RotatingFile::new`.
+    pub fn size_kb(self, size: usize) -> Self {
+        self.max_size(ByteSize::kb(size as u64))
+    }
+
+    /// Same as `rotate_every(Duration::from_secs(interval))`, as `interval`
+    /// in `RotatingFile::new`.
+    pub fn interval(self, interval: u64) -> Self {
+        self.rotate_every(Duration::from_secs(interval))
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.config.compression = Some(compression);
+        self
+    }
+
+    /// Date format as used in chrono
+    /// <https://docs.rs/chrono/latest/chrono/format/strftime/>, default to
+    /// `%Y-%m-%d-%H-%M-%S`.
+    pub fn date_format(mut self, date_format: &str) -> Self {
+        self.config.date_format = date_format.to_string();
+        self
+    }
+
+    /// File name prefix, default to empty.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.config.prefix = prefix.to_string();
+        self
+    }
+
+    /// File name suffix, default to `.log`.
+    pub fn suffix(mut self, suffix: &str) -> Self {
+        self.config.suffix = suffix.to_string();
+        self
+    }
+
+    /// Deletes rotated-out files older than `max_age` in the background,
+    /// see `RotatingFile::with_retention_age`.
+    pub fn retention_age(mut self, max_age: Duration) -> Self {
+        self.config.retention_age = Some(max_age);
+        self
+    }
+
+    /// Buffer size of the active file, see `RotatingFile::with_buffer_size`.
+    pub fn buffer_size(mut self, bytes: usize) -> Self {
+        self.config.buffer_size = bytes;
+        self
+    }
+
+    /// Level to compress at, see `RotatingFile::with_compression_level`.
+    pub fn compression_level(mut self, level: i32) -> Self {
+        self.config.compression_level = Some(level);
+        self
+    }
+
+    /// Replaces everything configured so far with `config`, e.g.
+    /// `Config::default()` with a few fields set.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Creates the `RotatingFile`, which can be configured further with its
+    /// `with_*` methods.
+    pub fn build(self) -> RotatingFile {
+        let limits = self.limits();
+        let config = self.config;
+        let rotating_file = RotatingFile::new(
+            &self.root_dir,
+            None,
+            None,
+            config.compression,
+            Some(config.date_format.clone()),
+            Some(config.prefix.clone()),
+            Some(config.suffix.clone()),
+        );
+        Self::configure(rotating_file, limits, &config)
+    }
+
+    /// Creates the `RotatingFile` like [`build`](Self::build), but fails if
+    /// `root_dir` is not writable, see `RotatingFile::try_new`.
+    pub fn try_build(self) -> Result<RotatingFile, RotatingFileError> {
+        let limits = self.limits();
+        let config = self.config;
+        let rotating_file = RotatingFile::try_new(
+            &self.root_dir,
+            None,
+            None,
+            config.compression,
+            Some(config.date_format.clone()),
+            Some(config.prefix.clone()),
+            Some(config.suffix.clone()),
+        )?;
+        Ok(Self::configure(rotating_file, limits, &config))
+    }
+
+    // max size in bytes and interval
+    fn limits(&self) -> (u64, Duration) {
+        (self.config.max_size.bytes(), self.config.interval)
+    }
+
+    // applies what `new` doesn't take
+    fn configure(
+        rotating_file: RotatingFile,
+        (max_bytes, interval): (u64, Duration),
+        config: &Config,
+    ) -> RotatingFile {
+        let mut rotating_file = rotating_file
+            .with_limits(max_bytes, interval)
+            .with_max_lines(config.max_lines)
+            .with_buffer_size(config.buffer_size);
+        if let Some(max_age) = config.retention_age {
+            rotating_file = rotating_file.with_retention_age(max_age);
+        }
+        if let Some(level) = config.compression_level {
+            rotating_file = rotating_file.with_compression_level(level);
+        }
+        rotating_file
+    }
 }
 
 /// A thread-safe rotating file with customizable rotation behavior.
 pub struct RotatingFile {
-    /// Root directory
-    root_dir: String,
-    /// Max size(in kilobytes) of the file after which it will rotate, 0 means unlimited
-    size: usize,
-    /// How often(in seconds) to rotate, 0 means unlimited
+    // shared with the compression and pre-creation threads
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Root directory, changed by `relocate`
+    root_dir: RwLock<String>,
+    /// Max size(in bytes) of the file after which it will rotate, 0 means unlimited
+    max_bytes: u64,
+    /// How often(in milliseconds) to rotate, 0 means unlimited
     interval: u64,
+    /// Calendar period to rotate at instead of `interval`, default to None
+    calendar: Option<CalendarPeriod>,
     /// Compression method, default to None
     compression: Option<Compression>,
+    /// Level of `compression`, default to None, i.e. its default level
+    compression_level: Option<i32>,
+    /// Files smaller than this(in bytes) are left uncompressed, default to 0
+    min_compress_size: u64,
+    /// Compression of files from a size(in bytes) on, smallest first, overriding `compression`
+    compression_tiers: Vec<(u64, CompressionLevel)>,
+    /// Custom compression, overriding `compression`, default to None
+    compressor: Option<Arc<dyn Compressor>>,
 
-    /// Format as used in chrono <https://docs.rs/chrono/latest/chrono/format/strftime/>, default to `%Y-%m-%d-%H-%M-%S`
-    date_format: String,
-    /// File name prefix, default to empty
-    prefix: String,
-    /// File name suffix, default to `.log`
-    suffix: String,
+    /// File name prefix, date format and suffix
+    naming: NamingConfig,
+    /// Rename files to their first and last write time at rotation, default to false
+    range_naming: bool,
+    /// What to do if the archive path already exists, default to `Truncate`
+    conflict: ConflictPolicy,
+    /// What to do if the next file name is taken, default to `Bump`
+    collision: CollisionPolicy,
+    /// Called with the existing file when the next file name is taken
+    collision_hook: Option<CollisionHook>,
+    /// How rotated-out files are measured, default to `Compressed`
+    size_measure: SizeMeasure,
+    /// How long(in seconds) before an interval boundary to pre-create the next file, 0 means disabled
+    precreate: u64,
+    /// Name files for the time they are opened rather than their interval, default to false
+    open_time_naming: bool,
+    /// Files are not rotated out by policies before this, default to zero
+    min_file_lifetime: Duration,
+    /// Compress the active file as it is written, default to false
+    streaming: bool,
+    /// Size in bits of the token bloom filter saved per file, 0 means disabled
+    bloom_bits: usize,
+    /// Lines between entries of the line index saved per file, 0 means disabled
+    line_index: u64,
+    /// Supplies the time files are bucketed and rotated by, default to the system clock
+    clock: Option<Clock>,
+    /// Max bytes of the compression backlog and what to do beyond it, default to unlimited
+    max_backlog: Option<(u64, BacklogPolicy)>,
+    /// Sequence numbers and timestamps prepended to each line
+    format: LineFormat,
+    /// Applied to bytes written by `write` and `write_all`, default to None
+    sanitizer: Option<Sanitizer>,
+    /// Rotation happens as soon as any of them says so
+    policies: Vec<Box<dyn RotationPolicy>>,
+    /// Creates the files written to, default to `FileBackend`
+    backend: Box<dyn Backend>,
+    /// Flush each line to the file before `writeln` returns and sync
+    /// directories after metadata changes, default to false
+    write_through: bool,
+    /// Where to write while `root_dir` is read-only, default to None
+    fallback_dir: Option<String>,
+    /// Where compressed files go, default to None, i.e. `root_dir`
+    archive_dir: Option<String>,
+    /// Where the active file is written until finalized, default to None,
+    /// i.e. `root_dir`
+    staging_dir: Option<String>,
+    /// Which rotated-out files are deleted and when
+    retention: Retention,
+    /// Called with each new file before anything is written to it
+    post_create: Option<PostCreateHook>,
+    /// Called with each error of finishing a rotated-out file
+    error_hook: Option<ErrorHook>,
+    /// Make finalized files read-only, default to false
+    seal: bool,
+    /// Recreate `root_dir` if deleted while running, default to false
+    recreate_root_dir: bool,
+    /// Links to the newest finalized files and to the active one
+    links: Links,
+    #[cfg(feature = "failpoints")]
+    failpoints: failpoints::FailPoints,
+    /// Buffer size of the active file, default to `DEFAULT_BUFFER_SIZE`
+    buffer_size: usize,
+    /// Max delay and size of coalesced writes, default to None
+    coalesce: Option<(Duration, usize)>,
+    /// The active file is rotated out after this long without writes,
+    /// default to None
+    idle_timeout: Option<Duration>,
+    /// Command run on each file after compression, the file is appended as
+    /// the last argument, default to empty
+    post_command: Vec<String>,
+    /// Stages after compression, default to None
+    pipeline: Option<Pipeline>,
+    manifest: Manifest,
+    sequence: naming::Sequence,
+    /// Called on each state change of a file
+    lifecycle_hook: Option<LifecycleHook>,
+    /// Where state changes are persisted, default to None
+    journal: Option<Journal>,
 
+    health: Mutex<Health>,
     // current context
-    context: Mutex<CurrentContext>,
+    context: Mutex<ContextState>,
+    opened: Condvar,
     // compression threads
-    handles: Arc<Mutex<Vec<JoinHandle<Result<(), Error>>>>>,
+    handles: Mutex<Vec<JoinHandle<Result<(), Error>>>>,
+    // files rotated out but not compressed yet
+    backlog: Mutex<CompressionBacklog>,
+    // receivers of the active file path
+    watchers: Mutex<Vec<Sender<PathBuf>>>,
+    // raw sizes of compressed files with `SizeMeasure::Raw`, forgotten once
+    // their files are found gone
+    raw_sizes: Mutex<HashMap<PathBuf, u64>>,
+    // receivers of rotation events
+    rotations: Mutex<Vec<Sender<RotationEvent>>>,
+    // receivers of `root_dir` being recreated
+    recreations: Mutex<Vec<Sender<PathBuf>>>,
+    // buckets seen so far and their receivers
+    buckets: Buckets,
+    // receivers of post-processing command results
+    post_processed: Mutex<Vec<Sender<PostProcessed>>>,
+    // receivers of files not compressed in the background
+    backlog_exceeded: Mutex<Vec<Sender<BacklogExceeded>>>,
+    // formatted date and index of the last created file
+    index_cache: IndexCache,
+    // next context prepared by the pre-creation thread
+    next: Mutex<Option<CurrentContext>>,
+    // files of past buckets written by `writeln_at`
+    backfill: Backfill,
+    // background threads
+    threads: Threads,
+    // set by `abort`, writes fail and compression is skipped from then on
+    abort: Arc<AbortState>,
+    // the rotation group this instance belongs to
+    group: OnceLock<Arc<GroupShared>>,
+    // how long each write took, including rotations
+    latency: LatencyRecorder,
+    /// Max size(in bytes) of each in-memory buffer and what to do beyond it, default to None
+    max_buffered: Option<(usize, OverflowPolicy)>,
+    // bytes dropped by `OverflowPolicy::Drop`
+    dropped: Arc<AtomicU64>,
 }
 
-unsafe impl Send for RotatingFile {}
-unsafe impl Sync for RotatingFile {}
-
 impl RotatingFile {
+    /// Returns a builder of a RotatingFile writing to `root_dir`, e.g.
+    /// `RotatingFile::builder(root_dir).max_size(ByteSize::mb(1)).build()`.
+    pub fn builder(root_dir: &str) -> RotatingFileBuilder {
+        RotatingFileBuilder {
+            root_dir: root_dir.to_string(),
+            config: Config::default(),
+        }
+    }
+
     /// Creates a new RotatingFile.
     ///
     /// ## Arguments
     ///
     /// - `root_dir` The directory to store files.
     /// - `size` Max size(in kilobytes) of the file after which it will rotate,
-    /// `None` and `0` mean unlimited.
+    ///   `None` and `0` mean unlimited.
     /// - `interval` How often(in seconds) to rotate, 0 means unlimited.
-    /// - `compression` Available values are `GZip` and `Zip`, default to `None`
+    /// - `compression` `GZip`, `Zip` or one behind a feature, default to `None`
     /// - `date_format` uses the syntax from chrono
-    /// <https://docs.rs/chrono/latest/chrono/format/strftime/>, default to `%Y-%m-%d-%H-%M-%S`
+    ///   <https://docs.rs/chrono/latest/chrono/format/strftime/>, default to `%Y-%m-%d-%H-%M-%S`
     /// - `prefix` File name prefix, default to empty
     /// - `suffix` File name suffix, default to `.log`
     pub fn new(
@@ -97,451 +617,5646 @@ impl RotatingFile {
             error!("{}", e);
         }
 
-        let interval = interval.unwrap_or(0);
+        let defaults = NamingConfig::default();
+        let date_format = date_format.unwrap_or_else(|| defaults.date_format.clone());
+        let prefix = prefix.unwrap_or_else(|| defaults.prefix.clone());
+        let suffix = suffix.unwrap_or_else(|| defaults.suffix.clone());
 
-        let date_format = date_format.unwrap_or_else(|| "%Y-%m-%d-%H-%M-%S".to_string());
-        let prefix = prefix.unwrap_or_else(|| "".to_string());
-        let suffix = suffix.unwrap_or_else(|| ".log".to_string());
+        let inner = Inner {
+            root_dir: RwLock::new(root_dir.to_string()),
+            max_bytes: 0,
+            interval: 0,
+            calendar: None,
+            compression,
+            compression_level: None,
+            naming: NamingConfig {
+                prefix,
+                date_format,
+                suffix,
+                ..defaults
+            },
+            range_naming: false,
+            conflict: ConflictPolicy::Truncate,
+            collision: CollisionPolicy::Bump,
+            collision_hook: None,
+            size_measure: SizeMeasure::Compressed,
+            min_compress_size: 0,
+            compression_tiers: Vec::new(),
+            compressor: None,
+            precreate: 0,
+            open_time_naming: false,
+            min_file_lifetime: Duration::ZERO,
+            streaming: false,
+            bloom_bits: 0,
+            line_index: 0,
+            clock: None,
+            max_backlog: None,
+            format: LineFormat::default(),
+            sanitizer: None,
+            policies: Vec::new(),
+            backend: Box::new(FileBackend::default()),
+            write_through: false,
+            fallback_dir: None,
+            archive_dir: None,
+            staging_dir: None,
+            retention: Retention::default(),
+            post_create: None,
+            error_hook: None,
+            seal: false,
+            recreate_root_dir: false,
+            links: Links::default(),
+            #[cfg(feature = "failpoints")]
+            failpoints: failpoints::FailPoints::default(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            coalesce: None,
+            idle_timeout: None,
+            post_command: Vec::new(),
+            pipeline: None,
+            manifest: Manifest::new(root_dir),
+            sequence: naming::Sequence::new(root_dir),
+            lifecycle_hook: None,
+            journal: None,
+            health: Mutex::new(Health::Healthy),
+            context: Mutex::new(ContextState::default()),
+            opened: Condvar::new(),
+            handles: Mutex::new(Vec::new()),
+            backlog: Mutex::new(CompressionBacklog::default()),
+            watchers: Mutex::new(Vec::new()),
+            raw_sizes: Mutex::new(HashMap::new()),
+            rotations: Mutex::new(Vec::new()),
+            recreations: Mutex::new(Vec::new()),
+            buckets: Buckets::default(),
+            post_processed: Mutex::new(Vec::new()),
+            backlog_exceeded: Mutex::new(Vec::new()),
+            index_cache: IndexCache::default(),
+            next: Mutex::new(None),
+            backfill: Backfill::default(),
+            threads: Threads::default(),
+            abort: Arc::new(AbortState::default()),
+            group: OnceLock::new(),
+            latency: LatencyRecorder::default(),
+            max_buffered: None,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        let rotating_file = RotatingFile {
+            inner: Arc::new(inner),
+        };
+        rotating_file.with_limits(
+            size.unwrap_or(0) as u64 * 1024,
+            Duration::from_secs(interval.unwrap_or(0)),
+        )
+    }
 
-        let context = Self::create_context(
-            interval,
-            root_dir,
-            date_format.as_str(),
-            prefix.as_str(),
-            suffix.as_str(),
-        );
+    // rotates by `max_bytes` and every `interval`, zero means unlimited
+    fn with_limits(mut self, max_bytes: u64, interval: Duration) -> Self {
+        let inner = self.inner_mut();
+        inner.max_bytes = max_bytes;
+        // sub-millisecond intervals round up
+        inner.interval = interval.as_millis() as u64
+            + u64::from(!interval.subsec_nanos().is_multiple_of(1_000_000));
+        if max_bytes > 0 {
+            inner.policies.push(Box::new(SizePolicy { max_bytes }));
+        }
+        self
+    }
 
-        RotatingFile {
-            root_dir: root_dir.to_string(),
-            size: size.unwrap_or(0),
+    /// Creates a new RotatingFile like [`new`](Self::new), but fails if
+    /// `root_dir` cannot be created or no file can be created in it, rather
+    /// than failing on the first write, or if `date_format` is invalid.
+    pub fn try_new(
+        root_dir: &str,
+        size: Option<usize>,
+        interval: Option<u64>,
+        compression: Option<Compression>,
+        date_format: Option<String>,
+        prefix: Option<String>,
+        suffix: Option<String>,
+    ) -> Result<Self, RotatingFileError> {
+        if let Some(date_format) = date_format.as_ref() {
+            let invalid = chrono::format::StrftimeItems::new(date_format)
+                .any(|item| matches!(item, chrono::format::Item::Error));
+            if invalid {
+                return Err(RotatingFileError::InvalidConfig(format!(
+                    "invalid date format {}",
+                    date_format
+                )));
+            }
+        }
+        std::fs::create_dir_all(root_dir)?;
+        let rotating_file = Self::new(
+            root_dir,
+            size,
             interval,
             compression,
             date_format,
             prefix,
             suffix,
-            context: Mutex::new(context),
-            handles: Arc::new(Mutex::new(Vec::new())),
-        }
+        );
+        rotating_file.inner.probe_root_dir()?;
+        Ok(rotating_file)
     }
 
-    pub fn writeln(&self, s: &str) -> Result<(), Error> {
-        let mut guard = self.context.lock().unwrap();
+    // configuration is only possible before background threads share `inner`
+    fn inner_mut(&mut self) -> &mut Inner {
+        Arc::get_mut(&mut self.inner).expect("RotatingFile must be configured before writing")
+    }
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+    /// Sets the separator between the date and the index that is added when
+    /// several files share the same date, and whether the index goes before
+    /// or after the date.
+    pub fn with_index_separator(mut self, separator: &str, position: IndexPosition) -> Self {
+        let naming = &mut self.inner_mut().naming;
+        naming.index_separator = separator.to_string();
+        naming.index_position = position;
+        self
+    }
 
-        if (self.size > 0 && guard.total_written + s.len() + 1 >= self.size * 1024)
-            || (self.interval > 0 && now >= (guard.timestamp + self.interval))
-        {
-            guard.file.flush()?;
-            guard.file.get_ref().sync_all()?;
-            let old_file = guard.file_path.clone();
-
-            // reset context
-            *guard = Self::create_context(
-                self.interval,
-                self.root_dir.as_str(),
-                self.date_format.as_str(),
-                self.prefix.as_str(),
-                self.suffix.as_str(),
-            );
+    /// Formats the dates in file names in `timezone` rather than UTC, e.g.
+    /// exchange-local time for ops tooling, and aligns intervals and calendar
+    /// periods to its wall clock, e.g. daily files start at local midnight.
+    pub fn with_naming_timezone(mut self, timezone: Timezone) -> Self {
+        self.inner_mut().naming.timezone = timezone;
+        self
+    }
 
-            // compress in a background thread
-            if let Some(c) = self.compression {
-                let handles_clone = self.handles.clone();
-                let handle = std::thread::spawn(move || Self::compress(old_file, c, handles_clone));
-                self.handles.lock().unwrap().push(handle);
+    /// Starts file names with a sequence number zero-padded to `digits`,
+    /// e.g. `000042-2024-05-01-13-00-00.log`, so that they sort in creation
+    /// order and never collide, even when the clock goes backwards or the
+    /// process restarts within the same second. `0` disables it.
+    ///
+    /// The next number is saved in `.rotfile-sequence` in `root_dir`, synced
+    /// before each file is created, and resumed after the highest number in
+    /// the directories if the state file is lost. Numbers of discarded
+    /// pre-created files are skipped, not reused.
+    ///
+    /// The number goes where `{seq}` is instead if the prefix or the suffix
+    /// has one, which turns numbering on by itself, unpadded.
+    pub fn with_sequence_naming(mut self, digits: usize) -> Self {
+        self.inner_mut().naming.sequence_digits = digits;
+        self
+    }
+
+    /// Sets the prefix and the suffix from a template of the whole file
+    /// name, split at `{date}`, where `{prefix}` and `{suffix}` stand for the
+    /// current ones, e.g. `{prefix}{date}-{hostname}-{pid}-{seq}{suffix}` so
+    /// that collectors on several hosts can share an NFS directory.
+    ///
+    /// Files with another `{hostname}` are left alone by retention and
+    /// readers, while those with another `{pid}` are treated as this
+    /// instance's, e.g. written before a restart. Fails with
+    /// `RotatingFileError::InvalidConfig` if the template has no `{date}`.
+    pub fn with_file_name_template(mut self, template: &str) -> Result<Self, RotatingFileError> {
+        let naming = &mut self.inner_mut().naming;
+        let template = template
+            .replace("{prefix}", &naming.prefix)
+            .replace("{suffix}", &naming.suffix);
+        let Some((prefix, suffix)) = template.split_once(naming::DATE) else {
+            return Err(RotatingFileError::InvalidConfig(format!(
+                "file name template {} has no {}",
+                template,
+                naming::DATE
+            )));
+        };
+        naming.prefix = prefix.to_string();
+        naming.suffix = suffix.to_string();
+        Ok(self)
+    }
+
+    /// Returns how files are named, e.g. to parse them with `FileInfo::parse`.
+    pub fn naming(&self) -> &NamingConfig {
+        &self.inner.naming
+    }
+
+    /// Renames each file at rotation time to include the timestamps of its
+    /// first and last write, e.g. `trades.20240501T00-20240501T01.log` with
+    /// prefix `trades.` and date format `%Y%m%dT%H`.
+    ///
+    /// This makes time-range selection by filename exact, rather than
+    /// inferred from the next file.
+    pub fn with_range_naming(mut self, enabled: bool) -> Self {
+        self.inner_mut().range_naming = enabled;
+        self
+    }
+
+    /// Sets what to do when the archive a rotated-out file is compressed to
+    /// already exists. The applied policy is reported in the `RotationEvent`.
+    pub fn with_conflict_policy(mut self, conflict: ConflictPolicy) -> Self {
+        self.inner_mut().conflict = conflict;
+        self
+    }
+
+    /// Sets what to do when the name of the next file is taken by an existing
+    /// file, compressed or not, and calls `hook` with it, so that a clock
+    /// jump never appends to archived data.
+    pub fn with_collision_policy<F>(mut self, collision: CollisionPolicy, hook: F) -> Self
+    where
+        F: Fn(&Path) + Send + Sync + 'static,
+    {
+        let inner = self.inner_mut();
+        inner.collision = collision;
+        inner.collision_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Sets how rotated-out files are measured by [`archived_size`](Self::archived_size).
+    ///
+    /// With `Raw`, files compressed by this instance count as their size
+    /// before compression, others as their size on disk.
+    pub fn with_size_measure(mut self, measure: SizeMeasure) -> Self {
+        self.inner_mut().size_measure = measure;
+        self
+    }
+
+    /// Keeps each file active for at least `lifetime`, even if a policy such
+    /// as the size limit trips sooner, so that bursts, e.g. replays, don't
+    /// produce storms of tiny files. The file grows past the size limit
+    /// meanwhile.
+    ///
+    /// Interval boundaries and `rotate` are not held back, so files never
+    /// span intervals.
+    pub fn with_min_file_lifetime(mut self, lifetime: Duration) -> Self {
+        self.inner_mut().min_file_lifetime = lifetime;
+        self
+    }
+
+    /// Compresses the active file as it is written instead of after
+    /// rotation, so that no uncompressed copy ever sits on disk and the size
+    /// limit applies to the compressed size, default to false.
+    ///
+    /// Only gzip and zstd can be streamed, other compressions log a warning
+    /// and still compress after rotation. Each flush ends a gzip member or
+    /// zstd frame, so that the active file can be read back at any time,
+    /// at the cost of some ratio when flushing often.
+    pub fn with_streaming_compression(mut self, enabled: bool) -> Self {
+        let inner = self.inner_mut();
+        inner.streaming = enabled;
+        if enabled && inner.streamed().is_none() {
+            if let Some(compression) = inner.compression {
+                warn!(
+                    "{:?} can't be streamed, compressing after rotation instead",
+                    compression
+                );
             }
         }
+        self
+    }
 
-        if let Err(e) = writeln!(&mut guard.file, "{}", s) {
-            error!(
-                "Failed to write to file {}: {}",
-                guard.file_path.to_str().unwrap(),
-                e
-            );
-        } else {
-            guard.total_written += s.len() + 1;
+    /// Caps the compression backlog, i.e. the raw size of rotated-out files
+    /// waiting for compression, at `max_bytes`, so that compression falling
+    /// behind can't fill the disk. A file that would exceed it is handled
+    /// with `policy` instead and reported to
+    /// [`watch_backlog_exceeded`](Self::watch_backlog_exceeded).
+    pub fn with_max_backlog_bytes(mut self, max_bytes: u64, policy: BacklogPolicy) -> Self {
+        self.inner_mut().max_backlog = Some((max_bytes, policy));
+        self
+    }
+
+    /// Indexes the tokens written to each file in a bloom filter of `bits`
+    /// bits, saved as `{file}.bloom` once the file is finalized, so that
+    /// [`index::files_containing`] skips files without a token, 0 disables
+    /// it, the default.
+    ///
+    /// About 10 bits per distinct token keep false positives near 1%.
+    pub fn with_bloom_index(mut self, bits: usize) -> Self {
+        self.inner_mut().bloom_bits = bits;
+        self
+    }
+
+    /// Indexes the byte offset and write time of every `every`th line of
+    /// each file, saved as `{file}.lines` once the file is finalized, so
+    /// that [`reader::open_at_line`] and [`reader::open_at_time`] seek
+    /// instead of scanning from the start, 0 disables it, the default.
+    ///
+    /// Only files left uncompressed get an index, offsets within compressed
+    /// ones can't be seeked to.
+    pub fn with_line_index(mut self, every: u64) -> Self {
+        self.inner_mut().line_index = every;
+        self
+    }
+
+    /// Rotates files once `max_age` has passed since their first write, on
+    /// top of the size and interval limits. Unlike `interval`, the deadline
+    /// isn't aligned to the epoch, e.g. for session-style captures.
+    pub fn with_max_file_age(mut self, max_age: Duration) -> Self {
+        self.inner_mut()
+            .policies
+            .push(Box::new(MaxAgePolicy { max_age }));
+        self
+    }
+
+    /// Rotates files at the start of each calendar `period` in the naming
+    /// time zone, UTC by default, e.g. exactly at midnight or on the first of
+    /// the month, replacing the `interval` if any. Files are named after the
+    /// start of their period, and pre-creation, gaps and bucket closes follow
+    /// the calendar too.
+    pub fn with_calendar_rotation(mut self, period: CalendarPeriod) -> Self {
+        let inner = self.inner_mut();
+        inner.interval = 0;
+        inner.calendar = Some(period);
+        self
+    }
+
+    /// Rotates the active file out, compressing it as configured, once
+    /// nothing was written to it for `idle`, in whole seconds of at least
+    /// one, e.g. to seal files of bursty streams during quiet periods rather
+    /// than on the next write. Rotations are reported with
+    /// `RotationTrigger::Idle`.
+    ///
+    /// A background thread checks every quarter of `idle`, between 100
+    /// milliseconds and a minute. It starts with the first write and is
+    /// stopped by `close`.
+    pub fn with_idle_rotation(mut self, idle: Duration) -> Self {
+        self.inner_mut().idle_timeout = Some(idle);
+        self
+    }
+
+    /// Keeps files of up to `buckets` past interval buckets open for
+    /// `writeln_at`, so that a replay job can backfill alongside live
+    /// capture. Default to 0, i.e. `writeln_at` writes to the active file.
+    pub fn with_backfill_buckets(mut self, buckets: usize) -> Self {
+        self.inner_mut().backfill.max = buckets;
+        self
+    }
+
+    /// Rotates files once they hold `max_lines` lines, i.e. after that many
+    /// `writeln` calls, on top of the size and interval limits, e.g. for
+    /// batch loaders expecting a fixed number of records per file. A block
+    /// written by `write_block` counts as its number of lines and is never
+    /// split across files. 0 means unlimited, the default.
+    pub fn with_max_lines(mut self, max_lines: u64) -> Self {
+        if max_lines > 0 {
+            self.inner_mut()
+                .policies
+                .push(Box::new(LinePolicy { max_lines }));
         }
+        self
+    }
 
-        Ok(())
+    /// Rotates files whenever `policy` says so, on top of the size and
+    /// interval limits and the other policies, i.e. the first policy to trip
+    /// wins, e.g. to rotate once a file holds a number of lines or on an
+    /// application flag. Rotations it causes are reported with
+    /// `RotationPolicy::trigger`.
+    pub fn with_rotation_policy<P: RotationPolicy + 'static>(mut self, policy: P) -> Self {
+        self.inner_mut().policies.push(Box::new(policy));
+        self
     }
 
-    pub fn close(&self) {
-        // wait for compression threads
-        let mut handles = self.handles.lock().unwrap();
-        for handle in handles.drain(..) {
-            if let Err(e) = handle.join().unwrap() {
-                error!("{}", e);
-            }
+    /// Leaves rotated-out files smaller than `bytes` uncompressed, since
+    /// compression overhead makes tiny files bigger, default to 0.
+    pub fn with_min_compress_size(mut self, bytes: u64) -> Self {
+        self.inner_mut().min_compress_size = bytes;
+        self
+    }
+
+    /// Compresses at `level` rather than the default level of the
+    /// compression passed to `new`, see `Compression::with_level`.
+    pub fn with_compression_level(mut self, level: i32) -> Self {
+        self.inner_mut().compression_level = Some(level);
+        self
+    }
+
+    /// Compresses rotated-out files of at least `min_size` bytes with
+    /// `compression`, decided per file at rotation, e.g. gzip at level 1 for
+    /// small files and zstd at level 19 for large ones.
+    ///
+    /// Each call adds a tier, a file uses the tier with the largest
+    /// `min_size` it reaches. Files smaller than every tier use the
+    /// compression passed to `new`.
+    pub fn with_compression_tier(mut self, min_size: u64, compression: CompressionLevel) -> Self {
+        let tiers = &mut self.inner_mut().compression_tiers;
+        tiers.push((min_size, compression));
+        tiers.sort_by_key(|(min_size, _)| *min_size);
+        self
+    }
+
+    /// Compresses rotated-out files with `compressor`, e.g. for an in-house
+    /// format, instead of the compression passed to `new`. Tiers added with
+    /// `with_compression_tier` still apply from their sizes on.
+    ///
+    /// Its extension is added to `NamingConfig::compressed_extensions`, so
+    /// that its archives are listed and retained like the built-in ones.
+    pub fn with_compressor<C: Compressor + 'static>(mut self, compressor: C) -> Self {
+        let inner = self.inner_mut();
+        let extension = compressor.extension().to_string();
+        if !inner.naming.compressed_extensions.contains(&extension) {
+            inner.naming.compressed_extensions.push(extension);
         }
-        drop(handles);
+        inner.compressor = Some(Arc::new(compressor));
+        self
+    }
+
+    /// Names files for the exact time they are created instead of the start
+    /// of the interval their contents belong to, default to false.
+    ///
+    /// Only makes a difference when rotating by interval. Pre-created files
+    /// are still named for their interval.
+    pub fn with_open_time_naming(mut self, enabled: bool) -> Self {
+        self.inner_mut().open_time_naming = enabled;
+        self
+    }
+
+    /// Pre-creates the next file `lead` seconds before each interval
+    /// boundary in a background thread, so that rotation in `writeln` doesn't
+    /// pay for the directory scan and `open()`.
+    ///
+    /// Only takes effect when rotating by interval, `0` disables it.
+    pub fn with_precreate(mut self, lead: u64) -> Self {
+        self.inner_mut().precreate = lead;
+        self
+    }
+
+    /// Prefixes each line with the time of the write, formatted with `format`
+    /// as used in chrono <https://docs.rs/chrono/latest/chrono/format/strftime/>
+    /// and followed by a space.
+    pub fn with_prepend_timestamp(mut self, format: &str) -> Self {
+        self.inner_mut().format.prepend_timestamp = Some(format.to_string());
+        self
+    }
+
+    /// Prefixes each line with a sequence number followed by a space, before
+    /// the timestamp if any.
+    ///
+    /// Numbers start from 0 and keep increasing across rotations, so
+    /// consumers can detect lost lines across file boundaries.
+    pub fn with_sequence_numbers(mut self, enabled: bool) -> Self {
+        self.inner_mut().format.sequence_numbers = enabled;
+        self
+    }
+
+    /// Sets what to do with newlines embedded in a line, which break
+    /// consumers reading one record per line. Default to `Keep`.
+    pub fn with_newline_policy(mut self, policy: NewlinePolicy) -> Self {
+        self.inner_mut().format.newlines = policy;
+        self
+    }
+
+    /// Strips ANSI escape sequences, e.g. colors, from each line, so that
+    /// colored output piped in from other components is archived clean.
+    pub fn with_strip_ansi(mut self, enabled: bool) -> Self {
+        self.inner_mut().format.strip_ansi = enabled;
+        self
+    }
+
+    /// Passes the bytes of `write`, `write_all` and `io::Write` through
+    /// `sanitizer`, e.g. to archive the output of another process as valid
+    /// UTF-8 without colors. Lines are left to the line options.
+    ///
+    /// Each call is sanitized on its own, so a UTF-8 sequence or an escape
+    /// sequence split across calls isn't recognized, wrap it in a
+    /// `LineWriter` to write whole lines.
+    pub fn with_sanitizer(mut self, sanitizer: Sanitizer) -> Self {
+        self.inner_mut().sanitizer = Some(sanitizer);
+        self
+    }
+
+    /// Appends a space and the CRC32 of each line in hex, covering the
+    /// sequence number and timestamp if any.
+    ///
+    /// Readers can check lines with [`verify_checksum`] to detect and trim
+    /// a torn tail after a crash instead of passing it downstream.
+    pub fn with_line_checksums(mut self, enabled: bool) -> Self {
+        self.inner_mut().format.checksums = enabled;
+        self
+    }
+
+    /// Opens files with `O_DSYNC`(`FILE_FLAG_WRITE_THROUGH` on Windows) and
+    /// flushes each line, so that it has hit stable storage by the time
+    /// `writeln` returns.
+    ///
+    /// Files are opened with `Backend::create_write_through`, so it applies
+    /// to any backend set by `with_backend`, before or after.
+    ///
+    /// The parent directory is synced as well after files are created,
+    /// renamed or deleted, so that rotation survives a power loss.
+    ///
+    /// Meant for audit trails where no line may be lost, at a large cost in
+    /// throughput.
+    pub fn with_write_through(mut self, enabled: bool) -> Self {
+        self.inner_mut().write_through = enabled;
+        self
+    }
+
+    /// Buffers up to `max_bytes` of lines and hands them to the OS in one
+    /// write once the buffer is full or its oldest line has waited for
+    /// `max_delay`, e.g. to coalesce tiny lines on network filesystems where
+    /// each write is a round trip, while bounding how stale the file gets.
+    ///
+    /// By default lines are buffered as set by `with_buffer_size`, with no
+    /// bound on how long they wait. A background thread flushes lines left
+    /// waiting once writes stop, it starts with the first write and is
+    /// stopped by `close`. Ignored with `with_write_through`.
+    pub fn with_write_coalescing(mut self, max_delay: Duration, max_bytes: usize) -> Self {
+        self.inner_mut().coalesce = Some((max_delay, max_bytes));
+        self
+    }
+
+    /// Buffers up to `bytes` of lines before handing them to the OS, default
+    /// to `DEFAULT_BUFFER_SIZE`. Overridden by `with_write_coalescing`.
+    pub fn with_buffer_size(mut self, bytes: usize) -> Self {
+        self.inner_mut().buffer_size = bytes;
+        self
+    }
+
+    /// Creates files with `backend` instead of `FileBackend`, e.g.
+    /// `NullBackend` for benchmarks.
+    pub fn with_backend<B: Backend + 'static>(mut self, backend: B) -> Self {
+        self.inner_mut().backend = Box::new(backend);
+        self
+    }
+
+    /// Caps every in-memory buffer of this instance at `max` bytes, i.e.
+    /// lines written while the next file is being created and the queue of
+    /// [`tracing::non_blocking`](crate::tracing), and sets what happens to
+    /// writes beyond it, so that the writer never exhausts memory.
+    pub fn with_max_buffered_bytes(mut self, max: usize, policy: OverflowPolicy) -> Self {
+        self.inner_mut().max_buffered = Some((max, policy));
+        self
+    }
 
-        let mut guard = self.context.lock().unwrap();
-        if let Err(e) = guard.file.flush() {
+    /// Returns how many bytes were dropped by `OverflowPolicy::Drop`.
+    pub fn dropped_bytes(&self) -> u64 {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Returns a budget for a buffer outside the active file's lock.
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    pub(crate) fn buffer_budget(&self) -> writer::BufferBudget {
+        writer::BufferBudget::new(self.inner.max_buffered, self.inner.dropped.clone())
+    }
+
+    /// Writes to `dir` while `root_dir` is read-only, e.g. after a failing
+    /// disk has been remounted, instead of dropping lines.
+    ///
+    /// Writing moves back to `root_dir` as soon as it's writable again.
+    pub fn with_fallback_dir(mut self, dir: &str) -> Self {
+        if let Err(e) = std::fs::create_dir_all(dir) {
             error!("{}", e);
         }
-        if let Err(e) = guard.file.get_ref().sync_all() {
+        self.inner_mut().fallback_dir = Some(dir.to_string());
+        self
+    }
+
+    /// Moves compressed files to `dir` instead of leaving them in
+    /// `root_dir`, e.g. to a slower archive volume, while files left
+    /// uncompressed stay in `root_dir`.
+    ///
+    /// Files are compressed in `root_dir` then moved, with a rename if `dir`
+    /// is on the same filesystem, and otherwise by copying, syncing the copy,
+    /// verifying its checksum and deleting the original, so that neither a
+    /// crash nor a faulty copy loses the archive.
+    /// Retention applies to both directories. Files written compressed by
+    /// `with_streaming_compression` are final as is and stay in `root_dir`.
+    pub fn with_archive_dir(mut self, dir: &str) -> Self {
+        if let Err(e) = std::fs::create_dir_all(dir) {
             error!("{}", e);
         }
+        self.inner_mut().archive_dir = Some(dir.to_string());
+        self
     }
 
-    fn create_context(
-        interval: u64,
-        root_dir: &str,
-        date_format: &str,
-        prefix: &str,
-        suffix: &str,
-    ) -> CurrentContext {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let timestamp = if interval > 0 {
-            now / interval * interval
-        } else {
-            now
-        };
+    /// Writes the active file in `dir` and moves it to `root_dir` only once
+    /// finalized, i.e. renamed and compressed, so that `root_dir` only ever
+    /// holds complete files, e.g. for consumers treating it as an
+    /// append-only bucket. `close` finalizes the active file too.
+    ///
+    /// `dir` should be on the same filesystem as `root_dir` so that files
+    /// appear atomically, with a rename, rather than as a copy. Files left
+    /// in `dir` by a crash stay there, see `repair_last_file`.
+    pub fn with_staging_dir(mut self, dir: &str) -> Self {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            error!("{}", e);
+        }
+        self.inner_mut().staging_dir = Some(dir.to_string());
+        self
+    }
 
-        let dt = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp(timestamp as i64, 0), Utc);
-        let dt_str = dt.format(date_format).to_string();
+    /// Calls `hook` with the path of a rotated-out file before it's deleted
+    /// for retention, which is skipped if `hook` returns false, e.g. because
+    /// the file hasn't been uploaded yet.
+    ///
+    /// The hook runs on the thread doing the deletion, so keep it quick.
+    pub fn with_pre_delete_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Path) -> bool + Send + Sync + 'static,
+    {
+        self.inner_mut().retention.pre_delete = Some(Box::new(hook));
+        self
+    }
 
-        let mut file_name = format!("{}{}{}", prefix, dt_str, suffix);
-        let mut index = 1;
-        while Path::new(root_dir).join(file_name.as_str()).exists()
-            || Path::new(root_dir).join(file_name.clone() + ".gz").exists()
-            || Path::new(root_dir)
-                .join(file_name.clone() + ".zip")
-                .exists()
-        {
-            file_name = format!("{}{}-{}{}", prefix, dt_str, index, suffix);
-            index += 1;
-        }
+    /// Limits deletion of rotated-out files to those whose file name matches
+    /// one of the `include` globs, all if empty, except those matching one of
+    /// the `exclude` globs, e.g. `*audit*` for files with a legal retention
+    /// requirement. `*` matches any run of characters and `?` any one.
+    ///
+    /// Applies to every deletion through `delete_rotated`, before the
+    /// pre-delete hook.
+    pub fn with_retention_patterns(mut self, include: &[&str], exclude: &[&str]) -> Self {
+        let inner = self.inner_mut();
+        inner.retention.include = include.iter().map(|p| p.to_string()).collect();
+        inner.retention.exclude = exclude.iter().map(|p| p.to_string()).collect();
+        self
+    }
 
-        let file_path = Path::new(root_dir).join(file_name).into_os_string();
+    /// Calls `hook` with the path of each new file before anything is written
+    /// to it, e.g. to set extended attributes or an SELinux label.
+    ///
+    /// If `hook` fails the file is deleted and the write that needed it
+    /// fails with the same error.
+    pub fn with_post_create_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Path) -> Result<(), Error> + Send + Sync + 'static,
+    {
+        self.inner_mut().post_create = Some(Box::new(hook));
+        self
+    }
 
-        let file = fs::OpenOptions::new()
-            .append(true)
-            .create(true)
-            .open(file_path.as_os_str())
-            .unwrap();
+    /// Calls `hook` with each error of compressing, post-processing or
+    /// running the pipeline on a rotated-out file as it happens, e.g. to
+    /// alert on it, rather than only once [`try_close`](Self::try_close)
+    /// returns the first.
+    ///
+    /// A panic in there, e.g. in a custom `Compressor` or `Uploader`, is
+    /// caught and reported as `RotatingFileError::Compression` too, leaving
+    /// the raw file in place. The hook runs on the thread that failed.
+    pub fn with_error_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&RotatingFileError) + Send + Sync + 'static,
+    {
+        self.inner_mut().error_hook = Some(Box::new(hook));
+        self
+    }
 
-        CurrentContext {
-            file: BufWriter::new(file),
-            file_path,
-            timestamp,
-            total_written: 0,
-        }
+    /// Takes the current time from `clock` instead of the system clock, e.g.
+    /// the exchange time tracked from market data, so that files are named,
+    /// bucketed and rotated by it when the two drift apart.
+    ///
+    /// It applies to the timestamps of lines too. It should not go
+    /// backwards by more than an interval, as with the system clock.
+    pub fn with_clock<F>(mut self, clock: F) -> Self
+    where
+        F: Fn() -> SystemTime + Send + Sync + 'static,
+    {
+        self.inner_mut().clock = Some(Box::new(clock));
+        self
     }
 
-    fn compress(
-        file: OsString,
-        compress: Compression,
-        handles: Arc<Mutex<Vec<JoinHandle<Result<(), Error>>>>>,
-    ) -> Result<(), Error> {
-        let mut out_file_path = file.clone();
-        match compress {
-            Compression::GZip => out_file_path.push(".gz"),
-            Compression::Zip => out_file_path.push(".zip"),
-        }
+    /// Calls `hook` with the path and the new state of a file each time it
+    /// moves along its [`FileLifecycle`], on the thread making the change.
+    pub fn with_lifecycle_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Path, FileLifecycle) + Send + Sync + 'static,
+    {
+        self.inner_mut().lifecycle_hook = Some(Box::new(hook));
+        self
+    }
 
-        let out_file = fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .open(out_file_path.as_os_str())?;
+    /// Persists each state change of a file to a journal in `root_dir`, so
+    /// that recovery can tell where a crash left each file, see
+    /// [`lifecycle`](Self::lifecycle). Default to false.
+    pub fn with_lifecycle_journal(mut self, enabled: bool) -> Self {
+        let inner = self.inner_mut();
+        inner.journal = enabled.then(|| Journal::new(&inner.root_dir()));
+        self
+    }
 
-        let input_buf = fs::read(file.as_os_str())?;
+    /// Returns the last journaled state of each file by file name, empty
+    /// unless enabled with `with_lifecycle_journal`.
+    pub fn lifecycle(&self) -> Result<HashMap<String, FileLifecycle>, Error> {
+        self.inner
+            .journal
+            .as_ref()
+            .map_or(Ok(HashMap::new()), |journal| journal.load())
+    }
 
-        match compress {
-            Compression::GZip => {
-                let mut encoder = GzEncoder::new(out_file, flate2::Compression::new(9));
-                encoder.write_all(&input_buf)?;
-                encoder.flush()?;
-            }
-            Compression::Zip => {
-                let file_name = Path::new(file.as_os_str())
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap();
-                let mut zip = zip::ZipWriter::new(out_file);
-                zip.start_file(file_name, zip::write::FileOptions::default())?;
-                zip.write_all(&input_buf)?;
-                zip.finish()?;
-            }
-        }
+    /// Makes files read-only once finalized, i.e. renamed and compressed as
+    /// configured, and immutable on Linux where the process is allowed to,
+    /// so that nothing on the host appends to or truncates archived data.
+    /// Default to false.
+    ///
+    /// `delete_rotated` lifts the immutable flag before deleting.
+    pub fn with_seal(mut self, enabled: bool) -> Self {
+        self.inner_mut().seal = enabled;
+        self
+    }
 
-        let ret = fs::remove_file(file.as_os_str());
+    /// Deletes the oldest rotated-out files through `delete_rotated` each
+    /// time a file is finalized, as long as the files in `root_dir` take more
+    /// than `max_size` on disk, e.g. `ByteSize::gb(10)` on an edge box with a
+    /// small disk. Default to unlimited.
+    ///
+    /// Files are measured as by [`archived_size`](Self::archived_size), i.e.
+    /// on disk unless set otherwise by `with_size_measure`, and include those
+    /// left by earlier runs. The active file counts towards the quota but is
+    /// never deleted, nor are files being compressed.
+    pub fn with_max_total_size(mut self, max_size: ByteSize) -> Self {
+        self.inner_mut().retention.max_total_size = max_size.bytes();
+        self
+    }
 
-        // remove from the handles vector
-        if let Ok(ref mut guard) = handles.try_lock() {
-            let current_id = std::thread::current().id();
+    /// Deletes rotated-out files last modified more than `max_age` ago, left
+    /// by earlier runs included, through `delete_rotated` in a background
+    /// thread, which scans `root_dir` every tenth of `max_age`, between a
+    /// second and an hour.
+    ///
+    /// The thread starts with the first write and is stopped by `close`.
+    pub fn with_retention_age(mut self, max_age: Duration) -> Self {
+        self.inner_mut().retention.max_age = Some(max_age);
+        self
+    }
+
+    /// Recreates `root_dir` when the next file can't be created because it
+    /// has been deleted, e.g. by an overeager cleanup job, instead of failing
+    /// every write until restart. Default to false.
+    ///
+    /// Lines written to the active file before the next rotation are lost
+    /// along with the directory. See `watch_dir_recreated`.
+    pub fn with_recreate_root_dir(mut self, enabled: bool) -> Self {
+        self.inner_mut().recreate_root_dir = enabled;
+        self
+    }
+
+    /// Maintains links to the newest `count` finalized files in the `recent`
+    /// subdirectory of `root_dir`, so that tools don't need to list and sort
+    /// the whole directory. Links are symlinks on Unix and hard links
+    /// elsewhere, and are removed along with their files by
+    /// `delete_rotated`. Default to 0, i.e. disabled.
+    pub fn with_recent_links(mut self, count: usize) -> Self {
+        self.inner_mut().links.recent = count;
+        self
+    }
+
+    /// Maintains a link named `name` in `root_dir`, e.g. `current.log`, to
+    /// the active file, replaced atomically each time a file is created, so
+    /// that `tail -F current.log` follows rotations. Like `recent/`, the
+    /// link is a symlink on Unix and a hard link elsewhere. Files are
+    /// created on the first write after a rotation, until then the link
+    /// points at the rotated-out file, or nowhere if it was renamed or
+    /// compressed. Default to None, i.e. disabled.
+    pub fn with_current_link(mut self, name: &str) -> Self {
+        self.inner_mut().links.current = Some(name.to_string());
+        self
+    }
+
+    /// Runs `command` on each rotated-out file once compressed, with the
+    /// path of the file appended as the last argument, in a background
+    /// thread, e.g. `["zstd", "-19", "--rm"]` or an upload script.
+    ///
+    /// Its output is captured and sent to [`watch_post_processed`](Self::watch_post_processed).
+    /// Failing to run or a non-zero exit is logged and returned by
+    /// [`try_close`](Self::try_close). The pipeline, if any, runs after it,
+    /// so commands that move or delete the file don't mix with one.
+    pub fn with_post_process_command<I, S>(mut self, command: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.inner_mut().post_command = command.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Runs each rotated-out file through `pipeline` once compressed, e.g. to
+    /// upload it and delete the local copy, in a background thread.
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.inner_mut().pipeline = Some(pipeline);
+        self
+    }
+
+    /// Finishes the pipeline for files whose progress was recorded in the
+    /// manifest, e.g. before a crash, returns how many were resumed.
+    ///
+    /// Call it at startup. Files rotated out but not yet checksummed aren't
+    /// recorded, so they aren't resumed.
+    pub fn resume_pipeline(&self) -> Result<usize, RotatingFileError> {
+        let inner = &self.inner;
+        let Some(pipeline) = inner.pipeline.as_ref() else {
+            return Ok(0);
+        };
+        let mut entries = inner.manifest.load()?;
+        // forget files that are done or gone
+        let root_dir = inner.root_dir();
+        entries.retain(|file_name, (stage, _, _)| {
+            !pipeline.is_done(*stage) && Path::new(&root_dir).join(file_name).exists()
+        });
+        inner.manifest.compact(&entries)?;
+
+        for (file_name, (stage, checksum, id)) in entries.iter() {
+            let path = Path::new(&root_dir).join(file_name);
+            pipeline
+                .run(
+                    &inner.manifest,
+                    &path,
+                    *id,
+                    Some((*stage, *checksum)),
+                    |path| inner.transition(path, FileLifecycle::Shipped),
+                    |path| inner.delete_rotated(path),
+                )
+                .map_err(RotatingFileError::Compression)?;
+        }
+        Ok(entries.len())
+    }
+
+    /// Deletes a rotated-out file unless the pre-delete hook vetoes it,
+    /// returns whether it was deleted.
+    ///
+    /// This is the single place where retention deletes files, exposed so
+    /// that custom retention goes through the hook and keeps
+    /// [`archived_size`](Self::archived_size) accurate.
+    pub fn delete_rotated(&self, path: &Path) -> Result<bool, RotatingFileError> {
+        Ok(self.inner.delete_rotated(path)?)
+    }
+
+    /// Returns whether files are written to `root_dir`, the fallback
+    /// directory or nowhere because `root_dir` is read-only.
+    pub fn health(&self) -> Health {
+        *self.inner.health.lock().unwrap()
+    }
+
+    /// Returns a receiver that yields the path of the active file.
+    ///
+    /// The current path, if any, is sent immediately, then the new path each
+    /// time a file is created, so a tailer always knows which file to follow.
+    pub fn watch_current_path(&self) -> Receiver<PathBuf> {
+        let (tx, rx) = channel();
+        let guard = self.inner.context.lock().unwrap();
+        if let Some(ctx) = guard.current.as_ref() {
+            let _ = tx.send(ctx.file_path.clone());
+        }
+        self.inner.watchers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Writes `s` followed by a newline, with the line options applied.
+    ///
+    /// The line is written whole, never interleaved with writes of other
+    /// threads, however long it is, since the active file stays locked until
+    /// all of it has been handed to the file.
+    ///
+    /// Fails with `RotatingFileError::Io` if the line could not be written,
+    /// e.g. because the disk is full, in which case it is lost.
+    pub fn writeln(&self, s: &str) -> Result<(), RotatingFileError> {
+        if self.inner.context.is_poisoned() {
+            return Err(RotatingFileError::Poisoned);
+        }
+        let s = self.inner.format.clean(s)?;
+        let inner = &self.inner;
+        self.write_record(1, |sequence, now| {
+            inner.format.format(&s, now, sequence).into_bytes()
+        })?;
+        Ok(())
+    }
+
+    /// Writes a line like `writeln`, but to the file of the bucket `at`
+    /// falls in, e.g. when replaying historical data alongside live capture.
+    ///
+    /// Lines of the current bucket or later go to the active file. Files of
+    /// past buckets, up to `with_backfill_buckets`, are kept open side by
+    /// side, each rotating by the policies but the interval and finalized
+    /// independently of the active file. The oldest is finalized when a line
+    /// of yet another past bucket arrives, and `close` finalizes the rest.
+    ///
+    /// Fails with `ErrorKind::InvalidInput` if `at` is before the Unix epoch.
+    pub fn writeln_at(&self, s: &str, at: SystemTime) -> Result<(), RotatingFileError> {
+        let inner = &self.inner;
+        let at_ms = at
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "time before the Unix epoch"))?
+            .as_millis() as u64;
+        let now_ms = inner.now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let bucket = inner.bucket(at_ms);
+        if inner.backfill.max == 0 || !inner.bucketed() || bucket >= inner.bucket(now_ms) {
+            return self.writeln(s);
+        }
+        let Ok(mut guard) = inner.context.lock() else {
+            return Err(RotatingFileError::Poisoned);
+        };
+        inner.check_aborted()?;
+        let s = inner.format.clean(s)?;
+        let sequence = guard.sequence;
+        guard.sequence += 1;
+        drop(guard);
+        let line = inner.format.format(&s, at, sequence).into_bytes();
+        inner.backfill(bucket, &line, at)?;
+        Ok(())
+    }
+
+    /// Writes each line of `block`, e.g. an order book snapshot, with the
+    /// line options applied, all in the same file.
+    ///
+    /// Lines get consecutive sequence numbers and, as for `writeln`, the
+    /// block is never interleaved with writes of other threads. A block
+    /// larger than the size limit still lands in one file, which exceeds the
+    /// limit.
+    pub fn write_block(&self, block: &str) -> Result<(), RotatingFileError> {
+        if self.inner.context.is_poisoned() {
+            return Err(RotatingFileError::Poisoned);
+        }
+        let inner = &self.inner;
+        let lines = block
+            .lines()
+            .map(|line| inner.format.clean(line))
+            .collect::<Result<Vec<_>, _>>()?;
+        if lines.is_empty() {
+            return Ok(());
+        }
+        self.write_record(lines.len() as u64, |sequence, now| {
+            let mut buf = Vec::new();
+            for (i, line) in lines.iter().enumerate() {
+                let formatted = inner.format.format(line, now, sequence + i as u64);
+                buf.extend_from_slice(formatted.as_bytes());
+            }
+            buf
+        })?;
+        Ok(())
+    }
+
+    /// Writes `buf` as is, e.g. a protobuf frame, with rotation and size
+    /// accounting applied as for lines but no line options such as
+    /// checksums. Always writes the whole buffer, returning its length.
+    pub fn write(&self, buf: &[u8]) -> Result<usize, RotatingFileError> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    /// Writes `buf` as is, in a single file, see [`write`](Self::write).
+    pub fn write_all(&self, buf: &[u8]) -> Result<(), RotatingFileError> {
+        if self.inner.context.is_poisoned() {
+            return Err(RotatingFileError::Poisoned);
+        }
+        let sanitized;
+        let buf = match self.inner.sanitizer.as_ref() {
+            Some(sanitizer) => {
+                sanitized = sanitizer.sanitize(buf)?;
+                sanitized.as_bytes()
+            }
+            None => buf,
+        };
+        self.write_record(1, |_, _| buf.to_vec())?;
+        Ok(())
+    }
+
+    /// Writes the bytes `record` returns given the first of `count` sequence
+    /// numbers and the current time, rotating before or after them but never
+    /// in between.
+    pub(crate) fn write_record<F>(&self, count: u64, record: F) -> Result<(), RotatingFileError>
+    where
+        F: FnMut(u64, SystemTime) -> Vec<u8>,
+    {
+        let start = Instant::now();
+        let result = self.append(count, record);
+        self.inner.latency.record(start.elapsed());
+        result
+    }
+
+    fn append<F>(&self, count: u64, mut record: F) -> Result<(), RotatingFileError>
+    where
+        F: FnMut(u64, SystemTime) -> Vec<u8>,
+    {
+        let inner = &self.inner;
+        inner.check_aborted()?;
+        inner.start_threads();
+
+        let now_time = inner.now();
+        let since_epoch = now_time.duration_since(UNIX_EPOCH).unwrap();
+        let (now, now_ms) = (since_epoch.as_secs(), since_epoch.as_millis() as u64);
+
+        let poisoned = |_| RotatingFileError::Poisoned;
+        let mut guard = inner.context.lock().map_err(poisoned)?;
+        // sequence numbers are taken under the lock so they are ordered in the file
+        let line = loop {
+            let line = record(guard.sequence, now_time);
+            if !guard.opening {
+                break line;
+            }
+            let buffered = guard.pending.len() + line.len();
+            // lines buffered during creation must still fit in the next file
+            let fits_file = inner.max_bytes == 0 || (buffered as u64) < inner.max_bytes;
+            match inner.max_buffered {
+                Some((max, policy)) if !guard.pending.is_empty() && buffered > max => {
+                    match policy {
+                        OverflowPolicy::Block => {}
+                        OverflowPolicy::Drop => {
+                            inner
+                                .dropped
+                                .fetch_add(line.len() as u64, Ordering::Relaxed);
+                            return Ok(());
+                        }
+                        OverflowPolicy::Error => return Err(writer::overflow_error(max).into()),
+                    }
+                }
+                _ if fits_file => break line,
+                _ => {}
+            }
+            guard = inner.opened.wait(guard).map_err(poisoned)?;
+        };
+        guard.sequence += count;
+
+        // at most one rotation per write, however many policies trip
+        let mut trigger = guard.current.as_ref().and_then(|ctx| {
+            let stats = ctx.stats(line.len());
+            if inner.bucketed() && inner.bucket(now_ms) != ctx.bucket {
+                return Some(RotationTrigger::Interval);
+            }
+            if ctx.activated.elapsed() < inner.min_file_lifetime {
+                return None;
+            }
+            inner
+                .policies
+                .iter()
+                .find(|policy| policy.should_rotate(&stats, now_time))
+                .map(|policy| policy.trigger())
+        });
+        // move back from the fallback directory once possible
+        if trigger.is_none() && inner.health() == Health::Fallback && now >= guard.probe_at {
+            guard.probe_at = now + PROBE_INTERVAL;
+            if inner.root_dir_writable() {
+                trigger = Some(RotationTrigger::External);
+            }
+        }
+        let old = trigger.and_then(|trigger| Some((guard.current.take()?, trigger)));
+
+        // written whole under the lock, even past the capacity of the
+        // `BufWriter`, so that records of other threads can't interleave
+        if let Some(ctx) = guard.current.as_mut() {
+            #[cfg(feature = "failpoints")]
+            inner.failpoints.check(failpoints::FailPoint::Write)?;
+            match ctx.write(&line, now).and_then(|_| inner.write_through(ctx)) {
+                Err(e) if e.kind() == ErrorKind::ReadOnlyFilesystem => {
+                    error!("Failed to write to file {}: {}", ctx.file_path.display(), e);
+                    // the file can be neither flushed nor renamed, abandon it
+                    // and write the line to the next one
+                    guard.current = None;
+                }
+                result => return Ok(result?),
+            }
+        }
+
+        // buffer in memory while the next file is being created
+        let start = guard.pending.len();
+        guard.pending.extend_from_slice(&line);
+        if guard.opening {
+            // the line is flushed along with the buffer once the file is
+            // created, with write-through it has to wait to know it was
+            if inner.write_through {
+                let openings = guard.openings;
+                while guard.openings == openings {
+                    guard = inner.opened.wait(guard).map_err(poisoned)?;
+                }
+                if let Some((kind, message)) = guard.open_error.as_ref() {
+                    return Err(Error::new(*kind, message.clone()).into());
+                }
+            }
+            return Ok(());
+        }
+        guard.opening = true;
+        drop(guard);
+
+        // a member of a rotation group rotating starts a new generation
+        let group = inner.group.get();
+        let advanced =
+            group.and_then(|group| Some((group, group.advance(old.as_ref()?.0.generation)?)));
+
+        // rotate and create the next file outside the lock, files are
+        // created lazily on the first write
+        let finalized = old.map_or(Ok(()), |(old, trigger)| {
+            inner.finalize(old, trigger).map(|_| ())
+        });
+        let created = inner.next_context(now_time);
+
+        let mut guard = inner.context.lock().map_err(poisoned)?;
+        guard.opening = false;
+        let result = created.and_then(|mut ctx| {
+            ctx.generation = group.map_or(0, |group| group.generation());
+            ctx.activated = Instant::now();
+            let pending = std::mem::take(&mut guard.pending);
+            let written = ctx
+                .write(&pending, now)
+                .and_then(|_| inner.write_through(&mut ctx));
+            inner.notify_watchers(ctx.file_path.clone());
+            // under the lock, so that links are replaced in order
+            if let Err(e) = inner.link_current(&ctx.file_path) {
+                error!("Failed to link {}: {}", ctx.file_path.display(), e);
+            }
+            inner.abort.publish(&ctx);
+            guard.current = Some(ctx);
+            written
+        });
+        match result.as_ref().map_err(|e| e.kind()) {
+            // there is nowhere to write buffered lines to, and waiting
+            // writers with write-through are told theirs are lost
+            Err(ErrorKind::ReadOnlyFilesystem) => guard.pending.clear(),
+            Err(_) if inner.write_through => guard.pending.clear(),
+            // the line is reported as lost, it mustn't reach the next file,
+            // unlike lines of other writers buffered meanwhile
+            Err(_) if guard.pending.len() >= start + line.len() => {
+                guard.pending.drain(start..start + line.len());
+            }
+            _ => {}
+        }
+        guard.openings += 1;
+        guard.open_error = result.as_ref().err().map(|e| (e.kind(), e.to_string()));
+        drop(guard);
+        inner.opened.notify_all();
+
+        // rotate the other members once the next file is in place
+        let grouped = advanced.map_or(Ok(()), |(group, generation)| {
+            group.rotate_others(inner, generation)
+        });
+
+        Ok(finalized.and(result).and(grouped)?)
+    }
+
+    /// Returns a receiver that yields an event each time a rotated-out file
+    /// has been finalized.
+    pub fn watch_rotations(&self) -> Receiver<RotationEvent> {
+        let (tx, rx) = channel();
+        self.inner.rotations.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Returns a receiver that yields a [`Gap`] each time the next file is
+    /// created more than one interval after the last one, including files
+    /// left in `root_dir` by an earlier run. Only applies to interval
+    /// rotation.
+    pub fn watch_gaps(&self) -> Receiver<Gap> {
+        let (tx, rx) = channel();
+        self.inner.buckets.gaps.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Returns a receiver that yields `root_dir` each time it is recreated,
+    /// see `with_recreate_root_dir`.
+    pub fn watch_dir_recreated(&self) -> Receiver<PathBuf> {
+        let (tx, rx) = channel();
+        self.inner.recreations.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Returns a receiver that yields a [`PostProcessed`] each time the
+    /// command set with `with_post_process_command` has run.
+    pub fn watch_post_processed(&self) -> Receiver<PostProcessed> {
+        let (tx, rx) = channel();
+        self.inner.post_processed.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Returns a receiver that yields a [`BacklogExceeded`] each time a file
+    /// is not compressed in the background because of `max_backlog_bytes`.
+    pub fn watch_backlog_exceeded(&self) -> Receiver<BacklogExceeded> {
+        let (tx, rx) = channel();
+        self.inner.backlog_exceeded.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Returns a receiver that yields a [`BucketClosed`] for each interval
+    /// bucket once all its files have been finalized. The bucket of the
+    /// active file is left open on `close`, as a later run may add to it.
+    pub fn watch_bucket_closes(&self) -> Receiver<BucketClosed> {
+        let (tx, rx) = channel();
+        self.inner.buckets.closes.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Returns how many background threads of this instance are alive, i.e.
+    /// compression, pre-creation and the services started by the `with_*`
+    /// methods or `reopen_on_sighup`, `serve_control` and `start_watchdog`.
+    ///
+    /// Threads are named `rotfile-<kind>-<prefix>`, without the dash if the
+    /// prefix is empty, where the kind is `compress`, `precreate`, `sweep`,
+    /// `coalesce`, `idle`, `sighup`, `control` or `watchdog`.
+    pub fn thread_count(&self) -> usize {
+        self.inner.thread_count()
+    }
+
+    /// Returns the failures armed for this instance.
+    #[cfg(feature = "failpoints")]
+    pub fn failpoints(&self) -> &failpoints::FailPoints {
+        &self.inner.failpoints
+    }
+
+    /// Returns the files rotated out but not compressed yet, counted from
+    /// rotation, so that alerting can react before raw files fill the disk.
+    pub fn compression_backlog(&self) -> CompressionBacklog {
+        *self.inner.backlog.lock().unwrap()
+    }
+
+    /// Rotates the active file out now, regardless of the limits, e.g. at
+    /// an application checkpoint, the next one is created on the next write.
+    ///
+    /// Returns the path of the file rotated out, once renamed as configured
+    /// but before compression, see `watch_rotations` for the archive. Fails
+    /// with `ErrorKind::NotFound` if there is no active file, i.e. nothing
+    /// was written since the last rotation.
+    pub fn rotate(&self) -> Result<PathBuf, RotatingFileError> {
+        let rotated = self.inner.rotate(RotationTrigger::Manual)?;
+        rotated.ok_or_else(|| Error::new(ErrorKind::NotFound, "no active file to rotate").into())
+    }
+
+    /// Flushes the active file to disk.
+    pub fn flush(&self) -> Result<(), RotatingFileError> {
+        Ok(self.inner.flush()?)
+    }
+
+    /// Lets go of the active file so that the next write creates a new one,
+    /// e.g. after an external `logrotate` moved it away.
+    ///
+    /// If the active file was moved or deleted, it is flushed and closed but
+    /// otherwise left to whoever moved it, i.e. not renamed nor compressed.
+    /// Otherwise it is rotated out as by `rotate`, with
+    /// `RotationTrigger::External`.
+    pub fn reopen(&self) -> Result<(), RotatingFileError> {
+        Ok(self.inner.reopen()?)
+    }
+
+    /// Moves writing to `new_root` without closing, e.g. to switch data
+    /// volumes under a running crawler.
+    ///
+    /// The active file and those of past buckets are rotated out with
+    /// `RotationTrigger::External` and their compression waited for, the
+    /// next write creates a file in `new_root`. The manifest, journal and
+    /// sequence state follow. With `migrate`, rotated-out files in the old
+    /// `root_dir` are moved too, with their bloom filters and line indexes;
+    /// otherwise they're left behind and no longer seen by retention,
+    /// readers nor `resume_pipeline`. The fallback, staging and archive
+    /// directories don't change.
+    pub fn relocate<P: AsRef<Path>>(
+        &self,
+        new_root: P,
+        migrate: bool,
+    ) -> Result<(), RotatingFileError> {
+        Ok(self.inner.relocate(new_root.as_ref(), migrate)?)
+    }
+
+    /// Calls `reopen` each time the process receives SIGHUP, in a background
+    /// thread, the way daemons cooperate with `logrotate`'s `postrotate`
+    /// scripts, e.g. `kill -HUP $(cat app.pid)`.
+    ///
+    /// Installs a process-wide SIGHUP handler, replacing any other, so that
+    /// every instance calling this reopens on each signal.
+    #[cfg(all(unix, feature = "sighup"))]
+    pub fn reopen_on_sighup(&self) -> Result<(), Error> {
+        let inner = &self.inner;
+        let handle = sighup::spawn(Arc::downgrade(inner), inner.thread_name("sighup"))?;
+        inner.threads.services.lock().unwrap().push(handle);
+        Ok(())
+    }
+
+    /// Serves a unix domain socket at `path` in a background thread, so that
+    /// ops can poke a running service from another process, e.g.
+    /// `echo rotate | nc -U control.sock`.
+    ///
+    /// Commands are one per line, each answered with one line:
+    ///
+    /// - `rotate` Rotates the active file out, answers `ok`.
+    /// - `flush` Flushes the active file to disk, answers `ok`.
+    /// - `status` Answers `key=value` pairs separated by spaces, e.g. the
+    ///   health and the active file.
+    ///
+    /// Failures are answered with `error: <message>`. The socket is removed
+    /// by `close`.
+    #[cfg(unix)]
+    pub fn serve_control(&self, path: &str) -> Result<(), Error> {
+        let inner = &self.inner;
+        let handle = control::spawn(
+            Arc::downgrade(inner),
+            Path::new(path),
+            inner.thread_name("control"),
+        )?;
+        inner.threads.services.lock().unwrap().push(handle);
+        Ok(())
+    }
+
+    /// Pings the systemd watchdog in a background thread, along with a
+    /// `STATUS=` line such as the health, the last write time and the number
+    /// of background threads, so that a hung writer gets the service
+    /// restarted.
+    ///
+    /// Returns false if the service has no watchdog, i.e. `NOTIFY_SOCKET`
+    /// or `WATCHDOG_USEC` isn't set.
+    #[cfg(all(unix, feature = "systemd"))]
+    pub fn start_watchdog(&self) -> Result<bool, Error> {
+        let Some((socket, interval)) = systemd::watchdog_from_env() else {
+            return Ok(false);
+        };
+        let inner = &self.inner;
+        let handle = systemd::spawn(
+            Arc::downgrade(inner),
+            socket,
+            interval,
+            inner.thread_name("watchdog"),
+        )?;
+        inner.threads.services.lock().unwrap().push(handle);
+        Ok(true)
+    }
+
+    /// Returns how long writes have taken so far, spikes included, e.g. when
+    /// a write waits for the next file to be created.
+    pub fn write_latency(&self) -> Histogram {
+        self.inner.latency.snapshot()
+    }
+
+    /// Returns the total size in bytes of the rotated-out files in
+    /// `root_dir` and the archive directory, those of earlier runs included,
+    /// measured as configured by `with_size_measure`.
+    ///
+    /// The directories are listed on each call.
+    pub fn archived_size(&self) -> u64 {
+        let inner = &self.inner;
+        let active = inner.active_files();
+        match inner.measured_files() {
+            Ok(files) => files
+                .iter()
+                .filter(|(file, _)| !active.contains(file))
+                .map(|(_, size)| size)
+                .sum(),
+            Err(e) => {
+                error!("Failed to measure {}: {}", inner.root_dir(), e);
+                0
+            }
+        }
+    }
+
+    /// Trims a torn final line, e.g. left by a crash, from the most recent
+    /// uncompressed file in `root_dir`, or the staging directory if any,
+    /// returns the number of bytes discarded.
+    ///
+    /// A line is torn if it lacks the trailing newline or, with
+    /// `with_line_checksums`, fails its CRC32. Call it at startup, before the
+    /// first write.
+    pub fn repair_last_file(&self) -> Result<u64, RotatingFileError> {
+        let inner = &self.inner;
+        let mut last = None;
+        let dir = inner
+            .staging_dir
+            .clone()
+            .unwrap_or_else(|| inner.root_dir());
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let Some(info) = FileInfo::parse(&path, &inner.naming) else {
+                continue;
+            };
+            if info.compression.is_some() {
+                continue;
+            }
+            let key = info.sort_key();
+            if last.as_ref().is_none_or(|(k, _)| key > *k) {
+                last = Some((key, path));
+            }
+        }
+        match last {
+            Some((_, path)) => Ok(writer::trim_torn_tail(&path, inner.format.checksums)?),
+            None => Ok(0),
+        }
+    }
+
+    /// Waits for background threads and flushes the active file, logging
+    /// errors, use [`try_close`](Self::try_close) to get them instead.
+    pub fn close(&self) {
+        if let Err(e) = self.try_close() {
+            error!("{}", e);
+        }
+    }
+
+    /// Like [`close`](Self::close), but returns the first error, e.g. a
+    /// failed compression or flush, and logs the others.
+    pub fn try_close(&self) -> Result<(), RotatingFileError> {
+        let mut result = Ok(());
+        let mut fail = |e: RotatingFileError| match result {
+            Ok(()) => result = Err(e),
+            Err(_) => error!("{}", e),
+        };
+        let inner = &self.inner;
+        inner.stop_threads(&mut fail);
+
+        // past buckets are done once backfilling stops
+        let backfilled = std::mem::take(&mut *lock_or(&inner.backfill.files, &mut fail));
+        for (_, ctx) in backfilled {
+            if let Err(e) = inner.finalize(ctx, RotationTrigger::Interval) {
+                fail(e.into());
+            }
+        }
+
+        // publish the active file, which would otherwise stay staged
+        if inner.staging_dir.is_some() {
+            if let Err(e) = inner.rotate(RotationTrigger::External) {
+                fail(e.into());
+            }
+        }
+
+        // wait for compression threads
+        let handles = std::mem::take(&mut *lock_or(&inner.handles, &mut fail));
+        for handle in handles {
+            match handle.join() {
+                Ok(Err(e)) => fail(RotatingFileError::Compression(e)),
+                Err(_) => fail(RotatingFileError::Poisoned),
+                Ok(Ok(())) => {}
+            }
+        }
+
+        let Ok(mut guard) = inner.context.lock() else {
+            fail(RotatingFileError::Poisoned);
+            return result;
+        };
+        while guard.opening {
+            let Ok(waited) = inner.opened.wait(guard) else {
+                fail(RotatingFileError::Poisoned);
+                return result;
+            };
+            guard = waited;
+        }
+        if let Some(ctx) = guard.current.as_mut() {
+            if let Err(e) = ctx.flush() {
+                fail(e.into());
+            }
+        }
+        drop(guard);
+        result
+    }
+}
+
+/// Locks `mutex`, poisoned or not, reporting it to `fail` if it was.
+fn lock_or<'a, T>(
+    mutex: &'a Mutex<T>,
+    fail: &mut impl FnMut(RotatingFileError),
+) -> MutexGuard<'a, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        fail(RotatingFileError::Poisoned);
+        poisoned.into_inner()
+    })
+}
+
+/// Maps a poisoned lock to `RotatingFileError::Poisoned`, carried in the
+/// `io::Error` of functions returning one.
+fn poisoned<T>(_: PoisonError<T>) -> Error {
+    RotatingFileError::Poisoned.into()
+}
+
+/// Locks `mutex` if it's free, poisoned or not.
+fn try_lock<T>(mutex: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    match mutex.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(poisoned)) => Some(poisoned.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
+}
+
+/// Writes raw bytes, e.g. for `serde_json::to_writer`, with rotation applied
+/// as for lines, ignoring line options such as checksums.
+///
+/// Each `write` call lands in a single file, but files may rotate between
+/// calls, so wrap it in a `BufWriter` flushed after each record to keep
+/// records whole.
+impl Write for &RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        Ok(RotatingFile::write(self, buf)?)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(RotatingFile::flush(self)?)
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        Ok(RotatingFile::write(self, buf)?)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(RotatingFile::flush(self)?)
+    }
+}
+
+impl Inner {
+    fn root_dir(&self) -> String {
+        self.root_dir.read().unwrap().clone()
+    }
+
+    fn current_path(&self) -> Option<PathBuf> {
+        let guard = self.context.lock().unwrap();
+        guard.current.as_ref().map(|ctx| ctx.file_path.clone())
+    }
+
+    fn rotate(self: &Arc<Self>, trigger: RotationTrigger) -> Result<Option<PathBuf>, Error> {
+        self.rotate_before(u64::MAX, trigger)
+    }
+
+    /// Rotates the active file out if it was created before rotation group
+    /// generation `generation`.
+    fn rotate_before(
+        self: &Arc<Self>,
+        generation: u64,
+        trigger: RotationTrigger,
+    ) -> Result<Option<PathBuf>, Error> {
+        let mut guard = self.context.lock().map_err(poisoned)?;
+        while guard.opening {
+            guard = self.opened.wait(guard).map_err(poisoned)?;
+        }
+        let old = guard.current.take_if(|ctx| ctx.generation < generation);
+        drop(guard);
+        old.map(|old| self.finalize(old, trigger)).transpose()
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        for ctx in self.backfill.files.lock().map_err(poisoned)?.values_mut() {
+            ctx.flush()?;
+        }
+        let mut guard = self.context.lock().map_err(poisoned)?;
+        guard.current.as_mut().map_or(Ok(()), |ctx| ctx.flush())
+    }
+
+    /// Returns `key=value` pairs describing the state of the instance.
+    fn status(&self) -> String {
+        let guard = self.context.lock().unwrap();
+        let (path, written, last_write) =
+            guard.current.as_ref().map_or((String::new(), 0, 0), |ctx| {
+                let last_write = ctx.first_write.map_or(0, |_| ctx.last_write);
+                (
+                    ctx.file_path.display().to_string(),
+                    ctx.total_written,
+                    last_write,
+                )
+            });
+        drop(guard);
+        let backlog = *self.backlog.lock().unwrap();
+        format!(
+            "health={:?} path={} written={} last_write={} threads={} backlog_files={} backlog_bytes={}",
+            self.health(),
+            path,
+            written,
+            last_write,
+            self.thread_count(),
+            backlog.files,
+            backlog.bytes
+        )
+    }
+
+    /// Flushes the buffered lines to the file in write-through mode.
+    fn write_through(&self, ctx: &mut CurrentContext) -> Result<(), Error> {
+        if self.write_through {
+            ctx.file.flush()
+        } else if let Some((max_delay, _)) = self.coalesce {
+            ctx.flush_if_older(max_delay)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Takes the file pre-created for the current interval, or creates one.
+    fn next_context(&self, now: SystemTime) -> Result<CurrentContext, Error> {
+        let now_ms = now.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let bucket = self.bucket(now_ms);
+        let timestamp = bucket / 1000;
+        // without intervals, the bucket is `now` truncated to milliseconds
+        let named = if self.open_time_naming || !self.bucketed() {
+            now.into()
+        } else {
+            DateTime::from_timestamp_millis(bucket as i64).unwrap()
+        };
+
+        let mut next = self.next.lock().unwrap();
+        let ctx = match next.take() {
+            Some(ctx) if ctx.bucket == bucket => ctx,
+            stale => {
+                *next = stale;
+                drop(next);
+                let mut ctx = self.create_context(timestamp, named)?;
+                ctx.bucket = bucket;
+                ctx
+            }
+        };
+        if self.bucketed() {
+            self.check_gap(bucket);
+            self.end_buckets(bucket);
+        }
+        Ok(ctx)
+    }
+
+    /// Flushes, renames and compresses a file that has been rotated out,
+    /// returns its path once renamed, before compression.
+    fn finalize(
+        self: &Arc<Self>,
+        old: CurrentContext,
+        trigger: RotationTrigger,
+    ) -> Result<PathBuf, Error> {
+        let span = Span::enter(Op::Rotate, &old.file_path, old.total_written as u64);
+        span.result(self.finalize_file(old, trigger))
+    }
+
+    fn finalize_file(
+        self: &Arc<Self>,
+        mut old: CurrentContext,
+        trigger: RotationTrigger,
+    ) -> Result<PathBuf, Error> {
+        old.flush()?;
+        let mut old_file = old.file_path;
+        drop(old.file);
+
+        // an empty file carries no data, delete it rather than compress it
+        if old.total_written == 0 {
+            fs::remove_file(&old_file)?;
+            self.transition(&old_file, FileLifecycle::Expired);
+            self.sync_dir(&old_file)?;
+            return Ok(old_file);
+        }
+
+        let renamed = match (self.range_naming, old.first_write) {
+            (true, Some(first_write)) => {
+                Some(self.rename_to_range(&old_file, first_write, old.last_write, trigger))
+            }
+            _ if self.naming.has_reason() => Some(self.rename_to_reason(&old_file, trigger)),
+            _ => None,
+        };
+        match renamed {
+            Some(Ok(renamed)) => old_file = renamed,
+            Some(Err(e)) => error!("Failed to rename file {}: {}", old_file.display(), e),
+            None => {}
+        }
+
+        let raw_size = old.total_written as u64;
+        let mut compression = self.compression_for(raw_size);
+        let mut synchronous = false;
+        if let (Some((max_bytes, policy)), true) = (self.max_backlog, compression.is_some()) {
+            let backlog = *self.backlog.lock().unwrap();
+            if backlog.bytes + raw_size > max_bytes {
+                warn!(
+                    "Compression backlog of {} bytes is over its limit, {:?} for {}",
+                    backlog.bytes,
+                    policy,
+                    old_file.display()
+                );
+                let exceeded = BacklogExceeded {
+                    path: old_file.clone(),
+                    backlog,
+                    policy,
+                };
+                self.backlog_exceeded
+                    .lock()
+                    .unwrap()
+                    .retain(|tx| tx.send(exceeded.clone()).is_ok());
+                match policy {
+                    BacklogPolicy::Synchronous => synchronous = true,
+                    BacklogPolicy::Skip => compression = None,
+                }
+            }
+        }
+        if compression.is_none() {
+            match self.publish(old_file.clone(), false) {
+                Ok(published) => old_file = published,
+                Err(e) => error!("Failed to publish file {}: {}", old_file.display(), e),
+            }
+        }
+
+        self.transition(&old_file, FileLifecycle::Sealed);
+
+        let bucket = old.bucket;
+        let event = RotationEvent {
+            path: old_file.clone(),
+            raw_size,
+            conflict: None,
+            id: Uuid::new_v4(),
+            trigger,
+        };
+        self.add_to_bucket(bucket);
+
+        let mut bloom = old.bloom.take();
+        if compression.is_none() {
+            if let Some(bloom) = bloom.take() {
+                self.save_bloom(&old_file, &bloom);
+            }
+            if let Some(line_index) = old.line_index.take() {
+                self.save_line_index(&old_file, &line_index);
+            }
+            self.seal(&old_file);
+            self.notify_rotations(event.clone());
+            self.finalized_in_bucket(bucket, &old_file, raw_size);
+            if self.pipeline.is_none() && self.post_command.is_empty() {
+                return Ok(old_file);
+            }
+        }
+
+        // compress, post-process and run the pipeline in a background thread
+        let kind = if compression.is_some() {
+            "compress"
+        } else if !self.post_command.is_empty() {
+            "post"
+        } else {
+            "pipeline"
+        };
+        let compressed = compression.is_some();
+        if compressed {
+            self.update_backlog(raw_size, true);
+            self.retention
+                .compressing
+                .lock()
+                .unwrap()
+                .push(old_file.clone());
+        }
+        if synchronous {
+            if let Err(e) = self.finish_caught(old_file.clone(), bucket, compression, bloom, event)
+            {
+                error!("Failed to finalize {}: {}", old_file.display(), e);
+            }
+            return Ok(old_file);
+        }
+        let inner = self.clone();
+        let file = old_file.clone();
+        let spawned = std::thread::Builder::new()
+            .name(self.thread_name(kind))
+            .spawn(move || inner.finish_caught(file, bucket, compression, bloom, event));
+        match spawned {
+            Ok(handle) => {
+                self.handles.lock().unwrap().push(handle);
+                Ok(old_file)
+            }
+            Err(e) => {
+                if compressed {
+                    self.update_backlog(raw_size, false);
+                    self.retention
+                        .compressing
+                        .lock()
+                        .unwrap()
+                        .retain(|p| *p != old_file);
+                    self.finalized_in_bucket(bucket, &old_file, raw_size);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Adds a file of `raw_size` bytes to the compression backlog, or removes
+    /// it.
+    fn update_backlog(&self, raw_size: u64, add: bool) {
+        let mut backlog = self.backlog.lock().unwrap();
+        if add {
+            backlog.files += 1;
+            backlog.bytes += raw_size;
+        } else {
+            backlog.files -= 1;
+            backlog.bytes -= raw_size;
+        }
+    }
+
+    /// Returns how to compress a rotated-out file of `raw_size` bytes.
+    fn compression_for(&self, raw_size: u64) -> Option<Arc<dyn Compressor>> {
+        if raw_size < self.min_compress_size || self.streamed().is_some() {
+            return None;
+        }
+        if let Some((_, compression)) = self
+            .compression_tiers
+            .iter()
+            .rev()
+            .find(|(min_size, _)| raw_size >= *min_size)
+        {
+            return Some(Arc::new(*compression));
+        }
+        if let Some(compressor) = self.compressor.as_ref() {
+            return Some(compressor.clone());
+        }
+        self.compression_level()
+            .map(|compression| Arc::new(compression) as Arc<dyn Compressor>)
+    }
+
+    /// Returns the compression passed to `new` at the configured level.
+    fn compression_level(&self) -> Option<CompressionLevel> {
+        let compression = self.compression?;
+        let level = self
+            .compression_level
+            .unwrap_or_else(|| compression.default_level());
+        Some(compression.with_level(level))
+    }
+
+    /// Returns the compression the active file is written with, if any.
+    fn streamed(&self) -> Option<CompressionLevel> {
+        if self.compressor.is_some() {
+            return None;
+        }
+        let compression = self.compression_level().filter(|_| self.streaming)?;
+        match compression.compression {
+            Compression::GZip => Some(compression),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Some(compression),
+            _ => None,
+        }
+    }
+
+    /// Runs `finish`, turning a panic into an error so that the raw file is
+    /// left behind as after a failed compression, and reports any error to
+    /// the error hook.
+    fn finish_caught(
+        &self,
+        file: PathBuf,
+        bucket: u64,
+        compression: Option<Arc<dyn Compressor>>,
+        bloom: Option<index::BloomFilter>,
+        event: RotationEvent,
+    ) -> Result<(), Error> {
+        let raw_size = event.raw_size;
+        let finish = || self.finish(file.clone(), bucket, compression, bloom, event);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(finish)).unwrap_or_else(
+            |payload| {
+                // undo the bookkeeping if `compress` didn't return
+                let mut compressing = self.retention.compressing.lock().unwrap();
+                if compressing.contains(&file) {
+                    compressing.retain(|p| *p != file);
+                    drop(compressing);
+                    self.update_backlog(raw_size, false);
+                    self.finalized_in_bucket(bucket, &file, raw_size);
+                }
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown panic");
+                Err(Error::other(format!(
+                    "finishing {} panicked: {}",
+                    file.display(),
+                    message
+                )))
+            },
+        );
+        if let (Err(e), Some(hook)) = (result.as_ref(), self.error_hook.as_ref()) {
+            hook(&RotatingFileError::Compression(Error::new(
+                e.kind(),
+                e.to_string(),
+            )));
+        }
+        result
+    }
+
+    /// Compresses a rotated-out file of `bucket` and runs the pipeline on
+    /// it, `event` is sent once it is compressed.
+    fn finish(
+        &self,
+        mut file: PathBuf,
+        bucket: u64,
+        compression: Option<Arc<dyn Compressor>>,
+        bloom: Option<index::BloomFilter>,
+        event: RotationEvent,
+    ) -> Result<(), Error> {
+        let (id, raw_size) = (event.id, event.raw_size);
+        if let Some(compression) = compression {
+            let compressed = self.compress(file.clone(), compression.as_ref(), event);
+            self.update_backlog(raw_size, false);
+            self.retention
+                .compressing
+                .lock()
+                .unwrap()
+                .retain(|p| *p != file);
+            // the bucket is done with the file either way
+            let finalized = compressed.as_ref().unwrap_or(&file);
+            if let Some(bloom) = bloom.as_ref() {
+                self.save_bloom(finalized, bloom);
+            }
+            self.finalized_in_bucket(bucket, finalized, raw_size);
+            file = compressed?;
+        }
+        if !self.post_command.is_empty() {
+            self.post_process(&file)?;
+        }
+        if let Some(pipeline) = self.pipeline.as_ref() {
+            pipeline.run(
+                &self.manifest,
+                &file,
+                id,
+                None,
+                |path| self.transition(path, FileLifecycle::Shipped),
+                |path| self.delete_rotated(path),
+            )?;
+        }
+
+        // remove from the handles vector
+        if let Ok(ref mut guard) = self.handles.try_lock() {
+            let current_id = std::thread::current().id();
             if let Some(pos) = guard.iter().position(|h| h.thread().id() == current_id) {
                 guard.remove(pos);
             }
         }
 
-        ret
+        Ok(())
+    }
+
+    /// Saves the bloom filter of `file` next to it, a failure only costs
+    /// searches the ability to skip it.
+    fn save_bloom(&self, file: &Path, bloom: &index::BloomFilter) {
+        let sidecar = index::sidecar_path(file);
+        if let Err(e) = fs::write(&sidecar, bloom.to_bytes()) {
+            error!("Failed to save {}: {}", sidecar.display(), e);
+        }
+    }
+
+    /// Saves the line index of `file` next to it, a failure only costs
+    /// readers the ability to seek.
+    fn save_line_index(&self, file: &Path, line_index: &index::LineIndex) {
+        let sidecar = index::line_index_path(file);
+        if let Err(e) = fs::write(&sidecar, line_index.to_bytes()) {
+            error!("Failed to save {}: {}", sidecar.display(), e);
+        }
+    }
+
+    /// Runs the post-processing command on `file`, fails if it can't be run
+    /// or exits with a non-zero status.
+    fn post_process(&self, file: &Path) -> Result<(), Error> {
+        let program = self.post_command[0].as_str();
+        let output = std::process::Command::new(program)
+            .args(&self.post_command[1..])
+            .arg(file)
+            .stdin(std::process::Stdio::null())
+            .output()
+            .inspect_err(|e| error!("Failed to run {} on {}: {}", program, file.display(), e))?;
+        let status = output.status;
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        let event = PostProcessed {
+            path: file.to_path_buf(),
+            output,
+        };
+        self.post_processed
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+
+        if status.success() {
+            return Ok(());
+        }
+        let e = Error::other(format!(
+            "{} exited with {} on {}: {}",
+            program,
+            status,
+            file.display(),
+            stderr
+        ));
+        error!("{}", e);
+        Err(e)
+    }
+
+    fn notify_rotations(&self, event: RotationEvent) {
+        if self.links.recent > 0 {
+            if let Err(e) = self.link_recent(&event.path) {
+                error!("Failed to link {} in recent/: {}", event.path.display(), e);
+            }
+        }
+        self.rotations
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+        if self.retention.max_total_size > 0 {
+            if let Err(e) = self.prune_to_total_size() {
+                error!("Failed to prune {}: {}", self.root_dir(), e);
+            }
+        }
+    }
+
+    fn notify_watchers(&self, path: PathBuf) {
+        // drop receivers that have gone away
+        self.watchers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(path.clone()).is_ok());
+    }
+
+    /// Returns the current time according to the clock.
+    fn now(&self) -> SystemTime {
+        self.clock
+            .as_ref()
+            .map_or_else(SystemTime::now, |clock| clock())
+    }
+
+    /// Moves a finalized file out of the staging directory into `root_dir`,
+    /// or a `compressed` one to the archive directory if any, returns where
+    /// it ended up.
+    fn publish(&self, path: PathBuf, compressed: bool) -> Result<PathBuf, Error> {
+        let dir = match (self.archive_dir.as_ref(), self.staging_dir.as_ref()) {
+            (Some(archive_dir), _) if compressed => archive_dir.clone(),
+            (_, Some(staging_dir)) if path.starts_with(staging_dir) => self.root_dir(),
+            _ => return Ok(path),
+        };
+        let published = Path::new(&dir).join(path.file_name().unwrap());
+        #[cfg(feature = "failpoints")]
+        self.failpoints.check(failpoints::FailPoint::Rename)?;
+        writer::move_file(&path, &published)?;
+        self.sync_dir(&published)?;
+        self.sync_dir(&path)?;
+        Ok(published)
+    }
+
+    /// Syncs the directory of `path` in write-through mode.
+    fn sync_dir(&self, path: &Path) -> Result<(), Error> {
+        if self.write_through {
+            writer::sync_parent(path)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn health(&self) -> Health {
+        *self.health.lock().unwrap()
+    }
+
+    fn set_health(&self, health: Health) {
+        let mut guard = self.health.lock().unwrap();
+        if *guard != health {
+            match health {
+                Health::Healthy => info!("{} is writable again", self.root_dir()),
+                _ => warn!("{} is read-only, health is {:?}", self.root_dir(), health),
+            }
+            *guard = health;
+        }
+    }
+
+    /// Checks whether a file can be created in `root_dir`.
+    fn root_dir_writable(&self) -> bool {
+        self.probe_root_dir().is_ok()
+    }
+
+    /// Creates and deletes a file in `root_dir`.
+    fn probe_root_dir(&self) -> Result<(), Error> {
+        let probe = Path::new(&self.root_dir()).join(".rotfile-probe");
+        let _ = fs::remove_file(&probe);
+        drop(self.backend.create(&probe)?);
+        fs::remove_file(&probe)
+    }
+
+    /// Creates a file in `root_dir`, or in the fallback directory while
+    /// `root_dir` is read-only, for interval `timestamp` and named for `named`.
+    fn create_context(
+        &self,
+        timestamp: u64,
+        named: DateTime<Utc>,
+    ) -> Result<CurrentContext, Error> {
+        let dir = &self.staging_dir.clone().unwrap_or_else(|| self.root_dir());
+        let mut created = self.create_context_in(dir, timestamp, named);
+        if self.recreate_root_dir
+            && created
+                .as_ref()
+                .is_err_and(|e| e.kind() == ErrorKind::NotFound)
+        {
+            fs::create_dir_all(dir)?;
+            warn!("{} was deleted, recreated it", dir);
+            let recreated = PathBuf::from(dir);
+            self.recreations
+                .lock()
+                .unwrap()
+                .retain(|tx| tx.send(recreated.clone()).is_ok());
+            created = self.create_context_in(dir, timestamp, named);
+        }
+        match created {
+            Err(e) if e.kind() == ErrorKind::ReadOnlyFilesystem => {
+                let Some(fallback_dir) = self.fallback_dir.as_ref() else {
+                    self.set_health(Health::ReadOnly);
+                    return Err(e);
+                };
+                let ctx = self.create_context_in(fallback_dir.as_str(), timestamp, named)?;
+                self.set_health(Health::Fallback);
+                Ok(ctx)
+            }
+            Err(e) => Err(e),
+            Ok(ctx) => {
+                self.set_health(Health::Healthy);
+                Ok(ctx)
+            }
+        }
+    }
+
+    fn create_context_in(
+        &self,
+        dir: &str,
+        timestamp: u64,
+        named: DateTime<Utc>,
+    ) -> Result<CurrentContext, Error> {
+        let root_dir = self.root_dir();
+        let sequence = if self.naming.has_sequence() {
+            let dirs = [Some(&root_dir), self.staging_dir.as_ref()]
+                .into_iter()
+                .chain([self.archive_dir.as_ref(), self.fallback_dir.as_ref()])
+                .flatten()
+                .map(String::as_str);
+            Some(self.sequence.take(dirs, &self.naming)?)
+        } else {
+            None
+        };
+        let dt_str = self
+            .naming
+            .sequenced(&self.naming.format_date(&named), sequence);
+
+        // continue from the last index used in this bucket instead of
+        // rescanning the directory, `create_new` guards against races
+        let mut index = self.index_cache.next(dt_str.as_str());
+        loop {
+            let file_name = self.naming.file_name(dt_str.as_str(), index);
+            let file_name = self.naming.expand(file_name, None, sequence);
+            let existing = std::iter::once(dir)
+                .chain(self.staging_dir.as_ref().map(|_| root_dir.as_str()))
+                .chain(self.archive_dir.as_deref())
+                .find_map(|dir| self.naming.taken(dir, &file_name));
+            if let Some(existing) = existing {
+                self.collide(&existing)?;
+                index += 1;
+                continue;
+            }
+            let file_name = file_name.replace(naming::REASON, naming::ACTIVE_REASON);
+            let streamed = self.streamed();
+            let file_path = match streamed {
+                Some(compression) => {
+                    Path::new(dir).join(file_name + compression.compression.extension())
+                }
+                None => Path::new(dir).join(file_name),
+            };
+            #[cfg(feature = "failpoints")]
+            self.failpoints.check(failpoints::FailPoint::Open)?;
+            let created = if self.write_through {
+                self.backend.create_write_through(&file_path)
+            } else {
+                self.backend.create(&file_path)
+            };
+            match created {
+                Ok(file) => {
+                    let (file, disk_written) = match streamed {
+                        Some(compression) => {
+                            let (file, written) = writer::compressing(file, &compression)?;
+                            (file, Some(written))
+                        }
+                        None => (file, None),
+                    };
+                    self.index_cache.set(dt_str, index);
+                    if let Some(hook) = self.post_create.as_ref() {
+                        if let Err(e) = hook(&file_path) {
+                            drop(file);
+                            let _ = fs::remove_file(&file_path);
+                            return Err(e);
+                        }
+                    }
+                    self.sync_dir(&file_path)?;
+                    self.transition(&file_path, FileLifecycle::Active);
+                    let capacity = self.coalesce.map_or(self.buffer_size, |(_, bytes)| bytes);
+                    let mut ctx = CurrentContext::new(file, file_path, timestamp, capacity);
+                    ctx.disk_written = disk_written;
+                    #[cfg(unix)]
+                    {
+                        ctx.sync_file = fs::File::open(&ctx.file_path).ok();
+                    }
+                    if self.bloom_bits > 0 {
+                        ctx.bloom = Some(index::BloomFilter::new(self.bloom_bits));
+                    }
+                    if self.line_index > 0 && streamed.is_none() {
+                        ctx.line_index = Some(index::LineIndex::new(self.line_index));
+                    }
+                    return Ok(ctx);
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    self.collide(&file_path)?;
+                    index += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reports that the name of the next file is taken by `existing`, fails
+    /// unless the index may be bumped.
+    fn collide(&self, existing: &Path) -> Result<(), Error> {
+        if let Some(hook) = self.collision_hook.as_ref() {
+            hook(existing);
+        }
+        match self.collision {
+            CollisionPolicy::Bump => {
+                warn!("{} already exists, bumping the index", existing.display());
+                Ok(())
+            }
+            CollisionPolicy::Error => Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} already exists", existing.display()),
+            )),
+        }
+    }
+
+    /// Compresses a rotated-out file, returns the path of the archive.
+    fn compress(
+        &self,
+        file: PathBuf,
+        compressor: &dyn Compressor,
+        event: RotationEvent,
+    ) -> Result<PathBuf, Error> {
+        #[cfg(feature = "failpoints")]
+        self.failpoints.check(failpoints::FailPoint::Compress)?;
+        // leave the raw file to whoever picks up after the abort
+        self.check_aborted()?;
+        let span = Span::enter(Op::Compress, &file, event.raw_size);
+        let compressed = compress::compress_file(&file, compressor, self.conflict);
+        let (out_file_path, conflict) = span.result(compressed)?;
+        let compressed_size = fs::metadata(&out_file_path)?.len();
+        span.record_compressed(compressed_size);
+        drop(span);
+        // the archive is created and the file deleted in the same directory
+        self.sync_dir(&out_file_path)?;
+        let out_file_path = self.publish(out_file_path, true)?;
+        self.transition(&out_file_path, FileLifecycle::Compressed);
+        self.seal(&out_file_path);
+
+        // the raw size can't be told from the archive later
+        if self.size_measure == SizeMeasure::Raw {
+            self.raw_sizes
+                .lock()
+                .unwrap()
+                .insert(out_file_path.clone(), event.raw_size);
+        }
+
+        self.notify_rotations(RotationEvent {
+            path: out_file_path.clone(),
+            conflict,
+            ..event
+        });
+        Ok(out_file_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+    use once_cell::sync::Lazy;
+    use std::io::Write;
+    use std::path::Path;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    const TEXT: &str = "The quick brown fox jumps over the lazy dog";
+
+    #[test]
+    fn rotate_by_size() {
+        let root_dir = "./target/tmp1";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let timestamp = current_timestamp_str();
+        let rotating_file =
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None);
+
+        for _ in 0..23 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+
+        rotating_file.close();
+
+        assert!(Path::new(root_dir)
+            .join(timestamp.clone() + ".log")
+            .exists());
+        assert!(!Path::new(root_dir)
+            .join(timestamp.clone() + "-1.log")
+            .exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+
+        let timestamp = current_timestamp_str();
+        let rotating_file =
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None);
+
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+
+        rotating_file.close();
+
+        assert!(Path::new(root_dir)
+            .join(timestamp.clone() + ".log")
+            .exists());
+        assert!(Path::new(root_dir)
+            .join(timestamp.clone() + "-1.log")
+            .exists());
+        assert_eq!(
+            format!("{}\n", TEXT),
+            std::fs::read_to_string(Path::new(root_dir).join(timestamp + "-1.log")).unwrap()
+        );
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_by_time() {
+        let root_dir = "./target/tmp2";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, None, Some(1), None, None, None, None);
+
+        let timestamp1 = current_timestamp_str();
+        rotating_file.writeln(TEXT).unwrap();
+
+        std::thread::sleep(Duration::from_secs(1));
+
+        let timestamp2 = current_timestamp_str();
+        rotating_file.writeln(TEXT).unwrap();
+
+        rotating_file.close();
+
+        assert!(Path::new(root_dir).join(timestamp1 + ".log").exists());
+        assert!(Path::new(root_dir).join(timestamp2 + ".log").exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_by_size_and_gzip() {
+        let root_dir = "./target/tmp3";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let timestamp = current_timestamp_str();
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            Some(1),
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        );
+
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+
+        rotating_file.close();
+
+        assert!(Path::new(root_dir)
+            .join(timestamp.clone() + ".log.gz")
+            .exists());
+        assert!(Path::new(root_dir).join(timestamp + "-1.log").exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn compress_with_conflict_policy() {
+        let root_dir = "./target/tmp13";
+        for policy in [
+            super::ConflictPolicy::Truncate,
+            super::ConflictPolicy::Error,
+            super::ConflictPolicy::UniqueSuffix,
+        ] {
+            let _ = std::fs::remove_dir_all(root_dir);
+            let timestamp = current_timestamp_str();
+            std::fs::create_dir_all(root_dir).unwrap();
+            let stale = Path::new(root_dir).join(timestamp.clone() + ".log.gz");
+            let rotating_file = super::RotatingFile::new(
+                root_dir,
+                Some(1),
+                None,
+                Some(super::Compression::GZip),
+                None,
+                None,
+                None,
+            )
+            .with_conflict_policy(policy);
+            let rx = rotating_file.watch_rotations();
+
+            // the stale archive doesn't exist yet when the first file is named
+            rotating_file.writeln(TEXT).unwrap();
+            std::fs::write(&stale, "stale").unwrap();
+            for _ in 0..23 {
+                rotating_file.writeln(TEXT).unwrap();
+            }
+            rotating_file.close();
+
+            match policy {
+                super::ConflictPolicy::Truncate => {
+                    let event = rx.try_recv().unwrap();
+                    assert_eq!(stale, event.path);
+                    assert_eq!(Some(policy), event.conflict);
+                    assert_ne!(b"stale".to_vec(), std::fs::read(&stale).unwrap());
+                }
+                super::ConflictPolicy::Error => {
+                    assert!(rx.try_recv().is_err());
+                    assert_eq!(b"stale".to_vec(), std::fs::read(&stale).unwrap());
+                    assert!(Path::new(root_dir).join(timestamp + ".log").exists());
+                }
+                super::ConflictPolicy::UniqueSuffix => {
+                    let event = rx.try_recv().unwrap();
+                    assert_eq!(
+                        Path::new(root_dir).join(timestamp + ".log.1.gz"),
+                        event.path
+                    );
+                    assert_eq!(Some(policy), event.conflict);
+                    assert_eq!(b"stale".to_vec(), std::fs::read(&stale).unwrap());
+                }
+            }
+        }
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_with_index_separator() {
+        let root_dir = "./target/tmp14";
+        for (separator, position, name) in [
+            ("_part", super::IndexPosition::AfterDate, "{}_part1.log"),
+            (".", super::IndexPosition::BeforeDate, "1.{}.log"),
+        ] {
+            let _ = std::fs::remove_dir_all(root_dir);
+            let timestamp = current_timestamp_str();
+            let rotating_file =
+                super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None)
+                    .with_index_separator(separator, position);
+
+            for _ in 0..24 {
+                rotating_file.writeln(TEXT).unwrap();
+            }
+            rotating_file.close();
+
+            let second_file = Path::new(root_dir).join(name.replace("{}", &timestamp));
+            assert!(second_file.exists());
+            let info = super::FileInfo::parse(&second_file, rotating_file.naming()).unwrap();
+            assert_eq!(1, info.index);
+        }
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn prepend_timestamp() {
+        let root_dir = "./target/tmp16";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_prepend_timestamp("%Y-%m-%dT%H:%M:%S%.3fZ");
+        let rx = rotating_file.watch_current_path();
+
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.close();
+
+        let content = std::fs::read_to_string(rx.try_recv().unwrap()).unwrap();
+        let (timestamp, line) = content.split_once(' ').unwrap();
+        assert!(DateTime::parse_from_rfc3339(timestamp).is_ok());
+        assert_eq!(format!("{}\n", TEXT), line);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn sequence_numbers() {
+        let root_dir = "./target/tmp17";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None)
+                .with_sequence_numbers(true);
+        let rx = rotating_file.watch_current_path();
+
+        for _ in 0..30 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.close();
+
+        let mut expected = 0;
+        for path in rx.try_iter() {
+            for line in std::fs::read_to_string(path).unwrap().lines() {
+                assert_eq!(format!("{} {}", expected, TEXT), line);
+                expected += 1;
+            }
+        }
+        assert_eq!(30, expected);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn line_checksums() {
+        let root_dir = "./target/tmp18";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_sequence_numbers(true)
+            .with_line_checksums(true);
+        let rx = rotating_file.watch_current_path();
+
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.close();
+
+        let content = std::fs::read_to_string(rx.try_recv().unwrap()).unwrap();
+        let line = content.strip_suffix('\n').unwrap();
+        assert_eq!(
+            Some(format!("0 {}", TEXT).as_str()),
+            super::verify_checksum(line)
+        );
+        // a torn line fails the check
+        assert_eq!(None, super::verify_checksum(&line[..line.len() - 1]));
+        assert_eq!(None, super::verify_checksum(&line.replace("fox", "cat")));
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn repair_last_file() {
+        let root_dir = "./target/tmp19";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_line_checksums(true);
+        let rx = rotating_file.watch_current_path();
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.close();
+        let path = rx.try_recv().unwrap();
+        let expected = std::fs::read_to_string(&path).unwrap();
+
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_line_checksums(true);
+        assert_eq!(0, rotating_file.repair_last_file().unwrap());
+
+        // a line cut short without its newline
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"The quick").unwrap();
+        assert_eq!(9, rotating_file.repair_last_file().unwrap());
+        assert_eq!(expected, std::fs::read_to_string(&path).unwrap());
+
+        // a complete line whose checksum doesn't match
+        file.write_all(b"The quick 00000000\n").unwrap();
+        assert_eq!(19, rotating_file.repair_last_file().unwrap());
+        assert_eq!(expected, std::fs::read_to_string(&path).unwrap());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn sanitizer() {
+        let root_dir = "./target/tmp97";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_sanitizer(super::Sanitizer {
+                strip_ansi: true,
+                ..Default::default()
+            });
+        let rx = rotating_file.watch_current_path();
+        let mut writer = std::io::LineWriter::new(&rotating_file);
+        writer
+            .write_all(b"\x1b[1;31mERROR\x1b[0m done \xff\n")
+            .unwrap();
+        drop(writer);
+        rotating_file.write_all(b"\x1b[32mOK\x1b[0m\n").unwrap();
+        rotating_file.close();
+
+        assert_eq!(
+            "ERROR done \u{fffd}\nOK\n",
+            std::fs::read_to_string(rx.try_recv().unwrap()).unwrap()
+        );
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn write_through_backend() {
+        use std::io::Error;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // counts syncs, without a write-through mode of its own
+        struct Counting(Arc<AtomicUsize>);
+        struct CountingFile(std::fs::File, Arc<AtomicUsize>);
+        impl super::Backend for Counting {
+            fn create(&self, path: &Path) -> Result<Box<dyn super::Sink>, Error> {
+                let file = std::fs::File::create_new(path)?;
+                Ok(Box::new(CountingFile(file, self.0.clone())))
+            }
+        }
+        impl Write for CountingFile {
+            fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+                self.0.write(buf)
+            }
+            fn flush(&mut self) -> Result<(), Error> {
+                self.0.flush()
+            }
+        }
+        impl super::Sink for CountingFile {
+            fn sync(&mut self) -> Result<(), Error> {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                self.0.sync_all()
+            }
+        }
+
+        let root_dir = "./target/tmp96";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let syncs = Arc::new(AtomicUsize::new(0));
+        // the backend set after write-through still writes through
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_write_through(true)
+            .with_backend(Counting(syncs.clone()));
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.writeln(TEXT).unwrap();
+        assert_eq!(2, syncs.load(Ordering::SeqCst));
+        rotating_file.close();
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn write_through() {
+        let root_dir = "./target/tmp20";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_write_through(true);
+        let rx = rotating_file.watch_current_path();
+
+        rotating_file.writeln(TEXT).unwrap();
+        // visible without close()
+        let path = rx.try_recv().unwrap();
+        assert_eq!(
+            format!("{}\n", TEXT),
+            std::fs::read_to_string(&path).unwrap()
+        );
+        rotating_file.writeln(TEXT).unwrap();
+        assert_eq!(
+            format!("{}\n{}\n", TEXT, TEXT),
+            std::fs::read_to_string(&path).unwrap()
+        );
+        rotating_file.close();
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn write_through_failed_open() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::mpsc::channel;
+        use std::sync::Mutex;
+
+        let root_dir = "./target/tmp108";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let (release_tx, release_rx) = channel::<()>();
+        let release_rx = Mutex::new(release_rx);
+        let failed = AtomicBool::new(false);
+        // the first file fails to be created once released
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_write_through(true)
+            .with_post_create_hook(move |_| {
+                if failed.swap(true, Ordering::Relaxed) {
+                    return Ok(());
+                }
+                let _ = release_rx.lock().unwrap().recv();
+                Err(std::io::ErrorKind::Other.into())
+            });
+
+        let writer = &rotating_file;
+        std::thread::scope(|scope| {
+            let creating = scope.spawn(|| writer.writeln("first"));
+            let waiting = scope.spawn(|| writer.writeln("second"));
+            // both lines buffered, the second one waiting for the file
+            while writer.inner.context.lock().unwrap().pending.len() < 13 {
+                std::thread::yield_now();
+            }
+            release_tx.send(()).unwrap();
+            assert!(creating.join().unwrap().is_err());
+            assert!(waiting.join().unwrap().is_err());
+        });
+
+        rotating_file.writeln("third").unwrap();
+        let path = rotating_file.inner.current_path().unwrap();
+        assert_eq!("third\n", std::fs::read_to_string(path).unwrap());
+        rotating_file.close();
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn newline_policy() {
+        let root_dir = "./target/tmp21";
+        for (policy, expected) in [
+            (super::NewlinePolicy::Keep, "a\\b\nc\r\n"),
+            (super::NewlinePolicy::Escape, "a\\\\b\\nc\\r\n"),
+            (super::NewlinePolicy::Replace, "a\\b c \n"),
+        ] {
+            let _ = std::fs::remove_dir_all(root_dir);
+            let rotating_file =
+                super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+                    .with_newline_policy(policy);
+            let rx = rotating_file.watch_current_path();
+
+            rotating_file.writeln("a\\b\nc\r").unwrap();
+            rotating_file.close();
+
+            let content = std::fs::read_to_string(rx.try_recv().unwrap()).unwrap();
+            assert_eq!(expected, content);
+        }
+
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_newline_policy(super::NewlinePolicy::Reject);
+        let err = rotating_file.writeln("a\nb").unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, err.kind());
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.close();
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn strip_ansi() {
+        let root_dir = "./target/tmp22";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_strip_ansi(true);
+        let rx = rotating_file.watch_current_path();
+
+        rotating_file
+            .writeln("\x1b[32mINFO\x1b[0m The quick brown fox")
+            .unwrap();
+        rotating_file.close();
+
+        assert_eq!(
+            "INFO The quick brown fox\n",
+            std::fs::read_to_string(rx.try_recv().unwrap()).unwrap()
+        );
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn thread_count() {
+        let root_dir = "./target/tmp23";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            None,
+            Some(3600),
+            None,
+            None,
+            Some("trades-".to_string()),
+            None,
+        )
+        .with_precreate(1)
+        .with_retention_age(Duration::from_secs(86400));
+        assert_eq!(0, rotating_file.thread_count());
+
+        // the pre-creation and retention threads
+        rotating_file.writeln(TEXT).unwrap();
+        assert_eq!(2, rotating_file.thread_count());
+        let handle = rotating_file.inner.threads.precreator.lock().unwrap();
+        assert_eq!(
+            Some("rotfile-precreate-trades-"),
+            handle.as_ref().unwrap().thread().name()
+        );
+        drop(handle);
+
+        rotating_file.close();
+        assert_eq!(0, rotating_file.thread_count());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn fallback_when_read_only() {
+        use std::io::{Error, ErrorKind};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+
+        // fails with EROFS under `root_dir` while `read_only` is set
+        struct Switch {
+            root_dir: &'static str,
+            read_only: Arc<AtomicBool>,
+        }
+        struct SwitchedFile {
+            file: std::fs::File,
+            read_only: Option<Arc<AtomicBool>>,
+        }
+        impl super::Backend for Switch {
+            fn create(&self, path: &Path) -> Result<Box<dyn super::Sink>, Error> {
+                let in_root = path.starts_with(self.root_dir);
+                if in_root && self.read_only.load(Ordering::SeqCst) {
+                    return Err(ErrorKind::ReadOnlyFilesystem.into());
+                }
+                let file = std::fs::File::create_new(path)?;
+                let read_only = in_root.then(|| self.read_only.clone());
+                Ok(Box::new(SwitchedFile { file, read_only }))
+            }
+        }
+        impl Write for SwitchedFile {
+            fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+                if let Some(true) = self.read_only.as_ref().map(|r| r.load(Ordering::SeqCst)) {
+                    return Err(ErrorKind::ReadOnlyFilesystem.into());
+                }
+                self.file.write(buf)
+            }
+            fn flush(&mut self) -> Result<(), Error> {
+                self.file.flush()
+            }
+        }
+        impl super::Sink for SwitchedFile {
+            fn sync(&mut self) -> Result<(), Error> {
+                self.file.sync_all()
+            }
+        }
+
+        let (root_dir, fallback_dir) = ("./target/tmp24", "./target/tmp24-fallback");
+        let _ = std::fs::remove_dir_all(root_dir);
+        let _ = std::fs::remove_dir_all(fallback_dir);
+        let read_only = Arc::new(AtomicBool::new(false));
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_backend(Switch {
+                root_dir,
+                read_only: read_only.clone(),
+            })
+            .with_write_through(true);
+        let rx = rotating_file.watch_current_path();
+
+        rotating_file.writeln("1").unwrap();
+        read_only.store(true, Ordering::SeqCst);
+        let err = rotating_file.writeln("2").unwrap_err();
+        assert_eq!(ErrorKind::ReadOnlyFilesystem, err.kind());
+        assert_eq!(super::Health::ReadOnly, rotating_file.health());
+        rotating_file.close();
+
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_write_through(true)
+            .with_fallback_dir(fallback_dir)
+            .with_backend(Switch {
+                root_dir,
+                read_only: read_only.clone(),
+            });
+        let rx2 = rotating_file.watch_current_path();
+
+        rotating_file.writeln("3").unwrap();
+        assert_eq!(super::Health::Fallback, rotating_file.health());
+        read_only.store(false, Ordering::SeqCst);
+        rotating_file.writeln("4").unwrap();
+        assert_eq!(super::Health::Healthy, rotating_file.health());
+        rotating_file.close();
+
+        let first = rx.try_recv().unwrap();
+        assert_eq!("1\n", std::fs::read_to_string(first).unwrap());
+        let fallback = rx2.try_recv().unwrap();
+        assert!(fallback.starts_with(fallback_dir));
+        assert_eq!("3\n", std::fs::read_to_string(fallback).unwrap());
+        let resumed = rx2.try_recv().unwrap();
+        assert!(resumed.starts_with(root_dir));
+        assert_eq!("4\n", std::fs::read_to_string(resumed).unwrap());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+        std::fs::remove_dir_all(fallback_dir).unwrap();
+    }
+
+    #[test]
+    fn write_through_rotation() {
+        let root_dir = "./target/tmp26";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let timestamp = current_timestamp_str();
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            Some(1),
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        )
+        .with_write_through(true);
+
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.close();
+
+        assert!(Path::new(root_dir)
+            .join(timestamp.clone() + ".log.gz")
+            .exists());
+        assert!(Path::new(root_dir).join(timestamp + "-1.log").exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn pre_delete_hook() {
+        let root_dir = "./target/tmp27";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None)
+                .with_pre_delete_hook(|path| !path.to_str().unwrap().ends_with("-1.log"));
+        let rx = rotating_file.watch_rotations();
+
+        for _ in 0..47 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.close();
+
+        let first = rx.try_recv().unwrap().path;
+        let second = rx.try_recv().unwrap().path;
+        assert!(rotating_file.delete_rotated(&first).unwrap());
+        assert!(!first.exists());
+        // vetoed
+        assert!(!rotating_file.delete_rotated(&second).unwrap());
+        assert!(second.exists());
+        assert_eq!(23 * (TEXT.len() as u64 + 1), rotating_file.archived_size());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn pipeline() {
+        use std::collections::HashMap;
+        use std::path::PathBuf;
+        use std::sync::{Arc, Mutex};
+
+        // fails the first upload, keeps checksums of uploaded files
+        #[derive(Clone, Default)]
+        struct Flaky(Arc<Mutex<(usize, HashMap<PathBuf, u32>)>>);
+        impl super::Uploader for Flaky {
+            fn upload(&self, path: &Path) -> Result<(), std::io::Error> {
+                let mut guard = self.0.lock().unwrap();
+                guard.0 += 1;
+                if guard.0 == 1 {
+                    return Err(std::io::ErrorKind::TimedOut.into());
+                }
+                let crc = crc32fast::hash(&std::fs::read(path)?);
+                guard.1.insert(path.to_path_buf(), crc);
+                Ok(())
+            }
+            fn verify(&self, path: &Path, checksum: u32) -> Result<bool, std::io::Error> {
+                Ok(self.0.lock().unwrap().1.get(path) == Some(&checksum))
+            }
+        }
+
+        let root_dir = "./target/tmp28";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let uploader = Flaky::default();
+        let pipeline = super::Pipeline::new(uploader.clone()).with_retries(1, Duration::ZERO);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            Some(1),
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        )
+        .with_pipeline(pipeline.clone());
+        let rx = rotating_file.watch_rotations();
+
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.close();
+
+        let event = rx.try_recv().unwrap();
+        let archive = event.path;
+        assert!(!archive.exists());
+        assert_eq!(2, uploader.0.lock().unwrap().0);
+        assert!(uploader.0.lock().unwrap().1.contains_key(&archive));
+        let manifest =
+            std::fs::read_to_string(Path::new(root_dir).join(".rotfile-manifest")).unwrap();
+        let last = manifest.lines().last().unwrap();
+        assert!(last.contains("\tdeleted\t"));
+        // the manifest entry carries the id of the rotation event
+        assert!(last.ends_with(&event.id.to_string()));
+
+        // a crash after the upload leaves the file to verify and delete
+        let file = Path::new(root_dir).join("2024-05-01-00-00-00.log");
+        std::fs::write(&file, TEXT).unwrap();
+        let crc = crc32fast::hash(TEXT.as_bytes());
+        uploader.0.lock().unwrap().1.insert(file.clone(), crc);
+        std::fs::write(
+            Path::new(root_dir).join(".rotfile-manifest"),
+            format!("2024-05-01-00-00-00.log\tuploaded\t{:08x}\n", crc),
+        )
+        .unwrap();
+
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_pipeline(pipeline);
+        assert_eq!(1, rotating_file.resume_pipeline().unwrap());
+        assert!(!file.exists());
+        assert_eq!(0, rotating_file.resume_pipeline().unwrap());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_ids() {
+        use std::collections::HashMap;
+        use std::path::PathBuf;
+        use std::sync::{Arc, Mutex};
+
+        // keeps the id each file was uploaded with
+        #[derive(Clone, Default)]
+        struct Tagging(Arc<Mutex<HashMap<PathBuf, super::Uuid>>>);
+        impl super::Uploader for Tagging {
+            fn upload(&self, _path: &Path) -> Result<(), std::io::Error> {
+                unreachable!()
+            }
+            fn upload_with_id(&self, path: &Path, id: super::Uuid) -> Result<(), std::io::Error> {
+                self.0.lock().unwrap().insert(path.to_path_buf(), id);
+                Ok(())
+            }
+            fn verify(&self, _path: &Path, _checksum: u32) -> Result<bool, std::io::Error> {
+                Ok(true)
+            }
+        }
+
+        let root_dir = "./target/tmp103";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let uploader = Tagging::default();
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            None,
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        )
+        .with_pipeline(super::Pipeline::new(uploader.clone()));
+        let rx = rotating_file.watch_rotations();
+        for _ in 0..2 {
+            rotating_file.writeln(TEXT).unwrap();
+            rotating_file.rotate().unwrap();
+        }
+        rotating_file.close();
+
+        let events = rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(2, events.len());
+        assert_ne!(events[0].id, events[1].id);
+        let manifest =
+            std::fs::read_to_string(Path::new(root_dir).join(".rotfile-manifest")).unwrap();
+        for event in events {
+            assert_eq!(Some(&event.id), uploader.0.lock().unwrap().get(&event.path));
+            // every stage of the file is recorded with the same id
+            let file_name = event.path.file_name().unwrap().to_str().unwrap();
+            let lines = manifest
+                .lines()
+                .filter(|line| line.starts_with(file_name))
+                .collect::<Vec<_>>();
+            assert_eq!(4, lines.len());
+            assert!(lines
+                .iter()
+                .all(|line| line.ends_with(&format!("\t{}", event.id))));
+        }
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serve_control() {
+        use std::io::{BufRead, BufReader};
+        use std::os::unix::net::UnixStream;
+
+        let root_dir = "./target/tmp29";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None);
+        let socket = format!("{}/control.sock", root_dir);
+        rotating_file.serve_control(&socket).unwrap();
+        let rx = rotating_file.watch_rotations();
+        rotating_file.writeln(TEXT).unwrap();
+
+        let mut stream = UnixStream::connect(&socket).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut command = |command: &str| {
+            writeln!(stream, "{}", command).unwrap();
+            let mut response = String::new();
+            reader.read_line(&mut response).unwrap();
+            response
+        };
+        assert!(command("status").starts_with("health=Healthy path=./target/tmp29/"));
+        assert_eq!("ok\n", command("flush"));
+        assert_eq!("ok\n", command("rotate"));
+        assert!(command("status").contains(" path= written=0 "));
+        assert!(command("reboot").starts_with("error: "));
+        assert!(rx.try_recv().is_ok());
+
+        rotating_file.close();
+        assert!(!Path::new(&socket).exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "systemd"))]
+    #[test]
+    fn start_watchdog() {
+        use std::os::unix::net::UnixDatagram;
+
+        let root_dir = "./target/tmp30";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None);
+        let socket = format!("{}/notify.sock", root_dir);
+        let systemd = UnixDatagram::bind(&socket).unwrap();
+        systemd
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+
+        std::env::set_var("NOTIFY_SOCKET", &socket);
+        std::env::set_var("WATCHDOG_USEC", "100000");
+        assert!(rotating_file.start_watchdog().unwrap());
+        rotating_file.writeln(TEXT).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = systemd.recv(&mut buf).unwrap();
+        let state = std::str::from_utf8(&buf[..n]).unwrap();
+        assert!(state.starts_with("WATCHDOG=1\nSTATUS=health=Healthy "));
+
+        rotating_file.close();
+        assert_eq!(0, rotating_file.thread_count());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn archived_size() {
+        for (i, measure) in [super::SizeMeasure::Raw, super::SizeMeasure::Compressed]
+            .into_iter()
+            .enumerate()
+        {
+            let root_dir = format!("./target/tmp12-{}", i);
+            let _ = std::fs::remove_dir_all(&root_dir);
+            let rotating_file = super::RotatingFile::new(
+                &root_dir,
+                Some(1),
+                None,
+                Some(super::Compression::GZip),
+                None,
+                None,
+                None,
+            )
+            .with_size_measure(measure);
+
+            for _ in 0..24 {
+                rotating_file.writeln(TEXT).unwrap();
+            }
+
+            rotating_file.close();
+
+            let raw_size = 23 * (TEXT.len() as u64 + 1);
+            match measure {
+                super::SizeMeasure::Raw => assert_eq!(raw_size, rotating_file.archived_size()),
+                super::SizeMeasure::Compressed => {
+                    assert!(rotating_file.archived_size() < raw_size)
+                }
+            }
+
+            std::fs::remove_dir_all(&root_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn archived_size_follows_directory() {
+        let root_dir = "./target/tmp93";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            None,
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        )
+        .with_size_measure(super::SizeMeasure::Raw);
+        let rx = rotating_file.watch_rotations();
+        for _ in 0..2 {
+            rotating_file.writeln(TEXT).unwrap();
+            rotating_file.rotate().unwrap();
+        }
+        rotating_file.close();
+        let raw_size = TEXT.len() as u64 + 1;
+        assert_eq!(2 * raw_size, rotating_file.archived_size());
+
+        // deleted by hand, forgotten
+        let archives = rx
+            .try_iter()
+            .map(|event| event.path)
+            .filter(|path| path.extension().unwrap() == "gz")
+            .collect::<Vec<_>>();
+        std::fs::remove_file(&archives[0]).unwrap();
+        assert_eq!(raw_size, rotating_file.archived_size());
+        assert_eq!(1, rotating_file.inner.raw_sizes.lock().unwrap().len());
+
+        // left by an earlier run, measured on disk
+        let reopened = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_size_measure(super::SizeMeasure::Raw);
+        let on_disk = std::fs::metadata(&archives[1]).unwrap().len();
+        assert_eq!(on_disk, reopened.archived_size());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_by_size_and_zip() {
+        let root_dir = "./target/tmp4";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let timestamp = current_timestamp_str();
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            Some(1),
+            None,
+            Some(super::Compression::Zip),
+            None,
+            None,
+            None,
+        );
+
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+
+        rotating_file.close();
+
+        assert!(Path::new(root_dir)
+            .join(timestamp.clone() + ".log.zip")
+            .exists());
+        assert!(Path::new(root_dir).join(timestamp + "-1.log").exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_by_time_and_gzip() {
+        let root_dir = "./target/tmp5";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            None,
+            Some(1),
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        );
+
+        let timestamp1 = current_timestamp_str();
+        rotating_file.writeln(TEXT).unwrap();
+
+        std::thread::sleep(Duration::from_secs(1));
+
+        let timestamp2 = current_timestamp_str();
+        rotating_file.writeln(TEXT).unwrap();
+
+        rotating_file.close();
+
+        assert!(Path::new(root_dir).join(timestamp1 + ".log.gz").exists());
+        assert!(Path::new(root_dir).join(timestamp2 + ".log").exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_by_time_and_zip() {
+        let root_dir = "./target/tmp6";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            None,
+            Some(1),
+            Some(super::Compression::Zip),
+            None,
+            None,
+            None,
+        );
+
+        let timestamp1 = current_timestamp_str();
+        rotating_file.writeln(TEXT).unwrap();
+
+        std::thread::sleep(Duration::from_secs(1));
+
+        let timestamp2 = current_timestamp_str();
+        rotating_file.writeln(TEXT).unwrap();
+
+        rotating_file.close();
+
+        assert!(Path::new(root_dir).join(timestamp1 + ".log.zip").exists());
+        assert!(Path::new(root_dir).join(timestamp2 + ".log").exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn referred_in_two_threads() {
+        static ROOT_DIR: Lazy<&'static str> = Lazy::new(|| "./target/tmp7");
+        static ROTATING_FILE: Lazy<super::RotatingFile> = Lazy::new(|| {
+            super::RotatingFile::new(
+                *ROOT_DIR,
+                Some(1),
+                None,
+                Some(super::Compression::GZip),
+                None,
+                None,
+                None,
+            )
+        });
+        let _ = std::fs::remove_dir_all(*ROOT_DIR);
+
+        let timestamp = current_timestamp_str();
+        let handle1 = std::thread::spawn(move || {
+            for _ in 0..23 {
+                ROTATING_FILE.writeln(TEXT).unwrap();
+            }
+        });
+
+        let handle2 = std::thread::spawn(move || {
+            for _ in 0..23 {
+                ROTATING_FILE.writeln(TEXT).unwrap();
+            }
+        });
+
+        // trigger the third file creation
+        ROTATING_FILE.writeln(TEXT).unwrap();
+
+        let _ = handle1.join();
+        let _ = handle2.join();
+
+        ROTATING_FILE.close();
+
+        assert!(Path::new(*ROOT_DIR)
+            .join(timestamp.clone() + ".log.gz")
+            .exists());
+        assert!(Path::new(*ROOT_DIR)
+            .join(timestamp.clone() + "-1.log.gz")
+            .exists());
+
+        let third_file = Path::new(*ROOT_DIR).join(timestamp.clone() + "-2.log");
+        assert!(third_file.exists());
+        assert_eq!(
+            TEXT.len() + 1,
+            std::fs::metadata(third_file).unwrap().len() as usize
+        );
+
+        std::fs::remove_dir_all(*ROOT_DIR).unwrap();
+    }
+
+    #[test]
+    fn no_empty_files() {
+        let root_dir = "./target/tmp10";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, None, Some(1), None, None, None, None);
+        rotating_file.close();
+
+        assert_eq!(0, std::fs::read_dir(root_dir).unwrap().count());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_by_time_with_precreate() {
+        let root_dir = "./target/tmp11";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, None, Some(2), None, None, None, None)
+                .with_precreate(1);
+
+        rotating_file.writeln(TEXT).unwrap();
+        std::thread::sleep(Duration::from_secs(2));
+        assert_eq!(2, std::fs::read_dir(root_dir).unwrap().count());
+        let rx = rotating_file.watch_current_path();
+        rotating_file.writeln(TEXT).unwrap();
+
+        rotating_file.close();
+
+        // the second file was pre-created, the unused third one is discarded
+        assert!(rx.try_recv().unwrap().exists());
+        assert_eq!(2, std::fs::read_dir(root_dir).unwrap().count());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn watch_current_path() {
+        let root_dir = "./target/tmp8";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let timestamp = current_timestamp_str();
+        let rotating_file =
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None);
+        let rx = rotating_file.watch_current_path();
+        assert!(rx.try_recv().is_err());
+
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.close();
+
+        assert_eq!(
+            Path::new(root_dir).join(timestamp.clone() + ".log"),
+            rx.try_recv().unwrap()
+        );
+        assert_eq!(
+            Path::new(root_dir).join(timestamp + "-1.log"),
+            rx.try_recv().unwrap()
+        );
+        assert!(rx.try_recv().is_err());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_with_range_naming() {
+        let root_dir = "./target/tmp9";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            None,
+            Some(1),
+            Some(super::Compression::GZip),
+            None,
+            Some("trades.".to_string()),
+            None,
+        )
+        .with_range_naming(true);
+
+        let timestamp1 = current_timestamp_str();
+        rotating_file.writeln(TEXT).unwrap();
+
+        std::thread::sleep(Duration::from_secs(1));
+
+        let timestamp2 = current_timestamp_str();
+        rotating_file.writeln(TEXT).unwrap();
+
+        rotating_file.close();
+
+        assert!(Path::new(root_dir)
+            .join(format!("trades.{}-{}.log.gz", timestamp1, timestamp1))
+            .exists());
+        assert!(Path::new(root_dir)
+            .join(format!("trades.{}.log", timestamp2))
+            .exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    fn current_timestamp_str() -> String {
+        let dt: DateTime<Utc> = SystemTime::now().into();
+        let dt_str = dt.format("%Y-%m-%d-%H-%M-%S").to_string();
+        dt_str
+    }
+
+    #[test]
+    fn watch_gaps() {
+        let root_dir = "./target/tmp33";
+        let _ = std::fs::remove_dir_all(root_dir);
+        std::fs::create_dir_all(root_dir).unwrap();
+        let rotating_file =
+            super::RotatingFile::new(root_dir, None, Some(3600), None, None, None, None);
+        let gaps = rotating_file.watch_gaps();
+
+        // a file left by an earlier run three hours ago
+        let now = Utc::now().timestamp() as u64;
+        let earlier = crate::policy::bucket(now, 3600) - 3 * 3600;
+        let info = super::FileInfo {
+            prefix: String::new(),
+            sequence: None,
+            pid: None,
+            timestamp: DateTime::from_timestamp(earlier as i64, 0).unwrap(),
+            end: None,
+            index: 0,
+            conflict_index: 0,
+            compression: None,
+            reason: None,
+            extension: None,
+        };
+        std::fs::write(
+            Path::new(root_dir).join(info.file_name(rotating_file.naming())),
+            TEXT,
+        )
+        .unwrap();
+
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.close();
+
+        let gap = gaps.try_recv().unwrap();
+        assert_eq!(earlier as i64 + 3600, gap.start.timestamp());
+        assert!(gap.end.timestamp() >= earlier as i64 + 3 * 3600);
+        assert!(gaps.try_recv().is_err());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn open_time_naming() {
+        let root_dir = "./target/tmp36";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let before = Utc::now().timestamp();
+        let rotating_file =
+            super::RotatingFile::new(root_dir, None, Some(86400), None, None, None, None)
+                .with_open_time_naming(true);
+        rotating_file.writeln(TEXT).unwrap();
+        let path = rotating_file.inner.current_path().unwrap();
+        rotating_file.close();
+
+        // named for the second it was opened, not midnight
+        let info = super::FileInfo::parse(&path, rotating_file.naming()).unwrap();
+        assert!(info.timestamp.timestamp() >= before);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    // relies on the share mode std opens files with, only run on Windows
+    #[cfg(windows)]
+    #[test]
+    fn rename_while_open() {
+        let root_dir = "./target/tmp37";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None);
+        rotating_file.writeln(TEXT).unwrap();
+        let path = rotating_file.inner.current_path().unwrap();
+
+        // the handle follows the file to its new name
+        let renamed = Path::new(root_dir).join("renamed.log");
+        std::fs::rename(&path, &renamed).unwrap();
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.rotate().unwrap();
+        rotating_file.close();
+
+        let content = std::fs::read_to_string(&renamed).unwrap();
+        assert_eq!(2, content.lines().count());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn post_create_hook() {
+        use std::sync::{Arc, Mutex};
+
+        let root_dir = "./target/tmp38";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let created = Arc::new(Mutex::new(Vec::new()));
+        let rotating_file = {
+            let created = created.clone();
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None)
+                .with_post_create_hook(move |path| {
+                    // nothing written yet
+                    assert_eq!(0, std::fs::metadata(path)?.len());
+                    created.lock().unwrap().push(path.to_path_buf());
+                    Ok(())
+                })
+        };
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.close();
+        assert_eq!(2, created.lock().unwrap().len());
+
+        // a failing hook leaves no file behind
+        let root_dir = "./target/tmp38/failing";
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_post_create_hook(|_| Err(std::io::ErrorKind::PermissionDenied.into()));
+        assert!(rotating_file.writeln(TEXT).is_err());
+        rotating_file.close();
+        assert_eq!(0, std::fs::read_dir(root_dir).unwrap().count());
+
+        std::fs::remove_dir_all("./target/tmp38").unwrap();
+    }
+
+    #[test]
+    fn create_outside_lock() {
+        use std::sync::mpsc::channel;
+        use std::sync::Mutex;
+
+        let root_dir = "./target/tmp105";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let (entered_tx, entered_rx) = channel();
+        let (release_tx, release_rx) = channel::<()>();
+        let (entered_tx, release_rx) = (Mutex::new(entered_tx), Mutex::new(release_rx));
+        // holds up the creation of the file until released
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_post_create_hook(move |_| {
+                entered_tx.lock().unwrap().send(()).unwrap();
+                let _ = release_rx.lock().unwrap().recv();
+                Ok(())
+            });
+
+        let writer = &rotating_file;
+        std::thread::scope(|scope| {
+            let creating = scope.spawn(|| writer.writeln("first").unwrap());
+            entered_rx.recv().unwrap();
+            // other writers buffer the line instead of waiting for the file
+            let (done_tx, done_rx) = channel();
+            scope.spawn(move || {
+                writer.writeln("second").unwrap();
+                done_tx.send(()).unwrap();
+            });
+            let buffered = done_rx.recv_timeout(Duration::from_secs(5));
+            release_tx.send(()).unwrap();
+            buffered.unwrap();
+            creating.join().unwrap();
+        });
+        let path = rotating_file.inner.current_path().unwrap();
+        rotating_file.close();
+        assert_eq!("first\nsecond\n", std::fs::read_to_string(path).unwrap());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn min_compress_size() {
+        let root_dir = "./target/tmp39";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            None,
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        )
+        .with_min_compress_size(1024);
+        let rx = rotating_file.watch_rotations();
+
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.rotate().unwrap();
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.rotate().unwrap();
+        rotating_file.close();
+
+        assert!(rx.recv().unwrap().path.to_str().unwrap().ends_with(".log"));
+        assert!(rx
+            .recv()
+            .unwrap()
+            .path
+            .to_str()
+            .unwrap()
+            .ends_with(".log.gz"));
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn compression_tiers() {
+        use super::Compression;
+
+        let root_dir = "./target/tmp40";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_compression_tier(0, Compression::GZip.with_level(1))
+            .with_compression_tier(1024, Compression::Zip.with_level(9));
+        let rx = rotating_file.watch_rotations();
+
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.rotate().unwrap();
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.rotate().unwrap();
+        rotating_file.close();
+
+        // compression threads may finish in any order
+        let mut events = [rx.recv().unwrap(), rx.recv().unwrap()];
+        events.sort_by_key(|event| event.raw_size);
+        assert!(events[0].path.to_str().unwrap().ends_with(".log.gz"));
+        assert!(events[1].path.to_str().unwrap().ends_with(".log.zip"));
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn null_backend() {
+        let root_dir = "./target/tmp41";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None)
+                .with_backend(super::NullBackend);
+        let rx = rotating_file.watch_rotations();
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.close();
+
+        // rotated as if the bytes had been written
+        let event = rx.try_recv().unwrap();
+        assert_eq!(23 * (TEXT.len() as u64 + 1), event.raw_size);
+        assert_eq!(0, std::fs::metadata(event.path).unwrap().len());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn lifecycle() {
+        use super::FileLifecycle;
+        use std::sync::{Arc, Mutex};
+
+        let root_dir = "./target/tmp42";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let rotating_file = {
+            let states = states.clone();
+            super::RotatingFile::new(
+                root_dir,
+                Some(1),
+                None,
+                Some(super::Compression::GZip),
+                None,
+                None,
+                None,
+            )
+            .with_lifecycle_journal(true)
+            .with_lifecycle_hook(move |_, state| states.lock().unwrap().push(state))
+        };
+        let rx = rotating_file.watch_rotations();
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.close();
+        let archive = rx.try_recv().unwrap().path;
+        assert!(rotating_file.delete_rotated(&archive).unwrap());
+
+        // the second file becomes active while the first is being compressed
+        let mut states = states.lock().unwrap().clone();
+        states.sort();
+        assert_eq!(
+            vec![
+                FileLifecycle::Active,
+                FileLifecycle::Active,
+                FileLifecycle::Sealed,
+                FileLifecycle::Compressed,
+                FileLifecycle::Expired
+            ],
+            states
+        );
+
+        let journal = rotating_file.lifecycle().unwrap();
+        assert_eq!(3, journal.len());
+        let archive_name = archive.file_name().unwrap().to_str().unwrap();
+        assert_eq!(Some(&FileLifecycle::Expired), journal.get(archive_name));
+        let raw_name = archive_name.strip_suffix(".gz").unwrap();
+        assert_eq!(Some(&FileLifecycle::Sealed), journal.get(raw_name));
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn seal() {
+        let root_dir = "./target/tmp43";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None)
+                .with_seal(true);
+        let rx = rotating_file.watch_rotations();
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.close();
+
+        let path = rx.try_recv().unwrap().path;
+        assert!(std::fs::metadata(&path).unwrap().permissions().readonly());
+        // the active file is left alone
+        let active = rotating_file.inner.current_path().unwrap();
+        assert!(!std::fs::metadata(active).unwrap().permissions().readonly());
+        assert!(rotating_file.delete_rotated(&path).unwrap());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn collision_policy() {
+        use std::sync::{Arc, Mutex};
+
+        let root_dir = "./target/tmp44";
+        let _ = std::fs::remove_dir_all(root_dir);
+        std::fs::create_dir_all(root_dir).unwrap();
+        // an archive of the current hour, e.g. written before the clock
+        // jumped backwards
+        let now = Utc::now().timestamp() as u64;
+        let info = super::FileInfo {
+            prefix: String::new(),
+            sequence: None,
+            pid: None,
+            timestamp: DateTime::from_timestamp(crate::policy::bucket(now, 3600) as i64, 0)
+                .unwrap(),
+            end: None,
+            index: 0,
+            conflict_index: 0,
+            compression: Some(super::Compression::GZip),
+            reason: None,
+            extension: None,
+        };
+        let naming = super::NamingConfig::default();
+        let archive = Path::new(root_dir).join(info.file_name(&naming));
+        std::fs::write(&archive, "").unwrap();
+
+        let collisions = Arc::new(Mutex::new(Vec::new()));
+        let hook = {
+            let collisions = collisions.clone();
+            move |path: &Path| collisions.lock().unwrap().push(path.to_path_buf())
+        };
+        let rotating_file =
+            super::RotatingFile::new(root_dir, None, Some(3600), None, None, None, None)
+                .with_collision_policy(super::CollisionPolicy::Error, hook.clone());
+        assert!(rotating_file.writeln(TEXT).is_err());
+        rotating_file.close();
+
+        let rotating_file =
+            super::RotatingFile::new(root_dir, None, Some(3600), None, None, None, None)
+                .with_collision_policy(super::CollisionPolicy::Bump, hook);
+        rotating_file.writeln(TEXT).unwrap();
+        let path = rotating_file.inner.current_path().unwrap();
+        rotating_file.close();
+
+        assert_eq!(vec![archive.clone(), archive], *collisions.lock().unwrap());
+        assert_eq!(1, super::FileInfo::parse(path, &naming).unwrap().index);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn compression_backlog() {
+        let root_dir = "./target/tmp45";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            None,
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        );
+        rotating_file.writeln(TEXT).unwrap();
+        // counted from rotation, before compression starts
+        rotating_file
+            .inner
+            .update_backlog(TEXT.len() as u64 + 1, true);
+        assert_eq!(
+            super::CompressionBacklog {
+                files: 1,
+                bytes: TEXT.len() as u64 + 1
+            },
+            rotating_file.compression_backlog()
+        );
+        rotating_file
+            .inner
+            .update_backlog(TEXT.len() as u64 + 1, false);
+
+        rotating_file.rotate().unwrap();
+        rotating_file.close();
+        assert_eq!(
+            super::CompressionBacklog::default(),
+            rotating_file.compression_backlog()
+        );
+        assert!(rotating_file
+            .inner
+            .status()
+            .ends_with(" backlog_files=0 backlog_bytes=0"));
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn builder() {
+        let root_dir = "./target/tmp48";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::builder(root_dir)
+            .size_kb(1)
+            .interval(3600)
+            .compression(super::Compression::GZip)
+            .date_format("%Y%m%dT%H")
+            .prefix("trades-")
+            .suffix(".txt")
+            .build();
+        rotating_file.writeln(TEXT).unwrap();
+        let path = rotating_file.inner.current_path().unwrap();
+        rotating_file.close();
+
+        let naming = rotating_file.naming();
+        assert_eq!("trades-", naming.prefix);
+        assert_eq!(".txt", naming.suffix);
+        assert!(super::FileInfo::parse(path, naming).is_some());
+        assert_eq!(1024, rotating_file.inner.max_bytes);
+        assert_eq!(3_600_000, rotating_file.inner.interval);
+        assert_eq!(
+            Some(super::Compression::GZip),
+            rotating_file.inner.compression
+        );
+
+        // typed limits, sub-second intervals are caught in append
+        let rotating_file = super::RotatingFile::builder(root_dir)
+            .max_size(super::ByteSize::b(1500))
+            .rotate_every(Duration::from_millis(500))
+            .build();
+        assert_eq!(1500, rotating_file.inner.max_bytes);
+        assert_eq!(500, rotating_file.inner.interval);
+        assert_eq!(1, rotating_file.inner.policies.len());
+
+        // the defaults are those of an unconfigured instance
+        let config = super::Config::default();
+        let naming = super::NamingConfig::default();
+        assert_eq!(naming.date_format, config.date_format);
+        assert_eq!(naming.prefix, config.prefix);
+        assert_eq!(naming.suffix, config.suffix);
+        let rotating_file = super::RotatingFile::builder(root_dir)
+            .config(super::Config {
+                max_lines: 10,
+                suffix: ".txt".to_string(),
+                ..config
+            })
+            .build();
+        assert_eq!(".txt", rotating_file.naming().suffix);
+        assert_eq!(naming.date_format, rotating_file.naming().date_format);
+        assert_eq!(0, rotating_file.inner.max_bytes);
+        assert_eq!(1, rotating_file.inner.policies.len());
+        assert_eq!(super::DEFAULT_BUFFER_SIZE, rotating_file.inner.buffer_size);
+        assert_eq!(None, rotating_file.inner.compression_level);
+
+        // buffer size and compression level
+        let rotating_file = super::RotatingFile::builder(root_dir)
+            .compression(super::Compression::GZip)
+            .compression_level(1)
+            .buffer_size(64 * 1024)
+            .build();
+        rotating_file.writeln(TEXT).unwrap();
+        let guard = rotating_file.inner.context.lock().unwrap();
+        assert_eq!(64 * 1024, guard.current.as_ref().unwrap().file.capacity());
+        drop(guard);
+        assert_eq!(
+            Some(super::Compression::GZip.with_level(1)),
+            rotating_file.inner.compression_level()
+        );
+        rotating_file.close();
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_limits() {
+        let root_dir = "./target/tmp102";
+        let _ = std::fs::remove_dir_all(root_dir);
+        // kilobytes and seconds in the old signatures
+        let legacy = super::RotatingFile::new(root_dir, Some(2), Some(60), None, None, None, None);
+        let typed = super::RotatingFile::builder(root_dir)
+            .max_size(super::ByteSize::kb(2))
+            .rotate_every(Duration::from_secs(60))
+            .build();
+        let shims = super::RotatingFile::builder(root_dir)
+            .size_kb(2)
+            .interval(60)
+            .build();
+        for rotating_file in [&legacy, &typed, &shims] {
+            assert_eq!(2048, rotating_file.inner.max_bytes);
+            assert_eq!(60_000, rotating_file.inner.interval);
+        }
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn default_config() {
+        let config = super::Config::default();
+        assert_eq!(super::naming::DEFAULT_DATE_FORMAT, config.date_format);
+        assert_eq!(super::naming::DEFAULT_PREFIX, config.prefix);
+        assert_eq!(super::naming::DEFAULT_SUFFIX, config.suffix);
+        assert_eq!(super::DEFAULT_BUFFER_SIZE, config.buffer_size);
+        // each compression at its own default level
+        assert_eq!(None, config.compression_level);
+        assert_eq!(
+            super::compress::DEFAULT_GZIP_LEVEL,
+            super::Compression::GZip.default_level()
+        );
+        assert_eq!(
+            super::compress::DEFAULT_ZIP_LEVEL,
+            super::Compression::Zip.default_level()
+        );
+        assert_eq!(0, config.max_size.bytes());
+        assert_eq!(Duration::ZERO, config.interval);
+        assert_eq!(0, config.max_lines);
+        assert_eq!(None, config.compression);
+        assert_eq!(None, config.retention_age);
+    }
+
+    #[test]
+    fn rotation_policy() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::time::SystemTime;
+
+        use super::{FileStats, RotationPolicy, RotationTrigger};
+
+        // rotates every 3 lines or when flagged
+        struct LinesOrFlag(Arc<AtomicBool>);
+
+        impl RotationPolicy for LinesOrFlag {
+            fn should_rotate(&self, stats: &FileStats, _now: SystemTime) -> bool {
+                stats.lines >= 3 || self.0.swap(false, Ordering::Relaxed)
+            }
+        }
+
+        let root_dir = "./target/tmp74";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let flag = Arc::new(AtomicBool::new(false));
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_rotation_policy(LinesOrFlag(flag.clone()));
+        let rx = rotating_file.watch_rotations();
+        for _ in 0..4 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        assert_eq!(RotationTrigger::Policy, rx.try_recv().unwrap().trigger);
+        flag.store(true, Ordering::Relaxed);
+        rotating_file.writeln(TEXT).unwrap();
+        let event = rx.try_recv().unwrap();
+        assert_eq!(RotationTrigger::Policy, event.trigger);
+        assert_eq!(TEXT.len() as u64 + 1, event.raw_size);
+        rotating_file.close();
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn retention_patterns() {
+        let root_dir = "./target/tmp49";
+        let _ = std::fs::remove_dir_all(root_dir);
+        std::fs::create_dir_all(root_dir).unwrap();
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_retention_patterns(&["*.log*"], &["*audit*"]);
+
+        for (file_name, deleted) in [
+            ("2024-05-01.log.gz", true),
+            ("2024-05-01.audit.log", false),
+            ("2024-05-01.csv", false),
+        ] {
+            let path = Path::new(root_dir).join(file_name);
+            std::fs::write(&path, TEXT).unwrap();
+            assert_eq!(deleted, rotating_file.delete_rotated(&path).unwrap());
+            assert_eq!(deleted, !path.exists());
+        }
+        rotating_file.close();
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn io_write() {
+        let root_dir = "./target/tmp50";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None);
+        let rx = rotating_file.watch_rotations();
+        for i in 0..24 {
+            let mut writer = std::io::BufWriter::new(&rotating_file);
+            writeln!(writer, "{} {}", i, TEXT).unwrap();
+            writer.flush().unwrap();
+        }
+        rotating_file.close();
+
+        // records stay whole across the rotation
+        let event = rx.try_recv().unwrap();
+        let content = std::fs::read_to_string(event.path).unwrap();
+        assert!(content.ends_with('\n'));
+        assert_eq!(content.len() as u64, event.raw_size);
+        assert!(content.len() < 1024);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn max_buffered_bytes() {
+        use std::sync::mpsc::channel;
+        use std::sync::Mutex;
+
+        let root_dir = "./target/tmp51";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let (tx, rx) = channel();
+        let tx = Mutex::new(tx);
+        let rotating_file = std::sync::Arc::new(
+            super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+                .with_max_buffered_bytes(100, super::OverflowPolicy::Drop)
+                .with_post_create_hook(move |_| {
+                    // hold the file open while lines are buffered
+                    tx.lock().unwrap().send(()).unwrap();
+                    std::thread::sleep(std::time::Duration::from_millis(200));
+                    Ok(())
+                }),
+        );
+        let writer = {
+            let rotating_file = rotating_file.clone();
+            std::thread::spawn(move || rotating_file.writeln(TEXT).unwrap())
+        };
+        rx.recv().unwrap();
+        for _ in 0..4 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        writer.join().unwrap();
+        rotating_file.close();
+
+        // the first two lines fit in 100 bytes
+        assert_eq!(3 * (TEXT.len() as u64 + 1), rotating_file.dropped_bytes());
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        let content = std::fs::read_to_string(&files[0]).unwrap();
+        assert_eq!(2, content.lines().count());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn bucket_closes() {
+        let root_dir = "./target/tmp52";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            Some(1),
+            Some(1),
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        );
+        let rotations = rotating_file.watch_rotations();
+        let closes = rotating_file.watch_bucket_closes();
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        // start a later bucket
+        std::thread::sleep(Duration::from_millis(1100));
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.close();
+
+        let events = closes.try_iter().collect::<Vec<_>>();
+        assert!(!events.is_empty());
+        for event in events.iter() {
+            for file in event.files.iter() {
+                let info = super::FileInfo::parse(file, rotating_file.naming()).unwrap();
+                assert_eq!(event.start, info.timestamp);
+                assert!(file.to_str().unwrap().ends_with(".gz"));
+            }
+        }
+        // every rotated-out file is in a closed bucket, the active one is not
+        let rotated = rotations.try_iter().collect::<Vec<_>>();
+        let files = events.iter().map(|e| e.files.len()).sum::<usize>();
+        assert_eq!(rotated.len(), files);
+        let raw_size = events.iter().map(|e| e.raw_size).sum::<u64>();
+        assert_eq!(24 * (TEXT.len() as u64 + 1), raw_size);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn write_bytes() {
+        let root_dir = "./target/tmp53";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None);
+        let rx = rotating_file.watch_rotations();
+        // not valid UTF-8
+        let blob = [0xffu8; 300];
+        for _ in 0..4 {
+            rotating_file.write_all(&blob).unwrap();
+        }
+        assert_eq!(blob.len(), rotating_file.write(&blob).unwrap());
+        rotating_file.close();
+
+        let event = rx.try_recv().unwrap();
+        assert_eq!(900, event.raw_size);
+        assert_eq!(vec![0xffu8; 900], std::fs::read(event.path).unwrap());
+        assert!(rx.try_recv().is_err());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn try_new() {
+        let root_dir = "./target/tmp54";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::builder(root_dir).try_build().unwrap();
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.try_close().unwrap();
+
+        let invalid = super::RotatingFile::builder(root_dir)
+            .date_format("%Y-%Q")
+            .try_build();
+        assert!(matches!(
+            invalid,
+            Err(super::RotatingFileError::InvalidConfig(_))
+        ));
+
+        // a file where the directory should be
+        let blocked = "./target/tmp54/blocked";
+        std::fs::write(blocked, TEXT).unwrap();
+        assert!(super::RotatingFile::try_new(blocked, None, None, None, None, None, None).is_err());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn recent_links() {
+        let root_dir = "./target/tmp55";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None)
+                .with_recent_links(2);
+        let rx = rotating_file.watch_rotations();
+        for _ in 0..96 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.close();
+
+        let rotated = rx.try_iter().map(|e| e.path).collect::<Vec<_>>();
+        assert!(rotated.len() > 2);
+        let recent_dir = Path::new(root_dir).join("recent");
+        let links = crate::reader::files(&recent_dir, rotating_file.naming()).unwrap();
+        let names = |paths: &[std::path::PathBuf]| {
+            paths
+                .iter()
+                .map(|p| p.file_name().unwrap().to_os_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&rotated[rotated.len() - 2..]), names(&links));
+        assert_eq!(
+            TEXT.len() * 23 + 23,
+            std::fs::read(&links[0]).unwrap().len()
+        );
+
+        // deleting a file removes its link
+        assert!(rotating_file
+            .delete_rotated(&rotated[rotated.len() - 1])
+            .unwrap());
+        assert_eq!(1, std::fs::read_dir(&recent_dir).unwrap().count());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn current_link() {
+        let root_dir = "./target/tmp90";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_current_link("current.log");
+        let link = Path::new(root_dir).join("current.log");
+        rotating_file.writeln("first").unwrap();
+        rotating_file.flush().unwrap();
+        assert_eq!("first\n", std::fs::read_to_string(&link).unwrap());
+
+        rotating_file.rotate().unwrap();
+        rotating_file.writeln("second").unwrap();
+        rotating_file.flush().unwrap();
+        assert_eq!("second\n", std::fs::read_to_string(&link).unwrap());
+        #[cfg(unix)]
+        assert_eq!(
+            std::fs::canonicalize(rotating_file.inner.current_path().unwrap()).unwrap(),
+            std::fs::read_link(&link).unwrap()
+        );
+        rotating_file.close();
+
+        // not mistaken for a file of the instance
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        assert_eq!(2, files.len());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn recreate_root_dir() {
+        let root_dir = "./target/tmp56";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_recreate_root_dir(true);
+        let rx = rotating_file.watch_dir_recreated();
+        rotating_file.writeln(TEXT).unwrap();
+        std::fs::remove_dir_all(root_dir).unwrap();
+
+        rotating_file.rotate().unwrap();
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.close();
+        assert_eq!(Path::new(root_dir), rx.try_recv().unwrap());
+        assert_eq!(1, std::fs::read_dir(root_dir).unwrap().count());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn min_file_lifetime() {
+        let root_dir = "./target/tmp57";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None)
+                .with_min_file_lifetime(Duration::from_secs(3600));
+        let rx = rotating_file.watch_rotations();
+        for _ in 0..96 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        // an explicit rotation is not held back
+        rotating_file.rotate().unwrap();
+        rotating_file.close();
+
+        let event = rx.try_recv().unwrap();
+        assert_eq!(96 * (TEXT.len() as u64 + 1), event.raw_size);
+        assert!(rx.try_recv().is_err());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn streaming_compression() {
+        use std::io::BufRead;
+
+        let root_dir = "./target/tmp59";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            Some(1),
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        )
+        .with_streaming_compression(true);
+        for _ in 0..1000 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        // readable while still active
+        rotating_file.flush().unwrap();
+        let files = crate::reader::files(root_dir, &Default::default()).unwrap();
+        let active = files.last().unwrap();
+        assert!(active.to_str().unwrap().ends_with(".log.gz"));
+        assert!(crate::reader::open(active).unwrap().lines().count() > 0);
+        rotating_file.close();
+
+        // the limit applies to the compressed size
+        let files = crate::reader::files(root_dir, &Default::default()).unwrap();
+        assert!(files.len() < 1000 * (TEXT.len() + 1) / 1024);
+        let mut lines = 0;
+        for file in files.iter() {
+            assert!(file.to_str().unwrap().ends_with(".log.gz"));
+            for line in crate::reader::open(file).unwrap().lines() {
+                assert_eq!(TEXT, line.unwrap());
+                lines += 1;
+            }
+        }
+        assert_eq!(1000, lines);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn reason_naming() {
+        let root_dir = "./target/tmp60";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            Some(1),
+            None,
+            None,
+            None,
+            None,
+            Some(".{reason}.log".to_string()),
+        );
+        for _ in 0..24 {
+            rotating_file.writeln(TEXT).unwrap();
+        }
+        rotating_file.rotate().unwrap();
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.close();
+
+        // the last file stays active until rotated out
+        let mut reasons = crate::reader::files(root_dir, rotating_file.naming())
+            .unwrap()
+            .iter()
+            .map(|file| {
+                super::FileInfo::parse(file, rotating_file.naming())
+                    .unwrap()
+                    .reason
+            })
+            .collect::<Vec<_>>();
+        reasons.sort();
+        let reasons = reasons
+            .iter()
+            .flatten()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["active", "manual", "size"], reasons);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn custom_compressor() {
+        use std::io::Read;
+
+        // an in-house format, upper-casing for the sake of the test
+        struct Upper;
+        impl super::Compressor for Upper {
+            fn extension(&self) -> &str {
+                ".up"
+            }
+
+            fn compress(
+                &self,
+                _file_name: &str,
+                _modified: SystemTime,
+                reader: &mut dyn Read,
+                writer: &mut dyn Write,
+            ) -> Result<(), std::io::Error> {
+                let mut buf = Vec::new();
+                reader.read_to_end(&mut buf)?;
+                writer.write_all(&buf.to_ascii_uppercase())
+            }
+        }
+
+        let root_dir = "./target/tmp61";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_compressor(Upper);
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.rotate().unwrap();
+        rotating_file.close();
+
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        assert_eq!(1, files.len());
+        let archive = files
+            .iter()
+            .find(|file| file.to_str().unwrap().ends_with(".log.up"))
+            .unwrap();
+        assert_eq!(
+            TEXT.to_ascii_uppercase() + "\n",
+            std::fs::read_to_string(archive).unwrap()
+        );
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn poisoned() {
+        let root_dir = "./target/tmp95";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None);
+        rotating_file.writeln(TEXT).unwrap();
+        std::thread::scope(|s| {
+            let handle = s.spawn(|| {
+                let _guard = rotating_file.inner.context.lock().unwrap();
+                panic!("while writing");
+            });
+            assert!(handle.join().is_err());
+        });
+
+        // fails instead of panicking
+        let e = rotating_file.writeln(TEXT).unwrap_err();
+        assert!(matches!(e, super::RotatingFileError::Poisoned));
+        let e = rotating_file.write_all(b"raw").unwrap_err();
+        assert!(matches!(e, super::RotatingFileError::Poisoned));
+        let e = Write::write(&mut &rotating_file, b"raw").unwrap_err();
+        assert_eq!(std::io::ErrorKind::Other, e.kind());
+        for result in [
+            rotating_file.rotate().map(|_| ()),
+            rotating_file.reopen(),
+            rotating_file.flush(),
+            rotating_file.relocate("./target/tmp95/relocated", false),
+        ] {
+            assert!(matches!(result, Err(super::RotatingFileError::Poisoned)));
+        }
+        assert!(matches!(
+            rotating_file.try_close(),
+            Err(super::RotatingFileError::Poisoned)
+        ));
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn disk_full() {
+        // creates files whose writes fail as on a full disk
+        struct Full;
+        struct FullSink;
+        impl Write for FullSink {
+            fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::StorageFull.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        impl super::Sink for FullSink {
+            fn sync(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        impl super::Backend for Full {
+            fn create(&self, path: &Path) -> std::io::Result<Box<dyn super::Sink>> {
+                std::fs::File::create(path)?;
+                Ok(Box::new(FullSink))
+            }
+        }
+
+        let root_dir = "./target/tmp106";
+        let _ = std::fs::remove_dir_all(root_dir);
+        // reported when the buffer is flushed
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_backend(Full);
+        rotating_file.writeln(TEXT).unwrap();
+        let e = rotating_file.flush().unwrap_err();
+        assert!(matches!(e, super::RotatingFileError::Io(_)));
+        assert_eq!(std::io::ErrorKind::StorageFull, e.kind());
+        drop(rotating_file);
+
+        // or by the write itself
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_backend(Full)
+            .with_write_through(true);
+        let e = rotating_file.writeln(TEXT).unwrap_err();
+        assert!(matches!(e, super::RotatingFileError::Io(_)));
+        assert_eq!(std::io::ErrorKind::StorageFull, e.kind());
+        drop(rotating_file);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn compressor_panic() {
+        use std::sync::{Arc, Mutex};
+
+        // writes part of the archive, then panics
+        struct Panicking;
+        impl super::Compressor for Panicking {
+            fn extension(&self) -> &str {
+                ".boom"
+            }
+
+            fn compress(
+                &self,
+                _file_name: &str,
+                _modified: SystemTime,
+                _reader: &mut dyn std::io::Read,
+                writer: &mut dyn Write,
+            ) -> Result<(), std::io::Error> {
+                writer.write_all(b"partial")?;
+                panic!("corrupt state");
+            }
+        }
+
+        let root_dir = "./target/tmp92";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let reported = errors.clone();
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_compressor(Panicking)
+            .with_error_hook(move |e| reported.lock().unwrap().push(e.to_string()));
+        rotating_file.writeln(TEXT).unwrap();
+        let rotated = rotating_file.rotate().unwrap();
+
+        // reported as a failed compression, not a poisoned thread
+        let e = rotating_file.try_close().unwrap_err();
+        assert!(matches!(e, super::RotatingFileError::Compression(_)));
+        let errors = errors.lock().unwrap();
+        assert_eq!(1, errors.len());
+        assert!(errors[0].contains("corrupt state"));
+        assert_eq!(0, rotating_file.compression_backlog().files);
+
+        // the raw file is left alone, without the partial archive
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        assert_eq!(vec![rotated.clone()], files);
+        assert_eq!(
+            format!("{}\n", TEXT),
+            std::fs::read_to_string(rotated).unwrap()
+        );
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn post_process_command() {
+        let root_dir = "./target/tmp62";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_post_process_command(["sh", "-c", "wc -c < \"$1\"", "sh"]);
+        let rx = rotating_file.watch_post_processed();
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.rotate().unwrap();
+        assert!(rotating_file.try_close().is_ok());
+
+        let processed = rx.try_recv().unwrap();
+        assert!(processed.output.status.success());
+        assert_eq!(
+            (TEXT.len() + 1).to_string(),
+            String::from_utf8_lossy(&processed.output.stdout).trim()
+        );
+
+        // a failing command is reported on close
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_post_process_command(["sh", "-c", "echo oops >&2; exit 3"]);
+        let rx = rotating_file.watch_post_processed();
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.rotate().unwrap();
+        let e = rotating_file.try_close().unwrap_err();
+        assert!(e.to_string().contains("oops"));
+        assert_eq!(Some(3), rx.try_recv().unwrap().output.status.code());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn collision_index() {
+        use std::time::UNIX_EPOCH;
+
+        let root_dir = "./target/tmp104";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_clock(|| UNIX_EPOCH + Duration::from_secs(1_714_521_600));
+        let mut names = Vec::new();
+        for _ in 0..3 {
+            rotating_file.writeln(TEXT).unwrap();
+            let path = rotating_file.rotate().unwrap();
+            names.push(path.file_name().unwrap().to_str().unwrap().to_string());
+        }
+        assert_eq!(
+            vec![
+                "2024-05-01-00-00-00.log",
+                "2024-05-01-00-00-00-1.log",
+                "2024-05-01-00-00-00-2.log"
+            ],
+            names
+        );
+
+        // taken by someone else, skipped by the exclusive create
+        std::fs::write(Path::new(root_dir).join("2024-05-01-00-00-00-3.log"), "").unwrap();
+        rotating_file.writeln(TEXT).unwrap();
+        let path = rotating_file.rotate().unwrap();
+        assert!(path.ends_with("2024-05-01-00-00-00-4.log"));
+
+        // continues from the last index instead of rescanning from the start
+        for name in names {
+            std::fs::remove_file(Path::new(root_dir).join(name)).unwrap();
+        }
+        rotating_file.writeln(TEXT).unwrap();
+        let path = rotating_file.rotate().unwrap();
+        assert!(path.ends_with("2024-05-01-00-00-00-5.log"));
+        rotating_file.close();
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn bloom_index() {
+        let root_dir = "./target/tmp63";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            None,
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        )
+        .with_bloom_index(4096);
+        for symbol in ["BTCUSDT", "ETHUSDT"] {
+            rotating_file
+                .writeln(&format!(r#"{{"s":"{}","p":1.5}}"#, symbol))
+                .unwrap();
+            rotating_file.rotate().unwrap();
+        }
+        rotating_file.close();
+
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        assert_eq!(2, files.len());
+        let btc =
+            crate::index::files_containing(root_dir, rotating_file.naming(), "BTCUSDT").unwrap();
+        assert_eq!(files[..1], btc);
+        let eth =
+            crate::index::files_containing(root_dir, rotating_file.naming(), "ETHUSDT").unwrap();
+        assert_eq!(files[1..], eth);
+
+        assert!(rotating_file.delete_rotated(&files[0]).unwrap());
+        assert!(!crate::index::sidecar_path(&files[0]).exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn max_backlog_bytes() {
+        use super::BacklogPolicy;
+
+        let root_dir = "./target/tmp64";
+        for policy in [BacklogPolicy::Synchronous, BacklogPolicy::Skip] {
+            let _ = std::fs::remove_dir_all(root_dir);
+            let rotating_file = super::RotatingFile::new(
+                root_dir,
+                None,
+                None,
+                Some(super::Compression::GZip),
+                None,
+                None,
+                None,
+            )
+            .with_max_backlog_bytes(TEXT.len() as u64, policy);
+            let rx = rotating_file.watch_backlog_exceeded();
+            rotating_file.writeln(TEXT).unwrap();
+            rotating_file.rotate().unwrap();
+
+            // done by the time rotate returns, no background thread involved
+            let exceeded = rx.try_recv().unwrap();
+            assert_eq!(policy, exceeded.policy);
+            assert_eq!(0, exceeded.backlog.bytes);
+            let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+            let compressed = files[0].to_str().unwrap().ends_with(".gz");
+            assert_eq!(policy == BacklogPolicy::Synchronous, compressed);
+            assert_eq!(0, rotating_file.compression_backlog().files);
+            rotating_file.close();
+        }
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn custom_clock() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        use std::time::UNIX_EPOCH;
+
+        let root_dir = "./target/tmp65";
+        let _ = std::fs::remove_dir_all(root_dir);
+        // exchange time, 2024-05-01 13:59:59 UTC
+        let exchange_time = Arc::new(AtomicU64::new(1_714_571_999));
+        let clock = exchange_time.clone();
+        let rotating_file =
+            super::RotatingFile::new(root_dir, None, Some(3600), None, None, None, None)
+                .with_clock(move || {
+                    UNIX_EPOCH + Duration::from_secs(clock.load(Ordering::Relaxed))
+                });
+        let rx = rotating_file.watch_rotations();
+        rotating_file.writeln(TEXT).unwrap();
+        exchange_time.fetch_add(1, Ordering::Relaxed);
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.close();
+
+        assert_eq!(
+            Path::new(root_dir).join("2024-05-01-13-00-00.log"),
+            rx.try_recv().unwrap().path
+        );
+        assert!(Path::new(root_dir).join("2024-05-01-14-00-00.log").exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn max_total_size() {
+        let root_dir = "./target/tmp66";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let line_size = TEXT.len() as u64 + 1;
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_max_total_size(super::ByteSize::b(3 * line_size));
+        let rx = rotating_file.watch_rotations();
+        for _ in 0..5 {
+            rotating_file.writeln(TEXT).unwrap();
+            rotating_file.rotate().unwrap();
+        }
+        rotating_file.close();
+
+        // the oldest are gone
+        let rotated = rx.try_iter().map(|event| event.path).collect::<Vec<_>>();
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        assert_eq!(rotated[2..], files);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn max_total_size_raw() {
+        let root_dir = "./target/tmp94";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let raw_size = 100 * (TEXT.len() as u64 + 1);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            None,
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        )
+        .with_size_measure(super::SizeMeasure::Raw)
+        .with_max_total_size(super::ByteSize::b(2 * raw_size));
+        for _ in 0..5 {
+            for _ in 0..100 {
+                rotating_file.writeln(TEXT).unwrap();
+            }
+            rotating_file.rotate().unwrap();
+        }
+        rotating_file.close();
+
+        // compressed, all five would fit; measured raw, only two do
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        assert_eq!(2, files.len());
+        assert!(rotating_file.archived_size() <= 2 * raw_size);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn retention_age() {
+        let root_dir = "./target/tmp67";
+        let _ = std::fs::remove_dir_all(root_dir);
+        std::fs::create_dir_all(root_dir).unwrap();
+        // files left by an earlier run two days ago, one compressed while
+        // its archive name was taken
+        let old = Path::new(root_dir).join("2024-05-01-00-00-00.log.gz");
+        let conflicted = Path::new(root_dir).join("2024-05-01-00-00-00.log.1.gz");
+        for path in [&old, &conflicted] {
+            let f = std::fs::File::create(path).unwrap();
+            f.set_modified(SystemTime::now() - Duration::from_secs(2 * 86400))
+                .unwrap();
+        }
+
+        let rotating_file = super::RotatingFile::builder(root_dir)
+            .retention_age(Duration::from_secs(86400))
+            .build();
+        rotating_file.writeln(TEXT).unwrap();
+        for _ in 0..100 {
+            if !old.exists() && !conflicted.exists() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!old.exists());
+        assert!(!conflicted.exists());
+        rotating_file.close();
+        assert!(rotating_file
+            .inner
+            .threads
+            .services
+            .lock()
+            .unwrap()
+            .is_empty());
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        assert_eq!(1, files.len());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn write_block() {
+        let root_dir = "./target/tmp68";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None)
+                .with_sequence_numbers(true);
+        let block = [TEXT; 10].join("\n");
+        for _ in 0..10 {
+            rotating_file.write_block(&block).unwrap();
+        }
+        rotating_file.close();
+
+        // no block straddles files, sequence numbers run on across them
+        let mut sequence = 0;
+        for file in crate::reader::files(root_dir, rotating_file.naming()).unwrap() {
+            let content = std::fs::read_to_string(file).unwrap();
+            assert_eq!(0, content.lines().count() % 10);
+            for line in content.lines() {
+                assert_eq!(format!("{} {}", sequence, TEXT), line);
+                sequence += 1;
+            }
+        }
+        assert_eq!(100, sequence);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn no_interleaving() {
+        use std::sync::Arc;
+
+        let root_dir = "./target/tmp69";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = Arc::new(
+            super::RotatingFile::new(root_dir, Some(256), None, None, None, None, None)
+                .with_line_checksums(true),
+        );
+        // lines well past the 8 KiB of the `BufWriter`
+        let threads = (b'a'..b'e')
+            .map(|c| {
+                let rotating_file = rotating_file.clone();
+                std::thread::spawn(move || {
+                    let line = String::from_utf8(vec![c; 64 * 1024]).unwrap();
+                    for _ in 0..20 {
+                        rotating_file.writeln(&line).unwrap();
+                        rotating_file
+                            .write_block(&[&line[..100]; 3].join("\n"))
+                            .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        rotating_file.close();
+
+        let mut lines = 0;
+        for file in crate::reader::files(root_dir, rotating_file.naming()).unwrap() {
+            let content = std::fs::read_to_string(file).unwrap();
+            let mut block = None;
+            for line in content.lines() {
+                let line = super::verify_checksum(line).unwrap();
+                let c = line.as_bytes()[0];
+                assert!(line.bytes().all(|b| b == c));
+                // the lines of a block follow each other
+                if line.len() == 100 {
+                    let (first, n) = block.unwrap_or((c, 0));
+                    assert_eq!(first, c);
+                    block = (n < 2).then_some((c, n + 1));
+                } else {
+                    assert_eq!(None, block);
+                }
+                lines += 1;
+            }
+            assert_eq!(None, block);
+        }
+        assert_eq!(4 * 20 * 4, lines);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn manual_rotate() {
+        let root_dir = "./target/tmp70";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            None,
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        );
+        let rx = rotating_file.watch_rotations();
+        rotating_file.writeln(TEXT).unwrap();
+        let current = rotating_file.inner.current_path().unwrap();
+        assert_eq!(current, rotating_file.rotate().unwrap());
+        let e = rotating_file.rotate().unwrap_err();
+        assert_eq!(std::io::ErrorKind::NotFound, e.kind());
+        rotating_file.close();
+
+        let mut archive = current.into_os_string();
+        archive.push(".gz");
+        assert_eq!(Path::new(&archive), rx.try_recv().unwrap().path);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn reopen() {
+        let root_dir = "./target/tmp72";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None);
+        let rx = rotating_file.watch_rotations();
+        rotating_file.writeln(TEXT).unwrap();
+        let current = rotating_file.inner.current_path().unwrap();
+
+        // moved away as by logrotate, left alone
+        let moved = format!("{}/moved.log", root_dir);
+        std::fs::rename(&current, &moved).unwrap();
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.reopen().unwrap();
+        assert!(rx.try_recv().is_err());
+        assert_eq!(
+            format!("{}\n{}\n", TEXT, TEXT),
+            std::fs::read_to_string(&moved).unwrap()
+        );
+
+        // still in place, rotated out
+        rotating_file.writeln(TEXT).unwrap();
+        let current = rotating_file.inner.current_path().unwrap();
+        rotating_file.reopen().unwrap();
+        let event = rx.try_recv().unwrap();
+        assert_eq!(current, event.path);
+        assert_eq!(super::RotationTrigger::External, event.trigger);
+        rotating_file.close();
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn relocate() {
+        let root_dir = "./target/tmp89";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let (old_root, new_root) = (format!("{}/old", root_dir), format!("{}/new", root_dir));
+        let rotating_file = super::RotatingFile::new(
+            &old_root,
+            None,
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        )
+        .with_sequence_naming(4);
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.rotate().unwrap();
+        rotating_file.writeln(TEXT).unwrap();
+
+        // both files are compressed, then moved along with the state
+        rotating_file.relocate(&new_root, true).unwrap();
+        let names = |dir: &str| {
+            let files = super::reader::files(dir, rotating_file.naming()).unwrap();
+            files
+                .iter()
+                .map(|file| file.file_name().unwrap().to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert!(names(&old_root).is_empty());
+        let moved = names(&new_root);
+        assert_eq!(2, moved.len());
+        assert!(moved.iter().all(|name| name.ends_with(".log.gz")));
+        assert!(Path::new(&new_root).join(".rotfile-sequence").exists());
+
+        rotating_file.writeln(TEXT).unwrap();
+        let current = rotating_file.inner.current_path().unwrap();
+        assert!(current.starts_with(&new_root));
+        assert!(current
+            .file_name()
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("0002-"));
+
+        // left behind
+        rotating_file.relocate(&old_root, false).unwrap();
+        assert_eq!(3, names(&new_root).len());
+        assert!(names(&old_root).is_empty());
+        rotating_file.close();
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "sighup"))]
+    #[test]
+    fn reopen_on_sighup() {
+        let root_dir = "./target/tmp73";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None);
+        let rx = rotating_file.watch_rotations();
+        rotating_file.reopen_on_sighup().unwrap();
+        rotating_file.writeln(TEXT).unwrap();
+
+        unsafe { libc::raise(libc::SIGHUP) };
+        let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(super::RotationTrigger::External, event.trigger);
+
+        rotating_file.close();
+        assert!(rotating_file
+            .inner
+            .threads
+            .services
+            .lock()
+            .unwrap()
+            .is_empty());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn archive_dir() {
+        let (root_dir, archive_dir) = ("./target/tmp75", "./target/tmp75-archive");
+        let _ = std::fs::remove_dir_all(root_dir);
+        let _ = std::fs::remove_dir_all(archive_dir);
+        let rotating_file = super::RotatingFile::new(
+            root_dir,
+            None,
+            None,
+            Some(super::Compression::GZip),
+            None,
+            None,
+            None,
+        )
+        .with_archive_dir(archive_dir);
+        let rx = rotating_file.watch_rotations();
+        rotating_file.writeln(TEXT).unwrap();
+        let rotated = rotating_file.rotate().unwrap();
+        rotating_file.close();
+
+        let archived = rx.try_recv().unwrap().path;
+        assert!(archived.starts_with(archive_dir));
+        assert_eq!(
+            rotated.file_name().unwrap().to_str().unwrap().to_string() + ".gz",
+            archived.file_name().unwrap().to_str().unwrap()
+        );
+        assert!(!rotated.exists());
+        assert_eq!(0, std::fs::read_dir(root_dir).unwrap().count());
+        let files = rotating_file.inner.rotated_files().unwrap();
+        assert_eq!(vec![archived.clone()], files);
+        assert!(rotating_file.delete_rotated(&archived).unwrap());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+        std::fs::remove_dir_all(archive_dir).unwrap();
+    }
+
+    #[test]
+    fn max_lines() {
+        let root_dir = "./target/tmp77";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::builder(root_dir).max_lines(10).build();
+        let rx = rotating_file.watch_rotations();
+        for i in 0..25 {
+            rotating_file.writeln(&i.to_string()).unwrap();
+        }
+        rotating_file.close();
+
+        for _ in 0..2 {
+            let event = rx.try_recv().unwrap();
+            assert_eq!(super::RotationTrigger::Lines, event.trigger);
+            let content = std::fs::read_to_string(event.path).unwrap();
+            assert_eq!(10, content.lines().count());
+        }
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        assert_eq!(3, files.len());
+        let content = std::fs::read_to_string(&files[2]).unwrap();
+        assert_eq!(5, content.lines().count());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use chrono::{DateTime, Utc};
-    use once_cell::sync::Lazy;
-    use std::path::Path;
-    use std::time::Duration;
-    use std::time::SystemTime;
+    #[test]
+    fn timezone_rotation() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        use std::time::UNIX_EPOCH;
 
-    const TEXT: &'static str = "The quick brown fox jumps over the lazy dog";
+        use chrono::TimeZone;
 
-    #[test]
-    fn rotate_by_size() {
-        let root_dir = "./target/tmp1";
+        let root_dir = "./target/tmp98";
         let _ = std::fs::remove_dir_all(root_dir);
-        let timestamp = current_timestamp_str();
+        // 23:00 on April 30th in +08:00
+        let start = Utc.with_ymd_and_hms(2024, 4, 30, 15, 0, 0).unwrap();
+        let now = Arc::new(AtomicU64::new(start.timestamp() as u64));
+        let clock = now.clone();
+        let offset = chrono::FixedOffset::east_opt(8 * 3600).unwrap();
         let rotating_file =
-            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None);
-
-        for _ in 0..23 {
-            rotating_file.writeln(TEXT).unwrap();
-        }
-
+            super::RotatingFile::new(root_dir, None, Some(86400), None, None, None, None)
+                .with_naming_timezone(super::Timezone::Fixed(offset))
+                .with_clock(move || {
+                    UNIX_EPOCH + Duration::from_secs(clock.load(Ordering::Relaxed))
+                });
+        let rx = rotating_file.watch_rotations();
+        rotating_file.writeln(TEXT).unwrap();
+        // local midnight, not UTC midnight 8 hours later
+        now.fetch_add(5400, Ordering::Relaxed);
+        rotating_file.writeln(TEXT).unwrap();
+        let event = rx.try_recv().unwrap();
+        assert_eq!(super::RotationTrigger::Interval, event.trigger);
+        assert!(event.path.ends_with("2024-04-30-00-00-00.log"));
+        let current = rotating_file.inner.current_path().unwrap();
+        assert!(current.ends_with("2024-05-01-00-00-00.log"));
         rotating_file.close();
 
-        assert!(Path::new(root_dir)
-            .join(timestamp.clone() + ".log")
-            .exists());
-        assert!(!Path::new(root_dir)
-            .join(timestamp.clone() + "-1.log")
-            .exists());
-
         std::fs::remove_dir_all(root_dir).unwrap();
+    }
 
-        let timestamp = current_timestamp_str();
-        let rotating_file =
-            super::RotatingFile::new(root_dir, Some(1), None, None, None, None, None);
+    #[test]
+    fn calendar_rotation() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        use std::time::UNIX_EPOCH;
 
-        for _ in 0..24 {
-            rotating_file.writeln(TEXT).unwrap();
-        }
+        use chrono::TimeZone;
 
+        let root_dir = "./target/tmp78";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let at = |d, h| {
+            Utc.with_ymd_and_hms(2024, 2, d, h, 0, 0)
+                .unwrap()
+                .timestamp() as u64
+        };
+        let now = Arc::new(AtomicU64::new(at(28, 23)));
+        let clock = now.clone();
+        let rotating_file =
+            super::RotatingFile::new(root_dir, None, Some(86400), None, None, None, None)
+                .with_calendar_rotation(super::CalendarPeriod::Month)
+                .with_clock(move || {
+                    UNIX_EPOCH + Duration::from_secs(clock.load(Ordering::Relaxed))
+                });
+        let rx = rotating_file.watch_rotations();
+        rotating_file.writeln(TEXT).unwrap();
+        // the next day, the interval passed to new is replaced
+        now.store(at(29, 12), Ordering::Relaxed);
+        rotating_file.writeln(TEXT).unwrap();
+        assert!(rx.try_recv().is_err());
+        now.store(at(29, 23) + 3600, Ordering::Relaxed);
+        rotating_file.writeln(TEXT).unwrap();
+        let event = rx.try_recv().unwrap();
+        assert_eq!(super::RotationTrigger::Interval, event.trigger);
+        assert!(event.path.ends_with("2024-02-01-00-00-00.log"));
+        let current = rotating_file.inner.current_path().unwrap();
+        assert!(current.ends_with("2024-03-01-00-00-00.log"));
         rotating_file.close();
 
-        assert!(Path::new(root_dir)
-            .join(timestamp.clone() + ".log")
-            .exists());
-        assert!(Path::new(root_dir)
-            .join(timestamp.clone() + "-1.log")
-            .exists());
-        assert_eq!(
-            format!("{}\n", TEXT),
-            std::fs::read_to_string(Path::new(root_dir).join(timestamp + "-1.log")).unwrap()
-        );
-
         std::fs::remove_dir_all(root_dir).unwrap();
     }
 
     #[test]
-    fn rotate_by_time() {
-        let root_dir = "./target/tmp2";
+    fn staging_dir() {
+        let (root_dir, staging_dir) = ("./target/tmp79", "./target/tmp79-staging");
         let _ = std::fs::remove_dir_all(root_dir);
-        let rotating_file =
-            super::RotatingFile::new(root_dir, None, Some(1), None, None, None, None);
+        let _ = std::fs::remove_dir_all(staging_dir);
+        for compression in [None, Some(super::Compression::GZip)] {
+            let rotating_file =
+                super::RotatingFile::new(root_dir, None, None, compression, None, None, None)
+                    .with_staging_dir(staging_dir);
+            let rx = rotating_file.watch_rotations();
+            rotating_file.writeln(TEXT).unwrap();
+            assert!(rotating_file
+                .inner
+                .current_path()
+                .unwrap()
+                .starts_with(staging_dir));
+            assert_eq!(
+                0,
+                crate::reader::files(root_dir, rotating_file.naming())
+                    .unwrap()
+                    .len()
+            );
+            rotating_file.close();
 
-        let timestamp1 = current_timestamp_str();
-        rotating_file.writeln(TEXT).unwrap();
+            let published = rx.try_recv().unwrap().path;
+            assert!(published.starts_with(root_dir));
+            assert_eq!(
+                compression.is_some(),
+                published.to_str().unwrap().ends_with(".gz")
+            );
+            assert_eq!(0, std::fs::read_dir(staging_dir).unwrap().count());
+            std::fs::remove_file(published).unwrap();
+        }
 
-        std::thread::sleep(Duration::from_secs(1));
+        std::fs::remove_dir_all(root_dir).unwrap();
+        std::fs::remove_dir_all(staging_dir).unwrap();
+    }
 
-        let timestamp2 = current_timestamp_str();
-        rotating_file.writeln(TEXT).unwrap();
+    #[test]
+    fn sub_second_interval() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        use std::time::UNIX_EPOCH;
 
+        let root_dir = "./target/tmp80";
+        let _ = std::fs::remove_dir_all(root_dir);
+        // milliseconds since epoch
+        let now = Arc::new(AtomicU64::new(1_714_521_600_100));
+        let clock = now.clone();
+        let rotating_file = super::RotatingFile::builder(root_dir)
+            .rotate_every(Duration::from_millis(500))
+            .build()
+            .with_clock(move || UNIX_EPOCH + Duration::from_millis(clock.load(Ordering::Relaxed)));
+        let rx = rotating_file.watch_rotations();
+        let buckets = rotating_file.watch_bucket_closes();
+        for ms in [100, 499, 500, 999, 1000] {
+            now.store(1_714_521_600_000 + ms, Ordering::Relaxed);
+            rotating_file.writeln(&ms.to_string()).unwrap();
+        }
         rotating_file.close();
 
-        assert!(Path::new(root_dir).join(timestamp1 + ".log").exists());
-        assert!(Path::new(root_dir).join(timestamp2 + ".log").exists());
+        let contents = rx
+            .try_iter()
+            .map(|event| std::fs::read_to_string(event.path).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["100\n499\n", "500\n999\n"], contents);
+        let start = buckets.try_recv().unwrap().start;
+        assert_eq!(1_714_521_600_000, start.timestamp_millis());
+        let start = buckets.try_recv().unwrap().start;
+        assert_eq!(1_714_521_600_500, start.timestamp_millis());
+        // both buckets of the first second share its name, told apart by index
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        let indexes = files
+            .iter()
+            .map(|file| {
+                super::FileInfo::parse(file, rotating_file.naming())
+                    .unwrap()
+                    .index
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 1, 0], indexes);
 
         std::fs::remove_dir_all(root_dir).unwrap();
     }
 
     #[test]
-    fn rotate_by_size_and_gzip() {
-        let root_dir = "./target/tmp3";
+    fn writeln_at() {
+        use std::time::UNIX_EPOCH;
+
+        let root_dir = "./target/tmp81";
         let _ = std::fs::remove_dir_all(root_dir);
-        let timestamp = current_timestamp_str();
-        let rotating_file = super::RotatingFile::new(
-            root_dir,
-            Some(1),
-            None,
-            Some(super::Compression::GZip),
-            None,
-            None,
-            None,
+        let now = UNIX_EPOCH + Duration::from_secs(1_714_521_600);
+        let hours_ago = |hours: u64| now - Duration::from_secs(hours * 3600);
+        let rotating_file =
+            super::RotatingFile::new(root_dir, None, Some(3600), None, None, None, None)
+                .with_backfill_buckets(2)
+                .with_clock(move || now);
+        let rx = rotating_file.watch_rotations();
+        rotating_file.writeln("live").unwrap();
+        rotating_file.writeln_at("2h", hours_ago(2)).unwrap();
+        rotating_file.writeln_at("1h", hours_ago(1)).unwrap();
+        rotating_file.writeln_at("2h again", hours_ago(2)).unwrap();
+        rotating_file.writeln_at("live again", now).unwrap();
+        // a third past bucket finalizes the oldest open one
+        rotating_file.writeln_at("3h", hours_ago(3)).unwrap();
+        let event = rx.try_recv().unwrap();
+        assert!(event.path.ends_with("2024-04-30-22-00-00.log"));
+        assert_eq!(
+            "2h\n2h again\n",
+            std::fs::read_to_string(event.path).unwrap()
         );
-
-        for _ in 0..24 {
-            rotating_file.writeln(TEXT).unwrap();
-        }
-
+        // fails instead of panicking
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(1);
+        let e = rotating_file.writeln_at("1969", before_epoch).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidInput, e.kind());
         rotating_file.close();
 
-        assert!(Path::new(root_dir)
-            .join(timestamp.clone() + ".log.gz")
-            .exists());
-        assert!(Path::new(root_dir).join(timestamp + "-1.log").exists());
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        let contents = files
+            .iter()
+            .map(|file| std::fs::read_to_string(file).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["3h\n", "2h\n2h again\n", "1h\n", "live\nlive again\n"],
+            contents
+        );
 
         std::fs::remove_dir_all(root_dir).unwrap();
     }
 
     #[test]
-    fn rotate_by_size_and_zip() {
-        let root_dir = "./target/tmp4";
+    fn idle_rotation() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        use std::time::UNIX_EPOCH;
+
+        let root_dir = "./target/tmp82";
         let _ = std::fs::remove_dir_all(root_dir);
-        let timestamp = current_timestamp_str();
+        let now = Arc::new(AtomicU64::new(1_714_521_600));
+        let clock = now.clone();
         let rotating_file = super::RotatingFile::new(
             root_dir,
-            Some(1),
             None,
-            Some(super::Compression::Zip),
+            None,
+            Some(super::Compression::GZip),
             None,
             None,
             None,
-        );
+        )
+        .with_idle_rotation(Duration::from_secs(1))
+        .with_clock(move || UNIX_EPOCH + Duration::from_secs(clock.load(Ordering::Relaxed)));
+        let rx = rotating_file.watch_rotations();
+        rotating_file.writeln(TEXT).unwrap();
+        now.fetch_add(2, Ordering::Relaxed);
 
-        for _ in 0..24 {
+        let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(super::RotationTrigger::Idle, event.trigger);
+        assert!(event.path.to_str().unwrap().ends_with(".gz"));
+        assert!(rotating_file.inner.current_path().is_none());
+        rotating_file.close();
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn sub_second_naming() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Arc;
+        use std::time::UNIX_EPOCH;
+
+        let root_dir = "./target/tmp84";
+        let _ = std::fs::remove_dir_all(root_dir);
+        // milliseconds since epoch
+        let now = Arc::new(AtomicU64::new(0));
+        let clock = now.clone();
+        let rotating_file = super::RotatingFile::builder(root_dir)
+            .date_format("%Y%m%dT%H%M%S%3f")
+            .build()
+            .with_clock(move || UNIX_EPOCH + Duration::from_millis(clock.load(Ordering::Relaxed)));
+        let mut paths = Vec::new();
+        for ms in [1_714_521_600_900, 1_714_521_600_950, 1_714_521_601_020] {
+            now.store(ms, Ordering::Relaxed);
             rotating_file.writeln(TEXT).unwrap();
+            paths.push(rotating_file.rotate().unwrap());
         }
-
         rotating_file.close();
 
-        assert!(Path::new(root_dir)
-            .join(timestamp.clone() + ".log.zip")
-            .exists());
-        assert!(Path::new(root_dir).join(timestamp + "-1.log").exists());
+        // no index, sorted by creation time
+        let names = paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "20240501T000000900.log",
+                "20240501T000000950.log",
+                "20240501T000001020.log"
+            ],
+            names
+        );
+        let info = super::FileInfo::parse(&paths[1], rotating_file.naming()).unwrap();
+        assert_eq!(1_714_521_600_950, info.timestamp.timestamp_millis());
 
         std::fs::remove_dir_all(root_dir).unwrap();
     }
 
     #[test]
-    fn rotate_by_time_and_gzip() {
-        let root_dir = "./target/tmp5";
+    fn abort() {
+        let root_dir = "./target/tmp85";
         let _ = std::fs::remove_dir_all(root_dir);
         let rotating_file = super::RotatingFile::new(
             root_dir,
             None,
-            Some(1),
+            None,
             Some(super::Compression::GZip),
             None,
             None,
             None,
         );
-
-        let timestamp1 = current_timestamp_str();
         rotating_file.writeln(TEXT).unwrap();
+        let path = rotating_file.inner.current_path().unwrap();
 
-        std::thread::sleep(Duration::from_secs(1));
+        // gives up while a writer holds the lock
+        let guard = rotating_file.inner.context.lock().unwrap();
+        let e = rotating_file.abort(Duration::from_millis(50)).unwrap_err();
+        assert_eq!(std::io::ErrorKind::TimedOut, e.kind());
+        drop(guard);
 
-        let timestamp2 = current_timestamp_str();
-        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.abort(Duration::from_secs(1)).unwrap();
+        let expected = format!("{}\n", TEXT);
+        assert_eq!(expected, std::fs::read_to_string(&path).unwrap());
+        let e = rotating_file.writeln(TEXT).unwrap_err();
+        assert_eq!(
+            std::io::ErrorKind::BrokenPipe,
+            std::io::Error::from(e).kind()
+        );
 
-        rotating_file.close();
+        // left raw instead of compressed
+        let rotated = rotating_file.rotate().unwrap();
+        assert!(rotating_file.try_close().is_err());
+        assert_eq!(expected, std::fs::read_to_string(rotated).unwrap());
 
-        assert!(Path::new(root_dir).join(timestamp1 + ".log.gz").exists());
-        assert!(Path::new(root_dir).join(timestamp2 + ".log").exists());
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn abort_handle() {
+        let root_dir = "./target/tmp99";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_write_through(true);
+        let handle = rotating_file.abort_handle();
+        rotating_file.writeln(TEXT).unwrap();
+        let path = rotating_file.inner.current_path().unwrap();
+
+        handle.abort();
+        let e = rotating_file.writeln(TEXT).unwrap_err();
+        assert_eq!(
+            std::io::ErrorKind::BrokenPipe,
+            std::io::Error::from(e).kind()
+        );
+        assert_eq!(
+            format!("{}\n", TEXT),
+            std::fs::read_to_string(path).unwrap()
+        );
+        drop(rotating_file);
 
         std::fs::remove_dir_all(root_dir).unwrap();
     }
 
     #[test]
-    fn rotate_by_time_and_zip() {
-        let root_dir = "./target/tmp6";
+    fn sequence_naming() {
+        use std::time::UNIX_EPOCH;
+
+        let root_dir = "./target/tmp86";
         let _ = std::fs::remove_dir_all(root_dir);
-        let rotating_file = super::RotatingFile::new(
-            root_dir,
-            None,
-            Some(1),
-            Some(super::Compression::Zip),
-            None,
-            None,
-            None,
+        let open = |secs: u64| {
+            super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+                .with_sequence_naming(6)
+                .with_clock(move || UNIX_EPOCH + Duration::from_secs(secs))
+        };
+        let mut names = Vec::new();
+        // restarts within the same second, then after the clock went back
+        for secs in [1_714_521_600, 1_714_521_600, 1_714_521_000] {
+            let rotating_file = open(secs);
+            rotating_file.writeln(TEXT).unwrap();
+            let rotated = rotating_file.rotate().unwrap();
+            names.push(rotated.file_name().unwrap().to_str().unwrap().to_string());
+            rotating_file.close();
+        }
+        assert_eq!(
+            vec![
+                "000000-2024-05-01-00-00-00.log",
+                "000001-2024-05-01-00-00-00.log",
+                "000002-2024-04-30-23-50-00.log"
+            ],
+            names
         );
+        let rotating_file = open(0);
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        let sorted = files
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, sorted);
 
-        let timestamp1 = current_timestamp_str();
+        // resumed from the files when the state file is lost
+        std::fs::remove_file(Path::new(root_dir).join(".rotfile-sequence")).unwrap();
         rotating_file.writeln(TEXT).unwrap();
+        let rotated = rotating_file.rotate().unwrap();
+        let info = super::FileInfo::parse(rotated, rotating_file.naming()).unwrap();
+        assert_eq!(Some(3), info.sequence);
+        rotating_file.close();
 
-        std::thread::sleep(Duration::from_secs(1));
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
 
-        let timestamp2 = current_timestamp_str();
+    #[test]
+    fn file_name_template() {
+        let root_dir = "./target/tmp88";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_file_name_template("{prefix}{date}-{hostname}-{pid}-{seq}{suffix}")
+            .unwrap();
         rotating_file.writeln(TEXT).unwrap();
-
+        let rotated = rotating_file.rotate().unwrap();
         rotating_file.close();
 
-        assert!(Path::new(root_dir).join(timestamp1 + ".log.zip").exists());
-        assert!(Path::new(root_dir).join(timestamp2 + ".log").exists());
+        let name = rotated.file_name().unwrap().to_str().unwrap();
+        let expected = format!(
+            "-{}-{}-0.log",
+            crate::naming::hostname(),
+            std::process::id()
+        );
+        assert!(name.ends_with(&expected), "{}", name);
+        let info = super::FileInfo::parse(&rotated, rotating_file.naming()).unwrap();
+        assert_eq!(Some(std::process::id()), info.pid);
+        assert_eq!(Some(0), info.sequence);
+
+        // another process of this host, and another host
+        let date = &name[..19];
+        let host = crate::naming::hostname();
+        let restarted = format!("{}-{}-1-1.log", date, host);
+        std::fs::write(Path::new(root_dir).join(&restarted), TEXT).unwrap();
+        let other = format!("{}-{}x-1-2.log", date, host);
+        std::fs::write(Path::new(root_dir).join(other), TEXT).unwrap();
+        let files = crate::reader::files(root_dir, rotating_file.naming()).unwrap();
+        assert_eq!(vec![rotated, Path::new(root_dir).join(restarted)], files);
+
+        // without a date, names couldn't be parsed back
+        let e = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_file_name_template("{prefix}{hostname}{suffix}")
+            .err()
+            .unwrap();
+        assert!(matches!(e, super::RotatingFileError::InvalidConfig(_)));
 
         std::fs::remove_dir_all(root_dir).unwrap();
     }
 
     #[test]
-    fn referred_in_two_threads() {
-        static ROOT_DIR: Lazy<&'static str> = Lazy::new(|| "./target/tmp7");
-        static ROTATING_FILE: Lazy<super::RotatingFile> = Lazy::new(|| {
-            super::RotatingFile::new(
-                *ROOT_DIR,
-                Some(1),
-                None,
-                Some(super::Compression::GZip),
-                None,
-                None,
-                None,
-            )
-        });
-        let _ = std::fs::remove_dir_all(*ROOT_DIR);
-
-        let timestamp = current_timestamp_str();
-        let handle1 = std::thread::spawn(move || {
-            for _ in 0..23 {
-                ROTATING_FILE.writeln(TEXT).unwrap();
-            }
-        });
-
-        let handle2 = std::thread::spawn(move || {
-            for _ in 0..23 {
-                ROTATING_FILE.writeln(TEXT).unwrap();
-            }
-        });
+    fn write_coalescing() {
+        let root_dir = "./target/tmp83";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = super::RotatingFile::new(root_dir, None, None, None, None, None, None)
+            .with_write_coalescing(Duration::from_millis(200), 64 * 1024);
+        rotating_file.writeln(TEXT).unwrap();
+        let path = rotating_file.inner.current_path().unwrap();
+        assert_eq!(0, std::fs::metadata(&path).unwrap().len());
 
-        // trigger the third file creation
-        ROTATING_FILE.writeln(TEXT).unwrap();
+        // flushed by the timer once writes stop
+        std::thread::sleep(Duration::from_secs(1));
+        let expected = format!("{}\n", TEXT);
+        assert_eq!(expected, std::fs::read_to_string(&path).unwrap());
+        rotating_file.close();
 
-        let _ = handle1.join();
-        let _ = handle2.join();
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
 
-        ROTATING_FILE.close();
+    #[test]
+    fn rotation_triggers() {
+        use super::RotationTrigger;
+        use std::sync::Arc;
 
-        assert!(Path::new(*ROOT_DIR)
-            .join(timestamp.clone() + ".log.gz")
-            .exists());
-        assert!(Path::new(*ROOT_DIR)
-            .join(timestamp.clone() + "-1.log.gz")
-            .exists());
+        let root_dir = "./target/tmp58";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = Arc::new(super::RotatingFile::new(
+            root_dir,
+            Some(1),
+            Some(1),
+            None,
+            None,
+            None,
+            None,
+        ));
+        let rx = rotating_file.watch_rotations();
+        rotating_file.writeln(TEXT).unwrap();
+        rotating_file.rotate().unwrap();
 
-        let third_file = Path::new(*ROOT_DIR).join(timestamp.clone() + "-2.log");
-        assert!(third_file.exists());
-        assert_eq!(
-            TEXT.len() + 1,
-            std::fs::metadata(third_file).unwrap().len() as usize
-        );
+        // threads racing across interval boundaries, both limits tripping
+        let threads = (0..4)
+            .map(|_| {
+                let rotating_file = rotating_file.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..120 {
+                        rotating_file.writeln(TEXT).unwrap();
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        rotating_file.close();
 
-        std::fs::remove_dir_all(*ROOT_DIR).unwrap();
-    }
+        let events = rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(RotationTrigger::Manual, events[0].trigger);
+        assert!(events[1..]
+            .iter()
+            .all(|e| matches!(e.trigger, RotationTrigger::Size | RotationTrigger::Interval)));
+        assert!(events.iter().any(|e| e.trigger == RotationTrigger::Size));
+        assert!(events
+            .iter()
+            .any(|e| e.trigger == RotationTrigger::Interval));
+        // files rotated by an interval boundary are the last of their bucket
+        let bucket = |e: &super::RotationEvent| {
+            super::FileInfo::parse(&e.path, rotating_file.naming())
+                .unwrap()
+                .timestamp
+        };
+        for pair in events.windows(2) {
+            if pair[0].trigger == RotationTrigger::Interval {
+                assert!(bucket(&pair[0]) < bucket(&pair[1]));
+            }
+        }
 
-    fn current_timestamp_str() -> String {
-        let dt: DateTime<Utc> = SystemTime::now().into();
-        let dt_str = dt.format("%Y-%m-%d-%H-%M-%S").to_string();
-        dt_str
+        std::fs::remove_dir_all(root_dir).unwrap();
     }
 }
@@ -0,0 +1,193 @@
+//! The states a file goes through, from active to deleted, optionally
+//! journaled so that recovery after a crash knows where each file was left.
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::*;
+
+use crate::{reader, writer, Inner};
+
+/// A state of a file, in the order they are reached. States may be skipped,
+/// e.g. `Compressed` without compression, but never go backwards.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum FileLifecycle {
+    /// Created and being written to
+    Active,
+    /// Rotated out, flushed, closed and renamed to its final name
+    Sealed,
+    /// Compressed to an archive, which takes over the state under its own name
+    Compressed,
+    /// Uploaded and verified by the pipeline
+    Shipped,
+    /// Deleted, by retention, the pipeline or for being empty
+    Expired,
+}
+
+impl FileLifecycle {
+    fn as_str(&self) -> &'static str {
+        match self {
+            FileLifecycle::Active => "active",
+            FileLifecycle::Sealed => "sealed",
+            FileLifecycle::Compressed => "compressed",
+            FileLifecycle::Shipped => "shipped",
+            FileLifecycle::Expired => "expired",
+        }
+    }
+
+    fn parse(s: &str) -> Option<FileLifecycle> {
+        match s {
+            "active" => Some(FileLifecycle::Active),
+            "sealed" => Some(FileLifecycle::Sealed),
+            "compressed" => Some(FileLifecycle::Compressed),
+            "shipped" => Some(FileLifecycle::Shipped),
+            "expired" => Some(FileLifecycle::Expired),
+            _ => None,
+        }
+    }
+}
+
+/// An append-only record of `{file name}\t{state}` lines, the last line of
+/// a file wins.
+pub(crate) struct Journal {
+    // locked while the journal is read or written
+    path: Mutex<PathBuf>,
+}
+
+impl Journal {
+    pub(crate) fn new(root_dir: &str) -> Self {
+        Journal {
+            path: Mutex::new(Path::new(root_dir).join(".rotfile-lifecycle")),
+        }
+    }
+
+    pub(crate) fn record(&self, path: &Path, state: FileLifecycle) -> Result<(), Error> {
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        let path = self.path.lock().unwrap();
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&*path)?;
+        writeln!(file, "{}\t{}", file_name, state.as_str())?;
+        file.sync_data()
+    }
+
+    /// Returns the last state of each file.
+    pub(crate) fn load(&self) -> Result<HashMap<String, FileLifecycle>, Error> {
+        let path = self.path.lock().unwrap();
+        let file = match fs::File::open(&*path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            file => file?,
+        };
+        let mut states = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            // skip a torn last line
+            if let Some((file_name, state)) = line
+                .split_once('\t')
+                .and_then(|(file_name, state)| Some((file_name, FileLifecycle::parse(state)?)))
+            {
+                states.insert(file_name.to_string(), state);
+            }
+        }
+        Ok(states)
+    }
+
+    /// Moves the journal to `root_dir`, if there is one yet.
+    pub(crate) fn relocate(&self, root_dir: &str) -> Result<(), Error> {
+        let mut path = self.path.lock().unwrap();
+        let relocated = Path::new(root_dir).join(path.file_name().unwrap());
+        match crate::writer::move_file(&path, &relocated) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        *path = relocated;
+        Ok(())
+    }
+}
+
+/// Links to the newest finalized files and to the active one, see
+/// `RotatingFile::with_recent_links` and `RotatingFile::with_current_link`.
+#[derive(Default)]
+pub(crate) struct Links {
+    /// How many of the newest finalized files to link in `recent/`, default to 0
+    pub(crate) recent: usize,
+    // serializes updates of `recent/`
+    recent_lock: Mutex<()>,
+    /// Name of the link to the active file in `root_dir`, default to None
+    pub(crate) current: Option<String>,
+}
+
+impl Inner {
+    /// Moves the file at `path` to `state`, journaling it if enabled.
+    pub(crate) fn transition(&self, path: &Path, state: FileLifecycle) {
+        if let Some(journal) = self.journal.as_ref() {
+            if let Err(e) = journal.record(path, state) {
+                error!("Failed to journal {} as {:?}: {}", path.display(), state, e);
+            }
+        }
+        if let Some(hook) = self.lifecycle_hook.as_ref() {
+            hook(path, state);
+        }
+    }
+
+    /// Makes a finalized file read-only if enabled.
+    pub(crate) fn seal(&self, path: &Path) {
+        if self.seal {
+            if let Err(e) = writer::seal(path) {
+                error!("Failed to seal {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Points the current link at the active file `path`, through a
+    /// temporary link renamed over the old one so that readers never find
+    /// it missing.
+    pub(crate) fn link_current(&self, path: &Path) -> Result<(), Error> {
+        let Some(name) = self.links.current.as_ref() else {
+            return Ok(());
+        };
+        let link = Path::new(&self.root_dir()).join(name);
+        let tmp = link.with_file_name(format!(".{}.tmp", name));
+        let _ = fs::remove_file(&tmp);
+        writer::link(&fs::canonicalize(path)?, &tmp)?;
+        fs::rename(&tmp, &link)
+    }
+
+    /// Links a finalized file in `recent/`, unlinking the oldest beyond the
+    /// configured count.
+    pub(crate) fn link_recent(&self, path: &Path) -> Result<(), Error> {
+        let recent_dir = Path::new(&self.root_dir()).join("recent");
+        let _guard = self.links.recent_lock.lock().unwrap();
+        fs::create_dir_all(&recent_dir)?;
+        let link = recent_dir.join(path.file_name().unwrap());
+        let _ = fs::remove_file(&link);
+        writer::link(&fs::canonicalize(path)?, &link)?;
+
+        let links = reader::files(&recent_dir, &self.naming)?;
+        for oldest in links
+            .iter()
+            .take(links.len().saturating_sub(self.links.recent))
+        {
+            fs::remove_file(oldest)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the link of a deleted file from `recent/`.
+    pub(crate) fn unlink_recent(&self, path: &Path) {
+        let Some(file_name) = path.file_name() else {
+            return;
+        };
+        let link = Path::new(&self.root_dir()).join("recent").join(file_name);
+        let _guard = self.links.recent_lock.lock().unwrap();
+        match fs::remove_file(&link) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                error!("Failed to unlink {}: {}", link.display(), e)
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,1260 @@
+//! File naming: how names are formatted, parsed back and kept unique.
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Mutex, OnceLock};
+
+use chrono::format::{Parsed, StrftimeItems};
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone, Utc};
+use log::*;
+
+use crate::compress::Compression;
+#[cfg(feature = "failpoints")]
+use crate::failpoints;
+use crate::policy::{self, RotationTrigger};
+use crate::{BucketClosed, Gap, Inner};
+
+/// Date format of `NamingConfig::default`, e.g. `2024-05-01-13-00-00`.
+pub const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d-%H-%M-%S";
+
+/// File name prefix of `NamingConfig::default`.
+pub const DEFAULT_PREFIX: &str = "";
+
+/// File name suffix of `NamingConfig::default`.
+pub const DEFAULT_SUFFIX: &str = ".log";
+
+/// Index separator of `NamingConfig::default`.
+pub const DEFAULT_INDEX_SEPARATOR: &str = "-";
+
+/// Replaced in the suffix by why the file was rotated out, see
+/// `RotationTrigger::name`, e.g. `.{reason}.log`.
+pub const REASON: &str = "{reason}";
+
+/// What `{reason}` reads while the file is active.
+pub const ACTIVE_REASON: &str = "active";
+
+/// Replaced in the prefix or suffix by the host name, e.g. to tell apart
+/// the files of several hosts sharing an NFS directory.
+pub const HOSTNAME: &str = "{hostname}";
+
+/// Replaced in the prefix or suffix by the id of the process that created
+/// the file.
+pub const PID: &str = "{pid}";
+
+/// Replaced in the prefix or suffix by the sequence number, which goes
+/// before the date otherwise, see `RotatingFile::with_sequence_naming`.
+pub const SEQ: &str = "{seq}";
+
+/// Splits a file name template into the prefix and the suffix, see
+/// `RotatingFile::with_file_name_template`.
+pub const DATE: &str = "{date}";
+
+// the start and, with range naming, the end of a file
+type Dates = (DateTime<Utc>, Option<DateTime<Utc>>);
+
+/// Where the collision index goes in a file name.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IndexPosition {
+    /// `{prefix}{date}{separator}{index}{suffix}`
+    AfterDate,
+    /// `{prefix}{index}{separator}{date}{suffix}`
+    BeforeDate,
+}
+
+/// The time zone dates in file names are formatted in, and intervals and
+/// calendar periods are aligned to, e.g. daily files start at midnight in
+/// `+08:00`.
+///
+/// When clocks go back, the repeated hour belongs to the period it first
+/// started, e.g. an hourly file spans both. When they go forward, a period
+/// starting in the skipped hour starts an hour later.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Timezone {
+    Utc,
+    /// The local time zone of the system, e.g. as set by `TZ`, with DST
+    Local,
+    /// A fixed offset from UTC, e.g. `FixedOffset::east_opt(8 * 3600)`
+    Fixed(FixedOffset),
+    /// A time zone of the IANA database, with DST, e.g.
+    /// `chrono_tz::America::New_York`
+    #[cfg(feature = "chrono-tz")]
+    Tz(chrono_tz::Tz),
+}
+
+impl Timezone {
+    fn format(&self, dt: &DateTime<Utc>, date_format: &str) -> String {
+        match self {
+            Timezone::Utc => dt.format(date_format).to_string(),
+            Timezone::Local => dt.with_timezone(&Local).format(date_format).to_string(),
+            Timezone::Fixed(offset) => dt.with_timezone(offset).format(date_format).to_string(),
+            #[cfg(feature = "chrono-tz")]
+            Timezone::Tz(tz) => dt.with_timezone(tz).format(date_format).to_string(),
+        }
+    }
+
+    /// Returns what the wall clock reads at `dt` in this time zone.
+    pub(crate) fn local(&self, dt: &DateTime<Utc>) -> NaiveDateTime {
+        match self {
+            Timezone::Utc => dt.naive_utc(),
+            Timezone::Local => dt.with_timezone(&Local).naive_local(),
+            Timezone::Fixed(offset) => dt.with_timezone(offset).naive_local(),
+            #[cfg(feature = "chrono-tz")]
+            Timezone::Tz(tz) => dt.with_timezone(tz).naive_local(),
+        }
+    }
+
+    /// Returns when the wall clock first reads `naive` in this time zone,
+    /// or an hour later if it never does, e.g. when clocks go forward.
+    pub(crate) fn instant(&self, naive: NaiveDateTime) -> DateTime<Utc> {
+        self.to_utc(naive)
+            .or_else(|| self.to_utc(naive + chrono::Duration::hours(1)))
+            .unwrap_or_else(|| naive.and_utc())
+    }
+
+    /// Reads `naive` in this time zone, the earliest one if ambiguous, e.g.
+    /// when clocks go back.
+    fn to_utc(self, naive: NaiveDateTime) -> Option<DateTime<Utc>> {
+        match self {
+            Timezone::Utc => Some(naive.and_utc()),
+            Timezone::Local => Local
+                .from_local_datetime(&naive)
+                .earliest()
+                .map(|dt| dt.to_utc()),
+            Timezone::Fixed(offset) => offset
+                .from_local_datetime(&naive)
+                .single()
+                .map(|dt| dt.to_utc()),
+            #[cfg(feature = "chrono-tz")]
+            Timezone::Tz(tz) => tz
+                .from_local_datetime(&naive)
+                .earliest()
+                .map(|dt| dt.to_utc()),
+        }
+    }
+}
+
+/// What to do when the name of the next file is taken by an existing file,
+/// finalized or not, e.g. after the clock jumped backwards or a restart.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CollisionPolicy {
+    /// Log a warning and try the next index.
+    Bump,
+    /// Fail the write that needed the file.
+    Error,
+}
+
+/// How files are named, i.e. `{prefix}{date}{suffix}`, with an index
+/// added when several files share the same date.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NamingConfig {
+    /// File name prefix, default to empty, may contain `{hostname}`, `{pid}`
+    /// and `{seq}` like the suffix
+    pub prefix: String,
+    /// Format as used in chrono <https://docs.rs/chrono/latest/chrono/format/strftime/>, default to `%Y-%m-%d-%H-%M-%S`
+    ///
+    /// Add `%3f`, `%6f` or `%f` when rotating more than once per second, so
+    /// that names sort chronologically instead of only differing by index.
+    pub date_format: String,
+    /// File name suffix, default to `.log`, `{reason}` in it reads `active`
+    /// until the file is rotated out and why it was afterwards, e.g. `size`.
+    /// `{hostname}`, `{pid}` and `{seq}` read the host name, the process id
+    /// and the sequence number, e.g. so that several processes can share a
+    /// directory, placeholders other than `{hostname}` must be separated by
+    /// something else than letters and digits
+    pub suffix: String,
+    /// Separates the index from the date, default to `-`
+    pub index_separator: String,
+    /// Where the index goes, default to `AfterDate`
+    pub index_position: IndexPosition,
+    /// Extensions of custom `Compressor`s, recognized on top of the
+    /// built-in ones, default to empty
+    pub compressed_extensions: Vec<String>,
+    /// Time zone of the dates, default to UTC
+    pub timezone: Timezone,
+    /// Digits the sequence number before the date, or in place of `{seq}`,
+    /// is zero-padded to, `0` for no sequence number unless there is a
+    /// `{seq}`, default to `0`, see `RotatingFile::with_sequence_naming`
+    pub sequence_digits: usize,
+}
+
+impl Default for NamingConfig {
+    fn default() -> Self {
+        NamingConfig {
+            prefix: DEFAULT_PREFIX.to_string(),
+            date_format: DEFAULT_DATE_FORMAT.to_string(),
+            suffix: DEFAULT_SUFFIX.to_string(),
+            index_separator: DEFAULT_INDEX_SEPARATOR.to_string(),
+            index_position: IndexPosition::AfterDate,
+            compressed_extensions: Vec::new(),
+            timezone: Timezone::Utc,
+            sequence_digits: 0,
+        }
+    }
+}
+
+impl NamingConfig {
+    /// Formats the name of the file described by `info`, the inverse of
+    /// `parse_name`.
+    ///
+    /// For every supported configuration, `parse_name(&format_name(info))`
+    /// returns `info` back, with its dates truncated to the precision of
+    /// `date_format`, provided that `info` could have been produced by this
+    /// configuration, i.e. its prefix is `prefix`, its sequence is `None`
+    /// if and only if `sequence_digits` is `0` and there is no `{seq}`, its
+    /// pid is `None` if and only if there is no `{pid}`, its reason is `None` if
+    /// the suffix has no `{reason}` and lowercase ASCII letters otherwise,
+    /// its extension is one of `compressed_extensions` and, with
+    /// `Timezone::Local`, its dates aren't in the hour repeated when clocks
+    /// go back. Supported
+    /// configurations are those with:
+    ///
+    /// - a `date_format` with at least a year, month and day, and
+    /// - a non-empty `index_separator` that isn't made of digits.
+    pub fn format_name(&self, info: &FileInfo) -> String {
+        info.file_name(self)
+    }
+
+    /// Parses a file name produced with this configuration, the inverse of
+    /// `format_name`, returns `None` for any other name, e.g. a sidecar.
+    /// Directories in `name`, if any, are ignored.
+    pub fn parse_name(&self, name: &str) -> Option<FileInfo> {
+        FileInfo::parse(name, self)
+    }
+
+    /// Lays out the name of the file with `stem` and `index`, placeholders
+    /// but `{reason}` are left in it, see `expand`.
+    pub(crate) fn file_name(&self, stem: &str, index: usize) -> String {
+        let sep = self.index_separator.as_str();
+        match (index, self.index_position) {
+            (0, _) => format!("{}{}{}", self.prefix, stem, self.suffix),
+            (_, IndexPosition::AfterDate) => {
+                format!("{}{}{}{}{}", self.prefix, stem, sep, index, self.suffix)
+            }
+            (_, IndexPosition::BeforeDate) => {
+                format!("{}{}{}{}{}", self.prefix, index, sep, stem, self.suffix)
+            }
+        }
+    }
+
+    /// Puts `sequence`, if any, before `stem`, e.g. `000042-{date}`, unless
+    /// it goes where `{seq}` is.
+    pub(crate) fn sequenced(&self, stem: &str, sequence: Option<u64>) -> String {
+        match sequence {
+            Some(sequence) if !self.has_placeholder(SEQ) => format!(
+                "{}{}{}",
+                self.format_sequence(sequence),
+                self.index_separator,
+                stem
+            ),
+            _ => stem.to_string(),
+        }
+    }
+
+    /// Replaces `{hostname}`, `{pid}` with `pid`, or this process's id if
+    /// `None`, and `{seq}` with `sequence` in `file_name`.
+    pub(crate) fn expand(
+        &self,
+        file_name: String,
+        pid: Option<u32>,
+        sequence: Option<u64>,
+    ) -> String {
+        if !file_name.contains('{') {
+            return file_name;
+        }
+        let pid = pid.unwrap_or_else(std::process::id);
+        let mut file_name = file_name
+            .replace(HOSTNAME, hostname())
+            .replace(PID, &pid.to_string());
+        if let Some(sequence) = sequence {
+            file_name = file_name.replace(SEQ, &self.format_sequence(sequence));
+        }
+        file_name
+    }
+
+    fn format_sequence(&self, sequence: u64) -> String {
+        format!("{:0width$}", sequence, width = self.sequence_digits)
+    }
+
+    /// Returns whether files are numbered, see `with_sequence_naming`.
+    pub(crate) fn has_sequence(&self) -> bool {
+        self.sequence_digits > 0 || self.has_placeholder(SEQ)
+    }
+
+    /// Returns whether the prefix or the suffix contains `placeholder`.
+    fn has_placeholder(&self, placeholder: &str) -> bool {
+        self.prefix.contains(placeholder) || self.suffix.contains(placeholder)
+    }
+
+    /// Returns whether the suffix contains `{reason}`.
+    pub(crate) fn has_reason(&self) -> bool {
+        self.suffix.contains(REASON)
+    }
+
+    pub(crate) fn format_date(&self, dt: &DateTime<Utc>) -> String {
+        self.timezone.format(dt, self.date_format.as_str())
+    }
+
+    /// Returns the first file name with `stem` and an index from `index`
+    /// onwards for which no file, compressed or not, with any reason,
+    /// exists, along with its index. `{reason}` is left in the name.
+    pub(crate) fn unique_file_name(
+        &self,
+        root_dir: &str,
+        stem: &str,
+        mut index: usize,
+        sequence: Option<u64>,
+    ) -> (String, usize) {
+        loop {
+            let file_name = self.expand(self.file_name(stem, index), None, sequence);
+            if self.taken(root_dir, &file_name).is_none() {
+                return (file_name, index);
+            }
+            index += 1;
+        }
+    }
+
+    /// Returns the extensions of every compression, built-in or custom.
+    fn extensions(&self) -> impl Iterator<Item = &str> {
+        Compression::ALL
+            .iter()
+            .map(|c| c.extension())
+            .chain(self.compressed_extensions.iter().map(String::as_str))
+    }
+
+    /// Returns the existing file, compressed or not, named `file_name` in
+    /// `root_dir`, with `{reason}` standing for any reason.
+    pub(crate) fn taken(&self, root_dir: &str, file_name: &str) -> Option<PathBuf> {
+        if file_name.contains(REASON) {
+            return std::iter::once(ACTIVE_REASON)
+                .chain(RotationTrigger::ALL.iter().map(|t| t.name()))
+                .find_map(|reason| self.taken(root_dir, &file_name.replace(REASON, reason)));
+        }
+        let path = Path::new(root_dir).join(file_name);
+        if path.exists() {
+            return Some(path);
+        }
+        self.extensions()
+            .map(|extension| Path::new(root_dir).join(file_name.to_string() + extension))
+            .find(|path| path.exists())
+    }
+
+    /// Parses a date formatted with `date_format`, fields missing from the
+    /// format default to zero, e.g. minutes and seconds for `%Y%m%dT%H`.
+    fn parse_date(&self, s: &str) -> Option<DateTime<Utc>> {
+        let mut parsed = Parsed::new();
+        chrono::format::parse(&mut parsed, s, StrftimeItems::new(&self.date_format)).ok()?;
+        if parsed.hour_div_12().is_none() {
+            parsed.set_hour(0).ok()?;
+        }
+        if parsed.minute().is_none() {
+            parsed.set_minute(0).ok()?;
+        }
+        let naive = parsed.to_naive_datetime_with_offset(0).ok()?;
+        self.timezone.to_utc(naive)
+    }
+
+    /// Parses the sequence number, if any, and the dates of a stem.
+    fn parse_stem(&self, stem: &str) -> Option<(Option<u64>, Dates)> {
+        if self.sequence_digits == 0 || self.has_placeholder(SEQ) {
+            return Some((None, self.parse_dates(stem)?));
+        }
+        let digits = stem.bytes().take_while(u8::is_ascii_digit).count();
+        if digits < self.sequence_digits {
+            return None;
+        }
+        let sequence = stem[..digits].parse().ok()?;
+        let dates = stem[digits..].strip_prefix(self.index_separator.as_str())?;
+        Some((Some(sequence), self.parse_dates(dates)?))
+    }
+
+    /// Parses `{date}` or `{first date}-{last date}`.
+    fn parse_dates(&self, s: &str) -> Option<Dates> {
+        if let Some(dt) = self.parse_date(s) {
+            return Some((dt, None));
+        }
+        // dates may contain dashes themselves, so try every split
+        s.match_indices('-').find_map(|(i, _)| {
+            let first = self.parse_date(&s[..i])?;
+            let last = self.parse_date(&s[i + 1..])?;
+            Some((first, Some(last)))
+        })
+    }
+}
+
+/// Returns whether `name` matches `pattern`, where `*` matches any run of
+/// characters and `?` any single one.
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let name = name.chars().collect::<Vec<_>>();
+    let (mut p, mut n) = (0, 0);
+    // where the last `*` was and how much of `name` it has swallowed
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Remembers the formatted date and index of the last created file, so that
+/// rotation continues from there instead of rescanning the directory.
+#[derive(Default)]
+pub(crate) struct IndexCache(Mutex<(String, usize)>);
+
+impl IndexCache {
+    /// Returns the index to try first for `stem`.
+    pub(crate) fn next(&self, stem: &str) -> usize {
+        let guard = self.0.lock().unwrap();
+        if guard.0 == stem {
+            guard.1 + 1
+        } else {
+            0
+        }
+    }
+
+    pub(crate) fn set(&self, stem: String, index: usize) {
+        *self.0.lock().unwrap() = (stem, index);
+    }
+}
+
+/// The next sequence number, persisted in `.rotfile-sequence` so that it
+/// keeps increasing across restarts.
+pub(crate) struct Sequence {
+    path: Mutex<PathBuf>,
+    // `None` until loaded
+    next: Mutex<Option<u64>>,
+}
+
+impl Sequence {
+    pub(crate) fn new(root_dir: &str) -> Self {
+        Sequence {
+            path: Mutex::new(Path::new(root_dir).join(".rotfile-sequence")),
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Takes the next sequence number, persisting the one after it first so
+    /// that a crash can't hand it out twice.
+    ///
+    /// The first call resumes from the state file, or after the highest
+    /// sequence number of the files in `dirs` if it's behind, e.g. when it
+    /// was lost.
+    pub(crate) fn take<'a>(
+        &self,
+        dirs: impl Iterator<Item = &'a str>,
+        naming: &NamingConfig,
+    ) -> Result<u64, Error> {
+        let mut next = self.next.lock().unwrap();
+        let path = self.path.lock().unwrap();
+        let sequence = match *next {
+            Some(sequence) => sequence,
+            None => {
+                let saved = match fs::read_to_string(&*path) {
+                    Ok(saved) => saved.trim().parse().map_err(|_| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!("invalid {}", path.display()),
+                        )
+                    })?,
+                    Err(e) if e.kind() == ErrorKind::NotFound => 0,
+                    Err(e) => return Err(e),
+                };
+                let seen = dirs
+                    .filter_map(|dir| fs::read_dir(dir).ok())
+                    .flatten()
+                    .filter_map(|entry| FileInfo::parse(entry.ok()?.path(), naming)?.sequence)
+                    .map(|sequence| sequence + 1)
+                    .max()
+                    .unwrap_or(0);
+                saved.max(seen)
+            }
+        };
+
+        let tmp = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        write!(file, "{}", sequence + 1)?;
+        file.sync_all()?;
+        fs::rename(tmp, &*path)?;
+        *next = Some(sequence + 1);
+        Ok(sequence)
+    }
+
+    /// Moves the state file to `root_dir`, if there is one yet.
+    pub(crate) fn relocate(&self, root_dir: &str) -> Result<(), Error> {
+        let mut path = self.path.lock().unwrap();
+        let relocated = Path::new(root_dir).join(path.file_name().unwrap());
+        match crate::writer::move_file(&path, &relocated) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        *path = relocated;
+        Ok(())
+    }
+}
+
+/// Returns the host name, as `{hostname}` reads.
+pub(crate) fn hostname() -> &'static str {
+    static NAME: OnceLock<String> = OnceLock::new();
+    NAME.get_or_init(|| {
+        #[cfg(unix)]
+        {
+            let mut buf = [0u8; 256];
+            // SAFETY: the buffer outlives the call and its length is passed
+            let ok = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0;
+            let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+            match std::str::from_utf8(&buf[..len]) {
+                Ok(name) if ok && !name.is_empty() => name.to_string(),
+                _ => "localhost".to_string(),
+            }
+        }
+        #[cfg(not(unix))]
+        std::env::var("COMPUTERNAME").unwrap_or_else(|_| "localhost".to_string())
+    })
+}
+
+/// What the placeholders of the prefix and suffix read in a file name.
+#[derive(Clone, Default)]
+struct Captured {
+    reason: Option<String>,
+    pid: Option<u32>,
+    sequence: Option<u64>,
+}
+
+/// Strips `template`, the prefix or with `from_end` the suffix, off `s`.
+///
+/// `{hostname}` must read this host's name, so that hosts sharing a
+/// directory leave each other's files alone, `{reason}` any lowercase ASCII
+/// letters, `{pid}` and `{seq}` any digits.
+fn strip_template<'a>(
+    mut s: &'a str,
+    template: &str,
+    from_end: bool,
+    captured: &mut Captured,
+) -> Option<&'a str> {
+    if !template.contains('{') {
+        return if from_end {
+            s.strip_suffix(template)
+        } else {
+            s.strip_prefix(template)
+        };
+    }
+    let template = template.replace(HOSTNAME, hostname());
+    let mut parts = Vec::new();
+    let mut rest = template.as_str();
+    while let Some((i, placeholder)) = [REASON, PID, SEQ]
+        .into_iter()
+        .filter_map(|placeholder| Some((rest.find(placeholder)?, placeholder)))
+        .min()
+    {
+        parts.push((&rest[..i], None));
+        parts.push(("", Some(placeholder)));
+        rest = &rest[i + placeholder.len()..];
+    }
+    parts.push((rest, None));
+    if from_end {
+        parts.reverse();
+    }
+
+    for (literal, placeholder) in parts {
+        let Some(placeholder) = placeholder else {
+            s = if from_end {
+                s.strip_suffix(literal)?
+            } else {
+                s.strip_prefix(literal)?
+            };
+            continue;
+        };
+        let allowed = |c: char| match placeholder {
+            REASON => c.is_ascii_lowercase(),
+            _ => c.is_ascii_digit(),
+        };
+        let (value, remaining) = if from_end {
+            let start = s.trim_end_matches(allowed).len();
+            (&s[start..], &s[..start])
+        } else {
+            let remaining = s.trim_start_matches(allowed);
+            (&s[..s.len() - remaining.len()], remaining)
+        };
+        if value.is_empty() {
+            return None;
+        }
+        match placeholder {
+            REASON => captured.reason = Some(value.to_string()),
+            PID => captured.pid = Some(value.parse().ok()?),
+            _ => captured.sequence = Some(value.parse().ok()?),
+        }
+        s = remaining;
+    }
+    Some(s)
+}
+
+/// The parts of a file name produced by this crate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileInfo {
+    pub prefix: String,
+    /// Sequence number, `None` if the naming has none
+    pub sequence: Option<u64>,
+    /// What `{pid}` reads in the name, `None` if the naming has none
+    pub pid: Option<u32>,
+    /// Start of the interval, or the first write with range naming
+    pub timestamp: DateTime<Utc>,
+    /// Last write with range naming
+    pub end: Option<DateTime<Utc>>,
+    /// Collision index, `0` if the name has none
+    pub index: usize,
+    /// Index put before the compression extension, `{file}.N.gz`, when the
+    /// archive name was taken, see `ConflictPolicy::UniqueSuffix`, `0` if
+    /// the name has none
+    pub conflict_index: usize,
+    /// Compression of the file, `None` if uncompressed
+    pub compression: Option<Compression>,
+    /// What `{reason}` reads in the name, `None` if the suffix has none
+    pub reason: Option<String>,
+    /// Extension of a custom `Compressor`, `None` if uncompressed or
+    /// compressed with a built-in compression
+    pub extension: Option<String>,
+}
+
+impl FileInfo {
+    /// Parses the file name of `path`, returns `None` if it wasn't produced
+    /// with `naming`.
+    pub fn parse<P: AsRef<Path>>(path: P, naming: &NamingConfig) -> Option<FileInfo> {
+        let file_name = path.as_ref().file_name()?.to_str()?;
+        let mut captured = Captured::default();
+        let mut rest = strip_template(file_name, &naming.prefix, false, &mut captured)?;
+
+        let mut compression = None;
+        for c in Compression::ALL {
+            if let Some(stripped) = rest.strip_suffix(c.extension()) {
+                rest = stripped;
+                compression = Some(*c);
+            }
+        }
+        let mut extension = None;
+        if compression.is_none() {
+            for custom in naming.compressed_extensions.iter() {
+                if let Some(stripped) = rest.strip_suffix(custom.as_str()) {
+                    rest = stripped;
+                    extension = Some(custom.clone());
+                    break;
+                }
+            }
+        }
+        let mut parsed = Self::parse_rest(rest, naming, captured.clone()).map(|p| (p, 0));
+        // `{file}.N.gz`, as compressed when the archive name was taken
+        if parsed.is_none() && (compression.is_some() || extension.is_some()) {
+            let (rest, conflict) = rest.rsplit_once('.')?;
+            if conflict.is_empty() || !conflict.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            parsed = Some((
+                Self::parse_rest(rest, naming, captured)?,
+                conflict.parse().ok()?,
+            ));
+        }
+        let (((sequence, dates), index, captured), conflict_index) = parsed?;
+
+        Some(FileInfo {
+            prefix: naming.prefix.clone(),
+            sequence: sequence.or(captured.sequence),
+            pid: captured.pid,
+            timestamp: dates.0,
+            end: dates.1,
+            index,
+            conflict_index,
+            compression,
+            reason: captured.reason,
+            extension,
+        })
+    }
+
+    /// Parses what is left of a file name once the prefix and compression
+    /// extension are stripped: the suffix, the date and the index.
+    fn parse_rest(
+        rest: &str,
+        naming: &NamingConfig,
+        mut captured: Captured,
+    ) -> Option<((Option<u64>, Dates), usize, Captured)> {
+        let stem = strip_template(rest, &naming.suffix, true, &mut captured)?;
+        if let Some(parsed) = naming.parse_stem(stem) {
+            return Some((parsed, 0, captured));
+        }
+        let sep = naming.index_separator.as_str();
+        let (stem, index) = match naming.index_position {
+            IndexPosition::AfterDate => stem.rsplit_once(sep)?,
+            IndexPosition::BeforeDate => {
+                let (index, stem) = stem.split_once(sep)?;
+                (stem, index)
+            }
+        };
+        if !index.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        Some((naming.parse_stem(stem)?, index.parse().ok()?, captured))
+    }
+
+    /// Returns what files sort by, oldest first.
+    pub(crate) fn sort_key(&self) -> (Option<u64>, DateTime<Utc>, usize, usize) {
+        (
+            self.sequence,
+            self.timestamp,
+            self.index,
+            self.conflict_index,
+        )
+    }
+
+    /// Returns the Hive-style partition columns of the file, i.e. `dt` and
+    /// `hour` of its timestamp in the time zone of `naming`, e.g. for a
+    /// shipping tool to lay out an archive that Athena or Trino can query
+    /// directly.
+    ///
+    /// Only computes them: files are neither placed in partition
+    /// directories nor described by sidecar metadata.
+    pub fn partition(&self, naming: &NamingConfig) -> Vec<(&'static str, String)> {
+        let timezone = naming.timezone;
+        vec![
+            ("dt", timezone.format(&self.timestamp, "%Y-%m-%d")),
+            ("hour", timezone.format(&self.timestamp, "%H")),
+        ]
+    }
+
+    /// Returns the Hive-style partition directory of the file relative to
+    /// an archive root, e.g. `dt=2024-05-01/hour=13`, for the caller to move
+    /// the file to, see `partition`.
+    pub fn partition_path(&self, naming: &NamingConfig) -> PathBuf {
+        self.partition(naming)
+            .into_iter()
+            .map(|(column, value)| format!("{}={}", column, value))
+            .collect()
+    }
+
+    /// Formats the file name, the inverse of `parse`.
+    pub fn file_name(&self, naming: &NamingConfig) -> String {
+        let mut stem = naming.format_date(&self.timestamp);
+        if let Some(end) = self.end.as_ref() {
+            stem = format!("{}-{}", stem, naming.format_date(end));
+        }
+        let stem = naming.sequenced(&stem, self.sequence);
+        let mut file_name = NamingConfig {
+            prefix: self.prefix.clone(),
+            ..naming.clone()
+        }
+        .file_name(stem.as_str(), self.index)
+        .replace(REASON, self.reason.as_deref().unwrap_or(ACTIVE_REASON));
+        file_name = naming.expand(file_name, self.pid, self.sequence);
+        if self.conflict_index > 0 {
+            file_name = format!("{}.{}", file_name, self.conflict_index);
+        }
+        if let Some(c) = self.compression {
+            file_name.push_str(c.extension());
+        }
+        if let Some(extension) = self.extension.as_deref() {
+            file_name.push_str(extension);
+        }
+        file_name
+    }
+}
+
+// rotated-out files of a bucket
+#[derive(Default)]
+struct BucketState {
+    // files not finalized yet
+    pending: usize,
+    files: Vec<PathBuf>,
+    raw_size: u64,
+    // the active file is in a later bucket
+    ended: bool,
+}
+
+/// Buckets with rotated-out files and the receivers of their gaps and
+/// closes, see `RotatingFile::watch_gaps` and
+/// `RotatingFile::watch_bucket_closes`.
+#[derive(Default)]
+pub(crate) struct Buckets {
+    // receivers of gaps between buckets
+    pub(crate) gaps: Mutex<Vec<Sender<Gap>>>,
+    // receivers of closed buckets
+    pub(crate) closes: Mutex<Vec<Sender<BucketClosed>>>,
+    // bucket of the last created file, loaded from `root_dir` on first use
+    last: Mutex<Option<u64>>,
+    // buckets with rotated-out files, by start
+    states: Mutex<BTreeMap<u64, BucketState>>,
+}
+
+impl Inner {
+    /// Returns whether files are bucketed by interval or calendar period.
+    pub(crate) fn bucketed(&self) -> bool {
+        self.interval > 0 || self.calendar.is_some()
+    }
+
+    /// Returns the start of the bucket `now` falls in, `now` itself if files
+    /// aren't bucketed, both in milliseconds since epoch.
+    pub(crate) fn bucket(&self, now: u64) -> u64 {
+        let timezone = self.naming.timezone;
+        match self.calendar {
+            Some(period) => period.start_in(now / 1000, timezone) * 1000,
+            None if self.interval == 0 => now,
+            None => policy::bucket_in(now, self.interval, timezone),
+        }
+    }
+
+    /// Returns the start of the bucket after the one starting at `bucket`.
+    pub(crate) fn next_bucket(&self, bucket: u64) -> u64 {
+        let timezone = self.naming.timezone;
+        match self.calendar {
+            Some(period) => period.next_in(bucket / 1000, timezone) * 1000,
+            None => policy::next_bucket_in(bucket, self.interval, timezone),
+        }
+    }
+
+    /// Reports the buckets skipped between the last created file and the one
+    /// starting at `timestamp`.
+    pub(crate) fn check_gap(&self, timestamp: u64) {
+        let mut last_bucket = self.buckets.last.lock().unwrap();
+        let last = *last_bucket.get_or_insert_with(|| {
+            // the newest file of an earlier run
+            fs::read_dir(self.root_dir())
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| FileInfo::parse(entry.ok()?.path(), &self.naming))
+                .map(|info| self.bucket(info.timestamp.timestamp_millis() as u64))
+                .filter(|bucket| *bucket < timestamp)
+                .max()
+                .unwrap_or(timestamp)
+        });
+        // a clock jumping backwards is not a gap
+        if timestamp <= last {
+            return;
+        }
+        *last_bucket = Some(timestamp);
+        drop(last_bucket);
+
+        let expected = self.next_bucket(last);
+        if timestamp > expected {
+            let gap = Gap {
+                start: DateTime::from_timestamp_millis(expected as i64).unwrap(),
+                end: DateTime::from_timestamp_millis(timestamp as i64).unwrap(),
+            };
+            warn!("No file from {} to {}", gap.start, gap.end);
+            self.buckets
+                .gaps
+                .lock()
+                .unwrap()
+                .retain(|tx| tx.send(gap.clone()).is_ok());
+        }
+    }
+
+    /// Ends the buckets before `timestamp`, closing those already finalized.
+    pub(crate) fn end_buckets(&self, timestamp: u64) {
+        let mut buckets = self.buckets.states.lock().unwrap();
+        for state in buckets.range_mut(..timestamp).map(|(_, state)| state) {
+            state.ended = true;
+        }
+        let closed = buckets
+            .extract_if(..timestamp, |_, state| state.pending == 0)
+            .collect::<Vec<_>>();
+        drop(buckets);
+        for (start, state) in closed {
+            self.notify_bucket_closed(start, state);
+        }
+    }
+
+    /// Adds a file of bucket `timestamp` to be finalized.
+    pub(crate) fn add_to_bucket(&self, timestamp: u64) {
+        if self.bucketed() {
+            let mut buckets = self.buckets.states.lock().unwrap();
+            buckets.entry(timestamp).or_default().pending += 1;
+        }
+    }
+
+    /// Records a finalized file of bucket `timestamp`, closing the bucket if
+    /// it was the last one.
+    pub(crate) fn finalized_in_bucket(&self, timestamp: u64, path: &Path, raw_size: u64) {
+        let mut buckets = self.buckets.states.lock().unwrap();
+        let Some(state) = buckets.get_mut(&timestamp) else {
+            return;
+        };
+        state.pending -= 1;
+        state.files.push(path.to_path_buf());
+        state.raw_size += raw_size;
+        if state.ended && state.pending == 0 {
+            let state = buckets.remove(&timestamp).unwrap();
+            drop(buckets);
+            self.notify_bucket_closed(timestamp, state);
+        }
+    }
+
+    fn notify_bucket_closed(&self, start: u64, state: BucketState) {
+        let event = BucketClosed {
+            start: DateTime::from_timestamp_millis(start as i64).unwrap(),
+            files: state.files,
+            raw_size: state.raw_size,
+        };
+        self.buckets
+            .closes
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(event.clone()).is_ok());
+    }
+
+    pub(crate) fn rename_to_range(
+        &self,
+        file: &Path,
+        first_write: u64,
+        last_write: u64,
+        trigger: RotationTrigger,
+    ) -> Result<PathBuf, Error> {
+        let format = |secs: u64| {
+            self.naming
+                .format_date(&DateTime::<Utc>::from_timestamp(secs as i64, 0).unwrap())
+        };
+        let stem = format!("{}-{}", format(first_write), format(last_write));
+        let sequence = FileInfo::parse(file, &self.naming).and_then(|info| info.sequence);
+        let stem = self.naming.sequenced(&stem, sequence);
+        // stay in the fallback directory if the file was written there
+        let root_dir = self.root_dir();
+        let dir = file.parent().unwrap_or(Path::new(&root_dir));
+        let (file_name, _) =
+            self.naming
+                .unique_file_name(dir.to_str().unwrap(), stem.as_str(), 0, sequence);
+
+        let file_name = file_name.replace(REASON, trigger.name());
+
+        let mut renamed = dir.join(file_name).into_os_string();
+        if let Some(compression) = self.streamed() {
+            renamed.push(compression.compression.extension());
+        }
+        let renamed = PathBuf::from(renamed);
+        #[cfg(feature = "failpoints")]
+        self.failpoints.check(failpoints::FailPoint::Rename)?;
+        fs::rename(file, &renamed)?;
+        self.sync_dir(&renamed)?;
+        Ok(renamed)
+    }
+
+    /// Replaces `active` with the name of `trigger` in the suffix of `file`.
+    pub(crate) fn rename_to_reason(
+        &self,
+        file: &Path,
+        trigger: RotationTrigger,
+    ) -> Result<PathBuf, Error> {
+        let file_name = file.file_name().unwrap().to_str().unwrap();
+        let sequence = FileInfo::parse(file, &self.naming).and_then(|info| info.sequence);
+        let suffix = |reason: &str| {
+            let suffix = self.naming.suffix.replace(REASON, reason);
+            self.naming.expand(suffix, None, sequence)
+        };
+        let active = suffix(ACTIVE_REASON);
+        let Some(pos) = file_name.rfind(active.as_str()) else {
+            // e.g. a file resumed from before the suffix changed
+            return Ok(file.to_path_buf());
+        };
+        let suffix = suffix(trigger.name());
+        let file_name = format!(
+            "{}{}{}",
+            &file_name[..pos],
+            suffix,
+            &file_name[pos + active.len()..]
+        );
+
+        let renamed = file.with_file_name(file_name);
+        #[cfg(feature = "failpoints")]
+        self.failpoints.check(failpoints::FailPoint::Rename)?;
+        fs::rename(file, &renamed)?;
+        self.sync_dir(&renamed)?;
+        Ok(renamed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::Compression;
+
+    #[test]
+    fn parse_file_info() {
+        let naming = super::NamingConfig {
+            prefix: "spot-trades-".to_string(),
+            date_format: "%Y%m%dT%H".to_string(),
+            suffix: ".log".to_string(),
+            ..Default::default()
+        };
+        let timestamp = Utc.with_ymd_and_hms(2024, 5, 1, 13, 0, 0).unwrap();
+
+        let info = super::FileInfo::parse("spot-trades-20240501T13-2.log.gz", &naming).unwrap();
+        assert_eq!("spot-trades-", info.prefix);
+        assert_eq!(timestamp, info.timestamp);
+        assert_eq!(None, info.end);
+        assert_eq!(2, info.index);
+        assert_eq!(Some(Compression::GZip), info.compression);
+        assert_eq!("spot-trades-20240501T13-2.log.gz", info.file_name(&naming));
+
+        let info =
+            super::FileInfo::parse("spot-trades-20240501T13-20240501T14.log", &naming).unwrap();
+        assert_eq!(timestamp, info.timestamp);
+        assert_eq!(Some(timestamp + chrono::Duration::hours(1)), info.end);
+        assert_eq!(0, info.index);
+        assert_eq!(None, info.compression);
+        assert_eq!(
+            "spot-trades-20240501T13-20240501T14.log",
+            info.file_name(&naming)
+        );
+
+        let naming = super::NamingConfig::default();
+        let info = super::FileInfo::parse("./target/2024-05-01-13-00-00-1.log", &naming).unwrap();
+        assert_eq!(timestamp, info.timestamp);
+        assert_eq!(1, info.index);
+
+        // compressed with `ConflictPolicy::UniqueSuffix`
+        let info = super::FileInfo::parse("2024-05-01-13-00-00-1.log.2.gz", &naming).unwrap();
+        assert_eq!(timestamp, info.timestamp);
+        assert_eq!((1, 2), (info.index, info.conflict_index));
+        assert_eq!("2024-05-01-13-00-00-1.log.2.gz", info.file_name(&naming));
+        assert!(super::FileInfo::parse("2024-05-01-13-00-00.log.2", &naming).is_none());
+
+        assert!(super::FileInfo::parse("2024-05-01-13-00-00.txt", &naming).is_none());
+
+        let naming = super::NamingConfig {
+            suffix: ".{reason}.log".to_string(),
+            ..Default::default()
+        };
+        let info = super::FileInfo::parse("2024-05-01-13-00-00-1.size.log.gz", &naming).unwrap();
+        assert_eq!(timestamp, info.timestamp);
+        assert_eq!(1, info.index);
+        assert_eq!(Some("size".to_string()), info.reason);
+        assert_eq!("2024-05-01-13-00-00-1.size.log.gz", info.file_name(&naming));
+        assert!(super::FileInfo::parse("2024-05-01-13-00-00.log", &naming).is_none());
+
+        let naming = super::NamingConfig {
+            compressed_extensions: vec![".myz".to_string()],
+            ..Default::default()
+        };
+        let info = super::FileInfo::parse("2024-05-01-13-00-00.log.myz", &naming).unwrap();
+        assert_eq!(None, info.compression);
+        assert_eq!(Some(".myz".to_string()), info.extension);
+        assert_eq!("2024-05-01-13-00-00.log.myz", info.file_name(&naming));
+        assert!(super::FileInfo::parse("2024-05-01-13-00-00-x.log", &naming).is_none());
+
+        let naming = super::NamingConfig {
+            timezone: super::Timezone::Fixed(chrono::FixedOffset::east_opt(8 * 3600).unwrap()),
+            ..Default::default()
+        };
+        let info = super::FileInfo::parse("2024-05-01-21-00-00.log", &naming).unwrap();
+        assert_eq!(timestamp, info.timestamp);
+        assert_eq!("2024-05-01-21-00-00.log", naming.format_name(&info));
+    }
+
+    #[test]
+    fn partition() {
+        let naming = super::NamingConfig::default();
+        let info = super::FileInfo::parse("2024-05-01-13-00-00.log.gz", &naming).unwrap();
+        assert_eq!(
+            vec![("dt", "2024-05-01".to_string()), ("hour", "13".to_string())],
+            info.partition(&naming)
+        );
+        assert_eq!(
+            std::path::Path::new("dt=2024-05-01/hour=13"),
+            info.partition_path(&naming)
+        );
+
+        // in the time zone of the names, across midnight
+        let naming = super::NamingConfig {
+            timezone: super::Timezone::Fixed(chrono::FixedOffset::east_opt(8 * 3600).unwrap()),
+            ..Default::default()
+        };
+        let info = super::FileInfo::parse("2024-05-02-05-00-00.log", &naming).unwrap();
+        assert_eq!(
+            Utc.with_ymd_and_hms(2024, 5, 1, 21, 0, 0).unwrap(),
+            info.timestamp
+        );
+        assert_eq!(
+            std::path::Path::new("dt=2024-05-02/hour=05"),
+            info.partition_path(&naming)
+        );
+    }
+
+    #[test]
+    fn index_cache() {
+        let cache = super::IndexCache::default();
+        assert_eq!(0, cache.next("2024-05-01-00-00-00"));
+        cache.set("2024-05-01-00-00-00".to_string(), 2);
+        assert_eq!(3, cache.next("2024-05-01-00-00-00"));
+        // a new bucket starts over
+        assert_eq!(0, cache.next("2024-05-01-00-00-01"));
+    }
+
+    #[cfg(feature = "chrono-tz")]
+    #[test]
+    fn tz_names() {
+        let naming = super::NamingConfig {
+            timezone: super::Timezone::Tz(chrono_tz::America::New_York),
+            ..Default::default()
+        };
+        // EDT, four hours behind
+        let info = super::FileInfo::parse("2024-05-01-09-00-00.log", &naming).unwrap();
+        assert_eq!(
+            Utc.with_ymd_and_hms(2024, 5, 1, 13, 0, 0).unwrap(),
+            info.timestamp
+        );
+        assert_eq!("2024-05-01-09-00-00.log", naming.format_name(&info));
+    }
+
+    #[test]
+    fn placeholders() {
+        let naming = super::NamingConfig {
+            suffix: "-{hostname}-{pid}-{seq}.{reason}.log".to_string(),
+            sequence_digits: 3,
+            ..Default::default()
+        };
+        let host = super::hostname();
+        let name = format!("2024-05-01-13-00-00-1-{}-4242-007.size.log.gz", host);
+        let info = super::FileInfo::parse(&name, &naming).unwrap();
+        assert_eq!(
+            Utc.with_ymd_and_hms(2024, 5, 1, 13, 0, 0).unwrap(),
+            info.timestamp
+        );
+        assert_eq!(1, info.index);
+        assert_eq!(Some(4242), info.pid);
+        assert_eq!(Some(7), info.sequence);
+        assert_eq!(Some("size".to_string()), info.reason);
+        assert_eq!(name, naming.format_name(&info));
+
+        let other = format!("2024-05-01-13-00-00-other{}-4242-007.size.log", host);
+        assert!(super::FileInfo::parse(other, &naming).is_none());
+        let unnumbered = format!("2024-05-01-13-00-00-{}-4242-.size.log", host);
+        assert!(super::FileInfo::parse(unnumbered, &naming).is_none());
+    }
+
+    #[test]
+    fn name_round_trip() {
+        use super::{FileInfo, IndexPosition, NamingConfig};
+
+        let timestamps = [
+            Utc.with_ymd_and_hms(2024, 5, 1, 13, 0, 0).unwrap(),
+            Utc.with_ymd_and_hms(1999, 12, 31, 23, 59, 59).unwrap(),
+            Utc.with_ymd_and_hms(2030, 1, 9, 0, 5, 7).unwrap(),
+        ];
+        let compressions = [
+            (None, None),
+            (Some(Compression::GZip), None),
+            (None, Some(".myz".to_string())),
+        ];
+        let mut checked = 0;
+        for prefix in ["", "trades-", "spot.btc_"] {
+            for date_format in [
+                "%Y-%m-%d-%H-%M-%S",
+                "%Y%m%dT%H%M%S",
+                "%Y%m%d",
+                "%Y-%m-%dT%H",
+            ] {
+                for suffix in [".log", "", ".{reason}.log", "-{reason}"] {
+                    for index_separator in ["-", ".", "_", "--"] {
+                        for index_position in [IndexPosition::AfterDate, IndexPosition::BeforeDate]
+                        {
+                            let naming = NamingConfig {
+                                prefix: prefix.to_string(),
+                                date_format: date_format.to_string(),
+                                suffix: suffix.to_string(),
+                                index_separator: index_separator.to_string(),
+                                index_position,
+                                compressed_extensions: vec![".myz".to_string()],
+                                timezone: super::Timezone::Utc,
+                                sequence_digits: 0,
+                            };
+                            let sequenced = NamingConfig {
+                                sequence_digits: 4,
+                                ..naming.clone()
+                            };
+                            // dates truncated to the precision of the format
+                            let truncate =
+                                |dt| naming.parse_date(&naming.format_date(&dt)).unwrap();
+                            let reasons = if naming.has_reason() {
+                                vec![Some("active".to_string()), Some("size".to_string())]
+                            } else {
+                                vec![None]
+                            };
+                            for (i, timestamp) in timestamps.iter().enumerate() {
+                                let end = timestamps.get(i + 1).map(|dt| truncate(*dt));
+                                for end in [None, end] {
+                                    for index in [0, 1, 12] {
+                                        for (compression, extension) in compressions.iter() {
+                                            for reason in reasons.iter() {
+                                                let info = FileInfo {
+                                                    prefix: prefix.to_string(),
+                                                    sequence: None,
+                                                    pid: None,
+                                                    timestamp: truncate(*timestamp),
+                                                    end,
+                                                    index,
+                                                    conflict_index: 0,
+                                                    compression: *compression,
+                                                    reason: reason.clone(),
+                                                    extension: extension.clone(),
+                                                };
+                                                let name = naming.format_name(&info);
+                                                assert_eq!(
+                                                    Some(&info),
+                                                    naming.parse_name(&name).as_ref(),
+                                                    "{} with {:?}",
+                                                    name,
+                                                    naming
+                                                );
+
+                                                // past the padding too
+                                                let info = FileInfo {
+                                                    sequence: Some(index as u64 * 1000),
+                                                    ..info
+                                                };
+                                                let name = sequenced.format_name(&info);
+                                                assert_eq!(
+                                                    Some(&info),
+                                                    sequenced.parse_name(&name).as_ref(),
+                                                    "{} with {:?}",
+                                                    name,
+                                                    sequenced
+                                                );
+                                                checked += 1;
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        assert!(checked > 10_000);
+    }
+
+    #[test]
+    fn glob_match() {
+        use super::glob_match;
+
+        assert!(glob_match("*audit*", "2024-05-01-audit.log.gz"));
+        assert!(glob_match("*.log", "2024-05-01.log"));
+        assert!(!glob_match("*.log", "2024-05-01.log.gz"));
+        assert!(glob_match("2024-0?-01*", "2024-05-01.log"));
+        assert!(!glob_match("audit", "audit.log"));
+        assert!(glob_match("*", ""));
+    }
+}
@@ -0,0 +1,278 @@
+//! Finalization of rotated-out files after compression: checksum, upload,
+//! verify and delete the local copy, resumable across restarts.
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::*;
+use uuid::Uuid;
+
+use crate::instrument::{Op, Span};
+
+/// A stage of the pipeline, in the order they complete.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Checksummed,
+    Uploaded,
+    Verified,
+    Deleted,
+}
+
+impl Stage {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Stage::Checksummed => "checksummed",
+            Stage::Uploaded => "uploaded",
+            Stage::Verified => "verified",
+            Stage::Deleted => "deleted",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Stage> {
+        match s {
+            "checksummed" => Some(Stage::Checksummed),
+            "uploaded" => Some(Stage::Uploaded),
+            "verified" => Some(Stage::Verified),
+            "deleted" => Some(Stage::Deleted),
+            _ => None,
+        }
+    }
+}
+
+/// Ships rotated-out files elsewhere, e.g. to S3.
+pub trait Uploader: Send + Sync {
+    /// Uploads the file at `path`, overwriting an earlier partial upload.
+    fn upload(&self, path: &Path) -> Result<(), Error>;
+
+    /// Uploads the file at `path` whose rotation is identified by `id`, as
+    /// in its `RotationEvent` and manifest entry, e.g. to tag the object
+    /// with it. Calls `upload` by default.
+    fn upload_with_id(&self, path: &Path, id: Uuid) -> Result<(), Error> {
+        let _ = id;
+        self.upload(path)
+    }
+
+    /// Returns whether the uploaded copy of `path` has CRC32 `checksum`.
+    fn verify(&self, path: &Path, checksum: u32) -> Result<bool, Error>;
+}
+
+/// Stages a rotated-out file goes through once compressed.
+///
+/// Each completed stage is recorded in a manifest in `root_dir`, so that
+/// [`RotatingFile::resume_pipeline`](crate::RotatingFile::resume_pipeline)
+/// can pick up where a crash left off.
+#[derive(Clone)]
+pub struct Pipeline {
+    uploader: Arc<dyn Uploader>,
+    retries: u32,
+    retry_delay: Duration,
+    delete_local: bool,
+}
+
+impl Pipeline {
+    /// Creates a pipeline uploading with `uploader`, retrying each stage 3
+    /// times 1 second apart and deleting local files once verified.
+    pub fn new<U: Uploader + 'static>(uploader: U) -> Self {
+        Pipeline {
+            uploader: Arc::new(uploader),
+            retries: 3,
+            retry_delay: Duration::from_secs(1),
+            delete_local: true,
+        }
+    }
+
+    /// Sets how many times a failed stage is retried, and how long to wait
+    /// before each retry.
+    pub fn with_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.retries = retries;
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Sets whether to delete the local file once its upload is verified,
+    /// default to true. Deletion goes through the pre-delete hook.
+    pub fn with_delete_local(mut self, enabled: bool) -> Self {
+        self.delete_local = enabled;
+        self
+    }
+
+    fn retry<T>(&self, stage: Stage, mut f: impl FnMut() -> Result<T, Error>) -> Result<T, Error> {
+        let mut attempt = 0;
+        loop {
+            match f() {
+                Err(e) if attempt < self.retries => {
+                    attempt += 1;
+                    warn!("Stage {} failed, retry {}: {}", stage.as_str(), attempt, e);
+                    std::thread::sleep(self.retry_delay);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs the stages after `done`, the last completed one along with the
+    /// checksum, for the file of rotation `id`, calling `shipped` once
+    /// verified and deleting the file with `delete`.
+    pub(crate) fn run(
+        &self,
+        manifest: &Manifest,
+        path: &Path,
+        id: Uuid,
+        done: Option<(Stage, u32)>,
+        shipped: impl Fn(&Path),
+        delete: impl Fn(&Path) -> Result<bool, Error>,
+    ) -> Result<(), Error> {
+        let (mut stage, checksum) = match done {
+            Some(done) => done,
+            None => {
+                let checksum = self.retry(Stage::Checksummed, || checksum(path))?;
+                manifest.record(path, Stage::Checksummed, checksum, id)?;
+                (Stage::Checksummed, checksum)
+            }
+        };
+        if stage < Stage::Uploaded {
+            let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+            let span = Span::enter(Op::Upload, path, size);
+            span.result(self.retry(Stage::Uploaded, || self.uploader.upload_with_id(path, id)))?;
+            drop(span);
+            stage = Stage::Uploaded;
+            manifest.record(path, stage, checksum, id)?;
+        }
+        if stage < Stage::Verified {
+            self.retry(Stage::Verified, || {
+                if self.uploader.verify(path, checksum)? {
+                    Ok(())
+                } else {
+                    Err(Error::new(ErrorKind::InvalidData, "checksum mismatch"))
+                }
+            })?;
+            stage = Stage::Verified;
+            manifest.record(path, stage, checksum, id)?;
+            shipped(path);
+        }
+        if self.delete_local && stage < Stage::Deleted && delete(path)? {
+            manifest.record(path, Stage::Deleted, checksum, id)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether a file whose last completed stage is `stage` is done.
+    pub(crate) fn is_done(&self, stage: Stage) -> bool {
+        stage == Stage::Deleted || (stage == Stage::Verified && !self.delete_local)
+    }
+}
+
+/// Returns the CRC32 of the file at `path`.
+pub(crate) fn checksum(path: &Path) -> Result<u32, Error> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buf[..n]);
+    }
+}
+
+/// A manifest entry: the last completed stage, the checksum and the id of
+/// the rotation.
+pub(crate) type Entry = (Stage, u32, Uuid);
+
+/// An append-only record of `{file name}\t{stage}\t{checksum}\t{id}` lines,
+/// the last line of a file wins.
+pub(crate) struct Manifest {
+    // locked while the manifest is read or written
+    path: Mutex<PathBuf>,
+}
+
+impl Manifest {
+    pub(crate) fn new(root_dir: &str) -> Self {
+        Manifest {
+            path: Mutex::new(Path::new(root_dir).join(".rotfile-manifest")),
+        }
+    }
+
+    fn record(&self, path: &Path, stage: Stage, checksum: u32, id: Uuid) -> Result<(), Error> {
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        let path = self.path.lock().unwrap();
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&*path)?;
+        writeln!(
+            file,
+            "{}\t{}\t{:08x}\t{}",
+            file_name,
+            stage.as_str(),
+            checksum,
+            id
+        )?;
+        file.sync_data()
+    }
+
+    /// Returns the last entry of each file. Entries written before ids were
+    /// recorded get a new id.
+    pub(crate) fn load(&self) -> Result<HashMap<String, Entry>, Error> {
+        let path = self.path.lock().unwrap();
+        let file = match fs::File::open(&*path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+            file => file?,
+        };
+        let mut entries = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let mut fields = line.split('\t');
+            let parsed = (|| {
+                let file_name = fields.next()?;
+                let stage = Stage::parse(fields.next()?)?;
+                let checksum = u32::from_str_radix(fields.next()?, 16).ok()?;
+                let id = match fields.next() {
+                    Some(id) => Uuid::parse_str(id).ok()?,
+                    None => Uuid::new_v4(),
+                };
+                Some((file_name.to_string(), (stage, checksum, id)))
+            })();
+            // skip a torn last line
+            if let Some((file_name, entry)) = parsed {
+                entries.insert(file_name, entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Rewrites the manifest with the given entries only.
+    pub(crate) fn compact(&self, entries: &HashMap<String, Entry>) -> Result<(), Error> {
+        let path = self.path.lock().unwrap();
+        let tmp = path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp)?;
+        for (file_name, (stage, checksum, id)) in entries {
+            writeln!(
+                file,
+                "{}\t{}\t{:08x}\t{}",
+                file_name,
+                stage.as_str(),
+                checksum,
+                id
+            )?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp, &*path)
+    }
+
+    /// Moves the manifest to `root_dir`, if there is one yet.
+    pub(crate) fn relocate(&self, root_dir: &str) -> Result<(), Error> {
+        let mut path = self.path.lock().unwrap();
+        let relocated = Path::new(root_dir).join(path.file_name().unwrap());
+        match crate::writer::move_file(&path, &relocated) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        *path = relocated;
+        Ok(())
+    }
+}
@@ -0,0 +1,694 @@
+//! Rotation policies: when the active file is rotated out, and retention:
+//! when rotated-out files are deleted.
+use std::collections::HashSet;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, Timelike};
+use log::*;
+
+use crate::instrument::{Op, Span};
+use crate::lifecycle::FileLifecycle;
+use crate::naming::{FileInfo, Timezone};
+use crate::{index, naming, reader, writer, Inner};
+
+/// Statistics of the active file, as seen by a `RotationPolicy` before each
+/// write.
+#[derive(Clone, Debug)]
+pub struct FileStats<'a> {
+    /// Path of the active file
+    pub path: &'a Path,
+    /// Start(in seconds since epoch) of the interval the file belongs to
+    pub timestamp: u64,
+    /// Bytes written to the file so far
+    pub written: u64,
+    /// Lines written to the file so far
+    pub lines: u64,
+    /// First write(in seconds since epoch) to the file, `None` if empty
+    pub first_write: Option<u64>,
+    /// Size in bytes of the write about to happen
+    pub next_write: u64,
+}
+
+/// Why a file was rotated out, see `RotationEvent::trigger`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RotationTrigger {
+    /// The size limit, `SizePolicy`
+    Size,
+    /// An interval boundary, `IntervalPolicy`, which wins over the other
+    /// policies tripping at the same write
+    Interval,
+    /// The max file age, `MaxAgePolicy`
+    Age,
+    /// The line count limit, `LinePolicy`
+    Lines,
+    /// No writes for a while, see `RotatingFile::with_idle_rotation`
+    Idle,
+    /// A custom `RotationPolicy`
+    Policy,
+    /// `RotatingFile::rotate`
+    Manual,
+    /// Anything else, e.g. the control socket, another member of a group
+    /// rotating or moving back from the fallback directory
+    External,
+}
+
+impl RotationTrigger {
+    /// Every trigger, e.g. to check all the names a file may end up with.
+    pub(crate) const ALL: [RotationTrigger; 8] = [
+        RotationTrigger::Size,
+        RotationTrigger::Interval,
+        RotationTrigger::Age,
+        RotationTrigger::Lines,
+        RotationTrigger::Idle,
+        RotationTrigger::Policy,
+        RotationTrigger::Manual,
+        RotationTrigger::External,
+    ];
+
+    /// Returns the name put in place of `{reason}` in file names, e.g.
+    /// `size` or `time` for interval boundaries.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RotationTrigger::Size => "size",
+            RotationTrigger::Interval => "time",
+            RotationTrigger::Age => "age",
+            RotationTrigger::Lines => "lines",
+            RotationTrigger::Idle => "idle",
+            RotationTrigger::Policy => "policy",
+            RotationTrigger::Manual => "manual",
+            RotationTrigger::External => "external",
+        }
+    }
+}
+
+/// Decides when the active file is rotated out.
+pub trait RotationPolicy: Send + Sync {
+    /// Returns true if the active file should be rotated out before the next
+    /// write goes to it.
+    fn should_rotate(&self, stats: &FileStats, now: SystemTime) -> bool;
+
+    /// Returns what to report as the trigger of rotations by this policy.
+    fn trigger(&self) -> RotationTrigger {
+        RotationTrigger::Policy
+    }
+}
+
+/// A size in bytes, e.g. `ByteSize::mb(100)`, so that sizes can't be
+/// mistaken for kilobytes or vice versa.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(u64);
+
+impl ByteSize {
+    pub const fn b(bytes: u64) -> Self {
+        ByteSize(bytes)
+    }
+
+    pub const fn kb(kilobytes: u64) -> Self {
+        ByteSize(kilobytes * 1024)
+    }
+
+    pub const fn mb(megabytes: u64) -> Self {
+        ByteSize(megabytes * 1024 * 1024)
+    }
+
+    pub const fn gb(gigabytes: u64) -> Self {
+        ByteSize(gigabytes * 1024 * 1024 * 1024)
+    }
+
+    /// Returns the size in bytes.
+    pub const fn bytes(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Rotates before a write would make the file reach `max_bytes`.
+#[derive(Copy, Clone, Debug)]
+pub struct SizePolicy {
+    pub max_bytes: u64,
+}
+
+impl RotationPolicy for SizePolicy {
+    fn should_rotate(&self, stats: &FileStats, _now: SystemTime) -> bool {
+        stats.written + stats.next_write >= self.max_bytes
+    }
+
+    fn trigger(&self) -> RotationTrigger {
+        RotationTrigger::Size
+    }
+}
+
+/// Rotates every `interval` seconds, at boundaries aligned to the epoch.
+#[derive(Copy, Clone, Debug)]
+pub struct IntervalPolicy {
+    pub interval: u64,
+}
+
+impl RotationPolicy for IntervalPolicy {
+    fn should_rotate(&self, stats: &FileStats, now: SystemTime) -> bool {
+        let now = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        now >= stats.timestamp + self.interval
+    }
+
+    fn trigger(&self) -> RotationTrigger {
+        RotationTrigger::Interval
+    }
+}
+
+/// A calendar period, see `CalendarPolicy`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CalendarPeriod {
+    /// From the top of each hour
+    Hour,
+    /// From midnight
+    Day,
+    /// From midnight on Monday, as in ISO 8601
+    Week,
+    /// From midnight on the first day of the month
+    Month,
+}
+
+impl CalendarPeriod {
+    /// Returns the start(in seconds since epoch) of the period `now` falls
+    /// in, in UTC.
+    pub fn start(&self, now: u64) -> u64 {
+        self.start_in(now, Timezone::Utc)
+    }
+
+    /// Returns the start of the period after the one starting at `start`, in
+    /// UTC.
+    pub fn next(&self, start: u64) -> u64 {
+        self.next_in(start, Timezone::Utc)
+    }
+
+    /// Returns the start(in seconds since epoch) of the period `now` falls
+    /// in, by the wall clock of `timezone`.
+    pub fn start_in(&self, now: u64, timezone: Timezone) -> u64 {
+        let local = timezone.local(&DateTime::from_timestamp(now as i64, 0).unwrap());
+        seconds(timezone, self.truncate(local))
+    }
+
+    /// Returns the start of the period after the one starting at `start`, by
+    /// the wall clock of `timezone`.
+    pub fn next_in(&self, start: u64, timezone: Timezone) -> u64 {
+        let local = timezone.local(&DateTime::from_timestamp(start as i64, 0).unwrap());
+        let start = self.truncate(local);
+        let next = match self {
+            CalendarPeriod::Hour => start + chrono::Duration::hours(1),
+            CalendarPeriod::Day => start + chrono::Duration::days(1),
+            CalendarPeriod::Week => start + chrono::Duration::weeks(1),
+            CalendarPeriod::Month => match start.month() {
+                12 => month_start(start.year() + 1, 1),
+                month => month_start(start.year(), month + 1),
+            },
+        };
+        seconds(timezone, next)
+    }
+
+    /// Returns the start of the period the wall clock reading `local` is in.
+    fn truncate(&self, local: NaiveDateTime) -> NaiveDateTime {
+        let date = local.date();
+        match self {
+            CalendarPeriod::Hour => date.and_hms_opt(local.hour(), 0, 0).unwrap(),
+            CalendarPeriod::Day => date.and_hms_opt(0, 0, 0).unwrap(),
+            CalendarPeriod::Week => {
+                let monday =
+                    date - chrono::Duration::days(date.weekday().num_days_from_monday().into());
+                monday.and_hms_opt(0, 0, 0).unwrap()
+            }
+            CalendarPeriod::Month => month_start(date.year(), date.month()),
+        }
+    }
+}
+
+fn month_start(year: i32, month: u32) -> NaiveDateTime {
+    let date = NaiveDate::from_ymd_opt(year, month, 1).unwrap();
+    date.and_hms_opt(0, 0, 0).unwrap()
+}
+
+// seconds since epoch when the wall clock of `timezone` reads `local`, 0 if
+// before the epoch
+fn seconds(timezone: Timezone, local: NaiveDateTime) -> u64 {
+    u64::try_from(timezone.instant(local).timestamp()).unwrap_or(0)
+}
+
+/// Rotates at the start of each calendar `period`, e.g. exactly at
+/// midnight, which `IntervalPolicy` can't do for periods of uneven length
+/// such as months.
+#[derive(Copy, Clone, Debug)]
+pub struct CalendarPolicy {
+    pub period: CalendarPeriod,
+}
+
+impl RotationPolicy for CalendarPolicy {
+    fn should_rotate(&self, stats: &FileStats, now: SystemTime) -> bool {
+        let now = now.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        now >= self.period.next(stats.timestamp)
+    }
+
+    fn trigger(&self) -> RotationTrigger {
+        RotationTrigger::Interval
+    }
+}
+
+/// Rotates once `max_age` has passed since the first write to the file,
+/// whenever that was, unlike `IntervalPolicy` which is aligned to the epoch.
+#[derive(Copy, Clone, Debug)]
+pub struct MaxAgePolicy {
+    pub max_age: Duration,
+}
+
+impl RotationPolicy for MaxAgePolicy {
+    fn should_rotate(&self, stats: &FileStats, now: SystemTime) -> bool {
+        stats.first_write.is_some_and(|first_write| {
+            let first_write = UNIX_EPOCH + Duration::from_secs(first_write);
+            now.duration_since(first_write)
+                .is_ok_and(|age| age >= self.max_age)
+        })
+    }
+
+    fn trigger(&self) -> RotationTrigger {
+        RotationTrigger::Age
+    }
+}
+
+/// Rotates once the file holds `max_lines` lines, e.g. `writeln` calls, so
+/// that files have predictable record counts.
+#[derive(Copy, Clone, Debug)]
+pub struct LinePolicy {
+    pub max_lines: u64,
+}
+
+impl RotationPolicy for LinePolicy {
+    fn should_rotate(&self, stats: &FileStats, _now: SystemTime) -> bool {
+        stats.lines >= self.max_lines
+    }
+
+    fn trigger(&self) -> RotationTrigger {
+        RotationTrigger::Lines
+    }
+}
+
+/// Returns the start of the interval `now` falls in, `now` itself if
+/// `interval` is 0.
+pub(crate) fn bucket(now: u64, interval: u64) -> u64 {
+    now.checked_div(interval)
+        .map_or(now, |buckets| buckets * interval)
+}
+
+/// Returns the start(in milliseconds since epoch) of the interval `now` falls
+/// in, with intervals aligned to the epoch by the wall clock of `timezone`.
+pub(crate) fn bucket_in(now: u64, interval: u64, timezone: Timezone) -> u64 {
+    if timezone == Timezone::Utc {
+        return bucket(now, interval);
+    }
+    let local = local_millis(timezone, now);
+    millis(timezone, bucket(local, interval))
+}
+
+/// Returns the start of the interval after the one starting at `start`, see
+/// `bucket_in`.
+pub(crate) fn next_bucket_in(start: u64, interval: u64, timezone: Timezone) -> u64 {
+    if timezone == Timezone::Utc {
+        return start + interval;
+    }
+    let local = local_millis(timezone, start);
+    millis(timezone, bucket(local, interval) + interval)
+}
+
+// what the wall clock of `timezone` reads at `instant`, in milliseconds
+fn local_millis(timezone: Timezone, instant: u64) -> u64 {
+    let dt = DateTime::from_timestamp_millis(instant as i64).unwrap();
+    let local = timezone.local(&dt).and_utc().timestamp_millis();
+    u64::try_from(local).unwrap_or(0)
+}
+
+// when the wall clock of `timezone` reads `local`, in milliseconds
+fn millis(timezone: Timezone, local: u64) -> u64 {
+    let local = DateTime::from_timestamp_millis(local as i64)
+        .unwrap()
+        .naive_utc();
+    u64::try_from(timezone.instant(local).timestamp_millis()).unwrap_or(0)
+}
+
+/// Called before a rotated-out file is deleted, returning false vetoes it.
+pub(crate) type PreDeleteHook = Box<dyn Fn(&Path) -> bool + Send + Sync>;
+
+/// Which rotated-out files are deleted and when, see
+/// `RotatingFile::with_max_total_size` and `RotatingFile::with_retention_age`.
+#[derive(Default)]
+pub(crate) struct Retention {
+    /// Max bytes of files in `root_dir` before the oldest are deleted, 0 means unlimited
+    pub(crate) max_total_size: u64,
+    /// Rotated-out files last modified longer ago are deleted in the background, default to None
+    pub(crate) max_age: Option<Duration>,
+    /// File name globs of files deletion is limited to, empty means all
+    pub(crate) include: Vec<String>,
+    /// File name globs of files that are never deleted
+    pub(crate) exclude: Vec<String>,
+    /// Called before a rotated-out file is deleted, returning false vetoes it
+    pub(crate) pre_delete: Option<PreDeleteHook>,
+    // rotated-out files being compressed, not to be deleted meanwhile
+    pub(crate) compressing: Mutex<Vec<PathBuf>>,
+    // serializes pruning to `max_total_size`
+    prune_lock: Mutex<()>,
+}
+
+impl Inner {
+    /// Returns the files in `root_dir` retention must not delete, i.e. the
+    /// active, the pre-created and those being compressed.
+    fn protected_files(&self) -> Vec<PathBuf> {
+        let mut protected = self.retention.compressing.lock().unwrap().clone();
+        protected.extend(self.active_files());
+        protected
+    }
+
+    /// Returns the files still written to, i.e. the active, the pre-created
+    /// and those of past buckets.
+    pub(crate) fn active_files(&self) -> Vec<PathBuf> {
+        let mut active = Vec::new();
+        if let Some(ctx) = self.context.lock().unwrap().current.as_ref() {
+            active.push(ctx.file_path.clone());
+        }
+        if let Some(ctx) = self.next.lock().unwrap().as_ref() {
+            active.push(ctx.file_path.clone());
+        }
+        let backfill = self.backfill.files.lock().unwrap();
+        active.extend(backfill.values().map(|ctx| ctx.file_path.clone()));
+        active
+    }
+
+    /// Returns the files in `root_dir` and the archive directory, the active
+    /// ones included, oldest first, with their sizes measured as configured
+    /// by `with_size_measure`.
+    pub(crate) fn measured_files(&self) -> Result<Vec<(PathBuf, u64)>, Error> {
+        let files = self.rotated_files()?;
+        let mut raw_sizes = self.raw_sizes.lock().unwrap();
+        // forget files deleted by any means, e.g. by hand
+        if !raw_sizes.is_empty() {
+            let existing = files.iter().collect::<HashSet<_>>();
+            raw_sizes.retain(|path, _| existing.contains(path));
+        }
+        Ok(files
+            .into_iter()
+            .filter_map(|file| {
+                // deleted meanwhile, e.g. by the pipeline
+                let size = fs::metadata(&file).ok()?.len();
+                let size = raw_sizes.get(&file).copied().unwrap_or(size);
+                Some((file, size))
+            })
+            .collect())
+    }
+
+    /// Deletes the rotated-out files in `root_dir` and the archive directory
+    /// last modified more than `max_age` ago, returns how many were deleted.
+    pub(crate) fn sweep(&self, max_age: Duration) -> Result<usize, Error> {
+        let protected = self.protected_files();
+        let mut deleted = 0;
+        for file in self.rotated_files()? {
+            if protected.contains(&file) {
+                continue;
+            }
+            // deleted meanwhile, e.g. by the pipeline
+            let Ok(modified) = fs::metadata(&file).and_then(|m| m.modified()) else {
+                continue;
+            };
+            let expired = SystemTime::now()
+                .duration_since(modified)
+                .is_ok_and(|age| age > max_age);
+            if expired && self.delete_rotated(&file)? {
+                debug!("Deleted {}, older than {:?}", file.display(), max_age);
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Deletes the oldest rotated-out files until the files in `root_dir`
+    /// and the archive directory fit in `max_total_size`.
+    pub(crate) fn prune_to_total_size(&self) -> Result<(), Error> {
+        let _guard = self.retention.prune_lock.lock().unwrap();
+        let files = self.measured_files()?;
+        let mut total = files.iter().map(|(_, size)| size).sum::<u64>();
+        if total <= self.retention.max_total_size {
+            return Ok(());
+        }
+
+        let protected = self.protected_files();
+        for (file, size) in files {
+            if total <= self.retention.max_total_size {
+                break;
+            }
+            if protected.contains(&file) {
+                continue;
+            }
+            if self.delete_rotated(&file)? {
+                debug!("Deleted {} to stay within the total size", file.display());
+                total -= size;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn delete_rotated(&self, path: &Path) -> Result<bool, Error> {
+        let file_name = path.file_name().and_then(|s| s.to_str()).unwrap_or("");
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| naming::glob_match(pattern, file_name))
+        };
+        if (!self.retention.include.is_empty() && !matches(&self.retention.include))
+            || matches(&self.retention.exclude)
+        {
+            debug!("{} is exempt from deletion", path.display());
+            return Ok(false);
+        }
+        if let Some(hook) = self.retention.pre_delete.as_ref() {
+            if !hook(path) {
+                debug!("Deletion of {} vetoed", path.display());
+                return Ok(false);
+            }
+        }
+        if self.seal {
+            writer::unseal(path)?;
+        }
+        let size = fs::metadata(path).map_or(0, |metadata| metadata.len());
+        let span = Span::enter(Op::Delete, path, size);
+        span.result(fs::remove_file(path))?;
+        drop(span);
+        if self.links.recent > 0 {
+            self.unlink_recent(path);
+        }
+        match fs::remove_file(index::sidecar_path(path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                warn!(
+                    "Failed to delete the bloom filter of {}: {}",
+                    path.display(),
+                    e
+                )
+            }
+            _ => {}
+        }
+        match fs::remove_file(index::line_index_path(path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                warn!(
+                    "Failed to delete the line index of {}: {}",
+                    path.display(),
+                    e
+                )
+            }
+            _ => {}
+        }
+        self.raw_sizes.lock().unwrap().remove(path);
+        self.transition(path, FileLifecycle::Expired);
+        self.sync_dir(path)?;
+        Ok(true)
+    }
+
+    /// Returns the rotated-out files in `root_dir` and the archive
+    /// directory, oldest first.
+    pub(crate) fn rotated_files(&self) -> Result<Vec<PathBuf>, Error> {
+        let mut files = reader::files(self.root_dir(), &self.naming)?;
+        if let Some(archive_dir) = self.archive_dir.as_ref() {
+            files.extend(reader::files(archive_dir, &self.naming)?);
+            files.sort_by_cached_key(|path| {
+                FileInfo::parse(path, &self.naming).map(|info| info.sort_key())
+            });
+        }
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{
+        ByteSize, CalendarPeriod, FileStats, IntervalPolicy, MaxAgePolicy, RotationPolicy,
+        SizePolicy,
+    };
+
+    #[test]
+    fn max_age() {
+        let stats = FileStats {
+            path: Path::new("2024-01-01-00-00-00.log"),
+            timestamp: 3600,
+            written: 1,
+            lines: 1,
+            first_write: Some(3590),
+            next_write: 1,
+        };
+        let policy = MaxAgePolicy {
+            max_age: Duration::from_secs(60),
+        };
+        let interval = IntervalPolicy { interval: 3600 };
+
+        // a file opened just before the boundary stays open past it
+        let now = UNIX_EPOCH + Duration::from_secs(3600 + 30);
+        assert!(!policy.should_rotate(&stats, now));
+        assert!(interval.should_rotate(&stats, UNIX_EPOCH + Duration::from_secs(7200)));
+        assert!(policy.should_rotate(&stats, UNIX_EPOCH + Duration::from_secs(3650)));
+        assert!(!policy.should_rotate(
+            &FileStats {
+                first_write: None,
+                ..stats.clone()
+            },
+            now
+        ));
+    }
+
+    #[test]
+    fn byte_size() {
+        assert_eq!(1500, ByteSize::b(1500).bytes());
+        assert_eq!(2048, ByteSize::kb(2).bytes());
+        assert_eq!(ByteSize::kb(1024), ByteSize::mb(1));
+        assert_eq!(ByteSize::mb(1024), ByteSize::gb(1));
+        assert!(ByteSize::kb(1) < ByteSize::b(1025));
+        assert_eq!(0, ByteSize::default().bytes());
+
+        let policy = SizePolicy {
+            max_bytes: ByteSize::kb(1).bytes(),
+        };
+        let mut stats = FileStats {
+            path: Path::new("2024-01-01-00-00-00.log"),
+            timestamp: 0,
+            written: 1000,
+            lines: 10,
+            first_write: Some(0),
+            next_write: 23,
+        };
+        assert!(!policy.should_rotate(&stats, UNIX_EPOCH));
+        stats.next_write = 24;
+        assert!(policy.should_rotate(&stats, UNIX_EPOCH));
+    }
+
+    #[test]
+    fn calendar_period() {
+        use chrono::{TimeZone, Utc};
+
+        let at = |y, mo, d, h, mi| {
+            Utc.with_ymd_and_hms(y, mo, d, h, mi, 0)
+                .unwrap()
+                .timestamp() as u64
+        };
+        // a Wednesday
+        let now = at(2024, 2, 28, 13, 45);
+        let cases = [
+            (
+                CalendarPeriod::Hour,
+                at(2024, 2, 28, 13, 0),
+                at(2024, 2, 28, 14, 0),
+            ),
+            (
+                CalendarPeriod::Day,
+                at(2024, 2, 28, 0, 0),
+                at(2024, 2, 29, 0, 0),
+            ),
+            (
+                CalendarPeriod::Week,
+                at(2024, 2, 26, 0, 0),
+                at(2024, 3, 4, 0, 0),
+            ),
+            (
+                CalendarPeriod::Month,
+                at(2024, 2, 1, 0, 0),
+                at(2024, 3, 1, 0, 0),
+            ),
+        ];
+        for (period, start, next) in cases {
+            assert_eq!(start, period.start(now), "{:?}", period);
+            assert_eq!(start, period.start(start), "{:?}", period);
+            assert_eq!(next, period.next(start), "{:?}", period);
+        }
+        let december = at(2023, 12, 31, 23, 59);
+        assert_eq!(
+            at(2024, 1, 1, 0, 0),
+            CalendarPeriod::Month.next(CalendarPeriod::Month.start(december))
+        );
+    }
+
+    #[test]
+    fn timezone_boundaries() {
+        use chrono::{FixedOffset, TimeZone, Utc};
+
+        use crate::naming::Timezone;
+
+        let at = |mo, d, h, mi| {
+            Utc.with_ymd_and_hms(2024, mo, d, h, mi, 0)
+                .unwrap()
+                .timestamp() as u64
+        };
+        // 00:30 on May 1st in +08:00
+        let timezone = Timezone::Fixed(FixedOffset::east_opt(8 * 3600).unwrap());
+        let month = CalendarPeriod::Month;
+        let start = month.start_in(at(4, 30, 16, 30), timezone);
+        assert_eq!(at(4, 30, 16, 0), start);
+        assert_eq!(at(5, 31, 16, 0), month.next_in(start, timezone));
+
+        // hours start at half past in +05:30
+        let timezone = Timezone::Fixed(FixedOffset::east_opt(5 * 3600 + 1800).unwrap());
+        let hourly = 3_600_000;
+        let start = super::bucket_in(at(5, 1, 13, 45) * 1000, hourly, timezone);
+        assert_eq!(at(5, 1, 13, 30) * 1000, start);
+        assert_eq!(
+            at(5, 1, 14, 30) * 1000,
+            super::next_bucket_in(start, hourly, timezone)
+        );
+    }
+
+    #[cfg(feature = "chrono-tz")]
+    #[test]
+    fn dst_boundaries() {
+        use chrono::{TimeZone, Utc};
+
+        use crate::naming::Timezone;
+
+        let timezone = Timezone::Tz(chrono_tz::America::New_York);
+        let at = |d, h| {
+            Utc.with_ymd_and_hms(2024, 3, d, h, 0, 0)
+                .unwrap()
+                .timestamp() as u64
+        };
+        // clocks go forward at 2:00 on March 10th, a day of 23 hours
+        let day = CalendarPeriod::Day;
+        let start = day.start_in(at(10, 12), timezone);
+        assert_eq!(at(10, 5), start);
+        assert_eq!(at(11, 4), day.next_in(start, timezone));
+        let daily = 86_400_000;
+        let start = super::bucket_in(at(10, 12) * 1000, daily, timezone);
+        assert_eq!(at(10, 5) * 1000, start);
+        assert_eq!(
+            at(11, 4) * 1000,
+            super::next_bucket_in(start, daily, timezone)
+        );
+
+        // the skipped hour starts at 3:00 local
+        let hour = CalendarPeriod::Hour;
+        assert_eq!(at(10, 7), hour.start_in(at(10, 7) + 60, timezone));
+        assert_eq!(at(10, 7), hour.next_in(at(10, 6), timezone));
+    }
+}
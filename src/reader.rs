@@ -0,0 +1,481 @@
+//! Reading rotated files back, e.g. for backtesting.
+//!
+//! ## Example
+//!
+//! ```
+//! use rotating_file::{reader, streams::StreamGroup, RotatingFile};
+//!
+//! let root_dir = "./target/tmp-reader";
+//! let streams = StreamGroup::new(&[".trades.log", ".depth.log"], |suffix| {
+//!     RotatingFile::new(root_dir, None, None, None, None, None, Some(suffix.to_string()))
+//! });
+//! streams.writeln(".depth.log", "2 depth").unwrap();
+//! streams.writeln(".trades.log", "1 trade").unwrap();
+//! streams.writeln(".trades.log", "3 trade").unwrap();
+//! streams.close();
+//!
+//! let shards = [".trades.log", ".depth.log"]
+//!     .iter()
+//!     .map(|suffix| {
+//!         let naming = streams.stream(suffix).unwrap().naming();
+//!         reader::files(root_dir, naming).unwrap()
+//!     })
+//!     .collect();
+//! let lines = reader::MergeReader::new(shards, |line: &str| {
+//!     line.split(' ').next().unwrap().parse::<u64>().unwrap()
+//! })
+//! .collect::<Result<Vec<_>, _>>()
+//! .unwrap();
+//! assert_eq!(vec!["1 trade", "2 depth", "3 trade"], lines);
+//! # std::fs::remove_dir_all(root_dir).unwrap();
+//! ```
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::fs;
+use std::io::{BufRead, BufReader, Cursor, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+
+use crate::compress::Compression;
+use crate::index;
+use crate::naming::{FileInfo, NamingConfig};
+
+/// Returns the files in `dir` named with `naming`, oldest first.
+pub fn files<P: AsRef<Path>>(dir: P, naming: &NamingConfig) -> Result<Vec<PathBuf>, Error> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if let Some(info) = FileInfo::parse(&path, naming) {
+            files.push((info.sort_key(), path));
+        }
+    }
+    files.sort();
+    Ok(files.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Opens the file at `path` for reading, decompressing it if its extension
+/// says so.
+pub fn open<P: AsRef<Path>>(path: P) -> Result<Box<dyn BufRead>, Error> {
+    let path = path.as_ref();
+    let file = fs::File::open(path)?;
+    let name = path.to_string_lossy();
+    #[cfg(feature = "zstd")]
+    if name.ends_with(Compression::Zstd.extension()) {
+        let decoder = zstd::stream::read::Decoder::new(file)?;
+        return Ok(Box::new(BufReader::new(decoder)));
+    }
+    #[cfg(feature = "lz4")]
+    if name.ends_with(Compression::Lz4.extension()) {
+        let decoder = lz4_flex::frame::FrameDecoder::new(file);
+        return Ok(Box::new(BufReader::new(decoder)));
+    }
+    #[cfg(feature = "xz")]
+    if name.ends_with(Compression::Xz.extension()) {
+        let decoder = xz2::read::XzDecoder::new_multi_decoder(file);
+        return Ok(Box::new(BufReader::new(decoder)));
+    }
+    #[cfg(feature = "bzip2")]
+    if name.ends_with(Compression::Bzip2.extension()) {
+        let decoder = bzip2::read::MultiBzDecoder::new(file);
+        return Ok(Box::new(BufReader::new(decoder)));
+    }
+    if name.ends_with(Compression::GZip.extension()) {
+        Ok(Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(
+            file,
+        ))))
+    } else if name.ends_with(Compression::Zip.extension()) {
+        // entries one after the other, in the order they were added
+        let mut archive = zip::ZipArchive::new(file).map_err(zip_error)?;
+        let mut buf = Vec::new();
+        for index in 0..archive.len() {
+            archive
+                .by_index(index)
+                .map_err(zip_error)?
+                .read_to_end(&mut buf)?;
+        }
+        Ok(Box::new(Cursor::new(buf)))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+/// Returns the names of the entries of the zip archive at `path`, in the
+/// order they were added, e.g. the files of a day bundled together.
+pub fn zip_entries<P: AsRef<Path>>(path: P) -> Result<Vec<String>, Error> {
+    let mut archive = zip::ZipArchive::new(fs::File::open(path)?).map_err(zip_error)?;
+    (0..archive.len())
+        .map(|index| {
+            Ok(archive
+                .by_index_raw(index)
+                .map_err(zip_error)?
+                .name()
+                .to_string())
+        })
+        .collect()
+}
+
+/// Opens the entry named `entry` of the zip archive at `path` for reading.
+pub fn open_zip_entry<P: AsRef<Path>>(path: P, entry: &str) -> Result<Box<dyn BufRead>, Error> {
+    let mut archive = zip::ZipArchive::new(fs::File::open(path)?).map_err(zip_error)?;
+    let mut buf = Vec::new();
+    archive
+        .by_name(entry)
+        .map_err(zip_error)?
+        .read_to_end(&mut buf)?;
+    Ok(Box::new(Cursor::new(buf)))
+}
+
+fn zip_error(e: zip::result::ZipError) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+/// Returns the decompressed content of the file at `path`, plain or
+/// compressed by this crate.
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> Result<String, Error> {
+    let mut content = String::new();
+    open(path)?.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Opens the file at `path` for reading from line `line`, counting from 0,
+/// seeking to the closest indexed line before it and skipping the rest, see
+/// `RotatingFile::with_line_index`. Files without an index, e.g. compressed
+/// ones, are read from the start.
+pub fn open_at_line<P: AsRef<Path>>(path: P, line: u64) -> Result<Box<dyn BufRead>, Error> {
+    let path = path.as_ref();
+    let (indexed, offset) = index::line_index(path)?.map_or((0, 0), |index| index.seek_line(line));
+    let mut reader = open_at(path, offset)?;
+    let mut buf = Vec::new();
+    for _ in indexed..line {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+    }
+    Ok(reader)
+}
+
+/// Opens the file at `path` for reading from the last indexed line written
+/// before `timestamp`, so that every line written at `timestamp` or later
+/// follows, see `RotatingFile::with_line_index`. Lines before `timestamp`
+/// may follow too, skip them by their content. Files without an index are
+/// read from the start.
+pub fn open_at_time<P: AsRef<Path>>(
+    path: P,
+    timestamp: DateTime<Utc>,
+) -> Result<Box<dyn BufRead>, Error> {
+    let path = path.as_ref();
+    let timestamp = timestamp.timestamp().max(0) as u64;
+    let (_, offset) = index::line_index(path)?.map_or((0, 0), |index| index.seek_time(timestamp));
+    open_at(path, offset)
+}
+
+// opens the file at `path` at byte `offset`, which is 0 unless it's plain
+fn open_at(path: &Path, offset: u64) -> Result<Box<dyn BufRead>, Error> {
+    if offset == 0 {
+        return open(path);
+    }
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(Box::new(BufReader::new(file)))
+}
+
+/// The lines of one shard, read file after file.
+struct Shard {
+    files: std::vec::IntoIter<PathBuf>,
+    reader: Option<Box<dyn BufRead>>,
+}
+
+impl Shard {
+    /// Returns the next line, moving on to the next file once a file fails
+    /// to be opened or read.
+    fn next_line(&mut self) -> Result<Option<String>, Error> {
+        loop {
+            if let Some(reader) = self.reader.as_mut() {
+                let mut line = String::new();
+                let read = reader
+                    .read_line(&mut line)
+                    .inspect_err(|_| self.reader = None)?;
+                if read > 0 {
+                    if line.ends_with('\n') {
+                        line.pop();
+                    }
+                    return Ok(Some(line));
+                }
+            }
+            match self.files.next() {
+                Some(path) => self.reader = Some(open(path)?),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// Merges the lines of several shards, each a list of files in order, into
+/// a single stream ordered by the key `key` extracts from each line, e.g.
+/// its timestamp.
+///
+/// Lines of each shard must already be ordered by key. Lines with equal keys
+/// come in the order of their shards.
+///
+/// A file that fails to be opened or read yields the error, then its shard
+/// goes on with its next file.
+pub struct MergeReader<K, F> {
+    shards: Vec<Shard>,
+    // the next line of each shard that has one
+    heads: BinaryHeap<Reverse<(K, usize, String)>>,
+    key: F,
+    // shards to read the next line of, all of them at first
+    stalled: Vec<usize>,
+    // errors to yield before the next line, with their shards
+    errors: VecDeque<(usize, Error)>,
+}
+
+impl<K, F> MergeReader<K, F>
+where
+    K: Ord,
+    F: FnMut(&str) -> K,
+{
+    /// Merges `shards`, each the files of a shard in order, e.g. as returned
+    /// by `files`, by the key `key` extracts from each line.
+    pub fn new(shards: Vec<Vec<PathBuf>>, key: F) -> Self {
+        let stalled = (0..shards.len()).collect();
+        MergeReader {
+            shards: shards
+                .into_iter()
+                .map(|files| Shard {
+                    files: files.into_iter(),
+                    reader: None,
+                })
+                .collect(),
+            heads: BinaryHeap::new(),
+            key,
+            stalled,
+            errors: VecDeque::new(),
+        }
+    }
+
+    /// Reads the next line of `shard`, keeping the error for later.
+    fn advance(&mut self, shard: usize) {
+        match self.shards[shard].next_line() {
+            Ok(Some(line)) => self.heads.push(Reverse(((self.key)(&line), shard, line))),
+            Ok(None) => {}
+            Err(e) => self.errors.push_back((shard, e)),
+        }
+    }
+}
+
+impl<K, F> Iterator for MergeReader<K, F>
+where
+    K: Ord,
+    F: FnMut(&str) -> K,
+{
+    type Item = Result<String, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // every shard must have its head before the smallest one is known
+        for shard in std::mem::take(&mut self.stalled) {
+            self.advance(shard);
+        }
+        if let Some((shard, e)) = self.errors.pop_front() {
+            // goes on with its next file on the next call
+            self.stalled.push(shard);
+            return Some(Err(e));
+        }
+        let Reverse((_, shard, line)) = self.heads.pop()?;
+        self.stalled.push(shard);
+        Some(Ok(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{files, read_to_string, MergeReader};
+    use crate::{Compression, RotatingFile};
+
+    const TEXT: &str = "The quick brown fox jumps over the lazy dog";
+
+    #[test]
+    fn merge_shards() {
+        let root_dir = "./target/tmp34";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let mut shards = Vec::new();
+        for (shard, keys) in [(0, [1, 4, 5]), (1, [2, 3, 6])] {
+            let dir = Path::new(root_dir).join(shard.to_string());
+            let rotating_file = RotatingFile::new(
+                dir.to_str().unwrap(),
+                None,
+                None,
+                Some(Compression::GZip),
+                None,
+                None,
+                None,
+            );
+            for key in keys {
+                rotating_file
+                    .writeln(&format!("{} {}", key, shard))
+                    .unwrap();
+                // one file per line
+                rotating_file.rotate().unwrap();
+            }
+            rotating_file.close();
+            shards.push(files(&dir, rotating_file.naming()).unwrap());
+        }
+        assert_eq!(3, shards[0].len());
+
+        let keys = MergeReader::new(shards, |line: &str| {
+            line.split(' ').next().unwrap().parse::<u64>().unwrap()
+        })
+        .map(|line| line.unwrap().split(' ').next().unwrap().to_string())
+        .collect::<Vec<_>>();
+        assert_eq!(vec!["1", "2", "3", "4", "5", "6"], keys);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn merge_errors() {
+        let root_dir = "./target/tmp109";
+        let _ = std::fs::remove_dir_all(root_dir);
+        std::fs::create_dir_all(root_dir).unwrap();
+        let file = |name: &str, content: &str| {
+            let path = Path::new(root_dir).join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        };
+        let missing = Path::new(root_dir).join("missing.log");
+        let shards = vec![
+            vec![
+                file("a.log", "1\n3\n"),
+                missing.clone(),
+                file("b.log", "5\n"),
+            ],
+            vec![missing, file("c.log", "2\n4\n")],
+        ];
+
+        // no line is lost to an error, and every shard is read
+        let items = MergeReader::new(shards, |line: &str| line.parse::<u64>().unwrap())
+            .map(|line| line.unwrap_or_else(|e| e.kind().to_string()))
+            .collect::<Vec<_>>();
+        let not_found = std::io::ErrorKind::NotFound.to_string();
+        assert_eq!(vec![&not_found, "1", "2", "3", &not_found, "4", "5"], items);
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn read_compressed() {
+        let root_dir = "./target/tmp46";
+        let _ = std::fs::remove_dir_all(root_dir);
+        for compression in [
+            None,
+            Some(Compression::GZip),
+            Some(Compression::Zip),
+            #[cfg(feature = "lz4")]
+            Some(Compression::Lz4),
+            #[cfg(feature = "xz")]
+            Some(Compression::Xz),
+            #[cfg(feature = "bzip2")]
+            Some(Compression::Bzip2),
+        ] {
+            let rotating_file =
+                RotatingFile::new(root_dir, None, None, compression, None, None, None);
+            let rx = rotating_file.watch_rotations();
+            rotating_file.writeln(TEXT).unwrap();
+            rotating_file.rotate().unwrap();
+            rotating_file.close();
+
+            let path = rx.recv().unwrap().path;
+            assert_eq!(format!("{}\n", TEXT), read_to_string(path).unwrap());
+        }
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn zip_bundle() {
+        use std::io::{Read, Write};
+
+        let root_dir = "./target/tmp47";
+        let _ = std::fs::remove_dir_all(root_dir);
+        std::fs::create_dir_all(root_dir).unwrap();
+        let path = std::path::Path::new(root_dir).join("2024-05-01.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        for name in ["2024-05-01-01-00-00.log", "2024-05-01-00-00-00.log"] {
+            zip.start_file(name, zip::write::FileOptions::default())
+                .unwrap();
+            writeln!(zip, "{}", name).unwrap();
+        }
+        zip.finish().unwrap();
+
+        // in the order they were added, not by name
+        let entries = super::zip_entries(&path).unwrap();
+        assert_eq!(
+            vec!["2024-05-01-01-00-00.log", "2024-05-01-00-00-00.log"],
+            entries
+        );
+        let mut content = String::new();
+        super::open_zip_entry(&path, &entries[1])
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!("2024-05-01-00-00-00.log\n", content);
+        assert_eq!(
+            "2024-05-01-01-00-00.log\n2024-05-01-00-00-00.log\n",
+            read_to_string(&path).unwrap()
+        );
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn open_at_line() {
+        use std::io::BufRead;
+
+        let root_dir = "./target/tmp91";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file =
+            RotatingFile::new(root_dir, None, None, None, None, None, None).with_line_index(10);
+        for i in 0..95 {
+            rotating_file.writeln(&format!("line {}", i)).unwrap();
+        }
+        let path = rotating_file.rotate().unwrap();
+        rotating_file.close();
+
+        let index = crate::index::line_index(&path).unwrap().unwrap();
+        assert_eq!(9 * 10, index.seek_line(94).0);
+        for line in [0, 9, 10, 57, 94] {
+            let first = super::open_at_line(&path, line)
+                .unwrap()
+                .lines()
+                .next()
+                .unwrap()
+                .unwrap();
+            assert_eq!(format!("line {}", line), first);
+        }
+        assert!(super::open_at_line(&path, 95)
+            .unwrap()
+            .lines()
+            .next()
+            .is_none());
+
+        // every line was written before the end of time
+        let lines = |timestamp| {
+            super::open_at_time(&path, timestamp)
+                .unwrap()
+                .lines()
+                .count()
+        };
+        assert_eq!(95, lines(chrono::DateTime::UNIX_EPOCH));
+        assert_eq!(5, lines(chrono::DateTime::<chrono::Utc>::MAX_UTC));
+
+        // deleted along with the file
+        assert!(rotating_file.delete_rotated(&path).unwrap());
+        assert!(!crate::index::line_index_path(&path).exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+}
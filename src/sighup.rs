@@ -0,0 +1,66 @@
+//! Reopening on SIGHUP for external `logrotate` setups, see
+//! `RotatingFile::reopen_on_sighup`.
+use std::io::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Once, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::*;
+
+use crate::Inner;
+
+// how often watchers check for a hangup
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// hangups received so far, the only thing safe to touch in the handler
+static HANGUPS: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    HANGUPS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Installs the SIGHUP handler once per process.
+fn install() -> Result<(), Error> {
+    static INSTALLED: Once = Once::new();
+    let mut result = Ok(());
+    INSTALLED.call_once(|| {
+        // SAFETY: the handler only touches an atomic, which is
+        // async-signal-safe
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_sighup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut()) != 0 {
+                result = Err(Error::last_os_error());
+            }
+        }
+    });
+    result
+}
+
+/// Reopens the instance each time the process receives SIGHUP, until it's
+/// closed or dropped.
+pub(crate) fn spawn(inner: Weak<Inner>, thread_name: String) -> Result<JoinHandle<()>, Error> {
+    install()?;
+    let mut seen = HANGUPS.load(Ordering::Relaxed);
+    std::thread::Builder::new()
+        .name(thread_name)
+        .spawn(move || {
+            while let Some(inner) = inner.upgrade() {
+                if inner.threads.stopped.load(Ordering::Acquire) {
+                    break;
+                }
+                let hangups = HANGUPS.load(Ordering::Relaxed);
+                if hangups != seen {
+                    seen = hangups;
+                    if let Err(e) = inner.reopen() {
+                        error!("Failed to reopen on SIGHUP: {}", e);
+                    }
+                }
+                drop(inner);
+                std::thread::park_timeout(POLL_INTERVAL);
+            }
+        })
+}
@@ -0,0 +1,124 @@
+//! Write latency statistics, see `RotatingFile::write_latency`.
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// values are bucketed by their highest bit and the 3 bits after it, so each
+// bucket is within 12.5% of the values in it
+const SUB_BITS: u32 = 3;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const BUCKETS: usize = (64 - SUB_BITS as usize + 1) * SUB_BUCKETS;
+
+fn bucket(nanos: u64) -> usize {
+    if nanos < SUB_BUCKETS as u64 {
+        return nanos as usize;
+    }
+    let high = 63 - nanos.leading_zeros();
+    let sub = (nanos >> (high - SUB_BITS)) as usize & (SUB_BUCKETS - 1);
+    (high - SUB_BITS + 1) as usize * SUB_BUCKETS + sub
+}
+
+// the smallest value in bucket `index`
+fn lower_bound(index: usize) -> u64 {
+    if index < SUB_BUCKETS {
+        return index as u64;
+    }
+    let high = (index / SUB_BUCKETS) as u32 + SUB_BITS - 1;
+    ((SUB_BUCKETS + index % SUB_BUCKETS) as u64) << (high - SUB_BITS)
+}
+
+/// Records latencies without locking, cheap enough for every write.
+pub(crate) struct LatencyRecorder {
+    counts: Vec<AtomicU64>,
+    max: AtomicU64,
+}
+
+impl Default for LatencyRecorder {
+    fn default() -> Self {
+        LatencyRecorder {
+            counts: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LatencyRecorder {
+    pub(crate) fn record(&self, latency: Duration) {
+        let nanos = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.counts[bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Histogram {
+        Histogram {
+            counts: self
+                .counts
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A snapshot of latencies, with a relative error of at most 12.5%.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    max: u64,
+}
+
+impl Histogram {
+    /// Returns how many latencies were recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Returns the highest latency recorded, exactly.
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max)
+    }
+
+    /// Returns the latency below which `quantile`, from 0.0 to 1.0, of the
+    /// recorded latencies fall, e.g. 0.99 for the 99th percentile.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let rank = (quantile.clamp(0.0, 1.0) * self.count() as f64).ceil() as u64;
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank.max(1) {
+                return Duration::from_nanos(lower_bound(index).min(self.max));
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{bucket, lower_bound, LatencyRecorder, BUCKETS};
+
+    #[test]
+    fn quantiles() {
+        for nanos in [0, 7, 8, 9, 15, 16, 1000, 123_456_789, u64::MAX] {
+            let index = bucket(nanos);
+            assert!(index < BUCKETS);
+            assert!(lower_bound(index) <= nanos);
+            assert!(index + 1 == BUCKETS || lower_bound(index + 1) > nanos);
+        }
+
+        let recorder = LatencyRecorder::default();
+        for micros in 1..=100 {
+            recorder.record(Duration::from_micros(micros));
+        }
+        // a rotation spike
+        recorder.record(Duration::from_millis(50));
+        let histogram = recorder.snapshot();
+        assert_eq!(101, histogram.count());
+        assert_eq!(Duration::from_millis(50), histogram.max());
+        let median = histogram.quantile(0.5).as_micros();
+        assert!((44..=51).contains(&median));
+        assert!(histogram.quantile(1.0) > Duration::from_millis(40));
+    }
+}
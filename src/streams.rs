@@ -0,0 +1,231 @@
+//! Several streams told apart by suffix that rotate together, and groups of
+//! independent instances that rotate at the same boundaries.
+//!
+//! ## Example
+//!
+//! ```
+//! use rotating_file::{streams::StreamGroup, RotatingFile};
+//!
+//! let root_dir = "./target/tmp-streams";
+//! let streams = StreamGroup::new(&[".trades.log", ".depth.log"], |suffix| {
+//!     RotatingFile::new(root_dir, Some(1), Some(3600), None, None, None, Some(suffix.to_string()))
+//! });
+//! streams.writeln(".trades.log", "BTC-USDT 42000 0.1").unwrap();
+//! streams.writeln(".depth.log", "BTC-USDT 41999 42001").unwrap();
+//! streams.close();
+//! # std::fs::remove_dir_all(root_dir).unwrap();
+//! ```
+use std::io::{Error, ErrorKind};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::{Inner, RotatingFile, RotationTrigger};
+
+/// A fixed set of streams in one directory, e.g. `.trades.log` and
+/// `.depth.log`, sharing one rotation schedule.
+///
+/// When one stream rotates, by size or time, all of them do, so files appear
+/// in matched pairs for downstream joining: the next file of each stream is
+/// created on its next write with the same date, and the same index as long
+/// as every stream is written to between rotations.
+pub struct StreamGroup {
+    streams: Vec<(String, RotatingFile)>,
+    // serializes writes so that rotations line up
+    lock: Mutex<()>,
+}
+
+impl StreamGroup {
+    /// Creates a group with a stream per suffix, each created by `make`,
+    /// which should use the same root directory and rotation settings and
+    /// the given suffix.
+    pub fn new<F>(suffixes: &[&str], make: F) -> Self
+    where
+        F: Fn(&str) -> RotatingFile,
+    {
+        StreamGroup {
+            streams: suffixes
+                .iter()
+                .map(|suffix| (suffix.to_string(), make(suffix)))
+                .collect(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Returns the stream with `suffix`, e.g. to watch its rotations.
+    pub fn stream(&self, suffix: &str) -> Option<&RotatingFile> {
+        self.streams
+            .iter()
+            .find(|(s, _)| s == suffix)
+            .map(|(_, stream)| stream)
+    }
+
+    /// Writes a line to the stream with `suffix`, rotating the others too if
+    /// it rotates.
+    pub fn writeln(&self, suffix: &str, s: &str) -> Result<(), Error> {
+        let stream = self.stream(suffix).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("no stream with suffix {}", suffix),
+            )
+        })?;
+
+        let _guard = self.lock.lock().unwrap();
+        let before = stream.inner.current_path();
+        stream.writeln(s)?;
+        if before.is_some() && before != stream.inner.current_path() {
+            for (_, other) in self.streams.iter().filter(|(s, _)| s != suffix) {
+                other.inner.rotate(RotationTrigger::External)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn close(&self) {
+        for (_, stream) in self.streams.iter() {
+            stream.close();
+        }
+    }
+}
+
+/// Instances registered from anywhere, possibly in different directories or
+/// with different rotation settings, that rotate at the same boundaries.
+///
+/// Whenever a member rotates, by size or time, every other member rotates its
+/// active file out too, so that the files of all members cover the same time
+/// ranges. [`RotatingFile::rotate`] only rotates the instance itself.
+#[derive(Clone, Default)]
+pub struct RotationGroup {
+    shared: Arc<GroupShared>,
+}
+
+#[derive(Default)]
+pub(crate) struct GroupShared {
+    // bumped each time a member rotates, files are tagged with it on creation
+    generation: Mutex<u64>,
+    members: Mutex<Vec<Weak<Inner>>>,
+}
+
+impl RotationGroup {
+    pub fn new() -> Self {
+        RotationGroup::default()
+    }
+
+    /// Adds `file` to the group, failing if it already belongs to one.
+    pub fn add(&self, file: &RotatingFile) -> Result<(), Error> {
+        file.inner
+            .group
+            .set(self.shared.clone())
+            .map_err(|_| Error::new(ErrorKind::AlreadyExists, "already in a rotation group"))?;
+        self.shared
+            .members
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&file.inner));
+        Ok(())
+    }
+}
+
+impl GroupShared {
+    pub(crate) fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    /// Starts a new generation if a file of the current one was rotated out,
+    /// returning it, or `None` if another member already did.
+    pub(crate) fn advance(&self, rotated: u64) -> Option<u64> {
+        let mut generation = self.generation.lock().unwrap();
+        if *generation == rotated {
+            *generation += 1;
+            Some(*generation)
+        } else {
+            None
+        }
+    }
+
+    /// Rotates out the files of members other than `inner` created before
+    /// `generation`.
+    pub(crate) fn rotate_others(&self, inner: &Arc<Inner>, generation: u64) -> Result<(), Error> {
+        let members: Vec<Arc<Inner>> = {
+            let mut members = self.members.lock().unwrap();
+            members.retain(|member| member.strong_count() > 0);
+            members.iter().filter_map(Weak::upgrade).collect()
+        };
+        let mut result = Ok(());
+        for member in members.iter().filter(|member| !Arc::ptr_eq(member, inner)) {
+            let rotated = member.rotate_before(generation, RotationTrigger::External);
+            result = result.and(rotated.map(|_| ()));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{RotationGroup, StreamGroup};
+    use crate::RotatingFile;
+
+    const TEXT: &str = "The quick brown fox jumps over the lazy dog";
+
+    #[test]
+    fn rotate_together() {
+        let root_dir = "./target/tmp31";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let timestamp = chrono::Utc::now().format("%Y-%m-%d-%H-%M-%S").to_string();
+        let streams = StreamGroup::new(&[".trades.log", ".depth.log"], |suffix| {
+            RotatingFile::new(
+                root_dir,
+                Some(1),
+                None,
+                None,
+                None,
+                None,
+                Some(suffix.to_string()),
+            )
+        });
+
+        streams.writeln(".depth.log", TEXT).unwrap();
+        for _ in 0..24 {
+            streams.writeln(".trades.log", TEXT).unwrap();
+        }
+        streams.writeln(".depth.log", TEXT).unwrap();
+        assert!(streams.writeln(".other.log", TEXT).is_err());
+        streams.close();
+
+        for suffix in [".trades.log", ".depth.log", "-1.trades.log", "-1.depth.log"] {
+            assert!(Path::new(root_dir)
+                .join(timestamp.clone() + suffix)
+                .exists());
+        }
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_group() {
+        let root_dir = "./target/tmp32";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let trades_dir = format!("{}/trades", root_dir);
+        let depth_dir = format!("{}/depth", root_dir);
+        let trades = RotatingFile::new(&trades_dir, Some(1), None, None, None, None, None);
+        let depth = RotatingFile::new(&depth_dir, None, None, None, None, None, None);
+        let group = RotationGroup::new();
+        group.add(&trades).unwrap();
+        group.add(&depth).unwrap();
+        assert!(group.add(&depth).is_err());
+
+        depth.writeln(TEXT).unwrap();
+        // the 24th line rotates trades, and depth along with it
+        for _ in 0..24 {
+            trades.writeln(TEXT).unwrap();
+        }
+        depth.writeln(TEXT).unwrap();
+        trades.close();
+        depth.close();
+
+        assert_eq!(2, std::fs::read_dir(&trades_dir).unwrap().count());
+        assert_eq!(2, std::fs::read_dir(&depth_dir).unwrap().count());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+//! systemd watchdog pings, see `RotatingFile::start_watchdog`.
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::io::Error;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::Ordering;
+use std::sync::Weak;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use log::*;
+
+use crate::Inner;
+
+/// Returns the socket to notify and how often to ping, `None` if the
+/// service has no watchdog.
+pub(crate) fn watchdog_from_env() -> Option<(OsString, Duration)> {
+    let socket = env::var_os("NOTIFY_SOCKET")?;
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    // ping twice per timeout as recommended by sd_watchdog_enabled(3)
+    Some((socket, Duration::from_micros(usec / 2)))
+}
+
+/// Pings the watchdog with the status of the instance every `interval`,
+/// until it's closed or dropped.
+///
+/// Gathering the status takes the lock of the active file, so a writer
+/// that hangs while holding it stops the pings.
+pub(crate) fn spawn(
+    inner: Weak<Inner>,
+    socket: OsString,
+    interval: Duration,
+    thread_name: String,
+) -> Result<JoinHandle<()>, Error> {
+    std::thread::Builder::new()
+        .name(thread_name)
+        .spawn(move || {
+            while let Some(inner) = inner.upgrade() {
+                if inner.threads.stopped.load(Ordering::Acquire) {
+                    break;
+                }
+                let state = format!("WATCHDOG=1\nSTATUS={}", inner.status());
+                drop(inner);
+                if let Err(e) = notify(&socket, &state) {
+                    error!("Failed to notify systemd: {}", e);
+                }
+                std::thread::park_timeout(interval);
+            }
+        })
+}
+
+/// Sends `state` to the `sd_notify` socket at `socket`.
+fn notify(socket: &OsStr, state: &str) -> Result<(), Error> {
+    let datagram = UnixDatagram::unbound()?;
+    match socket.as_bytes().strip_prefix(b"@") {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Some(name) => {
+            #[cfg(target_os = "android")]
+            use std::os::android::net::SocketAddrExt;
+            #[cfg(target_os = "linux")]
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            datagram.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            datagram.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,271 @@
+//! Background threads of an instance, started on the first write and
+//! stopped by `close`: pre-creation, sweeping, coalescing and idle
+//! rotation, besides the services started on request.
+use std::fs;
+use std::io::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::thread::JoinHandle;
+use std::time::{Duration, UNIX_EPOCH};
+
+use chrono::DateTime;
+use log::*;
+
+use crate::lifecycle::FileLifecycle;
+use crate::policy::RotationTrigger;
+use crate::writer::CurrentContext;
+use crate::{lock_or, Inner, RotatingFileError};
+
+/// The background threads of an instance and whether they were started.
+pub(crate) struct Threads {
+    precreator_started: Once,
+    sweeper_started: Once,
+    coalescer_started: Once,
+    idler_started: Once,
+    // pre-creation thread, joined first on close
+    pub(crate) precreator: Mutex<Option<JoinHandle<()>>>,
+    // control socket, watchdog, sweeper, coalescer and idler threads
+    pub(crate) services: Mutex<Vec<JoinHandle<()>>>,
+    // set on close and abort, threads exit when they next wake
+    pub(crate) stopped: AtomicBool,
+}
+
+impl Default for Threads {
+    fn default() -> Self {
+        Threads {
+            precreator_started: Once::new(),
+            sweeper_started: Once::new(),
+            coalescer_started: Once::new(),
+            idler_started: Once::new(),
+            precreator: Mutex::new(None),
+            services: Mutex::new(Vec::new()),
+            stopped: AtomicBool::new(false),
+        }
+    }
+}
+
+impl Inner {
+    /// Returns `rotfile-<kind>-<prefix>`, so that thread dumps can attribute
+    /// threads to instances.
+    pub(crate) fn thread_name(&self, kind: &str) -> String {
+        if self.naming.prefix.is_empty() {
+            format!("rotfile-{}", kind)
+        } else {
+            format!("rotfile-{}-{}", kind, self.naming.prefix)
+        }
+    }
+
+    pub(crate) fn thread_count(&self) -> usize {
+        let compressing = self
+            .handles
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|handle| !handle.is_finished())
+            .count();
+        let precreating = self
+            .threads
+            .precreator
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished());
+        let serving = self
+            .threads
+            .services
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|handle| !handle.is_finished())
+            .count();
+        compressing + usize::from(precreating) + serving
+    }
+
+    /// Starts the background threads the configuration calls for, once.
+    ///
+    /// Writes carry on without a thread that can't be spawned, e.g. at the
+    /// thread limit, rather than failing.
+    pub(crate) fn start_threads(self: &Arc<Self>) {
+        let threads = &self.threads;
+        if self.bucketed() && self.precreate > 0 {
+            threads
+                .precreator_started
+                .call_once(|| match self.spawn_precreator() {
+                    Ok(handle) => *threads.precreator.lock().unwrap() = Some(handle),
+                    Err(e) => error!("Failed to spawn the pre-creation thread: {}", e),
+                });
+        }
+        if let Some(max_age) = self.retention.max_age {
+            threads
+                .sweeper_started
+                .call_once(|| self.start_service("sweeping", self.spawn_sweeper(max_age)));
+        }
+        if let (Some((max_delay, _)), false) = (self.coalesce, self.write_through) {
+            threads
+                .coalescer_started
+                .call_once(|| self.start_service("coalescing", self.spawn_coalescer(max_delay)));
+        }
+        if let Some(idle) = self.idle_timeout {
+            threads
+                .idler_started
+                .call_once(|| self.start_service("idle rotation", self.spawn_idler(idle)));
+        }
+    }
+
+    /// Stops and joins the background threads and discards the unused
+    /// pre-created file, reporting poisoned locks to `fail`.
+    pub(crate) fn stop_threads(&self, fail: &mut impl FnMut(RotatingFileError)) {
+        self.threads.stopped.store(true, Ordering::Release);
+        if let Some(handle) = lock_or(&self.threads.precreator, fail).take() {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+        if let Some(unused) = lock_or(&self.next, fail).take() {
+            self.discard(unused);
+        }
+        // taken out first, the control thread counts them for its status
+        let services = std::mem::take(&mut *lock_or(&self.threads.services, fail));
+        for handle in services {
+            handle.thread().unpark();
+            let _ = handle.join();
+        }
+    }
+
+    /// Keeps the thread of service `name` to join on close, or logs that it
+    /// couldn't be spawned.
+    fn start_service(&self, name: &str, spawned: Result<JoinHandle<()>, Error>) {
+        match spawned {
+            Ok(handle) => self.threads.services.lock().unwrap().push(handle),
+            Err(e) => error!("Failed to spawn the {} thread: {}", name, e),
+        }
+    }
+
+    fn spawn_precreator(self: &Arc<Self>) -> Result<JoinHandle<()>, Error> {
+        let inner = Arc::downgrade(self);
+        let builder = std::thread::Builder::new().name(self.thread_name("precreate"));
+        builder.spawn(move || {
+            while let Some(inner) = inner.upgrade() {
+                if inner.threads.stopped.load(Ordering::Acquire) {
+                    break;
+                }
+                let timeout = inner.precreate_next();
+                // don't keep the file alive while parked
+                drop(inner);
+                std::thread::park_timeout(timeout);
+            }
+        })
+    }
+
+    /// Pre-creates the file of the next interval if it's due, returns how
+    /// long to wait before checking again.
+    fn precreate_next(&self) -> Duration {
+        let now = self.now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let boundary = self.next_bucket(self.bucket(now));
+        let wake_at = boundary.saturating_sub(self.precreate * 1000);
+        if now < wake_at {
+            return Duration::from_millis(wake_at - now);
+        }
+
+        let mut guard = self.next.lock().unwrap();
+        if guard.as_ref().map(|ctx| ctx.bucket) != Some(boundary) {
+            if let Some(stale) = guard.take() {
+                self.discard(stale);
+            }
+            let named = DateTime::from_timestamp_millis(boundary as i64).unwrap();
+            match self.create_context(boundary / 1000, named) {
+                Ok(mut ctx) => {
+                    ctx.bucket = boundary;
+                    *guard = Some(ctx);
+                }
+                Err(e) => error!("{}", e),
+            }
+        }
+        Duration::from_millis(boundary - now)
+    }
+
+    /// Deletes a pre-created file that was never written to.
+    pub(crate) fn discard(&self, ctx: CurrentContext) {
+        drop(ctx.file);
+        match fs::remove_file(&ctx.file_path).and_then(|_| self.sync_dir(&ctx.file_path)) {
+            Ok(()) => self.transition(&ctx.file_path, FileLifecycle::Expired),
+            Err(e) => error!("{}", e),
+        }
+    }
+
+    fn spawn_sweeper(self: &Arc<Self>, max_age: Duration) -> Result<JoinHandle<()>, Error> {
+        let every = (max_age / 10).clamp(Duration::from_secs(1), Duration::from_secs(3600));
+        let inner = Arc::downgrade(self);
+        let builder = std::thread::Builder::new().name(self.thread_name("sweep"));
+        builder.spawn(move || {
+            while let Some(inner) = inner.upgrade() {
+                if inner.threads.stopped.load(Ordering::Acquire) {
+                    break;
+                }
+                if let Err(e) = inner.sweep(max_age) {
+                    error!("Failed to sweep {}: {}", inner.root_dir(), e);
+                }
+                drop(inner);
+                std::thread::park_timeout(every);
+            }
+        })
+    }
+
+    /// Flushes lines of the active file waiting for `max_delay` or longer.
+    fn spawn_coalescer(self: &Arc<Self>, max_delay: Duration) -> Result<JoinHandle<()>, Error> {
+        let every = max_delay.max(Duration::from_millis(1));
+        let inner = Arc::downgrade(self);
+        let builder = std::thread::Builder::new().name(self.thread_name("coalesce"));
+        builder.spawn(move || {
+            while let Some(inner) = inner.upgrade() {
+                if inner.threads.stopped.load(Ordering::Acquire) {
+                    break;
+                }
+                let mut guard = inner.context.lock().unwrap();
+                if let Some(ctx) = guard.current.as_mut() {
+                    if let Err(e) = ctx.flush_if_older(max_delay) {
+                        error!("Failed to flush {}: {}", ctx.file_path.display(), e);
+                    }
+                }
+                drop(guard);
+                drop(inner);
+                std::thread::park_timeout(every);
+            }
+        })
+    }
+
+    /// Rotates the active file out if nothing was written to it for `idle`.
+    fn rotate_if_idle(self: &Arc<Self>, idle: Duration) -> Result<(), Error> {
+        let now = self.now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let idle = idle.as_secs().max(1);
+        let mut guard = self.context.lock().unwrap();
+        if guard.opening {
+            return Ok(());
+        }
+        let old = guard
+            .current
+            .take_if(|ctx| ctx.first_write.is_some() && now.saturating_sub(ctx.last_write) >= idle);
+        drop(guard);
+        match old {
+            Some(old) => self.finalize(old, RotationTrigger::Idle).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    fn spawn_idler(self: &Arc<Self>, idle: Duration) -> Result<JoinHandle<()>, Error> {
+        let every = (idle / 4).clamp(Duration::from_millis(100), Duration::from_secs(60));
+        let inner = Arc::downgrade(self);
+        let builder = std::thread::Builder::new().name(self.thread_name("idle"));
+        builder.spawn(move || {
+            while let Some(inner) = inner.upgrade() {
+                if inner.threads.stopped.load(Ordering::Acquire) {
+                    break;
+                }
+                if let Err(e) = inner.rotate_if_idle(idle) {
+                    error!("Failed to rotate idle file: {}", e);
+                }
+                drop(inner);
+                std::thread::park_timeout(every);
+            }
+        })
+    }
+}
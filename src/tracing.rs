@@ -0,0 +1,222 @@
+//! A non-blocking writer for `tracing-subscriber`, mirroring
+//! `tracing_appender::non_blocking`.
+//!
+//! ## Example
+//!
+//! ```
+//! use rotating_file::RotatingFile;
+//!
+//! let rotating_file = RotatingFile::new("./target/tmp-tracing", Some(1024), None, None, None, None, None);
+//! let (writer, _guard) = rotating_file::tracing::non_blocking(rotating_file);
+//! let subscriber = tracing_subscriber::fmt().with_writer(writer).finish();
+//! # drop(subscriber);
+//! # drop(_guard);
+//! # std::fs::remove_dir_all("./target/tmp-tracing").unwrap();
+//! ```
+use std::cell::Cell;
+use std::io::{Error, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::writer::BufferBudget;
+use crate::{RotatingFile, Sanitizer};
+
+enum Msg {
+    Write(Vec<u8>),
+    Shutdown,
+}
+
+thread_local! {
+    // set on worker threads, whose own events are discarded
+    static IN_WORKER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A writer that sends formatted events to a worker thread, which writes
+/// them to the rotating file.
+#[derive(Clone)]
+pub struct NonBlocking {
+    sender: Sender<Msg>,
+    // bytes queued for the worker
+    budget: Arc<BufferBudget>,
+}
+
+/// Flushes and closes the rotating file when dropped.
+///
+/// Events emitted after the guard is dropped are discarded, so keep it alive
+/// for as long as the subscriber is in use, e.g. in `main`.
+#[must_use]
+pub struct WorkerGuard {
+    sender: Sender<Msg>,
+    worker: Option<JoinHandle<()>>,
+    errors: Arc<AtomicU64>,
+}
+
+impl WorkerGuard {
+    /// Returns how many events the worker failed to write.
+    ///
+    /// Failures are also printed to stderr, never logged, since the logger
+    /// may feed this very writer, e.g. through a `tracing-log` bridge, and
+    /// the worker would wait on the queue only it drains. For the same
+    /// reason, events emitted on the worker thread, e.g. logs of the rotating
+    /// file itself, are discarded.
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+}
+
+/// Moves `rotating_file` to a worker thread and returns a writer for it,
+/// along with a guard that shuts the worker down when dropped.
+///
+/// Invalid UTF-8 in events is replaced with `U+FFFD`. The queue is bounded by
+/// `RotatingFile::with_max_buffered_bytes`.
+pub fn non_blocking(rotating_file: RotatingFile) -> (NonBlocking, WorkerGuard) {
+    non_blocking_with(rotating_file, Sanitizer::default())
+}
+
+/// Like `non_blocking`, but events are passed through `sanitizer` first,
+/// e.g. to strip the colors of an ANSI-enabled formatter.
+pub fn non_blocking_with(
+    rotating_file: RotatingFile,
+    sanitizer: Sanitizer,
+) -> (NonBlocking, WorkerGuard) {
+    let (sender, receiver) = channel();
+    let budget = Arc::new(rotating_file.buffer_budget());
+    let worker_budget = budget.clone();
+    let errors = Arc::new(AtomicU64::new(0));
+    let worker_errors = errors.clone();
+    let worker = std::thread::Builder::new()
+        .name("rotfile-tracing".to_string())
+        .spawn(move || {
+            IN_WORKER.with(|in_worker| in_worker.set(true));
+            let fail = |e: &dyn std::fmt::Display| {
+                worker_errors.fetch_add(1, Ordering::Relaxed);
+                eprintln!("rotating-file: failed to write an event: {}", e);
+            };
+            while let Ok(Msg::Write(buf)) = receiver.recv() {
+                worker_budget.release(buf.len());
+                let s = match sanitizer.sanitize(&buf) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        fail(&e);
+                        continue;
+                    }
+                };
+                // events are formatted with a trailing newline
+                for line in s.strip_suffix('\n').unwrap_or(&s).split('\n') {
+                    if let Err(e) = rotating_file.writeln(line) {
+                        fail(&e);
+                    }
+                }
+            }
+            worker_budget.close();
+            rotating_file.close();
+        })
+        .unwrap();
+
+    (
+        NonBlocking {
+            sender: sender.clone(),
+            budget,
+        },
+        WorkerGuard {
+            sender,
+            worker: Some(worker),
+            errors,
+        },
+    )
+}
+
+impl Write for NonBlocking {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if IN_WORKER.with(Cell::get) {
+            return Ok(buf.len());
+        }
+        if self.budget.reserve(buf.len())? {
+            // the worker is gone once the guard is dropped
+            let _ = self.sender.send(Msg::Write(buf.to_vec()));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for NonBlocking {
+    type Writer = NonBlocking;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Msg::Shutdown);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    #[test]
+    fn non_blocking() {
+        let root_dir = "./target/tmp15";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = crate::RotatingFile::new(root_dir, None, None, None, None, None, None);
+        let rx = rotating_file.watch_current_path();
+
+        let (mut writer, guard) = super::non_blocking(rotating_file);
+        writer.write_all(b"first event\n").unwrap();
+        writer.write_all(b"second event\nwith two lines\n").unwrap();
+        drop(guard);
+
+        assert_eq!(
+            "first event\nsecond event\nwith two lines\n",
+            std::fs::read_to_string(rx.recv().unwrap()).unwrap()
+        );
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn worker_errors() {
+        let root_dir = "./target/tmp110";
+        let _ = std::fs::remove_dir_all(root_dir);
+        let rotating_file = crate::RotatingFile::new(root_dir, None, None, None, None, None, None);
+        let rx = rotating_file.watch_current_path();
+        let sanitizer = crate::Sanitizer {
+            utf8: crate::Utf8Policy::Validate,
+            ..Default::default()
+        };
+
+        let (mut writer, guard) = super::non_blocking_with(rotating_file, sanitizer);
+        writer.write_all(b"invalid \xff\n").unwrap();
+        writer.write_all(b"valid\n").unwrap();
+        // events of the worker itself are discarded rather than queued
+        std::thread::spawn(move || {
+            super::IN_WORKER.with(|in_worker| in_worker.set(true));
+            writer.write_all(b"from the worker\n").unwrap();
+        })
+        .join()
+        .unwrap();
+        let errors = guard.errors.clone();
+        drop(guard);
+        assert_eq!(1, errors.load(std::sync::atomic::Ordering::Relaxed));
+        assert_eq!(
+            "valid\n",
+            std::fs::read_to_string(rx.recv().unwrap()).unwrap()
+        );
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+}
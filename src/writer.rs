@@ -0,0 +1,1298 @@
+//! The active file: where its bytes go, how lines are formatted, and how
+//! writing is reopened, relocated, backfilled or aborted.
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufWriter, Error, ErrorKind, Read, Write};
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+#[cfg(unix)]
+use std::sync::atomic::AtomicI32;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use log::*;
+
+use crate::compress::{Compression, CompressionLevel};
+use crate::index::{self, BloomFilter, LineIndex};
+use crate::policy::{FileStats, RotationTrigger};
+use crate::{poisoned, reader, try_lock, Inner, RotatingFile, RotatingFileError};
+
+/// An open file the active file's bytes are written to.
+pub trait Sink: Write + Send {
+    /// Flushes written data to stable storage.
+    fn sync(&mut self) -> Result<(), Error>;
+}
+
+impl Sink for fs::File {
+    fn sync(&mut self) -> Result<(), Error> {
+        self.sync_all()
+    }
+}
+
+/// Creates the files written to.
+pub trait Backend: Send + Sync {
+    /// Creates a new file at `path`, failing with `ErrorKind::AlreadyExists`
+    /// if there is one already.
+    fn create(&self, path: &Path) -> Result<Box<dyn Sink>, Error>;
+
+    /// Creates a new file like `create`, but whose writes reach stable
+    /// storage by the time it's flushed, see
+    /// `RotatingFile::with_write_through`.
+    ///
+    /// Defaults to syncing the file created by `create` on each flush.
+    fn create_write_through(&self, path: &Path) -> Result<Box<dyn Sink>, Error> {
+        Ok(Box::new(SyncingSink(self.create(path)?)))
+    }
+}
+
+/// Writes to files on the local file system.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FileBackend {
+    /// Open files with `O_DSYNC`, or `FILE_FLAG_WRITE_THROUGH` on Windows, so
+    /// that each write reaches stable storage before it returns, even
+    /// without `RotatingFile::with_write_through`
+    pub write_through: bool,
+}
+
+impl Backend for FileBackend {
+    fn create(&self, path: &Path) -> Result<Box<dyn Sink>, Error> {
+        FileBackend::open(path, self.write_through)
+    }
+
+    fn create_write_through(&self, path: &Path) -> Result<Box<dyn Sink>, Error> {
+        FileBackend::open(path, true)
+    }
+}
+
+impl FileBackend {
+    fn open(path: &Path, write_through: bool) -> Result<Box<dyn Sink>, Error> {
+        let mut options = fs::OpenOptions::new();
+        // std opens files with every share mode on Windows, so the active
+        // file can be renamed or deleted while it's open, as on unix
+        options.append(true).create_new(true);
+        if write_through {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.custom_flags(libc::O_DSYNC);
+            }
+            #[cfg(windows)]
+            {
+                use std::os::windows::fs::OpenOptionsExt;
+                const FILE_FLAG_WRITE_THROUGH: u32 = 0x80000000;
+                options.custom_flags(FILE_FLAG_WRITE_THROUGH);
+            }
+        }
+        Ok(Box::new(options.open(path)?))
+    }
+}
+
+/// Discards everything written, e.g. to benchmark formatting and the
+/// overhead of this crate without the cost of the disk.
+///
+/// Files are still created, empty, so that naming, rotation, compression and
+/// events work as usual.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct NullBackend;
+
+impl Backend for NullBackend {
+    fn create(&self, path: &Path) -> Result<Box<dyn Sink>, Error> {
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        Ok(Box::new(NullSink))
+    }
+}
+
+struct NullSink;
+
+impl Write for NullSink {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Sink for NullSink {
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+// syncs on each flush, for backends without a write-through mode
+struct SyncingSink(Box<dyn Sink>);
+
+impl Write for SyncingSink {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.0.flush()?;
+        self.0.sync()
+    }
+}
+
+impl Sink for SyncingSink {
+    fn sync(&mut self) -> Result<(), Error> {
+        self.0.sync()
+    }
+}
+
+// counts the bytes that reach the underlying sink
+struct CountingSink {
+    inner: Box<dyn Sink>,
+    written: Arc<AtomicU64>,
+}
+
+impl Write for CountingSink {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let n = self.inner.write(buf)?;
+        self.written.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+enum Encoder {
+    GZip(flate2::write::GzEncoder<CountingSink>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::stream::write::Encoder<'static, CountingSink>),
+}
+
+impl Encoder {
+    fn new(sink: CountingSink, compression: &CompressionLevel) -> Result<Self, Error> {
+        match compression.compression {
+            Compression::GZip => Ok(Encoder::GZip(flate2::write::GzEncoder::new(
+                sink,
+                flate2::Compression::new(compression.level.clamp(0, 9) as u32),
+            ))),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Encoder::Zstd(zstd::stream::write::Encoder::new(
+                sink,
+                compression.level,
+            )?)),
+            #[allow(unreachable_patterns)]
+            _ => Err(Error::new(
+                ErrorKind::Unsupported,
+                "only gzip and zstd can be streamed",
+            )),
+        }
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::GZip(encoder) => encoder,
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder,
+        }
+    }
+
+    fn sink(&mut self) -> &mut CountingSink {
+        match self {
+            Encoder::GZip(encoder) => encoder.get_mut(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.get_mut(),
+        }
+    }
+
+    /// Ends the stream in place, so that it can be retried if it fails.
+    fn try_finish(&mut self) -> Result<(), Error> {
+        match self {
+            Encoder::GZip(encoder) => encoder.try_finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.do_finish(),
+        }
+    }
+
+    fn finish(self) -> Result<CountingSink, Error> {
+        match self {
+            Encoder::GZip(encoder) => encoder.finish(),
+            #[cfg(feature = "zstd")]
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Compresses what is written to it as it goes. Each flush ends a gzip
+/// member or zstd frame, so that the file is complete after it and readers
+/// decode the members one after the other.
+struct CompressingSink {
+    // `None` if the next frame couldn't be started
+    encoder: Option<Encoder>,
+    compression: CompressionLevel,
+    // bytes written since the last frame ended
+    dirty: bool,
+    // a frame is being ended, retried before anything else is written to
+    // it if it failed
+    ending: bool,
+}
+
+impl CompressingSink {
+    fn encoder(&mut self) -> Result<&mut Encoder, Error> {
+        self.encoder.as_mut().ok_or_else(|| {
+            Error::new(
+                ErrorKind::BrokenPipe,
+                "the next compressed frame couldn't be started",
+            )
+        })
+    }
+
+    /// Ends the current frame and starts the next one, leaving the frame
+    /// being ended in place if it fails.
+    fn end_frame(&mut self) -> Result<(), Error> {
+        self.ending = true;
+        let encoder = self.encoder()?;
+        encoder.try_finish()?;
+        encoder.sink().flush()?;
+        if let Some(encoder) = self.encoder.take() {
+            let sink = encoder.finish()?;
+            self.encoder = Some(Encoder::new(sink, &self.compression)?);
+        }
+        self.ending = false;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Write for CompressingSink {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if self.ending {
+            self.end_frame()?;
+        }
+        let n = self.encoder()?.writer().write(buf)?;
+        self.dirty |= n > 0;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if !self.dirty && !self.ending {
+            return Ok(());
+        }
+        self.end_frame()
+    }
+}
+
+impl Sink for CompressingSink {
+    fn sync(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        self.encoder()?.sink().inner.sync()
+    }
+}
+
+/// Wraps `sink` to compress the bytes written to it, returns the wrapper and
+/// the count of compressed bytes written to `sink`.
+pub(crate) fn compressing(
+    sink: Box<dyn Sink>,
+    compression: &CompressionLevel,
+) -> Result<(Box<dyn Sink>, Arc<AtomicU64>), Error> {
+    let written = Arc::new(AtomicU64::new(0));
+    let sink = CountingSink {
+        inner: sink,
+        written: written.clone(),
+    };
+    let encoder = Encoder::new(sink, compression)?;
+    let sink = CompressingSink {
+        encoder: Some(encoder),
+        compression: *compression,
+        dirty: false,
+        ending: false,
+    };
+    Ok((Box::new(sink), written))
+}
+
+pub(crate) struct CurrentContext {
+    pub(crate) file: BufWriter<Box<dyn Sink>>,
+    pub(crate) file_path: PathBuf,
+    pub(crate) timestamp: u64,
+    // start(in milliseconds since epoch) of the bucket of the file
+    pub(crate) bucket: u64,
+    pub(crate) total_written: usize,
+    pub(crate) lines: u64,
+    // seconds since epoch of the first and the last write
+    pub(crate) first_write: Option<u64>,
+    pub(crate) last_write: u64,
+    // rotation group generation the file was created in
+    pub(crate) generation: u64,
+    // when the file became the active one
+    pub(crate) activated: Instant,
+    // compressed bytes on disk if compressed as written
+    pub(crate) disk_written: Option<Arc<AtomicU64>>,
+    // tokens written, if indexed
+    pub(crate) bloom: Option<BloomFilter>,
+    // offsets of lines written, if indexed
+    pub(crate) line_index: Option<LineIndex>,
+    // when the oldest byte still buffered was written, if any
+    pub(crate) buffered_since: Option<Instant>,
+    // opened read-only to be synced by `AbortHandle::abort`, if it could be
+    #[cfg(unix)]
+    pub(crate) sync_file: Option<fs::File>,
+}
+
+impl CurrentContext {
+    pub(crate) fn new(
+        file: Box<dyn Sink>,
+        file_path: PathBuf,
+        timestamp: u64,
+        capacity: usize,
+    ) -> Self {
+        CurrentContext {
+            file: BufWriter::with_capacity(capacity, file),
+            file_path,
+            timestamp,
+            bucket: timestamp * 1000,
+            total_written: 0,
+            lines: 0,
+            first_write: None,
+            last_write: timestamp,
+            generation: 0,
+            activated: Instant::now(),
+            disk_written: None,
+            bloom: None,
+            line_index: None,
+            buffered_since: None,
+            #[cfg(unix)]
+            sync_file: None,
+        }
+    }
+
+    pub(crate) fn write(&mut self, buf: &[u8], now: u64) -> Result<(), Error> {
+        self.file.write_all(buf)?;
+        self.total_written += buf.len();
+        self.lines += buf.iter().filter(|&&b| b == b'\n').count() as u64;
+        if let Some(bloom) = self.bloom.as_mut() {
+            bloom.insert_tokens(buf);
+        }
+        if let Some(line_index) = self.line_index.as_mut() {
+            line_index.insert(buf, now);
+        }
+        self.first_write.get_or_insert(now);
+        self.last_write = now;
+        if self.file.buffer().is_empty() {
+            self.buffered_since = None;
+        } else {
+            self.buffered_since.get_or_insert_with(Instant::now);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<(), Error> {
+        self.file.flush()?;
+        self.buffered_since = None;
+        self.file.get_mut().sync()
+    }
+
+    /// Hands the buffered bytes to the OS, without syncing, if the oldest
+    /// has waited for `max_delay`.
+    pub(crate) fn flush_if_older(&mut self, max_delay: Duration) -> Result<(), Error> {
+        if self
+            .buffered_since
+            .is_some_and(|since| since.elapsed() >= max_delay)
+        {
+            self.file.flush()?;
+            self.buffered_since = None;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn stats(&self, next_write: usize) -> FileStats<'_> {
+        FileStats {
+            path: self.file_path.as_path(),
+            timestamp: self.timestamp,
+            written: self
+                .disk_written
+                .as_ref()
+                .map_or(self.total_written as u64, |written| {
+                    written.load(Ordering::Relaxed)
+                }),
+            lines: self.lines,
+            first_write: self.first_write,
+            next_write: next_write as u64,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct ContextState {
+    // `None` until the first write and while the next file is being created
+    pub(crate) current: Option<CurrentContext>,
+    // lines written while the next file is being created outside the lock
+    pub(crate) pending: Vec<u8>,
+    pub(crate) opening: bool,
+    // how many times creating the next file completed, and the error of
+    // the last time, for writers waiting on it with write-through
+    pub(crate) openings: u64,
+    pub(crate) open_error: Option<(ErrorKind, String)>,
+    // sequence number of the next line
+    pub(crate) sequence: u64,
+    // when to check next whether `root_dir` is writable again
+    pub(crate) probe_at: u64,
+}
+
+/// What to do when an in-memory buffer reaches `max_buffered_bytes`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until the buffer has drained enough.
+    Block,
+    /// Drop the write, counted by `RotatingFile::dropped_bytes`.
+    Drop,
+    /// Fail the write with `ErrorKind::OutOfMemory`.
+    Error,
+}
+
+pub(crate) fn overflow_error(max: usize) -> Error {
+    Error::new(
+        ErrorKind::OutOfMemory,
+        format!("buffer limit of {} bytes reached", max),
+    )
+}
+
+/// Bytes held by a buffer outside the lock of the active file, bounded by
+/// `max_buffered_bytes`.
+pub(crate) struct BufferBudget {
+    limit: Option<(usize, OverflowPolicy)>,
+    // bytes in use and whether the consumer has gone away
+    used: Mutex<(usize, bool)>,
+    released: Condvar,
+    dropped: Arc<AtomicU64>,
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+impl BufferBudget {
+    pub(crate) fn new(limit: Option<(usize, OverflowPolicy)>, dropped: Arc<AtomicU64>) -> Self {
+        BufferBudget {
+            limit,
+            used: Mutex::new((0, false)),
+            released: Condvar::new(),
+            dropped,
+        }
+    }
+
+    /// Returns whether `n` more bytes may be buffered, waiting or failing as
+    /// configured if not. A single write larger than the limit is let in
+    /// once the buffer is empty.
+    pub(crate) fn reserve(&self, n: usize) -> Result<bool, Error> {
+        let Some((max, policy)) = self.limit else {
+            return Ok(true);
+        };
+        let mut used = self.used.lock().unwrap();
+        while !used.1 && used.0 > 0 && used.0 + n > max {
+            match policy {
+                OverflowPolicy::Block => used = self.released.wait(used).unwrap(),
+                OverflowPolicy::Drop => {
+                    self.dropped.fetch_add(n as u64, Ordering::Relaxed);
+                    return Ok(false);
+                }
+                OverflowPolicy::Error => return Err(overflow_error(max)),
+            }
+        }
+        used.0 += n;
+        Ok(true)
+    }
+
+    pub(crate) fn release(&self, n: usize) {
+        if self.limit.is_some() {
+            self.used.lock().unwrap().0 -= n;
+            self.released.notify_all();
+        }
+    }
+
+    /// Lets every waiting and future write in, once nothing drains the
+    /// buffer anymore.
+    pub(crate) fn close(&self) {
+        self.used.lock().unwrap().1 = true;
+        self.released.notify_all();
+    }
+}
+
+/// What to do with newlines embedded in a line passed to `writeln`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum NewlinePolicy {
+    /// Write them as they are, splitting the line.
+    #[default]
+    Keep,
+    /// Escape `\n` and `\r` as in JSON, along with backslashes so that the
+    /// line can be unescaped.
+    Escape,
+    /// Replace `\n` and `\r` with spaces.
+    Replace,
+    /// Fail with `ErrorKind::InvalidInput`.
+    Reject,
+}
+
+impl NewlinePolicy {
+    pub(crate) fn apply<'a>(&self, s: &'a str) -> Result<Cow<'a, str>, Error> {
+        // backslashes are escaped even without newlines to stay reversible
+        let special: &[char] = match self {
+            NewlinePolicy::Escape => &['\\', '\n', '\r'],
+            _ => &['\n', '\r'],
+        };
+        if !s.contains(special) {
+            return Ok(Cow::Borrowed(s));
+        }
+        match self {
+            NewlinePolicy::Keep => Ok(Cow::Borrowed(s)),
+            NewlinePolicy::Escape => Ok(Cow::Owned(
+                s.replace('\\', "\\\\")
+                    .replace('\n', "\\n")
+                    .replace('\r', "\\r"),
+            )),
+            NewlinePolicy::Replace => Ok(Cow::Owned(s.replace(['\n', '\r'], " "))),
+            NewlinePolicy::Reject => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "line contains a newline",
+            )),
+        }
+    }
+}
+
+/// What a `Sanitizer` does with bytes that aren't valid UTF-8.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Utf8Policy {
+    /// Replace invalid sequences with `U+FFFD`.
+    #[default]
+    Lossy,
+    /// Fail with `ErrorKind::InvalidData`.
+    Validate,
+}
+
+/// Turns raw bytes into text that is safe to open in standard tools.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sanitizer {
+    /// What to do with invalid UTF-8, default to `Lossy`
+    pub utf8: Utf8Policy,
+    /// Strip ANSI escape sequences, e.g. colors, default to false
+    pub strip_ansi: bool,
+}
+
+impl Sanitizer {
+    /// Converts `bytes` to text as configured.
+    pub fn sanitize<'a>(&self, bytes: &'a [u8]) -> Result<Cow<'a, str>, Error> {
+        let s = match self.utf8 {
+            Utf8Policy::Lossy => String::from_utf8_lossy(bytes),
+            Utf8Policy::Validate => Cow::Borrowed(
+                std::str::from_utf8(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+            ),
+        };
+        if !self.strip_ansi {
+            return Ok(s);
+        }
+        let stripped = match strip_ansi(&s) {
+            Cow::Owned(stripped) => Some(stripped),
+            Cow::Borrowed(_) => None,
+        };
+        Ok(stripped.map_or(s, Cow::Owned))
+    }
+}
+
+/// Removes ANSI escape sequences, e.g. colors and cursor movements, from `s`.
+pub fn strip_ansi(s: &str) -> Cow<'_, str> {
+    if !s.contains(['\x1b', '\u{9b}']) {
+        return Cow::Borrowed(s);
+    }
+    let mut stripped = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => match chars.next() {
+                Some('[') => skip_csi(&mut chars),
+                Some(']') => skip_osc(&mut chars),
+                // a two-character sequence
+                _ => {}
+            },
+            '\u{9b}' => skip_csi(&mut chars),
+            c => stripped.push(c),
+        }
+    }
+    Cow::Owned(stripped)
+}
+
+// parameters and intermediates up to the final character
+fn skip_csi(chars: &mut Peekable<Chars>) {
+    for c in chars.by_ref() {
+        if ('\x40'..='\x7e').contains(&c) {
+            break;
+        }
+    }
+}
+
+// up to BEL or ST, i.e. `ESC \`
+fn skip_osc(chars: &mut Peekable<Chars>) {
+    while let Some(c) = chars.next() {
+        match c {
+            '\x07' => break,
+            '\x1b' => {
+                chars.next_if_eq(&'\\');
+                break;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// How a line passed to `writeln` is written to the file.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LineFormat {
+    /// What to do with embedded newlines
+    pub(crate) newlines: NewlinePolicy,
+    /// Strip ANSI escape sequences
+    pub(crate) strip_ansi: bool,
+    /// Prefix each line with a sequence number
+    pub(crate) sequence_numbers: bool,
+    /// strftime format of a timestamp prepended to each line
+    pub(crate) prepend_timestamp: Option<String>,
+    /// Append a CRC32 of each line
+    pub(crate) checksums: bool,
+}
+
+impl LineFormat {
+    /// Strips ANSI escape sequences and applies the newline policy.
+    pub(crate) fn clean<'a>(&self, s: &'a str) -> Result<Cow<'a, str>, Error> {
+        let s = if self.strip_ansi {
+            strip_ansi(s)
+        } else {
+            Cow::Borrowed(s)
+        };
+        let applied = match self.newlines.apply(&s)? {
+            Cow::Owned(applied) => Some(applied),
+            Cow::Borrowed(_) => None,
+        };
+        Ok(applied.map_or(s, Cow::Owned))
+    }
+
+    /// Formats a line as written to the file, including the trailing newline.
+    pub(crate) fn format(&self, s: &str, now: SystemTime, sequence: u64) -> String {
+        let mut line = String::with_capacity(s.len() + 1);
+        if self.sequence_numbers {
+            line.push_str(&sequence.to_string());
+            line.push(' ');
+        }
+        if let Some(format) = self.prepend_timestamp.as_ref() {
+            let dt: DateTime<Utc> = now.into();
+            line.push_str(&dt.format(format).to_string());
+            line.push(' ');
+        }
+        line.push_str(s);
+        if self.checksums {
+            let crc = crc32fast::hash(line.as_bytes());
+            line.push_str(&format!(" {:08x}", crc));
+        }
+        line.push('\n');
+        line
+    }
+}
+
+/// Checks the CRC32 appended to a line written with checksums, returns the
+/// line without it, or `None` if it's missing or doesn't match, e.g. for a
+/// line torn by a crash.
+///
+/// `line` must not include the trailing newline.
+pub fn verify_checksum(line: &str) -> Option<&str> {
+    let (content, crc) = line.rsplit_once(' ')?;
+    if crc.len() != 8 {
+        return None;
+    }
+    let crc = u32::from_str_radix(crc, 16).ok()?;
+    (crc32fast::hash(content.as_bytes()) == crc).then_some(content)
+}
+
+/// Syncs the directory containing `path`, so that the creation, rename or
+/// deletion of `path` survives a power loss. A no-op on Windows, where
+/// directories can't be opened as files.
+pub(crate) fn sync_parent(path: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        fs::File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Makes the file at `path` read-only, and immutable on Linux where the
+/// process is allowed to, i.e. has `CAP_LINUX_IMMUTABLE`.
+pub(crate) fn seal(path: &Path) -> Result<(), Error> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)?;
+    // most processes aren't allowed to, and not every file system supports it
+    #[cfg(target_os = "linux")]
+    let _ = set_immutable(path, true);
+    Ok(())
+}
+
+/// Undoes what `seal` needs undone so that the file at `path` can be deleted.
+pub(crate) fn unseal(path: &Path) -> Result<(), Error> {
+    #[cfg(target_os = "linux")]
+    let _ = set_immutable(path, false);
+    // read-only files can't be deleted on Windows
+    #[cfg(windows)]
+    {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(false);
+        fs::set_permissions(path, permissions)?;
+    }
+    #[cfg(not(windows))]
+    let _ = path;
+    Ok(())
+}
+
+/// Creates `link` pointing at `target`, a symlink on Unix and a hard link
+/// elsewhere, where symlinks need extra privileges.
+pub(crate) fn link(target: &Path, link: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, link);
+    #[cfg(not(unix))]
+    return fs::hard_link(target, link);
+}
+
+/// Moves the file at `from` to `to`, which must not exist, falling back to
+/// `copy_and_delete` if they're on different filesystems.
+pub(crate) fn move_file(from: &Path, to: &Path) -> Result<(), Error> {
+    if to.exists() {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists", to.display()),
+        ));
+    }
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => copy_and_delete(from, to),
+        result => result,
+    }
+}
+
+/// Copies the file at `from` to `to` along with its modified time and
+/// permissions, syncs the copy and its directory, reads it back to verify
+/// its CRC32, then deletes `from`, so that a crash or a faulty copy leaves
+/// the file in both places rather than in neither.
+fn copy_and_delete(from: &Path, to: &Path) -> Result<(), Error> {
+    let mut input = fs::File::open(from)?;
+    let metadata = input.metadata()?;
+    let mut output = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)?;
+    let copied = copy_with_checksum(&mut input, &mut output)
+        .and_then(|crc| {
+            output.set_modified(metadata.modified()?)?;
+            output.sync_all()?;
+            Ok(crc)
+        })
+        .and_then(|crc| match crate::pipeline::checksum(to)? {
+            copied if copied == crc => Ok(()),
+            copied => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "checksum mismatch copying {} to {}: {:08x} != {:08x}",
+                    from.display(),
+                    to.display(),
+                    copied,
+                    crc
+                ),
+            )),
+        });
+    if let Err(e) = copied {
+        drop(output);
+        let _ = fs::remove_file(to);
+        return Err(e);
+    }
+    output.set_permissions(metadata.permissions())?;
+    sync_parent(to)?;
+    fs::remove_file(from)?;
+    sync_parent(from)
+}
+
+/// Copies `input` to `output`, returns the CRC32 of the bytes copied.
+fn copy_with_checksum(input: &mut fs::File, output: &mut fs::File) -> Result<u32, Error> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = input.read(&mut buf)?;
+        if n == 0 {
+            return Ok(hasher.finalize());
+        }
+        hasher.update(&buf[..n]);
+        output.write_all(&buf[..n])?;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_immutable(path: &Path, immutable: bool) -> Result<(), Error> {
+    use std::os::unix::io::AsRawFd;
+    const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+
+    let file = fs::File::open(path)?;
+    let mut flags: libc::c_int = 0;
+    // SAFETY: both ioctls take a pointer to an int holding the flags
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    let updated = if immutable {
+        flags | FS_IMMUTABLE_FL
+    } else {
+        flags & !FS_IMMUTABLE_FL
+    };
+    if updated != flags
+        && unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &updated) } < 0
+    {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Truncates a torn final line, i.e. one without a trailing newline or, with
+/// `checksums`, failing its CRC32, returns the number of bytes discarded.
+pub(crate) fn trim_torn_tail(path: &Path, checksums: bool) -> Result<u64, Error> {
+    let content = fs::read(path)?;
+    let Some(&last) = content.last() else {
+        return Ok(0);
+    };
+    // start of the last line, ignoring its newline if any
+    let body_end = content.len() - usize::from(last == b'\n');
+    let start = content[..body_end]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    let torn = last != b'\n'
+        || (checksums
+            && std::str::from_utf8(&content[start..body_end])
+                .ok()
+                .and_then(verify_checksum)
+                .is_none());
+    if !torn {
+        return Ok(0);
+    }
+
+    let file = fs::OpenOptions::new().write(true).open(path)?;
+    file.set_len(start as u64)?;
+    file.sync_all()?;
+    Ok((content.len() - start) as u64)
+}
+
+/// Files of past buckets written by `writeln_at`, see
+/// `RotatingFile::with_backfill_buckets`.
+#[derive(Default)]
+pub(crate) struct Backfill {
+    // by bucket
+    pub(crate) files: Mutex<BTreeMap<u64, CurrentContext>>,
+    // max number of files, 0 disables backfilling
+    pub(crate) max: usize,
+}
+
+impl Inner {
+    /// Fails once the instance was aborted.
+    pub(crate) fn check_aborted(&self) -> Result<(), Error> {
+        if self.abort.aborted.load(Ordering::Acquire) {
+            Err(Error::new(ErrorKind::BrokenPipe, "aborted"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Rotates the active file out, or closes it if it was moved away.
+    pub(crate) fn reopen(self: &Arc<Self>) -> Result<(), Error> {
+        let mut guard = self.context.lock().map_err(poisoned)?;
+        while guard.opening {
+            guard = self.opened.wait(guard).map_err(poisoned)?;
+        }
+        let Some(mut old) = guard.current.take() else {
+            return Ok(());
+        };
+        drop(guard);
+        if old.file_path.exists() {
+            self.finalize(old, RotationTrigger::External).map(|_| ())
+        } else {
+            info!("{} was moved away, reopening", old.file_path.display());
+            old.flush()
+        }
+    }
+
+    pub(crate) fn relocate(self: &Arc<Self>, new_root: &Path, migrate: bool) -> Result<(), Error> {
+        self.check_aborted()?;
+        fs::create_dir_all(new_root)?;
+        let new_root = new_root
+            .to_str()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "new_root is not UTF-8"))?
+            .to_string();
+
+        // switch under the context lock, so that no write opens a file in
+        // between
+        let mut guard = self.context.lock().map_err(poisoned)?;
+        while guard.opening {
+            guard = self.opened.wait(guard).map_err(poisoned)?;
+        }
+        let old = guard.current.take();
+        let mut root_dir = self.root_dir.write().map_err(poisoned)?;
+        let old_root = std::mem::replace(&mut *root_dir, new_root.clone());
+        drop(root_dir);
+        drop(guard);
+        info!("Relocating {} to {}", old_root, new_root);
+
+        if let Some(unused) = self.next.lock().map_err(poisoned)?.take() {
+            self.discard(unused);
+        }
+        if let Some(old) = old {
+            self.finalize(old, RotationTrigger::External)?;
+        }
+        let backfilled = std::mem::take(&mut *self.backfill.files.lock().map_err(poisoned)?);
+        for (_, ctx) in backfilled {
+            self.finalize(ctx, RotationTrigger::External)?;
+        }
+        // compression writes next to the raw file, i.e. in the old root
+        let handles = std::mem::take(&mut *self.handles.lock().map_err(poisoned)?);
+        for handle in handles {
+            match handle.join() {
+                Ok(Err(e)) => error!("{}", RotatingFileError::Compression(e)),
+                Err(_) => error!("{}", RotatingFileError::Poisoned),
+                Ok(Ok(())) => {}
+            }
+        }
+
+        self.manifest.relocate(&new_root)?;
+        if let Some(journal) = self.journal.as_ref() {
+            journal.relocate(&new_root)?;
+        }
+        self.sequence.relocate(&new_root)?;
+        if migrate {
+            for file in reader::files(&old_root, &self.naming)? {
+                let moved = Path::new(&new_root).join(file.file_name().unwrap());
+                move_file(&file, &moved)?;
+                for sidecar in [index::sidecar_path, index::line_index_path] {
+                    if sidecar(&file).exists() {
+                        move_file(&sidecar(&file), &sidecar(&moved))?;
+                    }
+                }
+                self.sync_dir(&moved)?;
+                self.sync_dir(&file)?;
+                let mut raw_sizes = self.raw_sizes.lock().map_err(poisoned)?;
+                if let Some(size) = raw_sizes.remove(&file) {
+                    raw_sizes.insert(moved, size);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `line` to the file of the past bucket `bucket`, written at
+    /// `at`, rotating it or finalizing the oldest file of another bucket as
+    /// needed.
+    pub(crate) fn backfill(
+        self: &Arc<Self>,
+        bucket: u64,
+        line: &[u8],
+        at: SystemTime,
+    ) -> Result<(), Error> {
+        let mut backfill = self.backfill.files.lock().unwrap();
+        let mut finalized = Vec::new();
+        if let Some(ctx) = backfill.get(&bucket) {
+            let stats = ctx.stats(line.len());
+            let policy = self.policies.iter().find(|policy| {
+                policy.trigger() != RotationTrigger::Interval && policy.should_rotate(&stats, at)
+            });
+            if let Some(policy) = policy {
+                finalized.push((backfill.remove(&bucket).unwrap(), policy.trigger()));
+            }
+        }
+        if !backfill.contains_key(&bucket) {
+            if backfill.len() >= self.backfill.max {
+                let (_, oldest) = backfill.pop_first().unwrap();
+                finalized.push((oldest, RotationTrigger::Interval));
+            }
+            let named = DateTime::from_timestamp_millis(bucket as i64).unwrap();
+            let mut ctx = self.create_context(bucket / 1000, named)?;
+            ctx.bucket = bucket;
+            self.notify_watchers(ctx.file_path.clone());
+            backfill.insert(bucket, ctx);
+        }
+        let ctx = backfill.get_mut(&bucket).unwrap();
+        let at = at.duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let written = ctx.write(line, at).and_then(|_| self.write_through(ctx));
+        drop(backfill);
+
+        let mut result = written;
+        for (old, trigger) in finalized {
+            if let Err(e) = self.finalize(old, trigger) {
+                error!("Failed to finalize a backfilled file: {}", e);
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+}
+
+impl RotatingFile {
+    /// Returns a handle that aborts this instance from a signal handler, to
+    /// be taken beforehand, e.g. into a `static OnceLock`.
+    pub fn abort_handle(&self) -> AbortHandle {
+        AbortHandle {
+            state: self.inner.abort.clone(),
+        }
+    }
+
+    /// Shuts down without waiting, e.g. in a panic hook or the thread
+    /// handling SIGTERM, where `close` could block for as long as
+    /// compression takes:
+    ///
+    /// - writes fail with `ErrorKind::BrokenPipe` from now on,
+    /// - buffered lines are flushed and synced, giving up after `deadline`,
+    /// - background threads are told to stop and compression jobs are
+    ///   abandoned, not joined.
+    ///
+    /// Compression deletes a raw file only once its archive is complete, so
+    /// exiting right after leaves every rotated-out file readable, at worst
+    /// next to a partial archive. Jobs not started yet leave the raw file.
+    ///
+    /// Locks are only tried, so a thread interrupted while holding one
+    /// can't hang it. Still, it allocates and isn't async-signal-safe, call
+    /// it from a regular thread, or [`AbortHandle::abort`] from a signal
+    /// handler. Fails with `ErrorKind::TimedOut` if buffered lines couldn't
+    /// be flushed in time.
+    pub fn abort(&self, deadline: Duration) -> Result<(), RotatingFileError> {
+        let inner = &self.inner;
+        let deadline = Instant::now() + deadline;
+        inner.abort.aborted.store(true, Ordering::Release);
+        inner.threads.stopped.store(true, Ordering::Release);
+        if let Ok(services) = inner.threads.services.try_lock() {
+            for handle in services.iter() {
+                handle.thread().unpark();
+            }
+        }
+
+        let mut flushed = false;
+        let mut backfill_flushed = false;
+        loop {
+            if !flushed {
+                if let Some(mut guard) = try_lock(&inner.context) {
+                    if let Some(ctx) = guard.current.as_mut() {
+                        ctx.flush()?;
+                    }
+                    flushed = true;
+                }
+            }
+            if !backfill_flushed {
+                if let Some(mut backfill) = try_lock(&inner.backfill.files) {
+                    for ctx in backfill.values_mut() {
+                        ctx.flush()?;
+                    }
+                    backfill_flushed = true;
+                }
+            }
+            if (flushed && backfill_flushed) || Instant::now() >= deadline {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        if flushed && backfill_flushed {
+            Ok(())
+        } else {
+            Err(Error::new(ErrorKind::TimedOut, "gave up flushing before the deadline").into())
+        }
+    }
+}
+
+/// Stops a `RotatingFile` from a signal handler, see
+/// [`RotatingFile::abort_handle`].
+#[derive(Clone)]
+pub struct AbortHandle {
+    state: Arc<AbortState>,
+}
+
+// what `AbortHandle::abort` touches, atomics only
+#[derive(Debug)]
+pub(crate) struct AbortState {
+    pub(crate) aborted: AtomicBool,
+    // a descriptor of the active file, -1 if none
+    #[cfg(unix)]
+    fd: AtomicI32,
+}
+
+impl Default for AbortState {
+    fn default() -> Self {
+        AbortState {
+            aborted: AtomicBool::new(false),
+            #[cfg(unix)]
+            fd: AtomicI32::new(-1),
+        }
+    }
+}
+
+impl AbortState {
+    /// Records `ctx` as the file to sync on abort.
+    pub(crate) fn publish(&self, ctx: &CurrentContext) {
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::AsRawFd;
+            let fd = ctx.sync_file.as_ref().map_or(-1, |file| file.as_raw_fd());
+            self.fd.store(fd, Ordering::Release);
+        }
+        #[cfg(not(unix))]
+        let _ = ctx;
+    }
+}
+
+impl AbortHandle {
+    /// Makes writes fail with `ErrorKind::BrokenPipe` from now on and syncs
+    /// the bytes of the active file already handed to the OS, with nothing
+    /// but atomics and `fsync`, so that it's async-signal-safe, e.g. in a
+    /// SIGTERM handler.
+    ///
+    /// Lines still buffered in memory are lost, there are none with
+    /// `with_write_through`. Background threads aren't stopped, the process
+    /// is expected to exit, which leaves files as after [`RotatingFile::abort`].
+    /// Only sets the flag on platforms other than unix.
+    pub fn abort(&self) {
+        self.state.aborted.store(true, Ordering::Release);
+        #[cfg(unix)]
+        {
+            let fd = self.state.fd.load(Ordering::Acquire);
+            if fd >= 0 {
+                // SAFETY: `fsync` is async-signal-safe, and harmless on a
+                // descriptor closed and reused since the file was rotated out
+                unsafe {
+                    libc::fsync(fd);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::{Sanitizer, Utf8Policy};
+
+    #[test]
+    fn sanitize() {
+        let colored = b"\x1b[1;31mERROR\x1b[0m \x1b]0;title\x07done \xff";
+
+        let sanitizer = Sanitizer::default();
+        assert_eq!(
+            "\x1b[1;31mERROR\x1b[0m \x1b]0;title\x07done \u{fffd}",
+            sanitizer.sanitize(colored).unwrap()
+        );
+
+        let sanitizer = Sanitizer {
+            strip_ansi: true,
+            ..Default::default()
+        };
+        assert_eq!("ERROR done \u{fffd}", sanitizer.sanitize(colored).unwrap());
+
+        let sanitizer = Sanitizer {
+            utf8: Utf8Policy::Validate,
+            strip_ansi: true,
+        };
+        assert_eq!(
+            ErrorKind::InvalidData,
+            sanitizer.sanitize(colored).unwrap_err().kind()
+        );
+        assert_eq!("plain", sanitizer.sanitize(b"plain").unwrap());
+    }
+
+    #[test]
+    fn copy_and_delete() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let root_dir = "./target/tmp76";
+        let _ = std::fs::remove_dir_all(root_dir);
+        std::fs::create_dir_all(root_dir).unwrap();
+        let (from, to) = (
+            std::path::Path::new(root_dir).join("from.log.gz"),
+            std::path::Path::new(root_dir).join("to.log.gz"),
+        );
+        std::fs::write(&from, "archived").unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(1_714_521_600);
+        let f = std::fs::File::options().write(true).open(&from).unwrap();
+        f.set_modified(modified).unwrap();
+        drop(f);
+        let mut permissions = std::fs::metadata(&from).unwrap().permissions();
+        permissions.set_readonly(true);
+        std::fs::set_permissions(&from, permissions.clone()).unwrap();
+
+        // a failed copy keeps the source
+        let missing = std::path::Path::new(root_dir).join("missing/to.log.gz");
+        assert!(super::copy_and_delete(&from, &missing).is_err());
+        assert_eq!("archived", std::fs::read_to_string(&from).unwrap());
+
+        super::copy_and_delete(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!("archived", std::fs::read_to_string(&to).unwrap());
+        let metadata = std::fs::metadata(&to).unwrap();
+        assert_eq!(modified, metadata.modified().unwrap());
+        assert_eq!(permissions, metadata.permissions());
+
+        // never overwrites
+        std::fs::write(&from, "again").unwrap();
+        let e = super::move_file(&from, &to).unwrap_err();
+        assert_eq!(ErrorKind::AlreadyExists, e.kind());
+        assert!(from.exists());
+
+        std::fs::remove_dir_all(root_dir).unwrap();
+    }
+
+    #[test]
+    fn compressing_flush_failure() {
+        use std::io::{Read, Write};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::{Arc, Mutex};
+
+        // fails writes while `failing` is set
+        #[derive(Clone, Default)]
+        struct Flaky {
+            failing: Arc<AtomicBool>,
+            written: Arc<Mutex<Vec<u8>>>,
+        }
+        impl Write for Flaky {
+            fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+                if self.failing.load(Ordering::Relaxed) {
+                    return Err(ErrorKind::StorageFull.into());
+                }
+                self.written.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> Result<(), std::io::Error> {
+                Ok(())
+            }
+        }
+        impl super::Sink for Flaky {
+            fn sync(&mut self) -> Result<(), std::io::Error> {
+                Ok(())
+            }
+        }
+
+        let flaky = Flaky::default();
+        let compression = crate::Compression::GZip.with_level(6);
+        let (mut sink, _) = super::compressing(Box::new(flaky.clone()), &compression).unwrap();
+        sink.write_all(b"first\n").unwrap();
+        flaky.failing.store(true, Ordering::Relaxed);
+        assert_eq!(ErrorKind::StorageFull, sink.flush().unwrap_err().kind());
+        // fails instead of panicking while the disk is full
+        assert!(sink.write_all(b"lost\n").is_err());
+        assert!(sink.sync().is_err());
+
+        flaky.failing.store(false, Ordering::Relaxed);
+        sink.write_all(b"second\n").unwrap();
+        sink.sync().unwrap();
+        let written = flaky.written.lock().unwrap().clone();
+        let mut content = String::new();
+        flate2::read::MultiGzDecoder::new(written.as_slice())
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!("first\nsecond\n", content);
+    }
+}